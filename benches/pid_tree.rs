@@ -0,0 +1,40 @@
+//! Walking the process tree, as `tu` does every sample, over synthetic process tables
+
+use std::hint::black_box;
+
+use criterion::{BenchmarkId, Criterion, criterion_group, criterion_main};
+use sysinfo::Pid;
+use tools::process::system::walk_pid_trees;
+
+/// PID of the root of the tree being walked
+const ROOT: usize = 1000;
+
+/// A table of `count` processes.  Those at even offsets from [`ROOT`] form a tree below it,
+/// each with four children, and the rest hang off init, as unrelated processes would.
+fn process_table(count: usize) -> Vec<(Pid, Option<Pid>)> {
+    (0..count)
+        .map(|i| {
+            let parent = match i {
+                0 => 1,
+                i if i % 2 == 1 => 1,
+                i => ROOT + 2 * ((i / 2 - 1) / 4),
+            };
+            (Pid::from(ROOT + i), Some(Pid::from(parent)))
+        })
+        .collect()
+}
+
+fn pid_tree(c: &mut Criterion) {
+    let mut group = c.benchmark_group("walk_pid_trees");
+    group.sample_size(10);
+    for count in [1_000, 5_000, 20_000] {
+        let table = process_table(count);
+        group.bench_with_input(BenchmarkId::from_parameter(count), &table, |b, table| {
+            b.iter(|| walk_pid_trees(&[Pid::from(ROOT)], || black_box(table).iter().copied()))
+        });
+    }
+    group.finish();
+}
+
+criterion_group!(benches, pid_tree);
+criterion_main!(benches);
@@ -0,0 +1,48 @@
+//! Splitting object versions into current, earlier and orphaned, as `bu size` does once
+//! a bucket is listed, over synthetic listings
+
+use std::hint::black_box;
+
+use aws_sdk_s3::types::ObjectVersion;
+use criterion::{BenchmarkId, Criterion, Throughput, criterion_group, criterion_main};
+use tools::s3::size::{Stats, VersionData};
+
+/// `count` versions, three per key across a hundred prefixes, listed as S3 would: by key,
+/// newest first.  Every third key has been deleted, so its versions are orphaned.
+fn versions(count: usize) -> Vec<ObjectVersion> {
+    (0..count)
+        .map(|i| {
+            let object = i / 3;
+            let deleted = object % 3 == 0;
+            ObjectVersion::builder()
+                .key(format!("prefix-{}/object-{}", object % 100, object))
+                .version_id(format!("version-{}", i))
+                .size((i % 4096) as i64)
+                .is_latest(i % 3 == 0 && !deleted)
+                .build()
+        })
+        .collect()
+}
+
+fn partition(c: &mut Criterion) {
+    let mut group = c.benchmark_group("size_report");
+    group.sample_size(10);
+    for count in [100_000, 1_000_000] {
+        let versions = versions(count);
+        group.throughput(Throughput::Elements(count as u64));
+        group.bench_with_input(
+            BenchmarkId::new("version_data", count),
+            &versions,
+            |b, versions| b.iter(|| VersionData::from_versions(black_box(versions))),
+        );
+        group.bench_with_input(
+            BenchmarkId::new("total", count),
+            &versions,
+            |b, versions| b.iter(|| Stats::from_object_versions(black_box(versions))),
+        );
+    }
+    group.finish();
+}
+
+criterion_group!(benches, partition);
+criterion_main!(benches);
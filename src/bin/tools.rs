@@ -0,0 +1,48 @@
+use clap::{CommandFactory, Parser, Subcommand};
+#[cfg(feature = "s3")]
+use tools::cli::s3;
+use tools::{
+    cli::monitor,
+    exit::{exit_with, parse_args},
+    output::man::{LOGGING_ENV, man_page},
+};
+
+#[derive(Parser)]
+#[command(name = "tools", version, about)]
+/// Task usage monitoring and S3 bucket utilities.  `tu` and `bu` are the same as
+/// `tools monitor` and `tools s3`.
+struct Cli {
+    #[command(subcommand)]
+    command: Command,
+}
+
+#[derive(Subcommand)]
+enum Command {
+    /// Run a command, monitoring its CPU, GPU and RAM usage (same as `tu`)
+    Monitor(Box<monitor::Cli>),
+    /// Work with object versions in S3 (same as `bu`)
+    #[cfg(feature = "s3")]
+    S3(Box<s3::Cli>),
+    /// Print a man page for `tools` to stdout
+    Man,
+}
+
+fn main() {
+    exit_with(match parse_args::<Cli>().command {
+        Command::Monitor(cli) => monitor::run(*cli),
+        #[cfg(feature = "s3")]
+        Command::S3(cli) => s3::run(*cli),
+        Command::Man => {
+            #[cfg(feature = "s3")]
+            let env: Vec<(&str, &str)> = LOGGING_ENV
+                .into_iter()
+                .chain(s3::AWS_ENV)
+                .chain([s3::CI_ENV])
+                .collect();
+            #[cfg(not(feature = "s3"))]
+            let env = LOGGING_ENV;
+            print!("{}", man_page(&Cli::command(), &env));
+            Ok(())
+        }
+    })
+}
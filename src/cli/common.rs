@@ -0,0 +1,202 @@
+use std::{
+    io::IsTerminal,
+    path::{Path, PathBuf},
+    time::Duration,
+};
+
+use clap::Args;
+use color_eyre::{Result, config::HookBuilder};
+
+use crate::{
+    log::{LogFormat, LogOptions, LogTarget, setup_logging},
+    output::{
+        console,
+        dialect::{CsvDialect, Quoting, parse_delimiter},
+        format::{ReportFormat, is_stdout},
+        progress,
+        template::FileNameValues,
+        units::SizeUnits,
+    },
+};
+
+/// Turns colour off whatever the flags say, see <https://no-color.org>
+pub const NO_COLOR_VAR: &str = "NO_COLOR";
+
+/// Logging and console flags shared by every binary, and available to each subcommand
+#[derive(Args, Debug, Clone)]
+pub struct CommonArgs {
+    /// Verbose mode (-v, -vv, -vvv).  $RUST_LOG overrides the filter for individual modules
+    #[arg(short, long, action = clap::ArgAction::Count, global = true)]
+    pub verbose: u8,
+
+    /// Only log errors, and skip status output such as log filter levels, the run summary
+    /// and which files were written
+    #[arg(short, long, conflicts_with = "verbose", global = true)]
+    pub quiet: bool,
+
+    /// Don't colour anything: log lines, error reports, size bars, comparisons or the
+    /// dashboard (also turned off by $NO_COLOR).  Output to a file or pipe is never coloured.
+    #[arg(long, global = true)]
+    pub no_color: bool,
+
+    /// Also append the log to this file, at debug level whatever the verbosity (defaults to
+    /// $TOOLS_LOG_FILE)
+    #[arg(long, value_name = "PATH", global = true)]
+    pub log_file: Option<PathBuf>,
+
+    /// How log lines are written: text, or json for log aggregators
+    #[arg(long, value_name = "FORMAT", default_value_t = LogFormat::Text, global = true)]
+    pub log_format: LogFormat,
+
+    /// Where the log goes: stderr, journald or syslog, e.g. when run by a systemd unit
+    #[arg(long, value_name = "TARGET", default_value_t = LogTarget::Stderr, global = true)]
+    pub log_target: LogTarget,
+
+    /// Write progress events, such as pages listed or samples written, as lines of JSON to
+    /// this file or FIFO (--progress-json=PATH), or to stderr if no path is given
+    #[arg(
+        long,
+        value_name = "PATH",
+        num_args = 0..=1,
+        default_missing_value = "-",
+        require_equals = true,
+        global = true
+    )]
+    pub progress_json: Option<PathBuf>,
+
+    /// Units for sizes written for people to read: binary (KiB, MiB, GiB) or si (kB, MB, GB).
+    /// Columns in bytes or MiB are unaffected.
+    #[arg(long, value_name = "UNITS", default_value_t = SizeUnits::Binary, global = true)]
+    pub units: SizeUnits,
+}
+
+impl CommonArgs {
+    /**
+     * Whether to colour console output, i.e. logs and errors on stderr
+     */
+    pub fn color(&self) -> bool {
+        self.color_allowed() && std::io::stderr().is_terminal()
+    }
+
+    /**
+     * Whether colour may be used anywhere, as neither `--no-color` nor `$NO_COLOR` say not
+     */
+    fn color_allowed(&self) -> bool {
+        !self.no_color && std::env::var_os(NO_COLOR_VAR).is_none_or(|value| value.is_empty())
+    }
+
+    /**
+     * Install the error report handler, in colour or not, choose the size units and whether
+     * anything may be coloured, open the progress stream, if any, then set up logging
+     */
+    pub fn init(&self) -> Result<()> {
+        self.units.set_default();
+        console::allow_color(self.color_allowed());
+        if let Some(path) = &self.progress_json {
+            progress::init(path)?;
+        }
+        HookBuilder::default()
+            .theme(match self.color() {
+                true => color_eyre::config::Theme::dark(),
+                false => color_eyre::config::Theme::new(),
+            })
+            .install()?;
+        setup_logging(&self.log_options())
+    }
+
+    pub fn log_options(&self) -> LogOptions {
+        LogOptions {
+            verbosity: self.verbose,
+            quiet: self.quiet,
+            color: self.color(),
+            file: self.log_file.clone(),
+            format: self.log_format,
+            target: self.log_target,
+        }
+    }
+}
+
+/// Where, and in which format, a command writes its report
+#[derive(Args, Debug, Clone)]
+pub struct OutputArgs {
+    /// Output file.  Defaults to a name with the format's extension, e.g. task_usage.jsonl.
+    /// {date}, {time}, {host} and, for tu, {command} are filled in (e.g. usage_{date}_{host}.csv),
+    /// and - writes to stdout, leaving everything else on stderr, for piping into other tools.
+    #[arg(
+        short = 'f',
+        long = "file",
+        visible_aliases = ["out-file", "out"],
+        short_alias = 'o',
+        value_name = "PATH"
+    )]
+    pub file: Option<PathBuf>,
+
+    /// Format of the output file: csv, tsv (tab separated), json (an array of objects), jsonl
+    /// (an object per line), table (aligned columns) or parquet.  Parquet has typed columns and is much
+    /// smaller for long runs, but is only readable once finished.
+    #[arg(long, value_name = "FORMAT", default_value_t = ReportFormat::Csv)]
+    pub format: ReportFormat,
+
+    /// Field delimiter of CSV files: a single character such as ';', which spreadsheets
+    /// expect where the comma is the decimal separator, or tab
+    #[arg(long, value_name = "CHAR", default_value = ",", value_parser = parse_delimiter)]
+    pub delimiter: u8,
+
+    /// Which CSV fields are quoted: necessary (those holding the delimiter, quotes or line
+    /// breaks), always, or non-numeric
+    #[arg(long, value_name = "WHEN", default_value_t = Quoting::Necessary)]
+    pub quote: Quoting,
+}
+
+impl OutputArgs {
+    /**
+     * The output file, with its placeholders filled in from `values`, or `default` with the
+     * format's extension if none was given
+     */
+    pub fn path(&self, default: &str, values: &FileNameValues) -> Result<PathBuf> {
+        match &self.file {
+            Some(file) => values.expand_path(file),
+            None => Ok(Path::new(default).with_extension(self.format.extension())),
+        }
+    }
+
+    /**
+     * Whether the report goes to stdout, so nothing else should
+     */
+    pub fn to_stdout(&self) -> bool {
+        self.file.as_deref().is_some_and(is_stdout)
+    }
+
+    /**
+     * How CSV files, including any besides the report, are delimited and quoted
+     */
+    pub fn csv_dialect(&self) -> CsvDialect {
+        CsvDialect {
+            delimiter: self.delimiter,
+            quoting: self.quote,
+        }
+    }
+}
+
+/**
+ * Parse a duration given on the command line, e.g. `500ms`, `90s` or `2h30m`.  A bare number
+ * is a number of seconds, as flags such as `--interval` used to take.
+ */
+pub fn parse_duration(s: &str) -> Result<Duration, String> {
+    let s = s.trim();
+    match s.parse::<u64>() {
+        Ok(seconds) => Ok(Duration::from_secs(seconds)),
+        Err(_) => humantime::parse_duration(s)
+            .map_err(|e| format!("expected a duration such as 500ms, 90s or 2h30m ({})", e)),
+    }
+}
+
+/**
+ * As [`parse_duration`], for flags such as `--interval` where zero would mean busy looping
+ */
+pub fn parse_nonzero_duration(s: &str) -> Result<Duration, String> {
+    match parse_duration(s)? {
+        Duration::ZERO => Err("must be longer than zero".to_string()),
+        duration => Ok(duration),
+    }
+}
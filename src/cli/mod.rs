@@ -0,0 +1,9 @@
+pub mod common;
+pub mod monitor;
+#[cfg(feature = "s3")]
+pub mod s3;
+
+pub use common::{CommonArgs, OutputArgs};
+
+#[cfg(test)]
+mod tests;
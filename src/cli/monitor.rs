@@ -0,0 +1,1909 @@
+#[cfg(windows)]
+use crate::process::job::JobObject;
+#[cfg(feature = "nvml")]
+use crate::process::{gpu::GpuSession, gpu_info::GpuInfo};
+use crate::{
+    cli::{CommonArgs, OutputArgs, common::{parse_duration, parse_nonzero_duration}},
+    exit::{Failure, SUCCESS, command_exit_code},
+    output::{
+        aggregate::Aggregator,
+        compress::Compression,
+        console::{self, ConsoleFormat, render_fields},
+        dialect::CsvDialect,
+        format::{ReportFormat, ReportOptions, is_stdout},
+        man::{LOGGING_ENV, man_page},
+        notify::{Notification, WebhookNotifier},
+        parquet::ColumnType,
+        progress::{self, ProgressEvent},
+        rotating::{RotatingCsvWriter, Rotation},
+        schema::{
+            BENCH_SCHEMA, DEVICE_USAGE_SCHEMA, PROCESS_USAGE_SCHEMA, SCHEMA_COLUMN, USAGE_SCHEMA,
+        },
+        sink::Sink,
+        statsd::StatsdSink,
+        table::TableSink,
+        template::FileNameValues,
+        units::{format_size, parse_size},
+    },
+    process::{
+        apple_gpu::AppleGpu,
+        bench::{BenchRun, BenchStats, Spread},
+        capture::OutputCapture,
+        compare::TraceComparison,
+        dashboard::{Dashboard, DashboardAction, LogBuffer},
+        gpu::{DeviceUsage, GpuBackend, MigInstance},
+        metadata::{MAX_PROCESSES, RunMetadata},
+        monitor::{Energy, Measurements, Monitor, Target, UsageSample, exit_status_code},
+        nvidia_smi::NvidiaSmi,
+        plot::{plot_comparison, plot_usage},
+        report::write_report,
+        sampling::{AdaptiveInterval, Schedule},
+        signals::SignalCatcher,
+        summary::{RunSummary, SummaryAccumulator},
+        system::{CpuRamUsage, MemoryMode, NumaBytes, ProcessUsage, ThreadAccounting},
+        trace::UsageTrace,
+    },
+};
+use bytesize::ByteSize;
+use chrono::{DateTime, Local, SecondsFormat, Utc};
+use clap::{CommandFactory, Parser, Subcommand, ValueEnum};
+use color_eyre::eyre::{Context, Result, bail};
+use regex::Regex;
+use std::{
+    collections::{HashMap, HashSet},
+    fs::{File, create_dir_all},
+    ops::ControlFlow,
+    path::{Path, PathBuf},
+    str::FromStr,
+    time::{Duration, Instant},
+};
+use sysinfo::{Pid, Signal};
+
+static MI_B: f32 = 2u64.pow(20) as f32;
+const DEFAULT_FILE: &str = "task_usage.csv";
+static PER_PROCESS_FILE: &str = "process_usage.csv";
+static PER_DEVICE_FILE: &str = "device_usage.csv";
+
+/// Shortest pause, and how long to use it for at the start, in adaptive mode
+const ADAPTIVE_MIN_INTERVAL: Duration = Duration::from_millis(500);
+const ADAPTIVE_WARMUP: Duration = Duration::from_secs(120);
+
+/// How long each `powermetrics` call spends measuring Apple GPU usage
+const APPLE_GPU_WINDOW: Duration = Duration::from_millis(200);
+
+#[cfg(not(feature = "nvml"))]
+const NO_NVML: &str = "Built without NVML support; rebuild with the `nvml` feature";
+
+#[derive(Parser)]
+#[command(name = "tu", version, about, subcommand_negates_reqs = true)]
+/// Run a command, monitoring CPU and RAM usage at regular intervals and saving to a CSV file.
+pub struct Cli {
+    #[command(flatten)]
+    pub common: CommonArgs,
+
+    #[command(subcommand)]
+    subcommand: Option<TuCommand>,
+
+    /// Whether to monitor NVIDIA GPUs through NVML
+    #[arg(long, value_enum, default_value_t = GpuMode::Auto)]
+    gpu: GpuMode,
+
+    /// Same as `--gpu on`
+    #[arg(short, long, action, conflicts_with = "gpu")]
+    nvml: bool,
+
+    /// Report Apple Silicon GPU utilisation using `powermetrics` (requires sudo), in place of
+    /// NVIDIA GPUs
+    #[arg(long, conflicts_with = "nvml")]
+    apple_gpu: bool,
+
+    /// Only monitor these GPUs, by NVML index (e.g. 0,2)
+    #[arg(long, value_name = "INDICES", value_delimiter = ',')]
+    gpu_index: Vec<u32>,
+
+    /// Also record Linux memory pressure: the % of the last 10s in which tasks were stalled
+    /// waiting for memory, machine-wide
+    #[arg(long)]
+    memory_pressure: bool,
+
+    /// Also record the tree's resident memory on each NUMA node (Linux only), to spot memory
+    /// allocated on a different socket from the one doing the work
+    #[arg(long, conflicts_with = "system")]
+    numa: bool,
+
+    /// How to count each process's RAM.  Summing RSS over a tree counts memory shared
+    /// between processes (e.g. forked workers) more than once; PSS and USS don't (Linux only).
+    #[arg(long, value_enum, default_value_t = MemoryAccounting::Rss)]
+    memory_mode: MemoryAccounting,
+
+    /// Whether threads get their own rows in `--per-process` output.  `threads` splits each
+    /// process's CPU between its threads, keeping totals the same; `tasks` counts every
+    /// thread as the OS reports it, so CPU and RAM of multi-threaded processes are counted
+    /// more than once (Linux only).
+    #[arg(long, value_enum, default_value_t = ThreadMode::Processes)]
+    thread_accounting: ThreadMode,
+
+    /// Also record whole-machine CPU, RAM and load average, to show whether the monitored
+    /// tree was competing with other work
+    #[arg(long, conflicts_with = "system")]
+    system_baseline: bool,
+
+    /// Also record mean CPU clock speed and, on Linux, how often the CPUs were thermally or
+    /// power throttled since the last sample
+    #[arg(long)]
+    cpu_frequency: bool,
+
+    /// Also record energy used each interval: CPU package and DRAM from RAPL counters
+    /// (machine-wide, usually needs root) and GPU from NVML power draw.  The summary gives
+    /// the total in kWh.
+    #[arg(long)]
+    energy: bool,
+
+    /// Also record GPU temperature, power draw and fan speed
+    #[arg(long)]
+    gpu_thermals: bool,
+
+    /// Also record GPU memory controller, video encoder and decoder utilisation, for
+    /// workloads such as transcoding where SM utilisation says little
+    #[arg(long)]
+    gpu_engines: bool,
+
+    /// Also record PCIe throughput to and from the GPUs, and how busy their memory
+    /// controllers were, to show whether a GPU is starved of data
+    #[arg(long)]
+    gpu_bandwidth: bool,
+
+    /// Time between samples (e.g. 500ms, 10s or 1m; a bare number is seconds)
+    #[arg(
+        short,
+        long,
+        value_name = "DURATION",
+        value_parser = parse_nonzero_duration,
+        default_value = "1s"
+    )]
+    interval: Duration,
+
+    /// Sample every 500ms at first and while usage changes quickly, backing off to
+    /// --max-interval through steady phases (ignores --interval)
+    #[arg(long)]
+    adaptive: bool,
+
+    /// Longest pause between samples in adaptive mode (e.g. 1m)
+    #[arg(
+        long,
+        value_name = "DURATION",
+        value_parser = parse_duration,
+        default_value = "1m",
+        requires = "adaptive"
+    )]
+    max_interval: Duration,
+
+    /// How often to scan every process for new children of the monitored tree.  In between,
+    /// only processes already in the tree are refreshed, which is much cheaper on busy
+    /// machines.  Anything up to --interval scans on every sample.
+    #[arg(
+        long,
+        value_name = "DURATION",
+        value_parser = parse_nonzero_duration,
+        default_value = "5s"
+    )]
+    rescan_interval: Duration,
+
+    /// Fold samples taken after --retain-raw into a min, a mean and a max row per period
+    /// (e.g. 1m), keeping the output of multi-week runs small while preserving peaks.  Adds
+    /// an `aggregate` column saying which each row is, or `raw`.
+    #[arg(long, value_name = "DURATION", value_parser = parse_duration)]
+    aggregate: Option<Duration>,
+
+    /// How long into the run every sample is written before --aggregate takes over
+    #[arg(
+        long,
+        value_name = "DURATION",
+        value_parser = parse_duration,
+        default_value = "1h"
+    )]
+    retain_raw: Duration,
+
+    /// Monitor an already running process (and its descendants) instead of running a command
+    #[arg(long, conflicts_with_all = ["command", "pattern", "system"])]
+    pid: Option<u32>,
+
+    /// Monitor all processes (and their descendants) whose command line matches a regex,
+    /// running until interrupted
+    #[arg(
+        long = "match",
+        value_name = "REGEX",
+        value_parser = Regex::new,
+        conflicts_with_all = ["command", "system"]
+    )]
+    pattern: Option<Regex>,
+
+    /// Monitor the whole machine indefinitely rather than a process tree, starting a new
+    /// dated output file each day unless `--rotate` says otherwise
+    #[arg(long, conflicts_with_all = ["command", "per_process"])]
+    system: bool,
+
+    /// Measure a cgroup (v2) as a whole, creating it if necessary.  A command is run inside
+    /// it; without one, whatever is already in the cgroup is monitored until it empties.
+    #[arg(long, value_name = "PATH", conflicts_with_all = ["pid", "pattern", "system"])]
+    cgroup: Option<PathBuf>,
+
+    /// Monitor everything in a running Docker/containerd/Podman container, by ID (requires
+    /// cgroup v2)
+    #[arg(
+        long,
+        value_name = "ID",
+        conflicts_with_all = ["pid", "pattern", "system", "cgroup", "command"]
+    )]
+    container: Option<String>,
+
+    /// Command to run
+    #[arg(
+        last = true,
+        required_unless_present_any = ["pid", "pattern", "system", "cgroup", "container"]
+    )]
+    command: Vec<String>,
+
+    /// How to write the timestamp column
+    #[arg(long, value_enum, default_value_t = TimestampFormat::Local)]
+    timestamp_format: TimestampFormat,
+
+    #[command(flatten)]
+    output: OutputArgs,
+
+    /// Compress output CSV and JSON files as they're written, adding `.gz` or `.zst` to their
+    /// names
+    #[arg(long, value_enum)]
+    compress: Option<CompressFormat>,
+
+    /// Start new output files `daily`, `hourly`, or once they reach a size (e.g. `100MB`).
+    /// Defaults to daily with `--system`, otherwise never.
+    #[arg(long, value_name = "WHEN", value_parser = parse_rotation)]
+    rotate: Option<Rotation>,
+
+    /// Only keep this many of the most recent output files, deleting older ones as new ones
+    /// are started
+    #[arg(long, value_name = "FILES", value_parser = clap::value_parser!(u64).range(1..))]
+    keep: Option<u64>,
+
+    /// Directory in which to also write a CSV with one row per process in the tree
+    #[arg(long, value_name = "OUT_DIR")]
+    per_process: Option<PathBuf>,
+
+    /// Directory in which to also write a CSV with one row per GPU device
+    #[arg(long, value_name = "OUT_DIR")]
+    per_device: Option<PathBuf>,
+
+    /// Record the command line and these environment variables (e.g.
+    /// `CUDA_VISIBLE_DEVICES,OMP_NUM_THREADS`) of each distinct process in the tree in the
+    /// metadata file
+    #[arg(
+        long,
+        value_name = "VARS",
+        value_delimiter = ',',
+        conflicts_with = "system"
+    )]
+    capture_env: Option<Vec<String>>,
+
+    /// Also push each sample to a StatsD endpoint (same as `--output statsd:HOST:PORT`)
+    #[arg(long, value_name = "HOST:PORT")]
+    statsd: Option<String>,
+
+    /// Prefix for StatsD metric names
+    #[arg(long, default_value = "tu")]
+    statsd_prefix: String,
+
+    /// Value of the `label` tag on StatsD metrics (defaults to the command name)
+    #[arg(long)]
+    statsd_label: Option<String>,
+
+    /// Also send each sample to a file as `FORMAT:PATH` (any --format, e.g. `csv:PATH` or
+    /// `jsonl:PATH`), `table` (aligned columns on stdout) or `statsd:HOST:PORT`.  Can be
+    /// repeated.  Paths can have placeholders, as for --file.
+    #[arg(long = "output", value_name = "SINK")]
+    outputs: Vec<OutputSpec>,
+
+    /// POST a JSON summary of the run (command, exit code, duration and peak usage) to this
+    /// URL when it ends, e.g. a Slack incoming webhook
+    #[arg(long, value_name = "URL")]
+    notify_url: Option<String>,
+
+    /// Stop if the tree's resident memory exceeds this (e.g. 32GB or 1.5GiB)
+    #[arg(long, value_name = "SIZE", value_parser = parse_size, conflicts_with = "system")]
+    max_ram: Option<ByteSize>,
+
+    /// Stop if the tree's GPU memory exceeds this (e.g. 20GB or 16GiB)
+    #[arg(long, value_name = "SIZE", value_parser = parse_size, conflicts_with = "system")]
+    max_gpu_mem: Option<ByteSize>,
+
+    /// Stop if monitoring has run for longer than this (e.g. 4h)
+    #[arg(
+        long,
+        value_name = "DURATION",
+        value_parser = parse_duration,
+        conflicts_with = "system"
+    )]
+    max_runtime: Option<Duration>,
+
+    /// Terminate the command's process tree if it runs for longer than this (e.g. 2h), and
+    /// exit with code 6
+    #[arg(
+        long,
+        value_name = "DURATION",
+        value_parser = parse_duration,
+        conflicts_with_all = ["pid", "pattern", "system", "container"]
+    )]
+    timeout: Option<Duration>,
+
+    /// When a limit is breached, SIGTERM the tree, then SIGKILL anything left after 10s
+    #[arg(long)]
+    kill_on_breach: bool,
+
+    /// Exit with 0 regardless of how the command exits, rather than 10 plus its exit code
+    #[arg(long)]
+    ignore_exit_code: bool,
+
+    /// Also write the end-of-run summary to a file (JSON if it ends in `.json`, TSV if it ends
+    /// in `.tsv`, else CSV).  The name can have placeholders, as for --file.
+    #[arg(long, value_name = "FILE")]
+    summary: Option<PathBuf>,
+
+    /// How the end-of-run summary is printed: text, table (which is also Markdown, for
+    /// pasting into chat) or tsv (for pasting into spreadsheets)
+    #[arg(long, value_name = "FORMAT", default_value_t = ConsoleFormat::Text)]
+    console_format: ConsoleFormat,
+
+    /// Render CPU/RAM/GPU charts to an SVG file at the end of the run
+    #[arg(long, value_name = "FILE")]
+    plot: Option<PathBuf>,
+
+    /// Show a live dashboard of usage and the command's recent output (press q to quit)
+    #[arg(long)]
+    tui: bool,
+
+    /// Copy the command's stdout to FILE, each line prefixed with a timestamp, while still
+    /// passing it through to the terminal
+    #[arg(long, value_name = "FILE")]
+    log_stdout: Option<PathBuf>,
+
+    /// As `--log-stdout`, for stderr
+    #[arg(long, value_name = "FILE")]
+    log_stderr: Option<PathBuf>,
+
+    /// Copy both stdout and stderr to FILE, labelling which stream each line came from
+    #[arg(long, value_name = "FILE", conflicts_with_all = ["log_stdout", "log_stderr"])]
+    tee: Option<PathBuf>,
+}
+
+#[derive(Clone, Copy, Debug, ValueEnum)]
+enum GpuMode {
+    /// Monitor GPUs if NVML can be initialised, otherwise carry on with just CPU and RAM
+    Auto,
+    /// Monitor GPUs, failing if NVML can't be initialised
+    On,
+    Off,
+}
+
+#[derive(Clone, Copy, Debug, ValueEnum)]
+enum ThreadMode {
+    /// One row per process, including its threads' CPU
+    Processes,
+    /// One row per thread, with each process's memory on its main thread's row
+    Threads,
+    /// Every process and thread as the OS reports them, double counting
+    Tasks,
+}
+
+#[derive(Clone, Copy, Debug, ValueEnum)]
+enum MemoryAccounting {
+    /// Resident set size, counting shared pages in every process which maps them
+    Rss,
+    /// Proportional set size, dividing shared pages between the processes sharing them
+    Pss,
+    /// Unique set size, counting only pages private to each process
+    Uss,
+}
+
+#[derive(Clone, Copy, Debug, ValueEnum)]
+enum CompressFormat {
+    Gzip,
+    Zstd,
+}
+
+#[derive(Clone, Copy, Debug, ValueEnum)]
+enum TimestampFormat {
+    /// Local time to the second, e.g. 2025-06-01 13:45:00
+    Local,
+    /// RFC 3339 with the local offset, e.g. 2025-06-01T13:45:00.123+01:00
+    Rfc3339,
+    /// RFC 3339 in UTC, e.g. 2025-06-01T12:45:00.123Z
+    Utc,
+    /// Milliseconds since the Unix epoch
+    EpochMs,
+}
+
+impl TimestampFormat {
+    fn format(self, now: DateTime<Local>) -> String {
+        match self {
+            TimestampFormat::Local => now.format("%Y-%m-%d %H:%M:%S").to_string(),
+            TimestampFormat::Rfc3339 => now.to_rfc3339_opts(SecondsFormat::Millis, false),
+            TimestampFormat::Utc => now
+                .with_timezone(&Utc)
+                .to_rfc3339_opts(SecondsFormat::Millis, true),
+            TimestampFormat::EpochMs => now.timestamp_millis().to_string(),
+        }
+    }
+}
+
+/// Extra destination for usage samples, given with `--output`
+#[derive(Clone, Debug)]
+enum OutputSpec {
+    File(ReportFormat, PathBuf),
+    Table,
+    Statsd(String),
+}
+
+impl FromStr for OutputSpec {
+    type Err = String;
+
+    fn from_str(s: &str) -> std::result::Result<Self, Self::Err> {
+        match s.split_once(':') {
+            None if s == "table" => Ok(OutputSpec::Table),
+            Some(("statsd", address)) if !address.is_empty() => {
+                Ok(OutputSpec::Statsd(address.into()))
+            }
+            Some((format, path))
+                if !path.is_empty()
+                    && let Ok(format) = format.parse() =>
+            {
+                Ok(OutputSpec::File(format, path.into()))
+            }
+            _ => Err(format!(
+                "expected FORMAT:PATH (e.g. csv:usage.csv), table or statsd:HOST:PORT, not '{}'",
+                s
+            )),
+        }
+    }
+}
+
+fn parse_rotation(s: &str) -> std::result::Result<Rotation, String> {
+    match s {
+        "daily" => Ok(Rotation::Daily),
+        "hourly" => Ok(Rotation::Hourly),
+        _ => parse_size(s)
+            .ok()
+            .filter(|size| size.as_u64() > 0)
+            .map(|size| Rotation::Size(size.as_u64()))
+            .ok_or_else(|| {
+                format!(
+                    "expected daily, hourly or a size such as 100MB, not '{}'",
+                    s
+                )
+            }),
+    }
+}
+
+#[derive(Subcommand)]
+enum TuCommand {
+    /// Render CPU/RAM/GPU charts from an existing usage CSV
+    Plot {
+        /// Usage CSV written by `tu`
+        csv: PathBuf,
+
+        /// Output SVG file
+        #[arg(short, long, default_value = "usage.svg")]
+        out: PathBuf,
+    },
+    /// Write a self-contained HTML report of a usage CSV, with interactive charts, the run's
+    /// metadata (from its `.meta.json` sidecar, if present) and summary statistics
+    Report {
+        /// Usage CSV written by `tu`
+        csv: PathBuf,
+
+        /// Output HTML file
+        #[arg(short, long, default_value = "report.html")]
+        out: PathBuf,
+    },
+    /// Compare two usage CSVs, e.g. before and after an optimisation, printing the change in
+    /// wall clock time and peak and mean CPU, RAM and GPU
+    Compare {
+        /// Usage CSV of the earlier run
+        old: PathBuf,
+
+        /// Usage CSV of the later run
+        new: PathBuf,
+
+        /// Smallest change, as a percentage, which counts as better or worse
+        #[arg(long, default_value_t = 5.0)]
+        threshold: f64,
+
+        /// Also overlay the two runs in an SVG plot
+        #[arg(long)]
+        plot: Option<PathBuf>,
+    },
+    /// Run a command several times, writing each run's wall clock time, peak RAM and mean
+    /// CPU and GPU, with their means and standard deviations, to a CSV
+    Bench {
+        /// How many times to run the command
+        #[arg(long, default_value_t = 5, value_parser = clap::value_parser!(u32).range(1..))]
+        runs: u32,
+
+        /// Output CSV file
+        #[arg(short, long, default_value = "bench.csv")]
+        out: PathBuf,
+
+        /// Command to benchmark
+        #[arg(last = true, required = true)]
+        command: Vec<String>,
+    },
+    /// Print the NVIDIA GPUs NVML can see, the driver and CUDA versions, which of the
+    /// queries `tu` relies on each GPU supports, and any problems, such as missing
+    /// permissions, which would leave GPU columns NA
+    GpuInfo,
+    /// Print a man page for `tu` to stdout, e.g. `tu man > /usr/local/share/man/man1/tu.1`
+    Man,
+}
+
+/**
+ * Run `tu`, or `tools monitor`, with its parsed arguments
+ */
+pub fn run(cli: Cli) -> Result<()> {
+    if let Some(TuCommand::Man) = cli.subcommand {
+        print!("{}", man_page(&Cli::command(), &LOGGING_ENV));
+        return Ok(());
+    }
+    cli.common.init()?;
+
+    match &cli.subcommand {
+        Some(TuCommand::Plot { csv, out }) => {
+            let trace = UsageTrace::from_csv(csv)?;
+            plot_usage(&trace, out)?;
+            if !cli.common.quiet {
+                println!("Plot written to {}", out.display());
+            }
+            return Ok(());
+        }
+        Some(TuCommand::Report { csv, out }) => {
+            let trace = UsageTrace::from_csv(csv)?;
+            let metadata_path = RunMetadata::path_for(csv);
+            let metadata = if metadata_path.exists() {
+                Some(RunMetadata::read_json(&metadata_path)?)
+            } else {
+                log::warn!("No run metadata found at {}", metadata_path.display());
+                None
+            };
+            let title = format!("Usage report: {}", csv.display());
+            write_report(&title, &trace, metadata.as_ref(), out)?;
+            if !cli.common.quiet {
+                println!("Report written to {}", out.display());
+            }
+            return Ok(());
+        }
+        Some(TuCommand::Compare {
+            old,
+            new,
+            threshold,
+            plot,
+        }) => {
+            let old_trace = UsageTrace::from_csv(old)?;
+            let new_trace = UsageTrace::from_csv(new)?;
+            if old_trace.schema_version != new_trace.schema_version {
+                log::warn!(
+                    "{} has schema version {} and {} version {}, so figures only one of them \
+                     records are NA",
+                    old.display(),
+                    old_trace.schema_version,
+                    new.display(),
+                    new_trace.schema_version
+                );
+            }
+            print!(
+                "{}",
+                TraceComparison::new(&old_trace, &new_trace, *threshold)
+                    .render(console::stdout_color())
+            );
+            if let Some(plot) = plot {
+                plot_comparison(
+                    (&old.display().to_string(), &old_trace),
+                    (&new.display().to_string(), &new_trace),
+                    plot,
+                )?;
+                if !cli.common.quiet {
+                    println!("Plot written to {}", plot.display());
+                }
+            }
+            return Ok(());
+        }
+        Some(TuCommand::Bench { runs, out, command }) => return bench(&cli, *runs, out, command),
+        #[cfg(feature = "nvml")]
+        Some(TuCommand::GpuInfo) => {
+            print!("{}", GpuInfo::detect());
+            return Ok(());
+        }
+        #[cfg(not(feature = "nvml"))]
+        Some(TuCommand::GpuInfo) => bail!("{}", NO_NVML),
+        Some(TuCommand::Man) => unreachable!("Handled before logging is set up"),
+        None => (),
+    }
+    let is_csv = matches!(cli.output.format, ReportFormat::Csv | ReportFormat::Tsv);
+    if cli.plot.is_some() && (!is_csv || cli.compress.is_some() || cli.output.to_stdout()) {
+        bail!("--plot needs uncompressed CSV or TSV output in a file");
+    }
+    // Anything else written to stdout would get mixed up with the report
+    let report_to_stdout = cli.output.to_stdout()
+        || cli
+            .outputs
+            .iter()
+            .any(|output| matches!(output, OutputSpec::File(_, path) if is_stdout(path)));
+    if report_to_stdout && cli.tui {
+        bail!("--tui can't be used when the report goes to stdout");
+    }
+
+    let gpu_opt = nvidia_session(&cli)?;
+    let apple_gpu_opt = cli
+        .apple_gpu
+        .then(|| AppleGpu::new(APPLE_GPU_WINDOW))
+        .transpose()?;
+
+    let rotation = match cli.rotate {
+        Some(rotation) => rotation,
+        None if cli.system => Rotation::Daily,
+        None => Rotation::Never,
+    };
+    if cli.keep.is_some() && rotation == Rotation::Never {
+        bail!("--keep needs rotating output, see --rotate");
+    }
+    let compression = match cli.compress {
+        None => Compression::None,
+        Some(CompressFormat::Gzip) => Compression::Gzip,
+        Some(CompressFormat::Zstd) => Compression::Zstd,
+    };
+    let file_names = FileNameValues::now(Some(default_label(&cli)));
+    let out_path = cli.output.path(DEFAULT_FILE, &file_names)?;
+    let report_options = ReportOptions {
+        rotation,
+        compression,
+        keep: cli.keep.map(|files| files as usize),
+        dialect: cli.output.csv_dialect(),
+        append: false,
+        column_type: usage_column_type,
+    };
+    let mut wtr = cli
+        .output
+        .format
+        .open::<UsageRecord>(&out_path, &report_options)?;
+
+    let mut per_process_wtr = cli
+        .per_process
+        .as_ref()
+        .map(|dir| open_breakdown(dir, PER_PROCESS_FILE, &report_options))
+        .transpose()?;
+
+    let mut per_device_wtr = cli
+        .per_device
+        .as_ref()
+        .map(|dir| open_breakdown(dir, PER_DEVICE_FILE, &report_options))
+        .transpose()?;
+
+    let logs = LogBuffer::new();
+    let capture_opt = if cli.tui
+        || cli.tee.is_some()
+        || cli.log_stdout.is_some()
+        || cli.log_stderr.is_some()
+        || report_to_stdout
+    {
+        let timestamp_format = cli.timestamp_format;
+        let capture = OutputCapture::new(
+            cli.tee.as_deref().or(cli.log_stdout.as_deref()),
+            cli.tee.as_deref().or(cli.log_stderr.as_deref()),
+            cli.tui.then(|| logs.clone()),
+            move |time| timestamp_format.format(time),
+        )?;
+        Some(match report_to_stdout {
+            true => capture.with_stdout_to_stderr(),
+            false => capture,
+        })
+    } else {
+        None
+    };
+
+    let statsd_outputs = cli.statsd.iter().cloned().map(OutputSpec::Statsd);
+    let mut sinks: Vec<Box<dyn Sink<UsageRecord>>> = Vec::new();
+    for output in cli.outputs.iter().cloned().chain(statsd_outputs) {
+        let sink: Box<dyn Sink<UsageRecord>> = match output {
+            OutputSpec::File(format, path) => {
+                format.open(&file_names.expand_path(&path)?, &report_options)?
+            }
+            OutputSpec::Table if cli.tui => bail!("--output table can't be used with --tui"),
+            OutputSpec::Table => Box::new(TableSink::new(std::io::stdout())),
+            OutputSpec::Statsd(address) => {
+                let host = sysinfo::System::host_name().unwrap_or_else(|| "unknown".into());
+                let label = cli
+                    .statsd_label
+                    .clone()
+                    .unwrap_or_else(|| default_label(&cli));
+                Box::new(StatsdSink::new(
+                    &address,
+                    &cli.statsd_prefix,
+                    vec![("host".into(), host), ("label".into(), label)],
+                )?)
+            }
+        };
+        sinks.push(sink);
+    }
+
+    let target = match (cli.pid, &cli.pattern, &cli.cgroup) {
+        _ if cli.system => Target::WholeSystem,
+        _ if let Some(id) = &cli.container => Target::container(id)?,
+        (Some(pid), _, _) => Target::attach(Pid::from_u32(pid)),
+        (None, Some(pattern), _) => Target::matching(pattern.clone()),
+        (None, None, Some(path)) => Target::in_cgroup(path, &cli.command, capture_opt.as_ref())?,
+        (None, None, None) => Target::spawn(&cli.command, capture_opt.as_ref())?,
+    };
+    let (gpu_models, gpu_driver_version, cuda_version, gpu_devices) = match &gpu_opt {
+        Some(gpu) => (
+            gpu.device_names()?,
+            gpu.driver_version(),
+            gpu.cuda_version(),
+            gpu.device_details()?,
+        ),
+        None => (Vec::new(), None, None, Vec::new()),
+    };
+    let has_mig = gpu_opt.as_ref().is_some_and(|gpu| gpu.has_mig());
+
+    let mut monitor = Monitor::new(target)?.with_measurements(Measurements {
+        memory_pressure: cli.memory_pressure,
+        numa: cli.numa,
+        cpu_frequency: cli.cpu_frequency,
+        energy: cli.energy,
+        gpu_thermals: cli.gpu_thermals,
+        gpu_engines: cli.gpu_engines,
+        gpu_bandwidth: cli.gpu_bandwidth,
+        system_baseline: cli.system_baseline,
+    });
+    if let Some(gpu) = gpu_opt {
+        monitor = monitor.with_boxed_gpu(gpu);
+    }
+    if let Some(apple_gpu) = apple_gpu_opt {
+        monitor = monitor.with_apple_gpu(apple_gpu);
+    }
+    monitor
+        .system()
+        .set_full_refresh_interval(cli.rescan_interval);
+    monitor.system().set_memory_mode(memory_mode(&cli));
+    monitor
+        .system()
+        .set_thread_accounting(thread_accounting(&cli));
+    let system_memory = monitor.system().total_memory() as f32;
+
+    let mut dashboard_opt = cli
+        .tui
+        .then(|| Dashboard::new(default_label(&cli), logs.clone()));
+
+    let mut aggregator_opt = cli.aggregate.map(|period| {
+        Aggregator::new(
+            cli.retain_raw,
+            period,
+            &[
+                SCHEMA_COLUMN,
+                "timestamp",
+                "elapsed_seconds",
+                "top_cpu_pid",
+                "top_ram_pid",
+            ],
+        )
+    });
+
+    let mut adaptive_opt = cli
+        .adaptive
+        .then(|| AdaptiveInterval::new(ADAPTIVE_MIN_INTERVAL, cli.max_interval, ADAPTIVE_WARMUP));
+    let mut pause = match &adaptive_opt {
+        Some(adaptive) => adaptive.current(),
+        None => cli.interval,
+    };
+    let start_time = Local::now();
+
+    let cpu_limit_cores = monitor.cpu_limit_cores();
+    let system = monitor.system();
+    let mut metadata = RunMetadata {
+        command_line: std::env::args().collect(),
+        hostname: sysinfo::System::host_name(),
+        username: system.current_username(),
+        start_time: start_time.to_rfc3339_opts(SecondsFormat::Millis, false),
+        cpu_model: system.cpu_model(),
+        cpu_count: system.cpu_count(),
+        total_memory_bytes: system.total_memory(),
+        cpu_limit_cores,
+        gpu_models,
+        gpu_driver_version,
+        cuda_version,
+        gpu_devices,
+        processes: Vec::new(),
+    };
+    // There's nowhere for a sidecar to go alongside stdout
+    let metadata_path = (!is_stdout(&out_path)).then(|| RunMetadata::path_for(&out_path));
+    if let Some(path) = &metadata_path {
+        metadata.write_json(path)?;
+    }
+    let start_instant = Instant::now();
+    let mut schedule = Schedule::new(start_instant, pause);
+    let mut summary = SummaryAccumulator::new();
+    let mut snapshotted = HashSet::new();
+    let exclude_prior_io = !monitor.target().is_spawned();
+    let mut exit_code = None;
+    let mut samples_written = 0;
+    let mut stop_reason = None;
+    let mut left_running = false;
+    let signals = SignalCatcher::install()?;
+
+    loop {
+        // Exit cuts the pause short, so the final sample includes the last part interval
+        let remaining = schedule.remaining(Instant::now());
+        let exited = match dashboard_opt.as_mut() {
+            Some(dashboard) => {
+                if dashboard.wait(remaining)? == DashboardAction::Quit {
+                    log::info!("Quit requested from dashboard");
+                    monitor.terminate(Signal::Term);
+                    break;
+                }
+                monitor.has_exited()?
+            }
+            None => monitor.wait_for_exit(remaining)?,
+        };
+
+        if let Some(signal) = signals.received() {
+            log::warn!("Received {:?}, stopping", signal);
+            // Only passed on to a command we started, not to processes we're observing
+            monitor.terminate(signal);
+            stop_reason = Some(format!("received {:?}", signal));
+            break;
+        }
+
+        if let Some(timeout) = cli.timeout
+            && start_instant.elapsed() > timeout
+        {
+            let reason = format!("timed out after {}", humantime::format_duration(timeout));
+            log::error!("Command {}", reason);
+            monitor.terminate(Signal::Term);
+            stop_reason = Some(reason);
+            exit_code = Some(Failure::TimedOut.code());
+            break;
+        }
+
+        let sample_instant = Instant::now();
+        let sample = monitor.sample()?;
+        let sample_duration = sample_instant.elapsed();
+
+        if let Some(env_names) = &cli.capture_env {
+            let mut added = false;
+            for process in sample.processes.iter().filter(|p| p.thread_of.is_none()) {
+                if metadata.processes.len() < MAX_PROCESSES
+                    && snapshotted.insert(process.pid)
+                    && let Some(snapshot) =
+                        monitor.system().process_snapshot(process.pid, env_names)
+                {
+                    added |= metadata.add_process(snapshot);
+                }
+            }
+            if added && let Some(path) = &metadata_path {
+                metadata.write_json(path)?;
+            }
+        }
+
+        match sample.group_io {
+            Some((read_bytes, written_bytes)) => {
+                summary.add_group_io(read_bytes, written_bytes, exclude_prior_io)
+            }
+            None => summary.add_processes(&sample.processes, exclude_prior_io),
+        }
+        summary.add_sample(&sample.cpu_ram, sample.gpu_percent);
+        if let Some(mig_instances) = &sample.gpu_mig_instances {
+            summary.add_mig_instances(mig_instances);
+        }
+        if let Some(joules) = sample.energy.as_ref().and_then(Energy::total_joules) {
+            summary.add_energy(joules);
+        }
+
+        if let Some(per_process_wtr) = per_process_wtr.as_mut() {
+            for process in &sample.processes {
+                let record = ProcessUsageRecord::new(
+                    start_time,
+                    sample.time,
+                    cli.timestamp_format,
+                    system_memory,
+                    process,
+                    sample.gpu_by_pid.as_ref(),
+                    sample.gpu_memory_by_pid.as_ref(),
+                );
+                per_process_wtr
+                    .serialize(&record)
+                    .wrap_err_with(|| format!("Failed to serialize record: {:?}", record))?;
+            }
+            per_process_wtr.flush()?;
+        }
+
+        if let Some(adaptive) = adaptive_opt.as_mut() {
+            pause = adaptive.next(start_instant.elapsed(), &sample.cpu_ram);
+            log::debug!("Next sample in {:?}", pause);
+        }
+        // Samples stay on the cadence, so an overrun is recorded rather than shifting the rest
+        let missed = schedule.advance(pause, Instant::now());
+        let overrun_opt = (missed > 0).then(|| {
+            log::debug!(
+                "Sample took {:?}, skipped {} samples",
+                sample_duration,
+                missed
+            );
+            sample_duration
+        });
+
+        if let Some(dashboard) = dashboard_opt.as_mut() {
+            dashboard.update(
+                sample.cpu_ram.cpu_percent,
+                sample.cpu_ram.memory_bytes,
+                100.0 * sample.cpu_ram.memory_bytes as f64 / system_memory as f64,
+                sample.gpu_percent,
+            )?;
+        }
+
+        let record = UsageRecord {
+            aggregate: aggregator_opt.is_some().then(|| "raw".into()),
+            sample_ms: format_count(overrun_opt.map(|d| d.as_millis())),
+            container: cli.container.clone(),
+            ..UsageRecord::new(start_time, cli.timestamp_format, system_memory, &sample)
+        };
+
+        let gauges = gauges(&sample, system_memory);
+        for sink in sinks.iter_mut() {
+            sink.write(&record, &gauges)?;
+        }
+        // Only the main output is aggregated, other outputs still get every sample
+        let records = match aggregator_opt.as_mut() {
+            Some(aggregator) => aggregator.push(record, start_instant.elapsed())?,
+            None => vec![record],
+        };
+        for record in records {
+            wtr.write(&record, &gauges)
+                .wrap_err_with(|| format!("Failed to serialize record: {:?}", record))?;
+        }
+        samples_written += 1;
+        progress::emit(&ProgressEvent::SampleWritten {
+            samples: samples_written,
+            elapsed_seconds: start_instant.elapsed().as_secs(),
+        });
+
+        if let (Some(per_device_wtr), Some(devices)) =
+            (per_device_wtr.as_mut(), sample.gpu_devices.as_ref())
+        {
+            for device in devices {
+                let record = DeviceUsageRecord::new(
+                    start_time,
+                    sample.time,
+                    cli.timestamp_format,
+                    device,
+                    has_mig,
+                );
+                per_device_wtr
+                    .serialize(&record)
+                    .wrap_err_with(|| format!("Failed to serialize record: {:?}", record))?;
+            }
+            per_device_wtr.flush()?;
+        }
+
+        if let Some(breach) = check_limits(&cli, &sample, start_time) {
+            log::error!("Limit breached: {}", breach);
+            if cli.kill_on_breach {
+                monitor.kill();
+            } else {
+                left_running = true;
+            }
+            stop_reason = Some(format!("limit breached: {}", breach));
+            exit_code = Some(Failure::LimitBreached.code());
+            break;
+        }
+
+        if exited {
+            log::info!("Monitored process has exited");
+            break;
+        }
+    }
+
+    // Restore the terminal before printing anything
+    drop(dashboard_opt);
+
+    // Don't wait on a child which was deliberately left running after a breach
+    let child_exit_code = if !left_running {
+        let status = monitor.wait()?;
+        if let Some(capture) = &capture_opt {
+            capture.finish();
+        }
+        status.map(exit_status_code)
+    } else {
+        None
+    };
+    if let Some(code) = child_exit_code
+        && code != 0
+    {
+        log::warn!("Command exited with code {}", code);
+    }
+    // A limit breach or timeout takes precedence, as it's probably why the command failed
+    if !cli.ignore_exit_code && exit_code.is_none() {
+        exit_code = child_exit_code
+            .map(command_exit_code)
+            .filter(|&code| code != SUCCESS);
+    }
+
+    if let Some(aggregator) = aggregator_opt.as_mut() {
+        for record in aggregator.flush()? {
+            wtr.write(&record, &[])
+                .wrap_err_with(|| format!("Failed to serialize record: {:?}", record))?;
+        }
+    }
+    wtr.finish()?;
+    for sink in sinks.iter_mut() {
+        sink.finish()?;
+    }
+    if let Some(per_process_wtr) = per_process_wtr.as_mut() {
+        per_process_wtr.finish()?;
+    }
+    if let Some(per_device_wtr) = per_device_wtr.as_mut() {
+        per_device_wtr.finish()?;
+    }
+
+    let run_summary = RunSummary {
+        exit_code: child_exit_code,
+        exit_time: monitor
+            .exit_time()
+            .map(|time| cli.timestamp_format.format(time)),
+        stop_reason,
+        ..summary.finish(start_instant.elapsed())
+    };
+    if !cli.common.quiet {
+        let printed = match cli.console_format {
+            ConsoleFormat::Text => run_summary.to_string(),
+            format => render_fields(format, &run_summary)?,
+        };
+        match report_to_stdout {
+            true => eprintln!("{}", printed),
+            false => println!("{}", printed),
+        }
+    }
+    if let Some(path) = &cli.summary {
+        let path = &file_names.expand_path(path)?;
+        write_summary(path, &run_summary, cli.output.csv_dialect())?;
+        log::info!("Summary written to {}", path.display());
+    }
+    if let Some(url) = &cli.notify_url {
+        let command = match cli.command.as_slice() {
+            [] => default_label(&cli),
+            command => command.join(" "),
+        };
+        let notification = Notification::new(command, metadata.hostname.clone(), &run_summary);
+        // The run's outputs are already written, so a failed notification only warrants a warning
+        if let Err(e) = WebhookNotifier::new(url).send(&notification) {
+            log::warn!("{:#}", e);
+        }
+    }
+
+    let usage_path = wtr.path().unwrap_or(&out_path);
+    log::info!("Usage report written to {}", usage_path.display());
+    if let Some(path) = &metadata_path {
+        log::info!("Run metadata written to {}", path.display());
+    }
+    if let Some(out) = &cli.plot {
+        let trace = UsageTrace::from_csv(usage_path)?;
+        plot_usage(&trace, out)?;
+        if !cli.common.quiet {
+            println!("Plot written to {}", out.display());
+        }
+    }
+    if let Some(per_process_wtr) = &per_process_wtr {
+        log::info!(
+            "Per-process report written to {}",
+            per_process_wtr.current_path().display()
+        );
+    }
+    if let Some(per_device_wtr) = &per_device_wtr {
+        log::info!(
+            "Per-device report written to {}",
+            per_device_wtr.current_path().display()
+        );
+    }
+
+    if let Some(code) = exit_code {
+        std::process::exit(code);
+    }
+
+    Ok(())
+}
+
+fn memory_mode(cli: &Cli) -> MemoryMode {
+    match cli.memory_mode {
+        MemoryAccounting::Rss => MemoryMode::Rss,
+        MemoryAccounting::Pss => MemoryMode::Pss,
+        MemoryAccounting::Uss => MemoryMode::Uss,
+    }
+}
+
+fn thread_accounting(cli: &Cli) -> ThreadAccounting {
+    match cli.thread_accounting {
+        ThreadMode::Processes => ThreadAccounting::Processes,
+        ThreadMode::Threads => ThreadAccounting::Threads,
+        ThreadMode::Tasks => ThreadAccounting::Tasks,
+    }
+}
+
+/**
+ * NVIDIA GPUs to monitor, through NVML if it can be loaded, otherwise through `nvidia-smi`
+ */
+fn nvidia_session(cli: &Cli) -> Result<Option<Box<dyn GpuBackend>>> {
+    let mode = if cli.nvml { GpuMode::On } else { cli.gpu };
+    let session: Option<Box<dyn GpuBackend>> = match mode {
+        GpuMode::On if cli.apple_gpu => bail!("--gpu on can't be combined with --apple-gpu"),
+        GpuMode::Off => None,
+        GpuMode::Auto if cli.apple_gpu => None,
+        GpuMode::Auto => match nvml_session(&cli.gpu_index, false)? {
+            Some(session) => Some(session),
+            None => nvidia_smi_fallback(&cli.gpu_index)?,
+        },
+        GpuMode::On => match nvml_session(&cli.gpu_index, true) {
+            Ok(session) => session,
+            Err(e) => match nvidia_smi_fallback(&cli.gpu_index)? {
+                Some(smi) => Some(smi),
+                None => return Err(e),
+            },
+        },
+    };
+
+    let gpu_options = !cli.gpu_index.is_empty()
+        || cli.gpu_thermals
+        || cli.gpu_engines
+        || cli.gpu_bandwidth
+        || cli.per_device.is_some()
+        || cli.max_gpu_mem.is_some();
+    if session.is_none() && gpu_options {
+        log::warn!("No NVIDIA GPUs are being monitored, so GPU options have no effect");
+    }
+    Ok(session)
+}
+
+/**
+ * NVIDIA GPUs through NVML, `None` if there are none.  If `required`, not finding any, or
+ * the requested ones, is an error.
+ */
+#[cfg(feature = "nvml")]
+fn nvml_session(indices: &[u32], required: bool) -> Result<Option<Box<dyn GpuBackend>>> {
+    let session = match (required, indices) {
+        (false, _) => GpuSession::detect(indices)?,
+        (true, []) => Some(GpuSession::new()?),
+        (true, indices) => Some(GpuSession::with_indices(indices)?),
+    };
+    Ok(session.map(|session| Box::new(session) as Box<dyn GpuBackend>))
+}
+
+#[cfg(not(feature = "nvml"))]
+fn nvml_session(_indices: &[u32], required: bool) -> Result<Option<Box<dyn GpuBackend>>> {
+    if required {
+        bail!("{}", NO_NVML)
+    }
+    Ok(None)
+}
+
+/**
+ * Monitor GPUs with `nvidia-smi` where NVML couldn't be used, e.g. because the driver's
+ * library doesn't match the bindings
+ */
+fn nvidia_smi_fallback(indices: &[u32]) -> Result<Option<Box<dyn GpuBackend>>> {
+    Ok(NvidiaSmi::detect(indices)?.map(|smi| {
+        log::warn!(
+            "NVML is unavailable, so falling back to nvidia-smi.  Per-process GPU utilisation \
+             is a single snapshot each interval rather than the whole interval, and MIG \
+             instances aren't told apart."
+        );
+        Box::new(smi) as Box<dyn GpuBackend>
+    }))
+}
+
+/**
+ * Run `command` `runs` times, monitoring each run as `tu` would, and write each run's
+ * headline figures followed by their mean and standard deviation to `out`
+ */
+fn bench(cli: &Cli, runs: u32, out: &Path, command: &[String]) -> Result<()> {
+    let mut results = Vec::new();
+    // Opened once, as initialising NVML can take longer than a short run
+    let mut gpu = nvidia_session(cli)?;
+    for run in 1..=runs {
+        log::info!("Benchmark run {} of {}", run, runs);
+        let mut monitor = Monitor::spawn(command)?.with_interval(cli.interval);
+        if let Some(gpu) = gpu.take() {
+            monitor = monitor.with_boxed_gpu(gpu);
+        }
+        monitor.system().set_memory_mode(memory_mode(cli));
+        monitor
+            .system()
+            .set_thread_accounting(thread_accounting(cli));
+
+        let start_instant = Instant::now();
+        let mut summary = SummaryAccumulator::new();
+        let status = monitor.run(|sample| {
+            summary.add_sample(&sample.cpu_ram, sample.gpu_percent);
+            Ok(ControlFlow::Continue(()))
+        })?;
+        let run_summary = RunSummary {
+            exit_code: status.map(exit_status_code),
+            ..summary.finish(start_instant.elapsed())
+        };
+        if let Some(code) = run_summary.exit_code
+            && code != 0
+        {
+            log::warn!("Run {} exited with code {}", run, code);
+        }
+        results.push(BenchRun::from(&run_summary));
+        gpu = monitor.take_gpu();
+    }
+
+    let stats = BenchStats::new(&results);
+    let mut wtr = cli
+        .output
+        .csv_dialect()
+        .writer()
+        .from_path(out)
+        .wrap_err_with(|| format!("Failed to create benchmark file {}", out.display()))?;
+    for (run, result) in results.iter().enumerate() {
+        wtr.serialize(BenchRecord::run(run + 1, result))?;
+    }
+    wtr.serialize(BenchRecord::stat("mean", &stats, |s| s.mean))?;
+    wtr.serialize(BenchRecord::stat("stddev", &stats, |s| s.stddev))?;
+    wtr.flush()?;
+
+    if cli.common.quiet {
+        return Ok(());
+    }
+    println!("Benchmark of {} runs:", runs);
+    let show = |name: &str, spread: Option<Spread>, format: &dyn Fn(f64) -> String| {
+        if let Some(spread) = spread {
+            println!(
+                "  {}: {} ± {}",
+                name,
+                format(spread.mean),
+                format(spread.stddev)
+            );
+        }
+    };
+    show("wall clock", stats.wall_clock_seconds, &|s| {
+        format!("{:.1}s", s)
+    });
+    show("peak RAM", stats.peak_ram_bytes, &|bytes| {
+        format_size(bytes as u64)
+    });
+    show("mean CPU", stats.mean_cpu_percent, &|percent| {
+        format!("{:.1}%", percent)
+    });
+    show("mean GPU", stats.mean_gpu_percent, &|percent| {
+        format!("{:.1}%", percent)
+    });
+    println!("Results written to {}", out.display());
+
+    Ok(())
+}
+
+fn write_summary(path: &Path, summary: &RunSummary, dialect: CsvDialect) -> Result<()> {
+    let has_extension = |extension| {
+        path.extension()
+            .is_some_and(|ext| ext.eq_ignore_ascii_case(extension))
+    };
+    let is_json = has_extension("json");
+    let dialect = match has_extension("tsv") {
+        true => CsvDialect {
+            delimiter: b'\t',
+            ..dialect
+        },
+        false => dialect,
+    };
+
+    if is_json {
+        let file = File::create(path)
+            .wrap_err_with(|| format!("Failed to create summary file {}", path.display()))?;
+        serde_json::to_writer_pretty(file, summary)?;
+    } else {
+        let mut wtr = dialect
+            .writer()
+            .from_path(path)
+            .wrap_err_with(|| format!("Failed to create summary file {}", path.display()))?;
+        wtr.serialize(summary)?;
+        wtr.flush()?;
+    }
+
+    Ok(())
+}
+
+/// Description of the first limit exceeded, if any
+fn check_limits(cli: &Cli, sample: &UsageSample, start_time: DateTime<Local>) -> Option<String> {
+    if let Some(max_ram) = cli.max_ram
+        && sample.cpu_ram.memory_bytes > max_ram.as_u64()
+    {
+        return Some(format!(
+            "RAM {} exceeds {}",
+            format_size(sample.cpu_ram.memory_bytes),
+            format_size(max_ram.as_u64())
+        ));
+    }
+
+    if let (Some(max_gpu_mem), Some(gpu_memory)) = (cli.max_gpu_mem, sample.gpu_memory_bytes())
+        && gpu_memory > max_gpu_mem.as_u64()
+    {
+        return Some(format!(
+            "GPU memory {} exceeds {}",
+            format_size(gpu_memory),
+            format_size(max_gpu_mem.as_u64())
+        ));
+    }
+
+    if let Some(max_runtime) = cli.max_runtime {
+        let runtime = (sample.time - start_time).to_std().unwrap_or_default();
+        if runtime > max_runtime {
+            return Some(format!(
+                "runtime {} exceeds {}",
+                humantime::format_duration(Duration::from_millis(runtime.as_millis() as u64)),
+                humantime::format_duration(max_runtime)
+            ));
+        }
+    }
+
+    None
+}
+
+/**
+ * Open `file_name` in `dir`, creating it if need be, for the per-process or per-device
+ * breakdown, rotated, compressed and kept as `options` say, like the main output
+ */
+pub(crate) fn open_breakdown(
+    dir: &Path,
+    file_name: &str,
+    options: &ReportOptions,
+) -> Result<RotatingCsvWriter> {
+    create_dir_all(dir).wrap_err_with(|| format!("Failed to create directory {}", dir.display()))?;
+    let wtr = RotatingCsvWriter::create(
+        dir.join(file_name),
+        options.rotation,
+        options.compression,
+        options.dialect,
+    )?;
+    Ok(match options.keep {
+        Some(files) => wtr.with_retention(files),
+        None => wtr,
+    })
+}
+
+fn default_label(cli: &Cli) -> String {
+    if cli.system {
+        "system".into()
+    } else if let Some(pid) = cli.pid {
+        format!("pid-{}", pid)
+    } else if let Some(id) = &cli.container {
+        id.clone()
+    } else if let Some(pattern) = &cli.pattern {
+        pattern.to_string()
+    } else {
+        PathBuf::from(&cli.command[0])
+            .file_name()
+            .map(|name| name.to_string_lossy().into_owned())
+            .unwrap_or_else(|| cli.command[0].clone())
+    }
+}
+
+/// The sample's figures as numbers, for sinks which can't use text
+fn gauges(sample: &UsageSample, system_memory: f32) -> Vec<(&'static str, f64)> {
+    let mut gauges = vec![
+        ("cpu_percent", sample.cpu_ram.cpu_percent as f64),
+        (
+            "ram_percent",
+            100.0 * sample.cpu_ram.memory_bytes as f64 / system_memory as f64,
+        ),
+        ("ram_mb", sample.cpu_ram.memory_bytes as f64 / MI_B as f64),
+    ];
+    if let Some(percent) = sample.cpu_percent_of_limit() {
+        gauges.push(("cpu_percent_of_quota", percent));
+    }
+    if let Some(cpu_times) = sample.cpu_times {
+        gauges.push(("cpu_user_seconds", cpu_times.user_seconds));
+        gauges.push(("cpu_system_seconds", cpu_times.system_seconds));
+    }
+    if let Some(virtual_bytes) = sample.cpu_ram.virtual_memory_bytes {
+        gauges.push(("virtual_mb", virtual_bytes as f64 / MI_B as f64));
+    }
+    if let Some(shared_bytes) = sample.cpu_ram.shared_memory_bytes {
+        gauges.push(("shared_mb", shared_bytes as f64 / MI_B as f64));
+    }
+    if let Some(swap_bytes) = sample.swap_bytes {
+        gauges.push(("swap_mb", swap_bytes as f64 / MI_B as f64));
+    }
+    if let Some(fd_count) = sample.fd_count {
+        gauges.push(("fd_count", fd_count as f64));
+    }
+    if let Some(process_count) = sample.process_count {
+        gauges.push(("process_count", process_count as f64));
+    }
+    if let Some(thread_count) = sample.thread_count {
+        gauges.push(("thread_count", thread_count as f64));
+    }
+    if let Some(counters) = sample.counters {
+        gauges.push(("voluntary_ctx_switches", counters.voluntary_switches as f64));
+        gauges.push((
+            "involuntary_ctx_switches",
+            counters.involuntary_switches as f64,
+        ));
+        gauges.push(("major_faults", counters.major_faults as f64));
+    }
+    if let Some(baseline) = &sample.system_baseline {
+        gauges.push(("system_cpu_percent", baseline.cpu_ram.cpu_percent as f64));
+        gauges.push((
+            "system_ram_mb",
+            baseline.cpu_ram.memory_bytes as f64 / MI_B as f64,
+        ));
+        if let Some(load_average) = baseline.load_average {
+            gauges.push(("load_average", load_average));
+        }
+    }
+    if let Some(Some(memory_pressure)) = sample.memory_pressure {
+        gauges.push(("memory_pressure", memory_pressure));
+    }
+    if let Some(clock) = &sample.cpu_clock {
+        if let Some(mhz) = clock.mhz {
+            gauges.push(("cpu_mhz", mhz as f64));
+        }
+        if let Some(throttles) = clock.thermal_throttles {
+            gauges.push(("cpu_thermal_throttles", throttles as f64));
+        }
+        if let Some(throttles) = clock.power_throttles {
+            gauges.push(("cpu_power_throttles", throttles as f64));
+        }
+    }
+    if let Some(joules) = sample.energy.as_ref().and_then(Energy::total_joules) {
+        gauges.push(("energy_j", joules));
+    }
+    if let Some(gpu_percent) = sample.gpu_percent {
+        gauges.push(("gpu_percent", gpu_percent as f64));
+    }
+    if let Some(gpu_memory) = sample.gpu_memory_bytes() {
+        gauges.push(("gpu_memory_mb", gpu_memory as f64 / MI_B as f64));
+    }
+    if let Some(temp_c) = sample.gpu_temp_c() {
+        gauges.push(("gpu_temp_c", temp_c as f64));
+    }
+    if let Some(power_w) = sample.gpu_power_w() {
+        gauges.push(("gpu_power_w", power_w));
+    }
+    if let Some(fan_percent) = sample.gpu_fan_percent() {
+        gauges.push(("gpu_fan_percent", fan_percent as f64));
+    }
+    if let Some(tx) = sample.gpu_pcie_tx_bytes_per_sec() {
+        gauges.push(("gpu_pcie_tx_mb_s", tx as f64 / MI_B as f64));
+    }
+    if let Some(rx) = sample.gpu_pcie_rx_bytes_per_sec() {
+        gauges.push(("gpu_pcie_rx_mb_s", rx as f64 / MI_B as f64));
+    }
+    if let Some(percent) = sample.gpu_memory_bandwidth_percent() {
+        gauges.push(("gpu_mem_bw_percent", percent as f64));
+    }
+    if let Some(engines) = &sample.gpu_engines {
+        gauges.push(("gpu_mem_percent", engines.memory_percent as f64));
+        gauges.push(("gpu_enc_percent", engines.encoder_percent as f64));
+        gauges.push(("gpu_dec_percent", engines.decoder_percent as f64));
+    }
+    gauges
+}
+
+#[derive(Debug, serde::Serialize, serde::Deserialize)]
+struct UsageRecord {
+    schema_version: u32,
+    timestamp: String,
+    elapsed_seconds: usize,
+    /// Only with `--aggregate`: `raw`, or the statistic of a folded row
+    #[serde(skip_serializing_if = "Option::is_none")]
+    aggregate: Option<String>,
+    cpu_percent: String,
+    /// Only when a cgroup limits the tree's CPU
+    #[serde(skip_serializing_if = "Option::is_none")]
+    cpu_percent_of_quota: Option<String>,
+    cpu_user_seconds: String,
+    cpu_system_seconds: String,
+    ram_percent: String,
+    ram_mb: String,
+    virtual_mb: String,
+    shared_mb: String,
+    swap_mb: String,
+    gpu_percent: String,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    gpu_memory_mb: Option<String>,
+    fd_count: String,
+    process_count: String,
+    thread_count: String,
+    top_cpu_pid: String,
+    top_cpu_name: String,
+    top_ram_pid: String,
+    top_ram_name: String,
+    voluntary_ctx_switches: String,
+    involuntary_ctx_switches: String,
+    major_faults: String,
+    /// How long taking the sample took, when that overran the interval
+    sample_ms: String,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    system_cpu_percent: Option<String>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    system_ram_mb: Option<String>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    load_average: Option<String>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    memory_pressure: Option<String>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    numa_mb: Option<String>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    cpu_mhz: Option<String>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    cpu_thermal_throttles: Option<String>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    cpu_power_throttles: Option<String>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    cpu_energy_j: Option<String>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    dram_energy_j: Option<String>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    gpu_energy_j: Option<String>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    gpu_temp_c: Option<String>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    gpu_power_w: Option<String>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    gpu_fan_percent: Option<String>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    gpu_pcie_tx_mb_s: Option<String>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    gpu_pcie_rx_mb_s: Option<String>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    gpu_mem_bw_percent: Option<String>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    gpu_mem_percent: Option<String>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    gpu_enc_percent: Option<String>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    gpu_dec_percent: Option<String>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    gpu_mig: Option<String>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    container: Option<String>,
+}
+
+impl UsageRecord {
+    fn new(
+        start_time: DateTime<Local>,
+        timestamp_format: TimestampFormat,
+        system_memory: f32,
+        sample: &UsageSample,
+    ) -> Self {
+        Self {
+            schema_version: USAGE_SCHEMA,
+            timestamp: timestamp_format.format(sample.time),
+            elapsed_seconds: elapsed_seconds(start_time, sample.time),
+            aggregate: None,
+            cpu_percent: format!("{:.1}", sample.cpu_ram.cpu_percent),
+            cpu_percent_of_quota: sample
+                .cpu_percent_of_limit()
+                .map(|percent| format!("{:.1}", percent)),
+            cpu_user_seconds: format_seconds(sample.cpu_times.map(|t| t.user_seconds)),
+            cpu_system_seconds: format_seconds(sample.cpu_times.map(|t| t.system_seconds)),
+            ram_percent: format_ram_percent(&sample.cpu_ram, system_memory),
+            ram_mb: format!("{:.1}", sample.cpu_ram.memory_bytes as f32 / MI_B),
+            virtual_mb: format_mb(sample.cpu_ram.virtual_memory_bytes),
+            shared_mb: format_mb(sample.cpu_ram.shared_memory_bytes),
+            swap_mb: format_mb(sample.swap_bytes),
+            gpu_percent: format_gpu_percent(sample.gpu_percent),
+            gpu_memory_mb: sample
+                .gpu_memory_bytes()
+                .map(|bytes| format!("{:.1}", bytes as f32 / MI_B)),
+            fd_count: format_count(sample.fd_count),
+            process_count: format_count(sample.process_count),
+            thread_count: format_count(sample.thread_count),
+            top_cpu_pid: format_count(sample.top_cpu_process().map(|p| p.pid.as_u32())),
+            top_cpu_name: format_count(sample.top_cpu_process().map(|p| &p.name)),
+            top_ram_pid: format_count(sample.top_ram_process().map(|p| p.pid.as_u32())),
+            top_ram_name: format_count(sample.top_ram_process().map(|p| &p.name)),
+            voluntary_ctx_switches: format_count(sample.counters.map(|c| c.voluntary_switches)),
+            involuntary_ctx_switches: format_count(sample.counters.map(|c| c.involuntary_switches)),
+            major_faults: format_count(sample.counters.map(|c| c.major_faults)),
+            sample_ms: "NA".into(),
+            system_cpu_percent: sample
+                .system_baseline
+                .as_ref()
+                .map(|b| format!("{:.1}", b.cpu_ram.cpu_percent)),
+            system_ram_mb: sample
+                .system_baseline
+                .as_ref()
+                .map(|b| format!("{:.1}", b.cpu_ram.memory_bytes as f32 / MI_B)),
+            load_average: sample
+                .system_baseline
+                .as_ref()
+                .map(|b| format_optional(b.load_average)),
+            memory_pressure: sample.memory_pressure.map(format_optional),
+            numa_mb: sample.numa_bytes.as_ref().map(format_numa_bytes),
+            cpu_mhz: sample.cpu_clock.as_ref().map(|c| format_count(c.mhz)),
+            cpu_thermal_throttles: sample
+                .cpu_clock
+                .as_ref()
+                .map(|c| format_count(c.thermal_throttles)),
+            cpu_power_throttles: sample
+                .cpu_clock
+                .as_ref()
+                .map(|c| format_count(c.power_throttles)),
+            cpu_energy_j: sample
+                .energy
+                .as_ref()
+                .map(|e| format_optional(e.cpu_joules)),
+            dram_energy_j: sample
+                .energy
+                .as_ref()
+                .map(|e| format_optional(e.dram_joules)),
+            gpu_energy_j: sample
+                .energy
+                .as_ref()
+                .map(|e| format_optional(e.gpu_joules)),
+            // Columns only appear when thermals are being sampled, so the header stays stable
+            gpu_temp_c: sample
+                .gpu_thermals
+                .as_ref()
+                .map(|_| format_optional(sample.gpu_temp_c())),
+            gpu_power_w: sample
+                .gpu_thermals
+                .as_ref()
+                .map(|_| format_optional(sample.gpu_power_w())),
+            gpu_fan_percent: sample
+                .gpu_thermals
+                .as_ref()
+                .map(|_| format_optional(sample.gpu_fan_percent())),
+            gpu_pcie_tx_mb_s: sample
+                .gpu_bandwidth
+                .as_ref()
+                .map(|_| format_mb(sample.gpu_pcie_tx_bytes_per_sec())),
+            gpu_pcie_rx_mb_s: sample
+                .gpu_bandwidth
+                .as_ref()
+                .map(|_| format_mb(sample.gpu_pcie_rx_bytes_per_sec())),
+            gpu_mem_bw_percent: sample
+                .gpu_bandwidth
+                .as_ref()
+                .map(|_| format_count(sample.gpu_memory_bandwidth_percent())),
+            gpu_mem_percent: sample.gpu_engines.map(|e| e.memory_percent.to_string()),
+            gpu_enc_percent: sample.gpu_engines.map(|e| e.encoder_percent.to_string()),
+            gpu_dec_percent: sample.gpu_engines.map(|e| e.decoder_percent.to_string()),
+            gpu_mig: sample
+                .gpu_mig_instances
+                .as_deref()
+                .map(format_mig_instances),
+            container: None,
+        }
+    }
+}
+
+#[derive(Debug, serde::Serialize)]
+struct ProcessUsageRecord {
+    schema_version: u32,
+    timestamp: String,
+    elapsed_seconds: usize,
+    pid: u32,
+    name: String,
+    cpu_percent: String,
+    ram_percent: String,
+    ram_mb: String,
+    virtual_mb: String,
+    shared_mb: String,
+    swap_mb: String,
+    gpu_percent: String,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    gpu_memory_mb: Option<String>,
+    fd_count: String,
+}
+
+impl ProcessUsageRecord {
+    fn new(
+        start_time: DateTime<Local>,
+        now: DateTime<Local>,
+        timestamp_format: TimestampFormat,
+        system_memory: f32,
+        process: &ProcessUsage,
+        gpu_by_pid: Option<&HashMap<Pid, u32>>,
+        gpu_memory_by_pid: Option<&HashMap<Pid, u64>>,
+    ) -> Self {
+        Self {
+            schema_version: PROCESS_USAGE_SCHEMA,
+            timestamp: timestamp_format.format(now),
+            elapsed_seconds: elapsed_seconds(start_time, now),
+            pid: process.pid.as_u32(),
+            name: process.name.clone(),
+            cpu_percent: format!("{:.1}", process.usage.cpu_percent),
+            ram_percent: format_ram_percent(&process.usage, system_memory),
+            ram_mb: format!("{:.1}", process.usage.memory_bytes as f32 / MI_B),
+            virtual_mb: format_mb(process.usage.virtual_memory_bytes),
+            shared_mb: format_mb(process.usage.shared_memory_bytes),
+            swap_mb: format_mb(process.swap_bytes),
+            // Processes without GPU samples are reported as using none of it
+            gpu_percent: format_gpu_percent(
+                gpu_by_pid.map(|by_pid| by_pid.get(&process.pid).copied().unwrap_or(0)),
+            ),
+            gpu_memory_mb: gpu_memory_by_pid.map(|by_pid| {
+                let bytes = by_pid.get(&process.pid).copied().unwrap_or(0);
+                format!("{:.1}", bytes as f32 / MI_B)
+            }),
+            fd_count: format_count(process.fd_count),
+        }
+    }
+}
+
+/// A row of `tu bench` output: one run, or a statistic over all of them
+#[derive(Debug, serde::Serialize)]
+struct BenchRecord {
+    schema_version: u32,
+    run: String,
+    wall_clock_seconds: String,
+    peak_ram_mb: String,
+    mean_cpu_percent: String,
+    mean_gpu_percent: String,
+    exit_code: String,
+}
+
+impl BenchRecord {
+    fn run(run: usize, result: &BenchRun) -> Self {
+        Self {
+            schema_version: BENCH_SCHEMA,
+            run: run.to_string(),
+            wall_clock_seconds: format!("{:.3}", result.wall_clock_seconds),
+            peak_ram_mb: format!("{:.1}", result.peak_ram_bytes as f32 / MI_B),
+            mean_cpu_percent: format!("{:.1}", result.mean_cpu_percent),
+            mean_gpu_percent: format_optional(result.mean_gpu_percent),
+            exit_code: format_count(result.exit_code),
+        }
+    }
+
+    fn stat(name: &str, stats: &BenchStats, figure: fn(&Spread) -> f64) -> Self {
+        let format = |spread: Option<Spread>, scale: f64, precision: usize| match spread {
+            Some(spread) => format!("{:.*}", precision, figure(&spread) / scale),
+            None => "NA".into(),
+        };
+        Self {
+            schema_version: BENCH_SCHEMA,
+            run: name.into(),
+            wall_clock_seconds: format(stats.wall_clock_seconds, 1.0, 3),
+            peak_ram_mb: format(stats.peak_ram_bytes, MI_B as f64, 1),
+            mean_cpu_percent: format(stats.mean_cpu_percent, 1.0, 1),
+            mean_gpu_percent: format(stats.mean_gpu_percent, 1.0, 1),
+            exit_code: "NA".into(),
+        }
+    }
+}
+
+#[derive(Debug, serde::Serialize)]
+struct DeviceUsageRecord {
+    schema_version: u32,
+    timestamp: String,
+    elapsed_seconds: usize,
+    device: u32,
+    /// Only present when MIG is in use, and `NA` for devices without it
+    #[serde(skip_serializing_if = "Option::is_none")]
+    mig_instance: Option<String>,
+    gpu_percent: u32,
+    gpu_memory_mb: String,
+}
+
+impl DeviceUsageRecord {
+    fn new(
+        start_time: DateTime<Local>,
+        now: DateTime<Local>,
+        timestamp_format: TimestampFormat,
+        device: &DeviceUsage,
+        has_mig: bool,
+    ) -> Self {
+        Self {
+            schema_version: DEVICE_USAGE_SCHEMA,
+            timestamp: timestamp_format.format(now),
+            elapsed_seconds: elapsed_seconds(start_time, now),
+            device: device.index,
+            mig_instance: has_mig.then(|| match device.mig_instance {
+                Some(instance) => instance.to_string(),
+                None => "NA".into(),
+            }),
+            gpu_percent: device.gpu_percent,
+            gpu_memory_mb: format!("{:.1}", device.memory_bytes as f32 / MI_B),
+        }
+    }
+}
+
+fn elapsed_seconds(start_time: DateTime<Local>, now: DateTime<Local>) -> usize {
+    (now - start_time).as_seconds_f32().round() as usize
+}
+
+/**
+ * Parquet type of each `UsageRecord` column
+ */
+fn usage_column_type(name: &str) -> ColumnType {
+    match name {
+        "timestamp" | "aggregate" | "gpu_mig" | "numa_mb" | "container" | "top_cpu_name"
+        | "top_ram_name" => ColumnType::Text,
+        "schema_version"
+        | "elapsed_seconds"
+        | "fd_count"
+        | "process_count"
+        | "thread_count"
+        | "top_cpu_pid"
+        | "top_ram_pid"
+        | "voluntary_ctx_switches"
+        | "involuntary_ctx_switches"
+        | "major_faults"
+        | "sample_ms"
+        | "cpu_mhz"
+        | "cpu_thermal_throttles"
+        | "cpu_power_throttles" => ColumnType::Int64,
+        _ => ColumnType::Double,
+    }
+}
+
+/**
+ * MiB on each node, e.g. `0:1843.2 1:12.5`
+ */
+fn format_numa_bytes(numa_bytes: &NumaBytes) -> String {
+    if numa_bytes.is_empty() {
+        return "NA".into();
+    }
+    numa_bytes
+        .iter()
+        .map(|(node, bytes)| format!("{}:{:.1}", node, *bytes as f32 / MI_B))
+        .collect::<Vec<_>>()
+        .join(" ")
+}
+
+fn format_mig_instances(mig_instances: &[MigInstance]) -> String {
+    if mig_instances.is_empty() {
+        return "NA".into();
+    }
+    mig_instances
+        .iter()
+        .map(MigInstance::to_string)
+        .collect::<Vec<_>>()
+        .join(" ")
+}
+
+fn format_ram_percent(cpu_ram: &CpuRamUsage, system_memory: f32) -> String {
+    format!(
+        "{:.1}",
+        100.0 * (cpu_ram.memory_bytes as f32 / system_memory)
+    )
+}
+
+fn format_gpu_percent(gpu_percent: Option<u32>) -> String {
+    format_optional(gpu_percent)
+}
+
+fn format_mb(bytes: Option<u64>) -> String {
+    bytes
+        .map(|bytes| format!("{:.1}", bytes as f32 / MI_B))
+        .unwrap_or_else(|| "NA".into())
+}
+
+fn format_count<T: ToString>(count: Option<T>) -> String {
+    count
+        .map(|count| count.to_string())
+        .unwrap_or_else(|| "NA".into())
+}
+
+/**
+ * To the resolution of the kernel's CPU time accounting
+ */
+fn format_seconds(seconds: Option<f64>) -> String {
+    seconds
+        .map(|seconds| format!("{:.2}", seconds))
+        .unwrap_or_else(|| "NA".into())
+}
+
+fn format_optional<T: Into<f64>>(value: Option<T>) -> String {
+    value
+        .map(|value| format!("{:.1}", value.into()))
+        .unwrap_or_else(|| "NA".into())
+}
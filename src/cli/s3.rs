@@ -0,0 +1,601 @@
+use std::{collections::HashMap, path::PathBuf};
+
+use aws_sdk_s3::Client;
+use chrono::Utc;
+
+use clap::{CommandFactory, Parser, Subcommand};
+use color_eyre::{
+    Result,
+    eyre::{Context, bail},
+};
+use dialoguer::Confirm;
+use tokio::runtime::Runtime;
+
+use crate::{
+    cli::{CommonArgs, OutputArgs},
+    exit::Failure,
+    output::{
+        append::keep_latest,
+        console::{self, ConsoleFormat, render_records},
+        format::ReportOptions,
+        select::{ColumnSelection, SelectedRow, sort_by_column},
+        template::FileNameValues,
+        units::format_size,
+        man::{LOGGING_ENV, man_page},
+    },
+    s3::{
+        size::{CSVSizeReport, RUN_TIME_FORMAT, SizeReport, TOTAL_URL, render_summary},
+        baseline::Baseline,
+        heavy::{HeavyOrder, heaviest_prefixes, render_heaviest},
+        metadata::BucketMetadata,
+        orphans::{CSVOrphan, list_orphans, list_prunable_orphans},
+        retention::{CSVExcessVersion, RetentionPolicy},
+        types::S3Location,
+        wrapper::S3Wrapper,
+    },
+};
+
+#[derive(Parser)]
+#[command(name = "bu", version, about)]
+/// Utility to support working with object versions in S3
+pub struct Cli {
+    #[command(flatten)]
+    pub common: CommonArgs,
+
+    /// AWS profile to load credentials and region from (defaults to $AWS_PROFILE)
+    #[arg(long, value_name = "NAME", global = true)]
+    profile: Option<String>,
+
+    #[command(subcommand)]
+    command: Command,
+}
+
+#[derive(Subcommand)]
+enum Command {
+    #[command(name = "size", about = "Report on a single bucket/prefix to console")]
+    Size {
+        /// S3 URL
+        #[arg(required = true)]
+        url: S3Location,
+    },
+    #[command(
+        name = "size-report",
+        about = "Report on a multiple buckets/prefixes to a file"
+    )]
+    SizeReport {
+        /// Comma separated S3 URLs
+        #[arg(required = true, value_delimiter = ',', num_args = 1..)]
+        urls: Vec<S3Location>,
+
+        #[command(flatten)]
+        output: OutputArgs,
+
+        /// How the reports are printed: text (as each location is done), table (which is also
+        /// Markdown, for pasting into chat) or tsv (for pasting into spreadsheets)
+        #[arg(long, value_name = "FORMAT", default_value_t = ConsoleFormat::Text)]
+        console_format: ConsoleFormat,
+
+        /// Add rows to the end of the report file, if it exists, rather than replacing it, to
+        /// track sizes over time.  Needs csv or tsv output.
+        #[arg(long)]
+        append: bool,
+
+        /// Once appended, keep only the newest row for each URL in the file
+        #[arg(long, requires = "append")]
+        dedup_latest: bool,
+
+        /// Columns to write, comma separated and in order, e.g. url,orphan_ver_b.  The schema
+        /// version always comes first.  All of them by default.
+        #[arg(long, value_delimiter = ',', value_name = "COLUMNS")]
+        columns: Vec<String>,
+
+        /// Order rows by a column, numerically for sizes and counts, rather than as the
+        /// locations were given.  Rows are then written once every location is done.
+        #[arg(long, value_name = "COLUMN")]
+        sort_by: Option<String>,
+
+        /// With --sort-by, put the largest first
+        #[arg(long, requires = "sort_by")]
+        desc: bool,
+
+        /// Don't add a row totalling every location, which is otherwise added, last, when
+        /// there's more than one
+        #[arg(long)]
+        no_total: bool,
+
+        /// An earlier report, e.g. last week's, to fill in the delta columns with each
+        /// location's growth since
+        #[arg(long, value_name = "PATH")]
+        baseline: Option<PathBuf>,
+    },
+    #[command(
+        name = "heavy",
+        about = "Find the prefixes holding the most under a bucket/prefix"
+    )]
+    Heavy {
+        /// S3 URL
+        #[arg(required = true)]
+        url: S3Location,
+
+        /// How many prefixes to show
+        #[arg(long, value_name = "N", default_value_t = 20)]
+        top: usize,
+
+        /// How many folders below the URL to add up at, e.g. 1 for its immediate subfolders
+        #[arg(long, value_name = "N", default_value_t = 1)]
+        depth: usize,
+
+        /// Rank by bytes in every version (total) or in orphaned versions (orphaned)
+        #[arg(long, value_name = "WHAT", default_value_t = HeavyOrder::Total)]
+        by: HeavyOrder,
+    },
+    #[command(
+        name = "orphans",
+        about = "List the orphaned versions under a bucket/prefix to a file, for review"
+    )]
+    Orphans {
+        /// S3 URL
+        #[arg(required = true)]
+        url: S3Location,
+
+        #[command(flatten)]
+        output: OutputArgs,
+    },
+    #[command(
+        name = "prune",
+        about = "Delete only the orphaned versions under bucket/prefix, as `orphans` lists them"
+    )]
+    Prune {
+        /// S3 URL to delete orphaned versions from
+        #[arg(required = true)]
+        url: S3Location,
+
+        /// Don't prompt for confirmation, e.g. in scripts.  Needs --confirm-bucket.
+        #[arg(short, long, visible_alias = "force")]
+        yes: bool,
+
+        /// Name of the bucket being pruned, repeated as a guard when not prompting, i.e.
+        /// with --yes or in CI
+        #[arg(long, value_name = "NAME")]
+        confirm_bucket: Option<String>,
+    },
+    #[command(
+        name = "enforce-retention",
+        about = "Delete the versions under bucket/prefix that a retention policy doesn't keep"
+    )]
+    EnforceRetention {
+        /// S3 URL
+        #[arg(required = true)]
+        url: S3Location,
+
+        /// TOML or YAML (.yaml/.yml) file setting keep_newest (versions of each key to
+        /// keep), keep_days (keep versions newer than this) and protect (patterns of keys to
+        /// leave alone)
+        #[arg(long, value_name = "PATH")]
+        policy: PathBuf,
+
+        /// Only write the manifest of what would be deleted
+        #[arg(long)]
+        dry_run: bool,
+
+        /// Audit manifest of the versions deleted, written before deleting them
+        #[command(flatten)]
+        output: OutputArgs,
+
+        /// Don't prompt for confirmation, e.g. in scripts.  Needs --confirm-bucket.
+        #[arg(short, long, visible_alias = "force")]
+        yes: bool,
+
+        /// Name of the bucket being cleaned up, repeated as a guard when not prompting, i.e.
+        /// with --yes or in CI
+        #[arg(long, value_name = "NAME")]
+        confirm_bucket: Option<String>,
+    },
+    #[command(
+        name = "destroy",
+        about = "Delete all objects and versions under bucket/prefix"
+    )]
+    Destroy {
+        /// S3 URL to purge all objects and versions from
+        #[arg(required = true)]
+        url: S3Location,
+
+        /// Don't prompt for confirmation, e.g. in scripts.  Needs --confirm-bucket.
+        #[arg(short, long, visible_alias = "force")]
+        yes: bool,
+
+        /// Name of the bucket being destroyed, repeated as a guard when not prompting, i.e.
+        /// with --yes or in CI
+        #[arg(long, value_name = "NAME")]
+        confirm_bucket: Option<String>,
+    },
+    /// Print a man page for `bu` to stdout
+    #[command(name = "man")]
+    Man,
+}
+
+const DEFAULT_REPORT: &str = "bucket_usage.csv";
+const DEFAULT_ORPHANS: &str = "orphans.csv";
+const DEFAULT_MANIFEST: &str = "retention_manifest.csv";
+
+/// Environment variables read by `bu`, besides those for logging
+pub const AWS_ENV: [(&str, &str); 3] = [
+    ("AWS_PROFILE", "Profile to load credentials and region from."),
+    ("AWS_REGION", "Region to send requests to."),
+    (
+        "AWS_ACCESS_KEY_ID, AWS_SECRET_ACCESS_KEY, AWS_SESSION_TOKEN",
+        "Credentials to use in place of a profile.",
+    ),
+];
+
+/// Set by most CI services, in which case `bu destroy` and `bu prune` don't prompt
+pub const CI_VAR: &str = "CI";
+
+/// [`CI_VAR`] for man pages
+pub const CI_ENV: (&str, &str) = (
+    CI_VAR,
+    "When set, other than to false or 0, destroy and prune don't prompt for confirmation, so \
+     need --confirm-bucket.",
+);
+
+/**
+ * Run `bu`, or `tools s3`, with its parsed arguments
+ */
+pub fn run(cli: Cli) -> Result<()> {
+    if let Command::Man = cli.command {
+        let env: Vec<(&str, &str)> =
+            LOGGING_ENV.into_iter().chain(AWS_ENV).chain([CI_ENV]).collect();
+        print!("{}", man_page(&Cli::command(), &env));
+        return Ok(());
+    }
+    cli.common.init()?;
+    let runtime = Runtime::new()?;
+
+    runtime.block_on(async {
+        let config = match cli.profile {
+            Some(profile) => aws_config::from_env().profile_name(profile).load().await,
+            None => aws_config::load_from_env().await,
+        };
+
+        let s3 = S3Wrapper {
+            client: Client::new(&config),
+        };
+
+        match cli.command {
+            Command::Destroy { url: s3_location, yes, confirm_bucket } => {
+                let what = "destroy all objects and versions";
+                if confirm(&s3_location, what, yes, confirm_bucket.as_deref())? {
+                    println!("*** Action confirmed ");
+                    s3.purge_all_versions_of_everything(
+                        &s3_location.bucket,
+                        &s3_location.key_prefix(),
+                        true,
+                    )
+                    .await?
+                } else {
+                    println!("*** Action dismissed")
+                }
+            }
+            Command::Prune {
+                url,
+                yes,
+                confirm_bucket,
+            } => {
+                let orphans = list_prunable_orphans(&url, &s3, true).await?;
+                let bytes = orphans.iter()
+                    .map(|orphan| orphan.size().unwrap_or_default().max(0) as u64)
+                    .sum();
+                println!(
+                    "{} orphaned versions, {}, under {}",
+                    orphans.len(),
+                    format_size(bytes),
+                    url
+                );
+                if orphans.is_empty() {
+                    return Ok(());
+                }
+                let what = "delete the orphaned versions";
+                if confirm(&url, what, yes, confirm_bucket.as_deref())? {
+                    println!("*** Action confirmed ");
+                    s3.delete_versions(&url.bucket, &orphans).await?
+                } else {
+                    println!("*** Action dismissed")
+                }
+            }
+            Command::EnforceRetention {
+                url,
+                policy,
+                dry_run,
+                output,
+                yes,
+                confirm_bucket,
+            } => {
+                let policy = RetentionPolicy::from_file(&policy)?;
+                let versions = s3
+                    .get_object_versions(&url.bucket, &url.key_prefix(), true)
+                    .await?;
+                let now = Utc::now();
+                let excess = policy.excess(&versions, now)?;
+
+                let out_file = output.path(DEFAULT_MANIFEST, &FileNameValues::now(None))?;
+                log::info!("Writing manifest to {}", out_file.display());
+                let mut writer = output.format.open::<CSVExcessVersion>(
+                    &out_file,
+                    &ReportOptions {
+                        column_type: CSVExcessVersion::column_type,
+                        dialect: output.csv_dialect(),
+                        ..Default::default()
+                    },
+                )?;
+                for (version, newer_versions) in &excess {
+                    let row = CSVExcessVersion::new(&url.bucket, version, *newer_versions, now);
+                    writer.write(&row, &[])?;
+                }
+                writer.finish()?;
+
+                let bytes = excess.iter()
+                    .map(|(version, _)| version.size().unwrap_or_default().max(0) as u64)
+                    .sum();
+                let summary = format!(
+                    "{} of {} versions, {}, under {} are beyond the retention policy",
+                    excess.len(),
+                    versions.len(),
+                    format_size(bytes),
+                    url
+                );
+                match output.to_stdout() {
+                    true => eprintln!("{}", summary),
+                    false => println!("{}", summary),
+                }
+                if dry_run || excess.is_empty() {
+                    return Ok(());
+                }
+                let what = format!("delete {} versions", excess.len());
+                if confirm(&url, &what, yes, confirm_bucket.as_deref())? {
+                    println!("*** Action confirmed ");
+                    let excess: Vec<_> = excess.into_iter()
+                        .map(|(version, _)| version.clone())
+                        .collect();
+                    s3.delete_versions(&url.bucket, &excess).await?
+                } else {
+                    println!("*** Action dismissed")
+                }
+            }
+            Command::Size { url: s3_location } => {
+                log::info!("Analysing: {}", &s3_location);
+                let report = crate::s3::size::build_size_report(&s3_location, &s3, true).await?;
+                println!("{}", report.render(console::stdout_color()));
+            }
+            Command::SizeReport {
+                urls,
+                output,
+                console_format,
+                append,
+                dedup_latest,
+                columns,
+                sort_by,
+                desc,
+                no_total,
+                baseline,
+            } => {
+                let baseline = match baseline {
+                    Some(path) => {
+                        let baseline = Baseline::from_csv(&path)?;
+                        if baseline.is_empty() {
+                            log::warn!("Baseline {} has no rows to compare with", path.display());
+                        }
+                        baseline
+                    }
+                    None => Baseline::default(),
+                };
+                let columns = ColumnSelection::new(CSVSizeReport::COLUMNS, &columns)?;
+                if let Some(column) = sort_by.as_deref().filter(|c| !columns.contains(c)) {
+                    bail!("Can't sort by '{}' unless it's one of the --columns", column);
+                }
+                if dedup_latest && !(columns.contains("url") && columns.contains("run_time")) {
+                    bail!("--dedup-latest needs the url and run_time columns");
+                }
+
+                //Quick check to fail fast if we don't have access, which also reads what the
+                //report says about each bucket
+                let mut buckets = HashMap::new();
+                for url in &urls {
+                    if buckets.contains_key(&url.bucket) {
+                        continue;
+                    }
+                    log::info!("Check access for {}", url);
+                    let metadata = s3.bucket_metadata(&url.bucket).await?;
+                    if let Some(status) = metadata.versioning {
+                        log::info!(" - versioning status: {}", status);
+                    }
+                    buckets.insert(url.bucket.clone(), metadata);
+                }
+
+                let out_file = output.path(DEFAULT_REPORT, &FileNameValues::now(None))?;
+                let mut writer = output.format.open::<SelectedRow>(&out_file, &ReportOptions {
+                    column_type: CSVSizeReport::column_type,
+                    dialect: output.csv_dialect(),
+                    append,
+                    ..Default::default()
+                })?;
+                let now = Utc::now();
+                let run_time = now.format(RUN_TIME_FORMAT).to_string();
+                log::info!("Writing to {}", out_file.display());
+                // With the report itself on stdout, what's for people goes to stderr
+                let to_stdout = output.to_stdout();
+                let show = |text: &str| match to_stdout {
+                    true => eprintln!("{}", text),
+                    false => println!("{}", text),
+                };
+                let color = match to_stdout {
+                    true => console::stderr_color(),
+                    false => console::stdout_color(),
+                };
+                // Report on as many as possible, rather than losing them all to one failure
+                let mut failed = Vec::new();
+                let mut done = Vec::new();
+                for url in &urls {
+                    log::info!("Analysing: {}", url);
+                    let report = match crate::s3::size::build_size_report(url, &s3, true).await {
+                        Ok(report) => report,
+                        Err(e) => {
+                            log::error!("Failed to report on {}: {:#}", url, e);
+                            failed.push(url.to_string());
+                            continue;
+                        }
+                    };
+                    if !cli.common.quiet && console_format == ConsoleFormat::Text {
+                        show(&report.render(color));
+                    }
+                    let delta = baseline.delta(
+                        &report.url,
+                        report.total.size.0,
+                        report.total.num_objects,
+                        now,
+                    );
+                    let row = CSVSizeReport::new(&report, &buckets[&url.bucket], &run_time)
+                        .with_delta(delta);
+                    let row = columns.select(&row)?;
+                    if sort_by.is_none() {
+                        writer.write(&row, &[])?;
+                    }
+                    done.push((report, row));
+                }
+                if let Some(column) = &sort_by {
+                    sort_by_column(&mut done, |(_, row)| row, column, desc)?;
+                    for (_, row) in &done {
+                        writer.write(row, &[])?;
+                    }
+                }
+                if !no_total && done.len() > 1 {
+                    let total = SizeReport::combined(done.iter().map(|(report, _)| report));
+                    let delta =
+                        baseline.delta(TOTAL_URL, total.total.size.0, total.total.num_objects, now);
+                    let row = CSVSizeReport::new(&total, &BucketMetadata::default(), &run_time)
+                        .with_delta(delta);
+                    let row = columns.select(&row)?;
+                    writer.write(&row, &[])?;
+                    done.push((total, row));
+                }
+                writer.finish()?;
+                let (reports, rows): (Vec<_>, Vec<_>) = done.into_iter().unzip();
+                if dedup_latest {
+                    let dialect = output.format.csv_dialect(output.csv_dialect());
+                    let dropped = keep_latest(&out_file, dialect, "url", "run_time")?;
+                    log::info!("Dropped {} older rows from {}", dropped, out_file.display());
+                }
+                if !cli.common.quiet {
+                    match console_format {
+                        ConsoleFormat::Text if reports.len() > 1 => {
+                            show(&render_summary(&reports))
+                        }
+                        ConsoleFormat::Text => (),
+                        format => show(&render_records(format, &rows)?),
+                    }
+                }
+                if !failed.is_empty() {
+                    return Err(Failure::Partial.because(format!(
+                        "Failed to report on {} of {} locations: {}",
+                        failed.len(),
+                        urls.len(),
+                        failed.join(", ")
+                    )));
+                }
+            }
+            Command::Heavy {
+                url,
+                top,
+                depth,
+                by,
+            } => {
+                log::info!("Weighing prefixes under {}", url);
+                let weights = heaviest_prefixes(&url, &s3, depth, top, by).await?;
+                println!("{}", render_heaviest(&url.bucket, &weights));
+            }
+            Command::Orphans { url, output } => {
+                let out_file = output.path(DEFAULT_ORPHANS, &FileNameValues::now(None))?;
+                log::info!("Listing orphaned versions under {}", url);
+                let orphans = list_orphans(&url, &s3, true).await?;
+                let mut writer = output.format.open::<CSVOrphan>(
+                    &out_file,
+                    &ReportOptions {
+                        column_type: CSVOrphan::column_type,
+                        dialect: output.csv_dialect(),
+                        ..Default::default()
+                    },
+                )?;
+                log::info!("Writing to {}", out_file.display());
+                let now = Utc::now();
+                for orphan in &orphans {
+                    writer.write(&CSVOrphan::new(&url.bucket, orphan, now), &[])?;
+                }
+                writer.finish()?;
+                if !cli.common.quiet {
+                    let bytes = orphans.iter()
+                        .map(|orphan| orphan.size().unwrap_or_default().max(0) as u64)
+                        .sum();
+                    let summary = format!(
+                        "{} orphaned versions, {}, under {}",
+                        orphans.len(),
+                        format_size(bytes),
+                        url
+                    );
+                    // With the list itself on stdout, what's for people goes to stderr
+                    match output.to_stdout() {
+                        true => eprintln!("{}", summary),
+                        false => println!("{}", summary),
+                    }
+                }
+            }
+            Command::Man => unreachable!("Handled before logging is set up"),
+        };
+
+        Ok(())
+    })
+}
+
+/**
+ * Whether to go ahead and do `what`, e.g. "destroy all objects and versions", under
+ * `location`.  Prompts unless `yes` is given or running in CI, in which case `confirm_bucket`
+ * must name the bucket.  A `confirm_bucket` naming any other bucket is an error.
+ */
+fn confirm(
+    location: &S3Location,
+    what: &str,
+    yes: bool,
+    confirm_bucket: Option<&str>,
+) -> Result<bool> {
+    if let Some(name) = confirm_bucket.filter(|name| *name != location.bucket) {
+        bail!(
+            "--confirm-bucket {} doesn't match the bucket of {}, {}",
+            name,
+            location,
+            location.bucket
+        );
+    }
+    let unprompted = match (yes, in_ci()) {
+        (true, _) => Some("--yes"),
+        (false, true) => Some(CI_VAR),
+        (false, false) => None,
+    };
+    match (unprompted, confirm_bucket) {
+        (Some(_), Some(_)) => Ok(true),
+        (Some(reason), None) => bail!(
+            "Not prompting for confirmation because of {}, so --confirm-bucket {} is needed \
+             to {} under {}",
+            reason,
+            location.bucket,
+            what,
+            location
+        ),
+        (None, _) => Confirm::new()
+            .with_prompt(format!(" Are you sure you want to {} under {}?", what, location))
+            .default(false)
+            .interact()
+            .wrap_err("Interaction error"),
+    }
+}
+
+fn in_ci() -> bool {
+    std::env::var(CI_VAR).is_ok_and(|value| !matches!(value.as_str(), "" | "false" | "0"))
+}
@@ -0,0 +1,60 @@
+use std::time::Duration;
+
+use clap::Parser;
+use color_eyre::Result;
+
+use super::{
+    common::{parse_duration, parse_nonzero_duration},
+    monitor::{self, open_breakdown},
+};
+use crate::output::{format::ReportOptions, rotating::Rotation};
+
+#[test]
+fn test_parse_human_durations() {
+    assert_eq!(Ok(Duration::from_millis(500)), parse_duration("500ms"));
+    assert_eq!(Ok(Duration::from_secs(90)), parse_duration("90s"));
+    assert_eq!(Ok(Duration::from_secs(9000)), parse_duration("2h30m"));
+    assert_eq!(Ok(Duration::from_secs(9000)), parse_duration("2h 30m"));
+    assert_eq!(Ok(Duration::from_secs(5)), parse_duration("5"));
+    // Intervals use parse_nonzero_duration instead
+    assert_eq!(Ok(Duration::ZERO), parse_duration("0s"));
+    for invalid in ["", "fast", "1.5", "-1s", "10 parsecs"] {
+        assert!(parse_duration(invalid).is_err(), "{}", invalid);
+    }
+}
+
+#[test]
+fn test_intervals_must_be_longer_than_zero() {
+    assert_eq!(Ok(Duration::from_millis(1)), parse_nonzero_duration("1ms"));
+    for zero in ["0", "0s", "0ms"] {
+        assert!(parse_nonzero_duration(zero).is_err(), "{}", zero);
+    }
+    for flag in ["--interval", "--rescan-interval"] {
+        let args = ["tu", flag, "0s", "--", "true"];
+        assert!(monitor::Cli::try_parse_from(args).is_err(), "{}", flag);
+    }
+    assert!(monitor::Cli::try_parse_from(["tu", "--max-runtime", "0s", "--", "true"]).is_ok());
+}
+
+#[test]
+fn test_per_process_breakdown_rotates_like_the_main_output() -> Result<()> {
+    let dir = std::env::temp_dir().join(format!("tu_test_breakdown_{}", std::process::id()));
+    let options = ReportOptions {
+        rotation: Rotation::Size(1),
+        keep: Some(2),
+        ..Default::default()
+    };
+    let mut wtr = open_breakdown(&dir, "process_usage.csv", &options)?;
+
+    // Every write fills the current file, so each starts a new one
+    for row in 0..4 {
+        wtr.serialize(("row", row))?;
+        wtr.flush()?;
+    }
+    wtr.finish()?;
+    let files = std::fs::read_dir(&dir)?.count();
+    std::fs::remove_dir_all(&dir)?;
+
+    assert_eq!(2, files);
+    Ok(())
+}
@@ -0,0 +1,171 @@
+use std::{fmt::Display, io};
+
+use clap::Parser;
+use color_eyre::{Report, Result};
+
+/// Exit code when everything worked
+pub const SUCCESS: i32 = 0;
+
+/// Exit code for any error without a more specific [`Failure`], including invalid arguments
+pub const ERROR: i32 = 1;
+
+/// Added to the exit code of a command run by `tu`, when it fails, to keep it clear of `tu`'s
+/// own codes
+pub const COMMAND_EXIT_OFFSET: i32 = 10;
+
+/// Every exit code, with what it means, for man pages and help
+pub const EXIT_CODES: [(&str, &str); 8] = [
+    ("0", "Success."),
+    ("1", "Any other error, including invalid arguments."),
+    (
+        "2",
+        "Partial failure: some of the work failed, e.g. one of several buckets.",
+    ),
+    ("3", "Access was denied, e.g. to a bucket."),
+    (
+        "4",
+        "Invalid target: the bucket, process, container or command doesn't exist.",
+    ),
+    ("5", "tu: the command breached a resource limit."),
+    ("6", "tu: the command was stopped at its --timeout."),
+    (
+        "10+",
+        "tu: the command failed, with exit code N (128 + N if killed by signal N), giving \
+         10 + N, up to 255.",
+    ),
+];
+
+/// A kind of failure with its own exit code, found anywhere among an error's causes
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Failure {
+    /// Some, but not all, of the work failed
+    Partial,
+    AccessDenied,
+    /// What to work on, such as a bucket, process or command, doesn't exist
+    InvalidTarget,
+    LimitBreached,
+    TimedOut,
+}
+
+impl Failure {
+    pub fn code(self) -> i32 {
+        match self {
+            Failure::Partial => 2,
+            Failure::AccessDenied => 3,
+            Failure::InvalidTarget => 4,
+            Failure::LimitBreached => 5,
+            Failure::TimedOut => 6,
+        }
+    }
+
+    /**
+     * An error reading `message`, caused by this failure
+     */
+    #[track_caller]
+    pub fn because<D>(self, message: D) -> Report
+    where
+        D: Display + Send + Sync + 'static,
+    {
+        Report::new(self).wrap_err(message)
+    }
+
+    /**
+     * The failure an I/O error, e.g. when starting a command, amounts to, if any
+     */
+    pub fn from_io(error: &io::Error) -> Option<Self> {
+        match error.kind() {
+            io::ErrorKind::NotFound => Some(Failure::InvalidTarget),
+            io::ErrorKind::PermissionDenied => Some(Failure::AccessDenied),
+            _ => None,
+        }
+    }
+
+    /**
+     * The failure an S3 error amounts to, going by its error code
+     */
+    #[cfg(feature = "s3")]
+    pub fn from_s3(error: &aws_sdk_s3::Error) -> Option<Self> {
+        use aws_sdk_s3::error::ProvideErrorMetadata;
+
+        match error.code()? {
+            "AccessDenied"
+            | "AllAccessDisabled"
+            | "InvalidAccessKeyId"
+            | "SignatureDoesNotMatch"
+            | "ExpiredToken" => Some(Failure::AccessDenied),
+            "NoSuchBucket" | "NotFound" | "InvalidBucketName" => Some(Failure::InvalidTarget),
+            _ => None,
+        }
+    }
+}
+
+impl Display for Failure {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            Failure::Partial => f.write_str("partial failure"),
+            Failure::AccessDenied => f.write_str("access denied"),
+            Failure::InvalidTarget => f.write_str("invalid target"),
+            Failure::LimitBreached => f.write_str("resource limit breached"),
+            Failure::TimedOut => f.write_str("timed out"),
+        }
+    }
+}
+
+impl std::error::Error for Failure {}
+
+/**
+ * Exit code for a command run by `tu` which exited with `code`
+ */
+pub fn command_exit_code(code: i32) -> i32 {
+    match code {
+        0 => SUCCESS,
+        code => (COMMAND_EXIT_OFFSET + code).clamp(COMMAND_EXIT_OFFSET, 255),
+    }
+}
+
+/**
+ * Exit code for an error: that of the first [`Failure`] among its causes, else [`ERROR`]
+ */
+pub fn exit_code(report: &Report) -> i32 {
+    report
+        .downcast_ref::<Failure>()
+        .copied()
+        .or_else(|| report.chain().find_map(failure_of))
+        .map_or(ERROR, Failure::code)
+}
+
+fn failure_of(error: &(dyn std::error::Error + 'static)) -> Option<Failure> {
+    if let Some(failure) = error.downcast_ref::<Failure>() {
+        return Some(*failure);
+    }
+    #[cfg(feature = "s3")]
+    if let Some(error) = error.downcast_ref::<aws_sdk_s3::Error>() {
+        return Failure::from_s3(error);
+    }
+    None
+}
+
+/**
+ * Exit the process with the outcome of `result`, printing the error, if there is one, as
+ * returning it from `main` would
+ */
+pub fn exit_with(result: Result<()>) -> ! {
+    match result {
+        Ok(()) => std::process::exit(SUCCESS),
+        Err(report) => {
+            eprintln!("Error: {:?}", report);
+            std::process::exit(exit_code(&report))
+        }
+    }
+}
+
+/**
+ * Parse the command line, exiting with [`ERROR`] if it's invalid, rather than clap's usual 2,
+ * which means a partial failure here
+ */
+pub fn parse_args<P: Parser>() -> P {
+    P::try_parse().unwrap_or_else(|e| {
+        let _ = e.print();
+        std::process::exit(if e.use_stderr() { ERROR } else { SUCCESS })
+    })
+}
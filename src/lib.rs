@@ -1,3 +1,13 @@
+//! Monitor the resources a process uses (`tu`) and report on S3 buckets (`bu`).
+//!
+//! The stable API is re-exported from [`prelude`].  The modules below are public so the
+//! binaries can share them, but their layout may change in any release.
+
+pub mod cli;
+pub mod exit;
 pub mod log;
+pub mod output;
+pub mod prelude;
+#[cfg(feature = "s3")]
 pub mod s3;
-pub mod process;
\ No newline at end of file
+pub mod process;
@@ -0,0 +1,256 @@
+pub mod system_log;
+
+#[cfg(test)]
+mod tests;
+
+use std::{fmt::Display, fs::OpenOptions, path::PathBuf, str::FromStr, sync::Mutex};
+
+use color_eyre::{Result, eyre::{Context, ContextCompat}};
+use tracing_subscriber::{
+    Layer, Registry,
+    filter::{LevelFilter, Targets},
+    fmt::{MakeWriter, format::FmtSpan},
+    layer::SubscriberExt,
+    util::SubscriberInitExt,
+};
+
+use self::system_log::SystemLogLayer;
+
+/// Log file to use when none is passed to [`setup_logging`]
+pub const LOG_FILE_VAR: &str = "TOOLS_LOG_FILE";
+
+/// Per-module filters, e.g. `info,aws_config=debug`, overriding those set by verbosity
+pub const FILTER_VAR: &str = "RUST_LOG";
+
+/// Most `-v` flags which make a difference
+const MAX_VERBOSITY: u8 = 3;
+
+/// How each log line is written
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default)]
+pub enum LogFormat {
+    /// Human readable
+    #[default]
+    Text,
+    /// One JSON object per line, with `timestamp`, `level`, `target` and `message`, for log
+    /// aggregators
+    Json,
+}
+
+impl FromStr for LogFormat {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s {
+            "text" => Ok(LogFormat::Text),
+            "json" => Ok(LogFormat::Json),
+            _ => Err(format!("expected text or json, not '{}'", s)),
+        }
+    }
+}
+
+impl Display for LogFormat {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            LogFormat::Text => f.write_str("text"),
+            LogFormat::Json => f.write_str("json"),
+        }
+    }
+}
+
+/// Where the console log goes
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default)]
+pub enum LogTarget {
+    #[default]
+    Stderr,
+    /// The systemd journal, with each entry's priority set from its level
+    Journald,
+    /// The local syslog daemon, at the matching severity
+    Syslog,
+}
+
+impl FromStr for LogTarget {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s {
+            "stderr" => Ok(LogTarget::Stderr),
+            "journald" => Ok(LogTarget::Journald),
+            "syslog" => Ok(LogTarget::Syslog),
+            _ => Err(format!("expected stderr, journald or syslog, not '{}'", s)),
+        }
+    }
+}
+
+impl Display for LogTarget {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            LogTarget::Stderr => f.write_str("stderr"),
+            LogTarget::Journald => f.write_str("journald"),
+            LogTarget::Syslog => f.write_str("syslog"),
+        }
+    }
+}
+
+impl LogFormat {
+    /**
+     * A layer writing to `writer` in this format.  At debug level and above, spans (e.g. each
+     * S3 page or sample) are logged as they close, with how long they took.
+     */
+    fn layer<W>(self, writer: W, level: LevelFilter, ansi: bool) -> Box<dyn Layer<Registry> + Send + Sync>
+    where
+        W: for<'w> MakeWriter<'w> + Send + Sync + 'static,
+    {
+        let span_events = if level >= LevelFilter::DEBUG { FmtSpan::CLOSE } else { FmtSpan::NONE };
+        let layer = tracing_subscriber::fmt::layer()
+            .with_writer(writer)
+            .with_ansi(ansi)
+            .with_span_events(span_events);
+        match self {
+            LogFormat::Text => layer.boxed(),
+            LogFormat::Json => layer.json().flatten_event(true).boxed(),
+        }
+    }
+}
+
+/// How much to log, and where
+#[derive(Debug, Clone, Default)]
+pub struct LogOptions {
+    /// Number of `-v` flags given
+    pub verbosity: u8,
+    /// Only log errors to the console, and don't print the filter levels
+    pub quiet: bool,
+    /// Colour console log lines
+    pub color: bool,
+    /// Also append the log to this file
+    pub file: Option<PathBuf>,
+    pub format: LogFormat,
+    pub target: LogTarget,
+}
+
+/**
+ * Log to stderr at the given verbosity and, if a file is given (or named by
+ * [`LOG_FILE_VAR`]), append to that file too, always at debug level or above.  Messages
+ * logged through the `log` crate are included.  Filters in [`FILTER_VAR`] take precedence
+ * over those from the verbosity, module by module.  With a target other than stderr, the
+ * console log goes there instead, falling back to stderr if it can't be reached.
+ */
+pub fn setup_logging(options: &LogOptions) -> Result<(), color_eyre::eyre::Error> {
+    fn set_log_level(local_level: LevelFilter, dep_level:  LevelFilter, log_file: Option<PathBuf>, options: &LogOptions, mut warnings: Vec<String>) -> Result<(), color_eyre::eyre::Error> {
+        let prog: String = std::env::current_exe().wrap_err("Error getting current_exe")?
+            .file_name().wrap_err("File path terminated in ..")?
+            .to_str().wrap_err("utf-8 validity failed")?
+            .to_owned();
+
+        let crate_name: &'static str = env!("CARGO_CRATE_NAME");
+
+        let overrides = match std::env::var(FILTER_VAR) {
+            Ok(directives) if !directives.trim().is_empty() => match directives.parse::<Targets>() {
+                Ok(overrides) => Some((directives, overrides)),
+                Err(e) => {
+                    warnings.push(format!("Ignoring {}='{}': {}", FILTER_VAR, directives, e));
+                    None
+                }
+            },
+            _ => None,
+        };
+        let targets = |level: LevelFilter, dep_level: LevelFilter| {
+            let defaults = Targets::new()
+                .with_default(dep_level)
+                .with_target(prog.clone(), level)
+                .with_target(crate_name, level);
+            match &overrides {
+                Some((_, overrides)) => override_targets(defaults, overrides),
+                None => defaults,
+            }
+        };
+        let (console_level, console_dep_level) = match options.quiet {
+            true => (LevelFilter::ERROR, LevelFilter::ERROR),
+            false => (local_level, dep_level),
+        };
+
+        let system_log = match options.target {
+            LogTarget::Stderr => None,
+            target => SystemLogLayer::connect(target, &prog)
+                .inspect_err(|e| warnings.push(format!("Logging to stderr instead: {:#}", e)))
+                .ok(),
+        };
+        // Status lines on stdout would be logged again, without a priority
+        let status_lines = options.format == LogFormat::Text && system_log.is_none() && !options.quiet;
+        let console_targets = targets(console_level, console_dep_level);
+        let mut layers = match system_log {
+            Some(layer) => vec![layer.with_filter(console_targets).boxed()],
+            None => vec![options.format
+                .layer(std::io::stderr, console_level, options.color)
+                .with_filter(console_targets)
+                .boxed()],
+        };
+        if let Some(path) = log_file {
+            let file = OpenOptions::new()
+                .create(true)
+                .append(true)
+                .open(&path)
+                .wrap_err_with(|| format!("Failed to open log file {}", path.display()))?;
+            // Whatever is shown on the console, the file has enough to investigate afterwards
+            let file_level = local_level.max(LevelFilter::DEBUG);
+            if status_lines {
+                eprintln!("Logging to {} at filter level {}", path.display(), file_level);
+            }
+            layers.push(options.format
+                .layer(Mutex::new(file), file_level, false)
+                .with_filter(targets(file_level, dep_level))
+                .boxed());
+        }
+        tracing_subscriber::registry()
+            .with(layers)
+            .try_init()
+            .wrap_err("Failed to set logger")?;
+
+        // Plain lines would trip up whatever is parsing JSON logs.  They go to stderr so
+        // stdout is left for reports, e.g. with `-f -`.
+        if status_lines {
+            eprintln!("Logging filter level for '{}' and '{}': {}", &prog, crate_name, local_level);
+            eprintln!("Dependency logging filter level: {}", dep_level);
+            if let Some((directives, _)) = &overrides {
+                eprintln!("Overridden by {}: {}", FILTER_VAR, directives);
+            }
+        }
+
+        tracing::info!("Logging filter level for '{}' and '{}': {}", &prog, crate_name, local_level);
+        tracing::info!("Dependency logging filter level: {}", dep_level);
+        if let Some((directives, _)) = &overrides {
+            tracing::info!("Overridden by {}: {}", FILTER_VAR, directives);
+        }
+        for warning in warnings {
+            tracing::warn!("{}", warning);
+        }
+        Ok(())
+    }
+
+    let log_file = options.file
+        .clone()
+        .or_else(|| std::env::var_os(LOG_FILE_VAR).map(PathBuf::from));
+    let mut warnings = Vec::new();
+    let level = options.verbosity;
+    if level > MAX_VERBOSITY {
+        warnings.push(format!("Too many levels of verbosity ({}), using {}.  Set {} for finer control.", level, MAX_VERBOSITY, FILTER_VAR));
+    }
+    match level.min(MAX_VERBOSITY) {
+        0 => set_log_level(LevelFilter::WARN, LevelFilter::WARN, log_file, options, warnings)?,
+        1 => set_log_level(LevelFilter::INFO, LevelFilter::WARN, log_file, options, warnings)?,
+        2 => set_log_level(LevelFilter::DEBUG, LevelFilter::WARN, log_file, options, warnings)?,
+        _ => set_log_level(LevelFilter::TRACE, LevelFilter::INFO, log_file, options, warnings)?,
+    };
+    Ok(())
+}
+
+/**
+ * `defaults` with the level of each target in `overrides` replaced, or added, as is the
+ * default level if `overrides` has one
+ */
+pub fn override_targets(defaults: Targets, overrides: &Targets) -> Targets {
+    let defaults = match overrides.default_level() {
+        Some(level) => defaults.with_default(level),
+        None => defaults,
+    };
+    defaults.with_targets(overrides.iter().map(|(target, level)| (target.to_string(), level)))
+}
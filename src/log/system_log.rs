@@ -0,0 +1,161 @@
+use std::{fmt::Write, os::unix::net::UnixDatagram, path::Path};
+
+use color_eyre::{Result, eyre::Context};
+use tracing::{
+    Event, Level, Subscriber,
+    field::{Field, Visit},
+};
+use tracing_subscriber::{Layer, layer::Context as LayerContext};
+
+use super::LogTarget;
+
+/// Where journald listens for its native protocol
+pub const JOURNALD_SOCKET: &str = "/run/systemd/journal/socket";
+
+/// Where syslog daemons (and journald) listen for RFC 3164 messages
+pub const SYSLOG_SOCKET: &str = "/dev/log";
+
+/// The `user` syslog facility
+const FACILITY_USER: u8 = 1;
+
+/// Sends each event to journald or syslog with the matching priority, for running under
+/// systemd, where stderr loses its levels and colour codes end up in the journal
+pub struct SystemLogLayer {
+    socket: UnixDatagram,
+    target: LogTarget,
+    identifier: String,
+    pid: u32,
+}
+
+impl SystemLogLayer {
+    /**
+     * Connect to the socket for `target`, tagging entries with `identifier`, usually the
+     * program name
+     */
+    pub fn connect(target: LogTarget, identifier: &str) -> Result<Self> {
+        let path = match target {
+            LogTarget::Journald => JOURNALD_SOCKET,
+            _ => SYSLOG_SOCKET,
+        };
+        let socket = UnixDatagram::unbound().wrap_err("Failed to create log socket")?;
+        socket
+            .connect(Path::new(path))
+            .wrap_err_with(|| format!("Failed to connect to {} at {}", target, path))?;
+
+        Ok(Self {
+            socket,
+            target,
+            identifier: identifier.to_string(),
+            pid: std::process::id(),
+        })
+    }
+}
+
+impl<S: Subscriber> Layer<S> for SystemLogLayer {
+    fn on_event(&self, event: &Event<'_>, _ctx: LayerContext<'_, S>) {
+        let mut message = MessageVisitor::default();
+        event.record(&mut message);
+        let metadata = event.metadata();
+        let priority = priority(metadata.level());
+        let target = message.log_target.as_deref().unwrap_or(metadata.target());
+
+        let datagram = match self.target {
+            LogTarget::Journald => journal_entry(priority, &self.identifier, target, &message.text),
+            _ => syslog_line(priority, &self.identifier, self.pid, target, &message.text)
+                .into_bytes(),
+        };
+        // Nowhere left to report a failure to log
+        let _ = self.socket.send(&datagram);
+    }
+}
+
+/// The event's message, followed by any other fields as `key=value`
+#[derive(Default)]
+struct MessageVisitor {
+    text: String,
+    /// Events bridged from the `log` crate have a generic target, with the real one here
+    log_target: Option<String>,
+}
+
+impl Visit for MessageVisitor {
+    fn record_str(&mut self, field: &Field, value: &str) {
+        if field.name() == "log.target" {
+            self.log_target = Some(value.to_string());
+        } else {
+            self.record_debug(field, &value)
+        }
+    }
+
+    fn record_debug(&mut self, field: &Field, value: &dyn std::fmt::Debug) {
+        let name = field.name();
+        // Where bridged events were logged, which journald and syslog don't need
+        if name.starts_with("log.") {
+            return;
+        }
+        if !self.text.is_empty() {
+            self.text.push(' ');
+        }
+        if name == "message" {
+            let _ = write!(self.text, "{:?}", value);
+        } else {
+            let _ = write!(self.text, "{}={:?}", name, value);
+        }
+    }
+}
+
+/**
+ * Syslog severity of a level, which journald uses as its `PRIORITY`
+ */
+pub fn priority(level: &Level) -> u8 {
+    match *level {
+        Level::ERROR => 3,
+        Level::WARN => 4,
+        Level::INFO => 6,
+        _ => 7,
+    }
+}
+
+/**
+ * A journald native protocol entry.  Values containing newlines are length prefixed, as the
+ * protocol requires.
+ */
+pub fn journal_entry(priority: u8, identifier: &str, target: &str, message: &str) -> Vec<u8> {
+    let mut entry = Vec::new();
+    let mut field = |key: &str, value: &str| {
+        entry.extend_from_slice(key.as_bytes());
+        if value.contains('\n') {
+            entry.push(b'\n');
+            entry.extend_from_slice(&(value.len() as u64).to_le_bytes());
+        } else {
+            entry.push(b'=');
+        }
+        entry.extend_from_slice(value.as_bytes());
+        entry.push(b'\n');
+    };
+    field("PRIORITY", &priority.to_string());
+    field("SYSLOG_IDENTIFIER", identifier);
+    field("TARGET", target);
+    field("MESSAGE", message);
+    entry
+}
+
+/**
+ * An RFC 3164 message for the `user` facility, leaving the timestamp and hostname to the
+ * syslog daemon
+ */
+pub fn syslog_line(
+    priority: u8,
+    identifier: &str,
+    pid: u32,
+    target: &str,
+    message: &str,
+) -> String {
+    format!(
+        "<{}>{}[{}]: {}: {}",
+        FACILITY_USER * 8 + priority,
+        identifier,
+        pid,
+        target,
+        message
+    )
+}
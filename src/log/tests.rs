@@ -0,0 +1,50 @@
+use tracing::Level;
+use tracing_subscriber::filter::{LevelFilter, Targets};
+
+use super::{
+    override_targets,
+    system_log::{journal_entry, priority, syslog_line},
+};
+
+#[test]
+fn test_rust_log_overrides_verbosity_per_target() {
+    let defaults = Targets::new()
+        .with_default(LevelFilter::WARN)
+        .with_target("tu", LevelFilter::DEBUG)
+        .with_target("tools", LevelFilter::DEBUG);
+
+    let overrides: Targets = "tools=warn,aws_config=trace".parse().unwrap();
+    let targets = override_targets(defaults.clone(), &overrides);
+    assert!(!targets.would_enable("tools::process", &Level::INFO));
+    assert!(targets.would_enable("tu", &Level::DEBUG));
+    assert!(targets.would_enable("aws_config::sso", &Level::TRACE));
+    assert!(!targets.would_enable("hyper", &Level::INFO));
+
+    let overrides: Targets = "info".parse().unwrap();
+    let targets = override_targets(defaults, &overrides);
+    assert!(targets.would_enable("hyper", &Level::INFO));
+    assert!(targets.would_enable("tools", &Level::DEBUG));
+}
+
+#[test]
+fn test_system_log_encoding() {
+    assert_eq!(priority(&Level::ERROR), 3);
+    assert_eq!(priority(&Level::WARN), 4);
+    assert_eq!(priority(&Level::TRACE), 7);
+
+    assert_eq!(
+        journal_entry(4, "tu", "tools::process", "GPU 0 is hot"),
+        b"PRIORITY=4\nSYSLOG_IDENTIFIER=tu\nTARGET=tools::process\nMESSAGE=GPU 0 is hot\n"
+    );
+
+    // Multi-line values are length prefixed
+    let mut expected = b"PRIORITY=3\nSYSLOG_IDENTIFIER=bu\nTARGET=tools\nMESSAGE\n".to_vec();
+    expected.extend_from_slice(&7u64.to_le_bytes());
+    expected.extend_from_slice(b"a\nb=c d\n");
+    assert_eq!(journal_entry(3, "bu", "tools", "a\nb=c d"), expected);
+
+    assert_eq!(
+        syslog_line(6, "tu", 42, "tools::process", "started"),
+        "<14>tu[42]: tools::process: started"
+    );
+}
@@ -0,0 +1,137 @@
+use std::{marker::PhantomData, time::Duration};
+
+use color_eyre::{Result, eyre::Context};
+use serde::{Serialize, de::DeserializeOwned};
+
+use super::sink::record_fields;
+
+/// Column saying which statistic a row holds: `raw`, `min`, `mean` or `max`
+pub const AGGREGATE_COLUMN: &str = "aggregate";
+
+const STATISTICS: [&str; 3] = ["min", "mean", "max"];
+
+/// Passes records through for the first part of a run, then folds each period's records into
+/// a min, a mean and a max row, so very long runs keep their peaks without every sample.
+///
+/// Records must round trip through CSV, and have an [`AGGREGATE_COLUMN`], which is set to
+/// the statistic each folded row holds.  Numeric columns are aggregated; label columns
+/// (e.g. timestamps and PIDs), and any which aren't numbers, are taken from the period's
+/// first record.  Means keep the most decimal places seen in the period, so whole number
+/// columns stay whole.
+pub struct Aggregator<R> {
+    retain_raw: Duration,
+    period: Duration,
+    labels: Vec<String>,
+    header: Vec<String>,
+    /// Values of each record in the current period
+    pending: Vec<Vec<String>>,
+    /// Index of the current period, counting from the end of `retain_raw`
+    current: Option<u128>,
+    _record: PhantomData<R>,
+}
+
+impl<R: Serialize + DeserializeOwned> Aggregator<R> {
+    pub fn new(retain_raw: Duration, period: Duration, labels: &[&str]) -> Self {
+        Self {
+            retain_raw,
+            period,
+            labels: labels.iter().map(|label| label.to_string()).collect(),
+            header: Vec::new(),
+            pending: Vec::new(),
+            current: None,
+            _record: PhantomData,
+        }
+    }
+
+    /**
+     * Add a record taken `elapsed` into the run, returning the rows to write now: the record
+     * itself while within `retain_raw`, otherwise any period it closes
+     */
+    pub fn push(&mut self, record: R, elapsed: Duration) -> Result<Vec<R>> {
+        let Some(since_raw) = elapsed.checked_sub(self.retain_raw) else {
+            return Ok(vec![record]);
+        };
+        let period = since_raw.as_nanos() / self.period.as_nanos().max(1);
+
+        let rows = match self.current {
+            Some(current) if current != period => self.flush()?,
+            _ => Vec::new(),
+        };
+        self.current = Some(period);
+        let (header, values) = record_fields(&record)?;
+        self.header = header;
+        self.pending.push(values);
+
+        Ok(rows)
+    }
+
+    /**
+     * Rows for the period in progress, e.g. when the run ends
+     */
+    pub fn flush(&mut self) -> Result<Vec<R>> {
+        if self.pending.is_empty() {
+            return Ok(Vec::new());
+        }
+        let pending = std::mem::take(&mut self.pending);
+
+        let mut wtr = csv::Writer::from_writer(Vec::new());
+        wtr.write_record(&self.header)?;
+        for statistic in STATISTICS {
+            let row: Vec<String> = self
+                .header
+                .iter()
+                .enumerate()
+                .map(|(i, column)| {
+                    if column == AGGREGATE_COLUMN {
+                        statistic.to_string()
+                    } else if self.labels.contains(column) {
+                        pending[0][i].clone()
+                    } else {
+                        aggregate(pending.iter().map(|values| values[i].as_str()), statistic)
+                            .unwrap_or_else(|| pending[0][i].clone())
+                    }
+                })
+                .collect();
+            wtr.write_record(&row)?;
+        }
+        let csv = wtr
+            .into_inner()
+            .wrap_err("Failed to format aggregated rows")?;
+
+        csv::Reader::from_reader(csv.as_slice())
+            .deserialize()
+            .collect::<Result<Vec<R>, _>>()
+            .wrap_err("Failed to aggregate rows")
+    }
+}
+
+/**
+ * A statistic of the values which are numbers, `None` if none are.  The min and max are the
+ * original text, so keep their formatting.
+ */
+pub fn aggregate<'a>(values: impl Iterator<Item = &'a str>, statistic: &str) -> Option<String> {
+    let numbers: Vec<(&str, f64)> = values
+        .filter_map(|value| value.parse().ok().map(|number| (value, number)))
+        .collect();
+    let compare = |a: &&(&str, f64), b: &&(&str, f64)| a.1.total_cmp(&b.1);
+    match statistic {
+        "min" => numbers
+            .iter()
+            .min_by(compare)
+            .map(|(text, _)| text.to_string()),
+        "max" => numbers
+            .iter()
+            .max_by(compare)
+            .map(|(text, _)| text.to_string()),
+        _ if numbers.is_empty() => None,
+        _ => {
+            let decimals = numbers
+                .iter()
+                .map(|(text, _)| text.split_once('.').map_or(0, |(_, d)| d.len()))
+                .max()
+                .unwrap_or(0);
+            let mean = numbers.iter().map(|(_, n)| n).sum::<f64>() / numbers.len() as f64;
+            Some(format!("{:.*}", decimals, mean))
+        }
+    }
+}
@@ -0,0 +1,169 @@
+use std::{
+    collections::HashMap,
+    fs::{self, File, OpenOptions},
+    path::{Path, PathBuf},
+};
+
+use color_eyre::{
+    Result,
+    eyre::{Context, OptionExt, bail},
+};
+use serde::Serialize;
+
+use super::{
+    dialect::CsvDialect,
+    sink::{Sink, record_fields},
+};
+
+/// CSV writer which adds rows to the end of an existing file, e.g. to track a report over
+/// time.  The file's header must match the records, so files from another schema aren't mixed.
+pub struct AppendingCsvWriter {
+    path: PathBuf,
+    /// Header already in the file, if it had one
+    existing_header: Option<Vec<String>>,
+    /// Whether the records' columns have been checked against, or written as, the header
+    header_done: bool,
+    writer: csv::Writer<File>,
+}
+
+impl AppendingCsvWriter {
+    /**
+     * Open `path` for appending, creating it if it doesn't exist
+     */
+    pub fn open<P: AsRef<Path>>(path: P, dialect: CsvDialect) -> Result<Self> {
+        let path = path.as_ref().to_path_buf();
+        let existing_header = match path.exists() {
+            true => read_header(&path, dialect)?,
+            false => None,
+        };
+        let file = OpenOptions::new()
+            .create(true)
+            .append(true)
+            .open(&path)
+            .wrap_err_with(|| format!("Failed to open {} for appending", path.display()))?;
+
+        Ok(Self {
+            path,
+            existing_header,
+            header_done: false,
+            writer: dialect.writer().has_headers(false).from_writer(file),
+        })
+    }
+
+    pub fn write_record<R: Serialize>(&mut self, record: &R) -> Result<()> {
+        let (header, values) = record_fields(record)?;
+        if !self.header_done {
+            match &self.existing_header {
+                Some(existing) if *existing != header => bail!(
+                    "Can't append to {} as its columns differ, e.g. it was written with an \
+                     older schema.  Move it aside to start a new file.",
+                    self.path.display()
+                ),
+                Some(_) => (),
+                None => self.write_line(&header)?,
+            }
+            self.header_done = true;
+        }
+        self.write_line(&values)?;
+        self.writer
+            .flush()
+            .wrap_err_with(|| format!("Failed to flush {}", self.path.display()))
+    }
+
+    fn write_line(&mut self, fields: &[String]) -> Result<()> {
+        self.writer
+            .write_record(fields)
+            .wrap_err_with(|| format!("Failed to write to {}", self.path.display()))
+    }
+}
+
+impl<R: Serialize> Sink<R> for AppendingCsvWriter {
+    fn write(&mut self, record: &R, _gauges: &[(&str, f64)]) -> Result<()> {
+        self.write_record(record)
+    }
+
+    fn path(&self) -> Option<&Path> {
+        Some(&self.path)
+    }
+}
+
+/**
+ * Header of the CSV file at `path`, or `None` if it's empty
+ */
+fn read_header(path: &Path, dialect: CsvDialect) -> Result<Option<Vec<String>>> {
+    let mut reader = csv::ReaderBuilder::new()
+        .delimiter(dialect.delimiter)
+        .has_headers(false)
+        .from_path(path)
+        .wrap_err_with(|| format!("Failed to open {}", path.display()))?;
+    reader
+        .records()
+        .next()
+        .transpose()
+        .map(|header| header.map(|header| header.iter().map(String::from).collect()))
+        .wrap_err_with(|| format!("Failed to read header of {}", path.display()))
+}
+
+/**
+ * Rewrite the CSV file at `path` keeping only the newest row for each value of `key_column`,
+ * judged by `time_column`, which must sort as text, e.g. UTC RFC 3339 times.  Of rows from
+ * the same time the last wins.  Rows keep their order.  Returns how many rows were dropped.
+ */
+pub fn keep_latest(
+    path: &Path,
+    dialect: CsvDialect,
+    key_column: &str,
+    time_column: &str,
+) -> Result<usize> {
+    let mut reader = csv::ReaderBuilder::new()
+        .delimiter(dialect.delimiter)
+        .from_path(path)
+        .wrap_err_with(|| format!("Failed to open {}", path.display()))?;
+    let header = reader
+        .headers()
+        .wrap_err_with(|| format!("Failed to read header of {}", path.display()))?
+        .clone();
+    let column = |name: &str| {
+        header
+            .iter()
+            .position(|column| column == name)
+            .ok_or_eyre(format!("{} has no {} column", path.display(), name))
+    };
+    let (key, time) = (column(key_column)?, column(time_column)?);
+    let rows = reader
+        .records()
+        .collect::<Result<Vec<_>, _>>()
+        .wrap_err_with(|| format!("Failed to read {}", path.display()))?;
+
+    // Index of the newest row for each key
+    let mut latest: HashMap<&str, usize> = HashMap::new();
+    for (i, row) in rows.iter().enumerate() {
+        let newest = latest.entry(&row[key]).or_insert(i);
+        if row[time] >= rows[*newest][time] {
+            *newest = i;
+        }
+    }
+
+    // Written alongside then renamed, so the file is never left half written
+    let mut temp = path.as_os_str().to_owned();
+    temp.push(".tmp");
+    let temp = PathBuf::from(temp);
+    let mut writer = dialect
+        .writer()
+        .from_path(&temp)
+        .wrap_err_with(|| format!("Failed to create {}", temp.display()))?;
+    writer.write_record(&header)?;
+    let mut kept = 0;
+    for (i, row) in rows.iter().enumerate() {
+        if latest.get(&row[key]) == Some(&i) {
+            writer.write_record(row)?;
+            kept += 1;
+        }
+    }
+    writer
+        .flush()
+        .wrap_err_with(|| format!("Failed to write {}", temp.display()))?;
+    fs::rename(&temp, path).wrap_err_with(|| format!("Failed to replace {}", path.display()))?;
+
+    Ok(rows.len() - kept)
+}
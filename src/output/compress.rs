@@ -0,0 +1,104 @@
+use std::{
+    fs::File,
+    io::{self, Write},
+    path::{Path, PathBuf},
+};
+
+use color_eyre::{Result, eyre::Context};
+use flate2::write::GzEncoder;
+
+use super::format::is_stdout;
+
+/// How output files are compressed
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Compression {
+    None,
+    Gzip,
+    Zstd,
+}
+
+impl Compression {
+    /**
+     * `path` with the compression's extension added, unless it's already there
+     */
+    pub fn apply_extension(self, path: &Path) -> PathBuf {
+        if is_stdout(path) {
+            return path.to_path_buf();
+        }
+        let extension = match self {
+            Compression::None => return path.to_path_buf(),
+            Compression::Gzip => "gz",
+            Compression::Zstd => "zst",
+        };
+        if path.extension().is_some_and(|ext| ext == extension) {
+            return path.to_path_buf();
+        }
+        let mut path = path.as_os_str().to_owned();
+        path.push(".");
+        path.push(extension);
+        path.into()
+    }
+}
+
+/// Where a [`CompressedFile`] ends up
+type Destination = Box<dyn Write + Send>;
+
+/// A file, or stdout, which may compress what's written to it.  Flushing ends a compressed
+/// block, so everything written so far can be decompressed even if the stream is never
+/// finished.
+pub enum CompressedFile {
+    Plain(Destination),
+    Gzip(GzEncoder<Destination>),
+    Zstd(zstd::Encoder<'static, Destination>),
+}
+
+impl CompressedFile {
+    /**
+     * Create `path`, or write to stdout if it's `-`
+     */
+    pub fn create(path: &Path, compression: Compression) -> Result<Self> {
+        let file: Destination = match is_stdout(path) {
+            true => Box::new(io::stdout()),
+            false => Box::new(
+                File::create(path)
+                    .wrap_err_with(|| format!("Failed to create {}", path.display()))?,
+            ),
+        };
+        Ok(match compression {
+            Compression::None => CompressedFile::Plain(file),
+            Compression::Gzip => {
+                CompressedFile::Gzip(GzEncoder::new(file, flate2::Compression::default()))
+            }
+            Compression::Zstd => CompressedFile::Zstd(zstd::Encoder::new(file, 0)?),
+        })
+    }
+
+    /**
+     * Write the end of the compressed stream.  Nothing more should be written afterwards.
+     */
+    pub fn finish(&mut self) -> io::Result<()> {
+        match self {
+            CompressedFile::Plain(file) => file.flush(),
+            CompressedFile::Gzip(encoder) => encoder.try_finish(),
+            CompressedFile::Zstd(encoder) => encoder.do_finish(),
+        }
+    }
+}
+
+impl Write for CompressedFile {
+    fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+        match self {
+            CompressedFile::Plain(file) => file.write(buf),
+            CompressedFile::Gzip(encoder) => encoder.write(buf),
+            CompressedFile::Zstd(encoder) => encoder.write(buf),
+        }
+    }
+
+    fn flush(&mut self) -> io::Result<()> {
+        match self {
+            CompressedFile::Plain(file) => file.flush(),
+            CompressedFile::Gzip(encoder) => encoder.flush(),
+            CompressedFile::Zstd(encoder) => encoder.flush(),
+        }
+    }
+}
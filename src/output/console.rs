@@ -0,0 +1,184 @@
+use std::{
+    fmt::Display,
+    io::IsTerminal,
+    str::FromStr,
+    sync::atomic::{AtomicBool, Ordering},
+};
+
+use color_eyre::Result;
+use comfy_table::{Table, presets::ASCII_MARKDOWN};
+use serde::Serialize;
+
+use super::sink::record_fields;
+
+/// Whether colour may be used at all, see [`allow_color`]
+static COLOR_ALLOWED: AtomicBool = AtomicBool::new(true);
+
+/// How results printed to the terminal are laid out
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default)]
+pub enum ConsoleFormat {
+    /// Sentences, for reading
+    #[default]
+    Text,
+    /// A bordered table, which is also Markdown so it renders when pasted into chat
+    Table,
+    /// Tab separated values, which paste into separate spreadsheet cells
+    Tsv,
+}
+
+impl FromStr for ConsoleFormat {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s {
+            "text" => Ok(ConsoleFormat::Text),
+            "table" => Ok(ConsoleFormat::Table),
+            "tsv" => Ok(ConsoleFormat::Tsv),
+            _ => Err(format!("expected text, table or tsv, not '{}'", s)),
+        }
+    }
+}
+
+impl Display for ConsoleFormat {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            ConsoleFormat::Text => f.write_str("text"),
+            ConsoleFormat::Table => f.write_str("table"),
+            ConsoleFormat::Tsv => f.write_str("tsv"),
+        }
+    }
+}
+
+/**
+ * Lay out rows under a header as a table or TSV.  Text has no generic layout, so is
+ * written as TSV too.
+ */
+pub fn render(format: ConsoleFormat, header: &[String], rows: &[Vec<String>]) -> String {
+    match format {
+        ConsoleFormat::Table => {
+            let mut table = Table::new();
+            table.load_preset(ASCII_MARKDOWN).set_header(header);
+            for row in rows {
+                table.add_row(row);
+            }
+            table.to_string()
+        }
+        ConsoleFormat::Text | ConsoleFormat::Tsv => std::iter::once(header)
+            .chain(rows.iter().map(Vec::as_slice))
+            .map(|fields| {
+                fields
+                    .iter()
+                    .map(|field| field.replace(['\t', '\n', '\r'], " "))
+                    .collect::<Vec<_>>()
+                    .join("\t")
+            })
+            .collect::<Vec<_>>()
+            .join("\n"),
+    }
+}
+
+/**
+ * Records as a row each, with their CSV columns
+ */
+pub fn render_records<R: Serialize>(format: ConsoleFormat, records: &[R]) -> Result<String> {
+    let mut header = Vec::new();
+    let mut rows = Vec::new();
+    for record in records {
+        let (names, values) = record_fields(record)?;
+        header = names;
+        rows.push(values);
+    }
+    Ok(render(format, &header, &rows))
+}
+
+/**
+ * A single record as a row for each of its fields, which fits a terminal better than one
+ * very wide row
+ */
+pub fn render_fields<R: Serialize>(format: ConsoleFormat, record: &R) -> Result<String> {
+    let (names, values) = record_fields(record)?;
+    let rows: Vec<Vec<String>> = names
+        .into_iter()
+        .zip(values)
+        .map(|(name, value)| vec![name, value])
+        .collect();
+    Ok(render(
+        format,
+        &["field".to_string(), "value".to_string()],
+        &rows,
+    ))
+}
+
+/**
+ * Allow or forbid colour in everything written to the console, e.g. following `--no-color`
+ * or `$NO_COLOR`
+ */
+pub fn allow_color(allowed: bool) {
+    COLOR_ALLOWED.store(allowed, Ordering::Relaxed);
+}
+
+/**
+ * Whether colour may be used at all, e.g. in the dashboard, which is always on a terminal
+ */
+pub fn color_allowed() -> bool {
+    COLOR_ALLOWED.load(Ordering::Relaxed)
+}
+
+/**
+ * Whether to colour what's written to stdout: only if allowed, and stdout is a terminal
+ * rather than a file or pipe
+ */
+pub fn stdout_color() -> bool {
+    color_allowed() && std::io::stdout().is_terminal()
+}
+
+/**
+ * Whether to colour what's written to stderr, e.g. log lines and error reports
+ */
+pub fn stderr_color() -> bool {
+    color_allowed() && std::io::stderr().is_terminal()
+}
+
+/// Colours for console text, as ANSI escape codes
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Colour {
+    Green,
+    Yellow,
+    Red,
+    /// Faint, for less important text
+    Dim,
+}
+
+impl Colour {
+    fn code(self) -> &'static str {
+        match self {
+            Colour::Green => "32",
+            Colour::Yellow => "33",
+            Colour::Red => "31",
+            Colour::Dim => "2",
+        }
+    }
+}
+
+/**
+ * `text` in `colour`, if `enabled`, e.g. when writing to a terminal
+ */
+pub fn paint(text: &str, colour: Colour, enabled: bool) -> String {
+    match enabled {
+        true => format!("\x1b[{}m{}\x1b[0m", colour.code(), text),
+        false => text.to_string(),
+    }
+}
+
+/**
+ * A bar `width` characters wide, filled in proportion to `fraction` (from 0 to 1).  The
+ * unfilled part is dimmed when coloured.
+ */
+pub fn bar(fraction: f64, width: usize, colour: Colour, enabled: bool) -> String {
+    let filled = (fraction.clamp(0.0, 1.0) * width as f64).round() as usize;
+    format!(
+        "{}{}",
+        paint(&"█".repeat(filled), colour, enabled),
+        paint(&"░".repeat(width - filled), Colour::Dim, enabled)
+    )
+}
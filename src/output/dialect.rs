@@ -0,0 +1,105 @@
+use std::{fmt::Display, str::FromStr};
+
+use csv::{QuoteStyle, WriterBuilder};
+
+/// When CSV fields are quoted
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default)]
+pub enum Quoting {
+    /// Only fields holding the delimiter, a quote or a line break
+    #[default]
+    Necessary,
+    /// Every field, so text columns read back as text whatever they hold
+    Always,
+    /// Every field that isn't a number
+    NonNumeric,
+}
+
+impl FromStr for Quoting {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s {
+            "necessary" => Ok(Quoting::Necessary),
+            "always" => Ok(Quoting::Always),
+            "non-numeric" => Ok(Quoting::NonNumeric),
+            _ => Err(format!(
+                "expected necessary, always or non-numeric, not '{}'",
+                s
+            )),
+        }
+    }
+}
+
+impl Display for Quoting {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            Quoting::Necessary => f.write_str("necessary"),
+            Quoting::Always => f.write_str("always"),
+            Quoting::NonNumeric => f.write_str("non-numeric"),
+        }
+    }
+}
+
+/// How CSV files are delimited and quoted
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct CsvDialect {
+    pub delimiter: u8,
+    pub quoting: Quoting,
+}
+
+impl Default for CsvDialect {
+    fn default() -> Self {
+        Self {
+            delimiter: b',',
+            quoting: Quoting::Necessary,
+        }
+    }
+}
+
+impl CsvDialect {
+    /**
+     * A CSV writer builder for this dialect
+     */
+    pub fn writer(&self) -> WriterBuilder {
+        let mut builder = WriterBuilder::new();
+        builder
+            .delimiter(self.delimiter)
+            .quote_style(match self.quoting {
+                Quoting::Necessary => QuoteStyle::Necessary,
+                Quoting::Always => QuoteStyle::Always,
+                Quoting::NonNumeric => QuoteStyle::NonNumeric,
+            });
+        builder
+    }
+}
+
+/**
+ * Parse a CSV delimiter given on the command line: a single character such as `;`, or `tab`
+ * (also `\t`) as tabs are awkward to type
+ */
+pub fn parse_delimiter(s: &str) -> Result<u8, String> {
+    match s {
+        "tab" | "\\t" | "\t" => Ok(b'\t'),
+        _ => match s.as_bytes() {
+            [b'"'] | [b'\n'] | [b'\r'] => Err(format!("{:?} can't be a delimiter", s)),
+            [byte] => Ok(*byte),
+            _ => Err(format!(
+                "expected a single ASCII character such as ';', or tab, not '{}'",
+                s
+            )),
+        },
+    }
+}
+
+/**
+ * Guess the delimiter of a CSV file from its header line: whichever of comma, semicolon and
+ * tab appears most, preferring a comma
+ */
+pub fn sniff_delimiter(header: &str) -> u8 {
+    let count = |delimiter| header.bytes().filter(|&b| b == delimiter).count();
+    [b';', b'\t']
+        .into_iter()
+        .filter(|&delimiter| count(delimiter) > count(b','))
+        .max_by_key(|&delimiter| count(delimiter))
+        .unwrap_or(b',')
+}
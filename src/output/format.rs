@@ -0,0 +1,210 @@
+use std::{fmt::Display, fs::File, path::Path, str::FromStr};
+
+use color_eyre::{
+    Result,
+    eyre::{Context, bail},
+};
+use serde::Serialize;
+
+use super::{
+    append::AppendingCsvWriter,
+    compress::Compression,
+    dialect::CsvDialect,
+    json::JsonSink,
+    parquet::{ColumnType, ParquetSink},
+    rotating::{RotatingCsvWriter, Rotation},
+    sink::Sink,
+    table::TableSink,
+};
+
+/// Path which sends a report to stdout, e.g. `-f -`
+pub const STDOUT_PATH: &str = "-";
+
+/**
+ * Whether `path` means stdout rather than a file
+ */
+pub fn is_stdout(path: &Path) -> bool {
+    path == Path::new(STDOUT_PATH)
+}
+
+/// File format for a report, whatever is in it
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default)]
+pub enum ReportFormat {
+    #[default]
+    Csv,
+    /// CSV delimited by tabs, which pastes into spreadsheets
+    Tsv,
+    /// A single array of objects
+    Json,
+    /// One object per line
+    Jsonl,
+    /// Aligned columns, for reading
+    Table,
+    /// Typed columns, compact for long runs, but only readable once finished
+    Parquet,
+}
+
+impl FromStr for ReportFormat {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s {
+            "csv" => Ok(ReportFormat::Csv),
+            "tsv" => Ok(ReportFormat::Tsv),
+            "json" => Ok(ReportFormat::Json),
+            "jsonl" => Ok(ReportFormat::Jsonl),
+            "table" => Ok(ReportFormat::Table),
+            "parquet" => Ok(ReportFormat::Parquet),
+            _ => Err(format!(
+                "expected csv, tsv, json, jsonl, table or parquet, not '{}'",
+                s
+            )),
+        }
+    }
+}
+
+impl Display for ReportFormat {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            ReportFormat::Csv => f.write_str("csv"),
+            ReportFormat::Tsv => f.write_str("tsv"),
+            ReportFormat::Json => f.write_str("json"),
+            ReportFormat::Jsonl => f.write_str("jsonl"),
+            ReportFormat::Table => f.write_str("table"),
+            ReportFormat::Parquet => f.write_str("parquet"),
+        }
+    }
+}
+
+/// How a report file is written, where the format supports it
+#[derive(Debug, Clone, Copy)]
+pub struct ReportOptions {
+    pub rotation: Rotation,
+    /// Not supported by Parquet, which compresses internally, or tables
+    pub compression: Compression,
+    /// Most files to keep when rotating
+    pub keep: Option<usize>,
+    /// Delimiter and quoting of CSV files
+    pub dialect: CsvDialect,
+    /// Add to the end of an existing file rather than replacing it.  Only CSV and TSV files,
+    /// which aren't rotated or compressed, can be appended to.
+    pub append: bool,
+    /// Parquet type of each column, by name
+    pub column_type: fn(&str) -> ColumnType,
+}
+
+impl Default for ReportOptions {
+    fn default() -> Self {
+        Self {
+            rotation: Rotation::Never,
+            compression: Compression::None,
+            keep: None,
+            dialect: CsvDialect::default(),
+            append: false,
+            column_type: |_| ColumnType::Text,
+        }
+    }
+}
+
+impl ReportFormat {
+    /**
+     * File extension for the format, without the dot
+     */
+    pub fn extension(self) -> &'static str {
+        match self {
+            ReportFormat::Csv => "csv",
+            ReportFormat::Tsv => "tsv",
+            ReportFormat::Json => "json",
+            ReportFormat::Jsonl => "jsonl",
+            ReportFormat::Table => "txt",
+            ReportFormat::Parquet => "parquet",
+        }
+    }
+
+    /**
+     * `dialect` as this format uses it, i.e. tab delimited for TSV
+     */
+    pub fn csv_dialect(self, dialect: CsvDialect) -> CsvDialect {
+        match self {
+            ReportFormat::Tsv => CsvDialect {
+                delimiter: b'\t',
+                ..dialect
+            },
+            _ => dialect,
+        }
+    }
+
+    /**
+     * A sink writing records of any type to `path` in this format
+     */
+    pub fn open<R: Serialize>(
+        self,
+        path: &Path,
+        options: &ReportOptions,
+    ) -> Result<Box<dyn Sink<R>>> {
+        if is_stdout(path) {
+            if options.rotation != Rotation::Never || options.keep.is_some() || options.append {
+                bail!("Output to stdout can't be rotated or appended to")
+            }
+            match self {
+                ReportFormat::Parquet => bail!("Parquet output can't go to stdout"),
+                ReportFormat::Table => return Ok(Box::new(TableSink::new(std::io::stdout()))),
+                _ => (),
+            }
+        }
+        if options.append {
+            if !matches!(self, ReportFormat::Csv | ReportFormat::Tsv) {
+                bail!("Only CSV and TSV output can be appended to, not {}", self)
+            }
+            if options.rotation != Rotation::Never || options.compression != Compression::None {
+                bail!("Appended output can't be rotated or compressed")
+            }
+            let dialect = self.csv_dialect(options.dialect);
+            return Ok(Box::new(AppendingCsvWriter::open(path, dialect)?));
+        }
+        Ok(match self {
+            ReportFormat::Csv | ReportFormat::Tsv => {
+                let csv = RotatingCsvWriter::create(
+                    path,
+                    options.rotation,
+                    options.compression,
+                    self.csv_dialect(options.dialect),
+                )?;
+                Box::new(match options.keep {
+                    Some(files) => csv.with_retention(files),
+                    None => csv,
+                })
+            }
+            ReportFormat::Json | ReportFormat::Jsonl => {
+                let json = match self {
+                    ReportFormat::Json => {
+                        JsonSink::array(path, options.rotation, options.compression)?
+                    }
+                    _ => JsonSink::lines(path, options.rotation, options.compression)?,
+                };
+                Box::new(match options.keep {
+                    Some(files) => json.with_retention(files),
+                    None => json,
+                })
+            }
+            ReportFormat::Table => {
+                if options.rotation != Rotation::Never || options.compression != Compression::None {
+                    bail!("Table output can't be rotated or compressed")
+                }
+                let file = File::create(path)
+                    .wrap_err_with(|| format!("Failed to create {}", path.display()))?;
+                Box::new(TableSink::new(file))
+            }
+            ReportFormat::Parquet => {
+                if options.compression != Compression::None {
+                    bail!("Parquet output is already compressed")
+                }
+                let parquet = ParquetSink::new(path, options.rotation, options.column_type);
+                Box::new(match options.keep {
+                    Some(files) => parquet.with_retention(files),
+                    None => parquet,
+                })
+            }
+        })
+    }
+}
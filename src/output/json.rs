@@ -0,0 +1,170 @@
+use std::{io::Write, path::Path};
+
+use color_eyre::{
+    Result,
+    eyre::{Context, eyre},
+};
+use serde::Serialize;
+
+use super::{
+    compress::{CompressedFile, Compression},
+    rotating::{Rotation, Rotator},
+    sink::{Sink, record_fields},
+};
+
+/// Writes records as JSON objects, with the same columns as CSV output.  Numbers and
+/// booleans keep their types and `NA` values become nulls.
+pub struct JsonSink {
+    rotator: Rotator,
+    compression: Compression,
+    /// One object per line (JSON Lines) rather than a single array
+    lines: bool,
+    /// `None` once finished
+    file: Option<CompressedFile>,
+    /// Records written to the current file
+    count: usize,
+}
+
+impl JsonSink {
+    /**
+     * A single JSON array per file, closed when the sink finishes or rotates
+     */
+    pub fn array<P: AsRef<Path>>(
+        base_path: P,
+        rotation: Rotation,
+        compression: Compression,
+    ) -> Result<Self> {
+        Self::new(base_path.as_ref(), rotation, compression, false)
+    }
+
+    /**
+     * One JSON object per line, so a file can be read while it is still being written
+     */
+    pub fn lines<P: AsRef<Path>>(
+        base_path: P,
+        rotation: Rotation,
+        compression: Compression,
+    ) -> Result<Self> {
+        Self::new(base_path.as_ref(), rotation, compression, true)
+    }
+
+    fn new(
+        base_path: &Path,
+        rotation: Rotation,
+        compression: Compression,
+        lines: bool,
+    ) -> Result<Self> {
+        let rotator = Rotator::new(base_path, rotation, compression);
+        let file = Some(CompressedFile::create(rotator.current_path(), compression)?);
+        Ok(Self {
+            rotator,
+            compression,
+            lines,
+            file,
+            count: 0,
+        })
+    }
+
+    /**
+     * Only keep the most recent `files` files written by this sink, deleting older ones as
+     * it rotates
+     */
+    pub fn with_retention(mut self, files: usize) -> Self {
+        self.rotator.set_retention(files);
+        self
+    }
+
+    pub fn current_path(&self) -> &Path {
+        self.rotator.current_path()
+    }
+
+    pub fn serialize<R: Serialize>(&mut self, record: &R) -> Result<()> {
+        self.rotate_if_due()?;
+        let (header, values) = record_fields(record)?;
+        let object = json_object(&header, &values)?;
+
+        let separator = match (self.lines, self.count) {
+            (true, _) => "",
+            (false, 0) => "[\n",
+            (false, _) => ",\n",
+        };
+        let terminator = if self.lines { "\n" } else { "" };
+        let path = self.rotator.current_path();
+        let file = self
+            .file
+            .as_mut()
+            .ok_or_else(|| eyre!("{} has already been finished", path.display()))?;
+        write!(file, "{}{}{}", separator, object, terminator)
+            .and_then(|_| file.flush())
+            .wrap_err_with(|| format!("Failed to write to {}", path.display()))?;
+        self.count += 1;
+        Ok(())
+    }
+
+    /**
+     * Close the array, if writing one, and end the compressed stream if there is one.
+     * Nothing more may be written.
+     */
+    pub fn finish(&mut self) -> Result<()> {
+        let Some(mut file) = self.file.take() else {
+            return Ok(());
+        };
+        let end = match (self.lines, self.count) {
+            (true, _) => "",
+            (false, 0) => "[]\n",
+            (false, _) => "\n]\n",
+        };
+        file.write_all(end.as_bytes())
+            .and_then(|_| file.finish())
+            .wrap_err_with(|| format!("Failed to finish {}", self.rotator.current_path().display()))
+    }
+
+    fn rotate_if_due(&mut self) -> Result<()> {
+        if self.file.is_some() && self.rotator.is_due() {
+            self.finish()?;
+            self.rotator.advance();
+            self.file = Some(CompressedFile::create(
+                self.rotator.current_path(),
+                self.compression,
+            )?);
+            self.count = 0;
+        }
+        Ok(())
+    }
+}
+
+impl<R: Serialize> Sink<R> for JsonSink {
+    fn write(&mut self, record: &R, _gauges: &[(&str, f64)]) -> Result<()> {
+        self.serialize(record)
+    }
+
+    fn finish(&mut self) -> Result<()> {
+        JsonSink::finish(self)
+    }
+
+    fn path(&self) -> Option<&Path> {
+        Some(self.current_path())
+    }
+}
+
+/**
+ * A JSON object from CSV column names and values, in column order
+ */
+pub fn json_object(header: &[String], values: &[String]) -> Result<String> {
+    let fields = header
+        .iter()
+        .zip(values)
+        .map(|(name, value)| {
+            let value = match value.as_str() {
+                "NA" | "" => "null".to_string(),
+                "true" | "false" => value.clone(),
+                // Anything else which is already valid JSON, i.e. a number
+                _ if serde_json::from_str::<serde_json::Number>(value).is_ok() => value.clone(),
+                _ => serde_json::to_string(value)?,
+            };
+            Ok(format!("{}:{}", serde_json::to_string(name)?, value))
+        })
+        .collect::<Result<Vec<String>, serde_json::Error>>()
+        .wrap_err("Failed to format record as JSON")?;
+    Ok(format!("{{{}}}", fields.join(",")))
+}
@@ -0,0 +1,220 @@
+use std::fmt::Write;
+
+use clap::{Arg, Command};
+
+use crate::{
+    exit::EXIT_CODES,
+    log::{FILTER_VAR, LOG_FILE_VAR},
+};
+
+/// Environment variables every binary reads when setting up logging
+pub const LOGGING_ENV: [(&str, &str); 2] = [
+    (
+        LOG_FILE_VAR,
+        "Log file to append to when --log-file isn't given.",
+    ),
+    (
+        FILTER_VAR,
+        "Comma separated target=level log filters, plus an optional default level, overriding \
+         those set by -v, e.g. info,aws_config=debug.",
+    ),
+];
+
+/**
+ * A section 1 man page for `cmd`, in roff, covering its options, each subcommand's options,
+ * the environment variables in `env`, given as name and description, and the exit codes
+ */
+pub fn man_page(cmd: &Command, env: &[(&str, &str)]) -> String {
+    let name = cmd.get_name();
+    let mut page = String::new();
+    let _ = writeln!(
+        page,
+        ".TH {} 1 \"\" \"{} {}\" \"User Commands\"",
+        name.to_uppercase(),
+        env!("CARGO_PKG_NAME"),
+        cmd.get_version().unwrap_or(env!("CARGO_PKG_VERSION"))
+    );
+
+    section(&mut page, "NAME");
+    let about = cmd
+        .get_about()
+        .map(|about| about.to_string())
+        .unwrap_or_default();
+    let _ = writeln!(page, "{} \\- {}", name, escape(first_line(&about)));
+
+    section(&mut page, "SYNOPSIS");
+    let usage = cmd.clone().render_usage().to_string();
+    let usage: Vec<String> = usage
+        .trim_start_matches("Usage: ")
+        .lines()
+        .map(|line| escape(line.trim()))
+        .collect();
+    let _ = writeln!(page, "{}", usage.join("\n.br\n"));
+
+    let description = cmd
+        .get_long_about()
+        .map(|about| about.to_string())
+        .unwrap_or(about);
+    if !description.is_empty() {
+        section(&mut page, "DESCRIPTION");
+        paragraphs(&mut page, &description);
+    }
+
+    let arguments: Vec<&Arg> = cmd
+        .get_arguments()
+        .filter(|arg| !arg.is_hide_set())
+        .collect();
+    if !arguments.is_empty() {
+        section(&mut page, "OPTIONS");
+        arguments
+            .into_iter()
+            .for_each(|arg| argument(&mut page, arg));
+    }
+
+    let subcommands: Vec<&Command> = cmd
+        .get_subcommands()
+        .filter(|sub| !sub.is_hide_set())
+        .collect();
+    if !subcommands.is_empty() {
+        section(&mut page, "COMMANDS");
+        for sub in subcommands {
+            let _ = writeln!(page, ".SS {} {}", name, escape(sub.get_name()));
+            let about = sub
+                .get_long_about()
+                .or(sub.get_about())
+                .map(|about| about.to_string())
+                .unwrap_or_default();
+            paragraphs(&mut page, &about);
+            sub.get_arguments()
+                .filter(|arg| !arg.is_hide_set())
+                .for_each(|arg| argument(&mut page, arg));
+        }
+    }
+
+    if !env.is_empty() {
+        section(&mut page, "ENVIRONMENT");
+        for (variable, description) in env {
+            let _ = writeln!(
+                page,
+                ".TP\n\\fB{}\\fR\n{}",
+                escape(variable),
+                escape(description)
+            );
+        }
+    }
+
+    section(&mut page, "EXIT STATUS");
+    for (code, description) in EXIT_CODES {
+        let _ = writeln!(page, ".TP\n\\fB{}\\fR\n{}", code, escape(description));
+    }
+
+    page
+}
+
+fn section(page: &mut String, title: &str) {
+    let _ = writeln!(page, ".SH {}", title);
+}
+
+fn first_line(text: &str) -> &str {
+    text.lines().next().unwrap_or_default()
+}
+
+/**
+ * Text separated by blank lines, as roff paragraphs
+ */
+fn paragraphs(page: &mut String, text: &str) {
+    for (i, paragraph) in text.split("\n\n").enumerate() {
+        if i > 0 {
+            page.push_str(".PP\n");
+        }
+        let _ = writeln!(page, "{}", escape(paragraph.trim()));
+    }
+}
+
+/**
+ * A tagged paragraph for an option or positional argument, with its default and possible
+ * values
+ */
+fn argument(page: &mut String, arg: &Arg) {
+    let values: Vec<String> = arg
+        .get_value_names()
+        .map(|names| {
+            names
+                .iter()
+                .map(|name| format!("\\fI{}\\fR", escape(name)))
+                .collect()
+        })
+        .unwrap_or_else(|| {
+            vec![format!(
+                "\\fI{}\\fR",
+                escape(&arg.get_id().to_string().to_uppercase())
+            )]
+        });
+    let mut tag = Vec::new();
+    if let Some(short) = arg.get_short() {
+        tag.push(format!("\\fB\\-{}\\fR", short));
+    }
+    if let Some(long) = arg.get_long() {
+        tag.push(format!("\\fB\\-\\-{}\\fR", escape(long)));
+    }
+    let mut tag = tag.join(", ");
+    if arg.is_positional() {
+        tag = values.join(" ");
+    } else if arg.get_action().takes_values() {
+        tag = format!("{} {}", tag, values.join(" "));
+    }
+    let _ = writeln!(page, ".TP\n{}", tag);
+
+    let help = arg
+        .get_long_help()
+        .or(arg.get_help())
+        .map(|help| help.to_string())
+        .unwrap_or_default();
+    let mut notes = Vec::new();
+    // Flags have defaults and possible values too, of true and false
+    if arg.get_action().takes_values() {
+        let defaults: Vec<String> = arg
+            .get_default_values()
+            .iter()
+            .map(|value| value.to_string_lossy().to_string())
+            .collect();
+        if !defaults.is_empty() {
+            notes.push(format!("[default: {}]", defaults.join(", ")));
+        }
+        let possible: Vec<String> = arg
+            .get_possible_values()
+            .iter()
+            .filter(|value| !value.is_hide_set())
+            .map(|value| value.get_name().to_string())
+            .collect();
+        if !possible.is_empty() {
+            notes.push(format!("[possible values: {}]", possible.join(", ")));
+        }
+    }
+    let text = [help, notes.join(" ")]
+        .into_iter()
+        .filter(|text| !text.is_empty())
+        .collect::<Vec<_>>()
+        .join(" ");
+    if !text.is_empty() {
+        paragraphs(page, &text);
+    }
+}
+
+/**
+ * Escape text for roff: backslashes and hyphens, and leading characters which would
+ * otherwise start a request
+ */
+pub fn escape(text: &str) -> String {
+    text.lines()
+        .map(|line| {
+            let line = line.replace('\\', "\\e").replace('-', "\\-");
+            if line.starts_with('.') || line.starts_with('\'') {
+                format!("\\&{}", line)
+            } else {
+                line
+            }
+        })
+        .collect::<Vec<_>>()
+        .join("\n")
+}
@@ -0,0 +1,22 @@
+pub mod aggregate;
+pub mod append;
+pub mod compress;
+pub mod console;
+pub mod dialect;
+pub mod format;
+pub mod json;
+pub mod man;
+pub mod notify;
+pub mod parquet;
+pub mod progress;
+pub mod rotating;
+pub mod schema;
+pub mod select;
+pub mod sink;
+pub mod statsd;
+pub mod table;
+pub mod template;
+pub mod units;
+
+#[cfg(test)]
+mod tests;
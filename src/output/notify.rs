@@ -0,0 +1,88 @@
+use std::time::Duration;
+
+use color_eyre::{Result, eyre::Context};
+use serde::Serialize;
+use ureq::Agent;
+
+use crate::{output::units::format_size, process::summary::RunSummary};
+
+/// Longest to wait for the webhook, so a dead endpoint doesn't hold up the end of a run
+const TIMEOUT: Duration = Duration::from_secs(10);
+
+/// What a webhook is sent when a run ends
+#[derive(Debug, Serialize)]
+pub struct Notification<'a> {
+    /// One line description, shown by Slack and similar chat webhooks
+    pub text: String,
+    /// What was monitored, e.g. the command line
+    pub command: String,
+    pub hostname: Option<String>,
+    #[serde(flatten)]
+    pub summary: &'a RunSummary,
+}
+
+impl<'a> Notification<'a> {
+    pub fn new(command: String, hostname: Option<String>, summary: &'a RunSummary) -> Self {
+        let outcome = match (&summary.stop_reason, summary.exit_code) {
+            (Some(reason), _) => format!("stopped ({})", reason),
+            (None, Some(code)) => format!("finished with exit code {}", code),
+            (None, None) => "finished".to_string(),
+        };
+        let host = hostname
+            .as_deref()
+            .map(|host| format!(" on {}", host))
+            .unwrap_or_default();
+        let gpu = summary
+            .peak_gpu_percent
+            .map(|gpu| format!(", GPU {}%", gpu))
+            .unwrap_or_default();
+        let text = format!(
+            "`{}`{} {} after {}s. Peak CPU {:.1}%, RAM {}{}",
+            command,
+            host,
+            outcome,
+            summary.wall_clock_seconds.round(),
+            summary.peak_cpu_percent,
+            format_size(summary.peak_ram_bytes),
+            gpu
+        );
+
+        Self {
+            text,
+            command,
+            hostname,
+            summary,
+        }
+    }
+}
+
+/// POSTs a JSON [`Notification`] to a URL, e.g. a Slack incoming webhook
+pub struct WebhookNotifier {
+    url: String,
+    agent: Agent,
+}
+
+impl WebhookNotifier {
+    pub fn new(url: &str) -> Self {
+        let agent = Agent::config_builder()
+            .timeout_global(Some(TIMEOUT))
+            .build()
+            .into();
+        Self {
+            url: url.to_string(),
+            agent,
+        }
+    }
+
+    pub fn send(&self, notification: &Notification) -> Result<()> {
+        let body = serde_json::to_string(notification).wrap_err("Failed to format notification")?;
+        self.agent
+            .post(&self.url)
+            .header("Content-Type", "application/json")
+            .send(body)
+            // The URL is often a secret, so is left out of errors
+            .wrap_err("Failed to send notification")?;
+        log::info!("Notification sent");
+        Ok(())
+    }
+}
@@ -0,0 +1,238 @@
+use std::{fs::File, path::Path, sync::Arc};
+
+use color_eyre::{
+    Result,
+    eyre::{Context, bail},
+};
+use parquet::{
+    basic::{self, ZstdLevel},
+    data_type::{ByteArray, ByteArrayType, DoubleType, Int64Type},
+    file::{properties::WriterProperties, writer::SerializedFileWriter},
+    schema::parser::parse_message_type,
+};
+use serde::Serialize;
+
+use super::{
+    compress::Compression,
+    rotating::{Rotation, Rotator},
+    sink::{Sink, record_fields},
+};
+
+/// Samples are written in row groups of this many, so at most this many are lost if the
+/// process dies before the file is closed
+const ROWS_PER_GROUP: usize = 1000;
+
+/// Parquet type for a column
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum ColumnType {
+    Int64,
+    Double,
+    Text,
+}
+
+/// Writes records as a Parquet file with typed columns, so long traces stay compact and
+/// load directly into pandas, duckdb, etc.  `NA` values become nulls.
+pub struct ParquetSink {
+    rotator: Rotator,
+    column_type: fn(&str) -> ColumnType,
+    /// Opened on the first record, as the columns aren't known until then
+    writer: Option<SerializedFileWriter<File>>,
+    columns: Vec<(String, ColumnType)>,
+    /// Buffered values for each column
+    rows: Vec<Vec<String>>,
+}
+
+impl ParquetSink {
+    /**
+     * `column_type` gives the type of each column, by name
+     */
+    pub fn new<P: AsRef<Path>>(
+        base_path: P,
+        rotation: Rotation,
+        column_type: fn(&str) -> ColumnType,
+    ) -> Self {
+        Self {
+            // Parquet compresses internally
+            rotator: Rotator::new(base_path.as_ref(), rotation, Compression::None),
+            column_type,
+            writer: None,
+            columns: Vec::new(),
+            rows: Vec::new(),
+        }
+    }
+
+    /**
+     * Only keep the most recent `files` files written by this sink, deleting older ones as
+     * it rotates
+     */
+    pub fn with_retention(mut self, files: usize) -> Self {
+        self.rotator.set_retention(files);
+        self
+    }
+
+    pub fn current_path(&self) -> &Path {
+        self.rotator.current_path()
+    }
+
+    pub fn serialize<R: Serialize>(&mut self, record: &R) -> Result<()> {
+        self.rotate_if_due()?;
+
+        let (header, values) = record_fields(record)?;
+        if self.writer.is_none() {
+            self.open(&header)?;
+        } else if header.len() != self.columns.len() {
+            bail!(
+                "Record has {} columns but {} has {}",
+                header.len(),
+                self.rotator.current_path().display(),
+                self.columns.len()
+            )
+        }
+
+        self.rows.push(values);
+        if self.rows.len() >= ROWS_PER_GROUP {
+            self.write_row_group()?;
+        }
+        Ok(())
+    }
+
+    /**
+     * Write any buffered rows and the file footer.  The file can't be read until this is done.
+     */
+    pub fn close(&mut self) -> Result<()> {
+        self.write_row_group()?;
+        if let Some(writer) = self.writer.take() {
+            writer.close().wrap_err_with(|| {
+                format!("Failed to close {}", self.rotator.current_path().display())
+            })?;
+        }
+        Ok(())
+    }
+
+    fn open(&mut self, header: &[String]) -> Result<()> {
+        self.columns = header
+            .iter()
+            .map(|name| (name.clone(), (self.column_type)(name)))
+            .collect();
+
+        let fields = self
+            .columns
+            .iter()
+            .map(|(name, column_type)| match column_type {
+                ColumnType::Int64 => format!("OPTIONAL INT64 {};", name),
+                ColumnType::Double => format!("OPTIONAL DOUBLE {};", name),
+                ColumnType::Text => format!("OPTIONAL BINARY {} (UTF8);", name),
+            })
+            .collect::<Vec<_>>()
+            .join(" ");
+        let schema = parse_message_type(&format!("message usage {{ {} }}", fields))
+            .wrap_err("Failed to build Parquet schema")?;
+        let properties = WriterProperties::builder()
+            .set_compression(basic::Compression::ZSTD(ZstdLevel::default()))
+            .build();
+
+        let file = File::create(self.rotator.current_path()).wrap_err_with(|| {
+            format!("Failed to create {}", self.rotator.current_path().display())
+        })?;
+        self.writer = Some(SerializedFileWriter::new(
+            file,
+            Arc::new(schema),
+            Arc::new(properties),
+        )?);
+        Ok(())
+    }
+
+    fn write_row_group(&mut self) -> Result<()> {
+        let Some(writer) = self.writer.as_mut() else {
+            return Ok(());
+        };
+        if self.rows.is_empty() {
+            return Ok(());
+        }
+
+        let mut row_group = writer.next_row_group()?;
+        let mut index = 0;
+        while let Some(mut column) = row_group.next_column()? {
+            let values = self.rows.iter().map(|row| row[index].as_str());
+            match self.columns[index].1 {
+                ColumnType::Int64 => {
+                    let (values, levels) = to_levels(values, |v| v.parse::<i64>().ok());
+                    column
+                        .typed::<Int64Type>()
+                        .write_batch(&values, Some(&levels), None)?;
+                }
+                ColumnType::Double => {
+                    let (values, levels) = to_levels(values, |v| v.parse::<f64>().ok());
+                    column
+                        .typed::<DoubleType>()
+                        .write_batch(&values, Some(&levels), None)?;
+                }
+                ColumnType::Text => {
+                    let (values, levels) = to_levels(values, |v| Some(ByteArray::from(v)));
+                    column
+                        .typed::<ByteArrayType>()
+                        .write_batch(&values, Some(&levels), None)?;
+                }
+            }
+            column.close()?;
+            index += 1;
+        }
+        row_group.close()?;
+
+        self.rows.clear();
+        Ok(())
+    }
+
+    fn rotate_if_due(&mut self) -> Result<()> {
+        // Until a row group is written the file is empty, so size rotation waits for one
+        if self.writer.is_some() && self.rotator.is_due() {
+            self.close()?;
+            self.rotator.advance();
+        }
+        Ok(())
+    }
+}
+
+impl<R: Serialize> Sink<R> for ParquetSink {
+    fn write(&mut self, record: &R, _gauges: &[(&str, f64)]) -> Result<()> {
+        self.serialize(record)
+    }
+
+    fn finish(&mut self) -> Result<()> {
+        self.close()
+    }
+
+    fn path(&self) -> Option<&Path> {
+        Some(self.current_path())
+    }
+}
+
+impl Drop for ParquetSink {
+    fn drop(&mut self) {
+        // Without a footer nothing in the file is readable, so make a last attempt
+        if let Err(e) = self.close() {
+            log::warn!("{:#}", e);
+        }
+    }
+}
+
+/**
+ * Non-null values, and definition levels marking which rows have one
+ */
+fn to_levels<'a, T>(
+    values: impl Iterator<Item = &'a str>,
+    parse: impl Fn(&str) -> Option<T>,
+) -> (Vec<T>, Vec<i16>) {
+    let mut present = Vec::new();
+    let mut levels = Vec::new();
+    for value in values {
+        match parse(value) {
+            Some(parsed) if value != "NA" => {
+                present.push(parsed);
+                levels.push(1);
+            }
+            _ => levels.push(0),
+        }
+    }
+    (present, levels)
+}
@@ -0,0 +1,114 @@
+use std::{
+    fs::OpenOptions,
+    io::Write,
+    path::Path,
+    sync::{Mutex, OnceLock},
+};
+
+use chrono::{SecondsFormat, Utc};
+use color_eyre::{Result, eyre::Context};
+use serde::Serialize;
+
+/// Where progress events go once [`init`] has been called
+static PROGRESS: OnceLock<Mutex<Option<Box<dyn Write + Send>>>> = OnceLock::new();
+
+/// Something worth telling a wrapping tool about, written as a line of JSON with an `event`
+/// field naming the variant
+#[derive(Debug, Clone, PartialEq, Serialize)]
+#[serde(tag = "event", rename_all = "snake_case")]
+pub enum ProgressEvent<'a> {
+    /// A page of an S3 listing has been fetched
+    PageListed {
+        bucket: &'a str,
+        prefix: &'a str,
+        page: usize,
+        /// Objects, versions and delete markers on this page
+        items: usize,
+        /// Items on this and every earlier page
+        total_items: usize,
+    },
+    /// A location's objects have been counted and sized
+    ObjectsCounted {
+        url: &'a str,
+        objects: usize,
+        bytes: u64,
+    },
+    /// A page of object versions and delete markers has been deleted
+    VersionsDeleted {
+        bucket: &'a str,
+        page: usize,
+        deleted: usize,
+        failed: usize,
+        bytes: u64,
+        total_deleted: usize,
+        total_bytes: u64,
+    },
+    /// A usage sample has been written to the main output
+    SampleWritten {
+        samples: usize,
+        elapsed_seconds: u64,
+    },
+}
+
+#[derive(Serialize)]
+struct Line<'a> {
+    time: String,
+    #[serde(flatten)]
+    event: &'a ProgressEvent<'a>,
+}
+
+/**
+ * Write progress events to `path`, e.g. a FIFO, or to stderr if it's `-`.  Only the first
+ * call counts.
+ */
+pub fn init(path: &Path) -> Result<()> {
+    let writer: Box<dyn Write + Send> = if path == Path::new("-") {
+        Box::new(std::io::stderr())
+    } else {
+        // Opening a FIFO waits for whatever is going to read it
+        Box::new(
+            OpenOptions::new()
+                .create(true)
+                .append(true)
+                .open(path)
+                .wrap_err_with(|| format!("Failed to open {} for progress", path.display()))?,
+        )
+    };
+    let _ = PROGRESS.set(Mutex::new(Some(writer)));
+    Ok(())
+}
+
+/**
+ * Write `event` as a line of JSON, if progress events were asked for.  If they can't be
+ * written, e.g. because the reader went away, they are turned off with a warning rather
+ * than failing the work they describe.
+ */
+pub fn emit(event: &ProgressEvent) {
+    let Some(progress) = PROGRESS.get() else {
+        return;
+    };
+    let mut writer = progress.lock().unwrap_or_else(|e| e.into_inner());
+    let Some(out) = writer.as_mut() else {
+        return;
+    };
+    let written = progress_line(event).and_then(|line| {
+        writeln!(out, "{}", line)
+            .and_then(|_| out.flush())
+            .wrap_err("Failed to write progress")
+    });
+    if let Err(e) = written {
+        log::warn!("No more progress events: {:#}", e);
+        *writer = None;
+    }
+}
+
+/**
+ * `event` as a line of JSON, stamped with the current time
+ */
+pub fn progress_line(event: &ProgressEvent) -> Result<String> {
+    serde_json::to_string(&Line {
+        time: Utc::now().to_rfc3339_opts(SecondsFormat::Millis, true),
+        event,
+    })
+    .wrap_err("Failed to format progress event")
+}
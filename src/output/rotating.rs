@@ -0,0 +1,271 @@
+use std::{
+    collections::VecDeque,
+    fs,
+    path::{Path, PathBuf},
+};
+
+use chrono::{DateTime, Local};
+use color_eyre::{
+    Result,
+    eyre::{Context, eyre},
+};
+use serde::Serialize;
+
+use super::{
+    compress::{CompressedFile, Compression},
+    dialect::CsvDialect,
+};
+
+/// When a [`RotatingCsvWriter`] should start a new file
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Rotation {
+    /// Write everything to the base path
+    Never,
+    /// Start a new file each day, e.g. `task_usage.2025-06-01.csv`
+    Daily,
+    /// Start a new file each hour, e.g. `task_usage.2025-06-01T13.csv`
+    Hourly,
+    /// Start a new file once the current one reaches this many bytes, named for the time
+    /// it was started, e.g. `task_usage.2025-06-01T13-45-00.csv`
+    Size(u64),
+}
+
+impl Rotation {
+    /**
+     * Label for a file started at `time`
+     */
+    fn label(self, time: DateTime<Local>) -> Option<String> {
+        match self {
+            Rotation::Never => None,
+            Rotation::Daily => Some(time.format("%Y-%m-%d").to_string()),
+            Rotation::Hourly => Some(time.format("%Y-%m-%dT%H").to_string()),
+            Rotation::Size(_) => Some(time.format("%Y-%m-%dT%H-%M-%S").to_string()),
+        }
+    }
+}
+
+/// Decides when a rotating output should move on to a new file, and removes old files
+/// beyond the retention limit
+pub(crate) struct Rotator {
+    base_path: PathBuf,
+    rotation: Rotation,
+    compression: Compression,
+    /// Most files to keep, including the current one
+    keep: Option<usize>,
+    current_label: Option<String>,
+    current_path: PathBuf,
+    /// Files written by this rotator, oldest first
+    written: VecDeque<PathBuf>,
+}
+
+impl Rotator {
+    pub(crate) fn new(base_path: &Path, rotation: Rotation, compression: Compression) -> Self {
+        let current_label = rotation.label(Local::now());
+        let current_path = path_for(base_path, compression, current_label.as_deref());
+        Self {
+            base_path: base_path.to_path_buf(),
+            rotation,
+            compression,
+            keep: None,
+            current_label,
+            current_path: current_path.clone(),
+            written: VecDeque::from([current_path]),
+        }
+    }
+
+    pub(crate) fn set_retention(&mut self, keep: usize) {
+        self.keep = Some(keep.max(1));
+    }
+
+    pub(crate) fn current_path(&self) -> &Path {
+        &self.current_path
+    }
+
+    /**
+     * Whether the current file should be closed and another started
+     */
+    pub(crate) fn is_due(&self) -> bool {
+        match self.rotation {
+            Rotation::Never => false,
+            Rotation::Daily | Rotation::Hourly => {
+                self.rotation.label(Local::now()) != self.current_label
+            }
+            Rotation::Size(max_bytes) => fs::metadata(&self.current_path)
+                .map(|metadata| metadata.len() >= max_bytes)
+                .unwrap_or(false),
+        }
+    }
+
+    /**
+     * Move on to the next file, deleting the oldest if there are now too many
+     */
+    pub(crate) fn advance(&mut self) {
+        self.current_label = self.rotation.label(Local::now());
+        let mut path = path_for(
+            &self.base_path,
+            self.compression,
+            self.current_label.as_deref(),
+        );
+        // Size rotation can start two files in the same second
+        let mut count = 1;
+        while path.exists() {
+            let label = format!("{}.{}", self.current_label.as_deref().unwrap_or(""), count);
+            path = path_for(&self.base_path, self.compression, Some(&label));
+            count += 1;
+        }
+        self.current_path = path;
+        self.written.push_back(self.current_path.clone());
+        log::info!("Rotated output to {}", self.current_path.display());
+
+        while let Some(keep) = self.keep
+            && self.written.len() > keep
+            && let Some(oldest) = self.written.pop_front()
+        {
+            match fs::remove_file(&oldest) {
+                Ok(()) => log::info!("Removed old output {}", oldest.display()),
+                Err(e) => log::warn!("Failed to remove old output {}: {}", oldest.display(), e),
+            }
+        }
+    }
+}
+
+/// CSV writer which can split its output across a series of files.  Each file gets its own
+/// header.
+pub struct RotatingCsvWriter {
+    rotator: Rotator,
+    compression: Compression,
+    dialect: CsvDialect,
+    /// `None` once finished
+    writer: Option<csv::Writer<CompressedFile>>,
+}
+
+impl RotatingCsvWriter {
+    pub fn new<P: AsRef<Path>>(base_path: P, rotation: Rotation) -> Result<Self> {
+        Self::compressed(base_path, rotation, Compression::None)
+    }
+
+    /**
+     * Compress the output, adding the compression's extension to file names (e.g.
+     * `task_usage.csv.zst`)
+     */
+    pub fn compressed<P: AsRef<Path>>(
+        base_path: P,
+        rotation: Rotation,
+        compression: Compression,
+    ) -> Result<Self> {
+        Self::create(base_path, rotation, compression, CsvDialect::default())
+    }
+
+    /**
+     * Write with another delimiter or quoting, e.g. semicolons for spreadsheets in locales
+     * where the comma is the decimal separator
+     */
+    pub fn create<P: AsRef<Path>>(
+        base_path: P,
+        rotation: Rotation,
+        compression: Compression,
+        dialect: CsvDialect,
+    ) -> Result<Self> {
+        let rotator = Rotator::new(base_path.as_ref(), rotation, compression);
+        let writer = Some(open(rotator.current_path(), compression, dialect)?);
+
+        Ok(Self {
+            rotator,
+            compression,
+            dialect,
+            writer,
+        })
+    }
+
+    /**
+     * Only keep the most recent `files` files written by this writer, deleting older ones
+     * as it rotates
+     */
+    pub fn with_retention(mut self, files: usize) -> Self {
+        self.rotator.set_retention(files);
+        self
+    }
+
+    /// The file currently being written to
+    pub fn current_path(&self) -> &Path {
+        self.rotator.current_path()
+    }
+
+    pub fn serialize<S: Serialize>(&mut self, record: S) -> Result<()> {
+        self.rotate_if_due()?;
+        let path = self.rotator.current_path();
+        self.writer
+            .as_mut()
+            .ok_or_else(|| eyre!("{} has already been finished", path.display()))?
+            .serialize(record)
+            .wrap_err_with(|| format!("Failed to write to {}", path.display()))
+    }
+
+    pub fn flush(&mut self) -> Result<()> {
+        match self.writer.as_mut() {
+            Some(writer) => writer.flush().wrap_err_with(|| {
+                format!("Failed to flush {}", self.rotator.current_path().display())
+            }),
+            None => Ok(()),
+        }
+    }
+
+    /**
+     * Flush, and end the compressed stream if there is one.  Nothing more may be written.
+     */
+    pub fn finish(&mut self) -> Result<()> {
+        let Some(writer) = self.writer.take() else {
+            return Ok(());
+        };
+        writer
+            .into_inner()
+            .map_err(|e| e.into_error())
+            .and_then(|mut file| file.finish())
+            .wrap_err_with(|| format!("Failed to finish {}", self.rotator.current_path().display()))
+    }
+
+    fn rotate_if_due(&mut self) -> Result<()> {
+        if self.writer.is_some() && self.rotator.is_due() {
+            self.finish()?;
+            self.rotator.advance();
+            self.writer = Some(open(
+                self.rotator.current_path(),
+                self.compression,
+                self.dialect,
+            )?);
+        }
+        Ok(())
+    }
+}
+
+fn open(
+    path: &Path,
+    compression: Compression,
+    dialect: CsvDialect,
+) -> Result<csv::Writer<CompressedFile>> {
+    Ok(dialect
+        .writer()
+        .from_writer(CompressedFile::create(path, compression)?))
+}
+
+/**
+ * Insert the label between the file stem and extension, e.g. `usage.csv` ->
+ * `usage.2025-06-01.csv`, and add any compression extension
+ */
+fn path_for(base_path: &Path, compression: Compression, label: Option<&str>) -> PathBuf {
+    let path = match label {
+        None => base_path.to_path_buf(),
+        Some(label) => {
+            let stem = base_path
+                .file_stem()
+                .map(|s| s.to_string_lossy().into_owned())
+                .unwrap_or_default();
+            let file_name = match base_path.extension() {
+                Some(ext) => format!("{}.{}.{}", stem, label, ext.to_string_lossy()),
+                None => format!("{}.{}", stem, label),
+            };
+            base_path.with_file_name(file_name)
+        }
+    };
+    compression.apply_extension(&path)
+}
@@ -0,0 +1,30 @@
+//! Versions of the columns in each kind of file `tu` and `bu` write.  A version goes up
+//! whenever a column is added, removed, renamed or changes meaning, and is recorded in a
+//! [`SCHEMA_COLUMN`] first in every row.  `schemas.md` lists what changed in each version.
+
+/// Column holding the schema version, first in every row
+pub const SCHEMA_COLUMN: &str = "schema_version";
+
+/// Schema of files written before versions were recorded, up to 0.4.1
+pub const UNVERSIONED: u32 = 1;
+
+/// `tu`'s main usage file, e.g. `task_usage.csv`
+pub const USAGE_SCHEMA: u32 = 2;
+
+/// `tu --per-process`'s `process_usage.csv`
+pub const PROCESS_USAGE_SCHEMA: u32 = 1;
+
+/// `tu --per-device`'s `device_usage.csv`
+pub const DEVICE_USAGE_SCHEMA: u32 = 1;
+
+/// `tu bench`'s `bench.csv`
+pub const BENCH_SCHEMA: u32 = 1;
+
+/// `bu size-report`'s `bucket_usage.csv`
+pub const SIZE_REPORT_SCHEMA: u32 = 5;
+
+/// `bu orphans`' `orphans.csv`
+pub const ORPHANS_SCHEMA: u32 = 1;
+
+/// `bu enforce-retention`'s `retention_manifest.csv`
+pub const RETENTION_MANIFEST_SCHEMA: u32 = 1;
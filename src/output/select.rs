@@ -0,0 +1,147 @@
+use std::cmp::Ordering;
+
+use color_eyre::{
+    Result,
+    eyre::{bail, eyre},
+};
+use serde::{Serialize, ser::SerializeStruct};
+
+use super::{schema::SCHEMA_COLUMN, sink::record_fields};
+
+/// Which of a report's columns to write, and in what order.  The schema version is always
+/// kept, first, so the file can still be read back.
+#[derive(Debug, Clone)]
+pub struct ColumnSelection {
+    /// Position in the full record, and name, of each column kept
+    columns: Vec<(usize, &'static str)>,
+}
+
+impl ColumnSelection {
+    /**
+     * Keep the `wanted` columns of records with the `available` columns, or all of them if
+     * none are wanted
+     */
+    pub fn new(available: &'static [&'static str], wanted: &[String]) -> Result<Self> {
+        let position = |name: &str| {
+            available
+                .iter()
+                .position(|column| *column == name)
+                .ok_or_else(|| {
+                    eyre!(
+                        "No column '{}', expected one of {}",
+                        name,
+                        available.join(", ")
+                    )
+                })
+        };
+        let mut columns: Vec<(usize, &'static str)> = Vec::new();
+        if let Ok(schema) = position(SCHEMA_COLUMN) {
+            columns.push((schema, available[schema]));
+        }
+        match wanted.is_empty() {
+            true => columns.extend(available.iter().copied().enumerate()),
+            false => {
+                for name in wanted {
+                    let i = position(name.trim())?;
+                    columns.push((i, available[i]));
+                }
+            }
+        }
+        let mut seen = Vec::new();
+        columns.retain(|&(i, _)| {
+            let first = !seen.contains(&i);
+            seen.push(i);
+            first
+        });
+        Ok(Self { columns })
+    }
+
+    /**
+     * Whether the named column is kept
+     */
+    pub fn contains(&self, column: &str) -> bool {
+        self.columns.iter().any(|&(_, name)| name == column)
+    }
+
+    /**
+     * The chosen columns of `record`, which must have the available columns in order
+     */
+    pub fn select<R: Serialize>(&self, record: &R) -> Result<SelectedRow> {
+        let (_, values) = record_fields(record)?;
+        let values = self
+            .columns
+            .iter()
+            .map(|&(i, name)| {
+                values
+                    .get(i)
+                    .cloned()
+                    .ok_or_else(|| eyre!("Record has no '{}' column", name))
+            })
+            .collect::<Result<_>>()?;
+        Ok(SelectedRow {
+            columns: self.columns.iter().map(|&(_, name)| name).collect(),
+            values,
+        })
+    }
+}
+
+/// Some of a record's columns, as they'd be written in a CSV
+#[derive(Debug, Clone, PartialEq)]
+pub struct SelectedRow {
+    columns: Vec<&'static str>,
+    values: Vec<String>,
+}
+
+impl SelectedRow {
+    /**
+     * Value of the named column, if it was selected
+     */
+    pub fn get(&self, column: &str) -> Option<&str> {
+        let i = self.columns.iter().position(|name| *name == column)?;
+        Some(&self.values[i])
+    }
+}
+
+impl Serialize for SelectedRow {
+    fn serialize<S: serde::Serializer>(&self, serializer: S) -> Result<S::Ok, S::Error> {
+        let mut row = serializer.serialize_struct("SelectedRow", self.columns.len())?;
+        for (name, value) in self.columns.iter().zip(&self.values) {
+            row.serialize_field(name, value)?;
+        }
+        row.end()
+    }
+}
+
+/**
+ * Sort `items` by the value `row` gives from each for `column`, as numbers if they all are,
+ * otherwise as text.  The sort is stable, so ties keep their order.
+ */
+pub fn sort_by_column<T>(
+    items: &mut [T],
+    row: fn(&T) -> &SelectedRow,
+    column: &str,
+    descending: bool,
+) -> Result<()> {
+    if items.iter().any(|item| row(item).get(column).is_none()) {
+        bail!(
+            "Can't sort by '{}' unless it's one of the columns written",
+            column
+        );
+    }
+    let value = |item: &T| row(item).get(column).unwrap_or_default().to_string();
+    let numeric = items.iter().all(|item| value(item).parse::<f64>().is_ok());
+    items.sort_by(|a, b| {
+        let order = match numeric {
+            true => {
+                let number = |item: &T| value(item).parse::<f64>().unwrap_or_default();
+                number(a).partial_cmp(&number(b)).unwrap_or(Ordering::Equal)
+            }
+            false => value(a).cmp(&value(b)),
+        };
+        match descending {
+            true => order.reverse(),
+            false => order,
+        }
+    });
+    Ok(())
+}
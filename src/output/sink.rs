@@ -0,0 +1,76 @@
+use std::path::Path;
+
+use color_eyre::{
+    Result,
+    eyre::{Context, OptionExt},
+};
+use serde::Serialize;
+
+use super::{rotating::RotatingCsvWriter, statsd::StatsdSink};
+
+/// Somewhere each usage sample is sent.  A run can write to several at once.
+pub trait Sink<R: Serialize> {
+    /**
+     * `gauges` are the record's figures as numbers, for sinks which can't use text
+     */
+    fn write(&mut self, record: &R, gauges: &[(&str, f64)]) -> Result<()>;
+
+    /**
+     * Called once no more records will be written
+     */
+    fn finish(&mut self) -> Result<()> {
+        Ok(())
+    }
+
+    /**
+     * File currently being written to, if any
+     */
+    fn path(&self) -> Option<&Path> {
+        None
+    }
+}
+
+impl<R: Serialize> Sink<R> for RotatingCsvWriter {
+    fn write(&mut self, record: &R, _gauges: &[(&str, f64)]) -> Result<()> {
+        self.serialize(record)?;
+        self.flush()
+    }
+
+    fn finish(&mut self) -> Result<()> {
+        RotatingCsvWriter::finish(self)
+    }
+
+    fn path(&self) -> Option<&Path> {
+        Some(self.current_path())
+    }
+}
+
+impl<R: Serialize> Sink<R> for StatsdSink {
+    fn write(&mut self, _record: &R, gauges: &[(&str, f64)]) -> Result<()> {
+        // Losing metrics shouldn't interrupt monitoring
+        if let Err(e) = self.send_gauges(gauges) {
+            log::warn!("{:#}", e);
+        }
+        Ok(())
+    }
+}
+
+/**
+ * Column names and values of a record, as they'd appear in a CSV
+ */
+pub(crate) fn record_fields<R: Serialize>(record: &R) -> Result<(Vec<String>, Vec<String>)> {
+    let mut wtr = csv::Writer::from_writer(Vec::new());
+    wtr.serialize(record)?;
+    let csv = wtr.into_inner().wrap_err("Failed to format record")?;
+
+    let mut rows = csv::ReaderBuilder::new()
+        .has_headers(false)
+        .from_reader(csv.as_slice())
+        .into_records();
+    let mut next_row = || -> Result<Vec<String>> {
+        let row = rows.next().ok_or_eyre("Record produced no CSV")??;
+        Ok(row.iter().map(String::from).collect())
+    };
+
+    Ok((next_row()?, next_row()?))
+}
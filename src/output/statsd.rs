@@ -0,0 +1,83 @@
+use std::net::{ToSocketAddrs, UdpSocket};
+
+use color_eyre::{
+    Result,
+    eyre::{Context, OptionExt},
+};
+
+/// Pushes gauges to a StatsD endpoint over UDP, tagged in the DogStatsD style
+/// (`prefix.name:value|g|#key:value,...`).
+pub struct StatsdSink {
+    socket: UdpSocket,
+    prefix: String,
+    tags: Vec<(String, String)>,
+}
+
+impl StatsdSink {
+    pub fn new(address: &str, prefix: &str, tags: Vec<(String, String)>) -> Result<Self> {
+        let address = address
+            .to_socket_addrs()
+            .wrap_err_with(|| format!("Failed to resolve StatsD address {}", address))?
+            .next()
+            .ok_or_eyre("StatsD address resolved to nothing")?;
+
+        let bind_address = if address.is_ipv4() {
+            "0.0.0.0:0"
+        } else {
+            "[::]:0"
+        };
+        let socket = UdpSocket::bind(bind_address).wrap_err("Failed to bind UDP socket")?;
+        socket
+            .connect(address)
+            .wrap_err_with(|| format!("Failed to connect to StatsD at {}", address))?;
+        log::info!("Sending metrics to StatsD at {}", address);
+
+        Ok(Self {
+            socket,
+            prefix: prefix.trim_end_matches('.').to_string(),
+            tags,
+        })
+    }
+
+    /// Send several gauges in a single packet
+    pub fn send_gauges(&self, gauges: &[(&str, f64)]) -> Result<()> {
+        if gauges.is_empty() {
+            return Ok(());
+        }
+
+        let payload = gauges
+            .iter()
+            .map(|(name, value)| self.format_gauge(name, *value))
+            .collect::<Vec<_>>()
+            .join("\n");
+        log::debug!("StatsD payload: {}", payload);
+
+        self.socket
+            .send(payload.as_bytes())
+            .wrap_err("Failed to send metrics to StatsD")?;
+
+        Ok(())
+    }
+
+    fn format_gauge(&self, name: &str, value: f64) -> String {
+        let metric = if self.prefix.is_empty() {
+            name.to_string()
+        } else {
+            format!("{}.{}", self.prefix, name)
+        };
+
+        let mut line = format!("{}:{}|g", metric, value);
+        if !self.tags.is_empty() {
+            let tags = self
+                .tags
+                .iter()
+                .map(|(k, v)| format!("{}:{}", k, v))
+                .collect::<Vec<_>>()
+                .join(",");
+            line.push_str("|#");
+            line.push_str(&tags);
+        }
+
+        line
+    }
+}
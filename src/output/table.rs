@@ -0,0 +1,66 @@
+use std::io::Write;
+
+use color_eyre::{Result, eyre::Context};
+use serde::Serialize;
+
+use super::sink::{Sink, record_fields};
+
+/// Header is repeated after this many rows, so it stays in view
+const ROWS_PER_HEADER: usize = 20;
+
+/// Prints records as aligned columns, e.g. to follow a run in the terminal
+pub struct TableSink<W: Write> {
+    out: W,
+    widths: Vec<usize>,
+    rows_since_header: usize,
+}
+
+impl<W: Write> TableSink<W> {
+    pub fn new(out: W) -> Self {
+        Self {
+            out,
+            widths: Vec::new(),
+            rows_since_header: 0,
+        }
+    }
+
+    pub fn write_record<R: Serialize>(&mut self, record: &R) -> Result<()> {
+        let (header, values) = record_fields(record)?;
+
+        // Columns only ever widen, and the header is reprinted when they do
+        let mut widened = self.widths.len() != header.len();
+        self.widths.resize(header.len(), 0);
+        for (i, width) in self.widths.iter_mut().enumerate() {
+            let needed = header[i].len().max(values[i].len());
+            if needed > *width {
+                *width = needed;
+                widened = true;
+            }
+        }
+
+        if widened || self.rows_since_header == ROWS_PER_HEADER {
+            self.write_line(&header)?;
+            self.rows_since_header = 0;
+        }
+        self.write_line(&values)?;
+        self.rows_since_header += 1;
+
+        self.out.flush().wrap_err("Failed to write table")
+    }
+
+    fn write_line(&mut self, fields: &[String]) -> Result<()> {
+        let line = fields
+            .iter()
+            .zip(&self.widths)
+            .map(|(field, width)| format!("{:>width$}", field, width = width))
+            .collect::<Vec<_>>()
+            .join("  ");
+        writeln!(self.out, "{}", line.trim_end()).wrap_err("Failed to write table")
+    }
+}
+
+impl<R: Serialize, W: Write> Sink<R> for TableSink<W> {
+    fn write(&mut self, record: &R, _gauges: &[(&str, f64)]) -> Result<()> {
+        self.write_record(record)
+    }
+}
@@ -0,0 +1,98 @@
+use std::path::{Path, PathBuf};
+
+use chrono::{DateTime, Local};
+use color_eyre::{
+    Result,
+    eyre::{bail, eyre},
+};
+
+/// Values for the `{date}`, `{time}`, `{host}` and `{command}` placeholders in output file
+/// names, so scheduled runs each write their own files
+#[derive(Debug, Clone)]
+pub struct FileNameValues {
+    /// When the run started
+    pub time: DateTime<Local>,
+    pub host: Option<String>,
+    /// Name of what's being monitored, e.g. the command's basename.  `None` where there's no
+    /// such thing, e.g. for `bu`.
+    pub command: Option<String>,
+}
+
+impl FileNameValues {
+    /**
+     * Values for a run starting now on this machine
+     */
+    pub fn now(command: Option<String>) -> Self {
+        Self {
+            time: Local::now(),
+            host: sysinfo::System::host_name(),
+            command,
+        }
+    }
+
+    /**
+     * Replace each `{name}` in `template` with its value.  `{{` and `}}` are literal braces.
+     * Values are made safe for file names, e.g. a `/` in a command becomes `_`.
+     */
+    pub fn expand(&self, template: &str) -> Result<String> {
+        let mut expanded = String::with_capacity(template.len());
+        let mut chars = template.chars();
+        while let Some(c) = chars.next() {
+            match c {
+                '{' if chars.as_str().starts_with('{') => {
+                    chars.next();
+                    expanded.push('{');
+                }
+                '}' if chars.as_str().starts_with('}') => {
+                    chars.next();
+                    expanded.push('}');
+                }
+                '{' => {
+                    let rest = chars.as_str();
+                    let (name, after) = rest
+                        .split_once('}')
+                        .ok_or_else(|| eyre!("Unclosed '{{' in '{}'", template))?;
+                    expanded.push_str(&self.value(name)?);
+                    chars = after.chars();
+                }
+                '}' => bail!("Unmatched '}}' in '{}', write '}}}}' for a brace", template),
+                c => expanded.push(c),
+            }
+        }
+        Ok(expanded)
+    }
+
+    /**
+     * `path` with any placeholders expanded.  Paths without any are returned as they are.
+     */
+    pub fn expand_path(&self, path: &Path) -> Result<PathBuf> {
+        let Some(template) = path.to_str().filter(|p| p.contains(['{', '}'])) else {
+            return Ok(path.to_path_buf());
+        };
+        self.expand(template).map(PathBuf::from)
+    }
+
+    fn value(&self, name: &str) -> Result<String> {
+        let value = match name {
+            "date" => self.time.format("%Y-%m-%d").to_string(),
+            "time" => self.time.format("%H-%M-%S").to_string(),
+            "host" => self.host.clone().unwrap_or_else(|| "unknown-host".into()),
+            "command" => self
+                .command
+                .clone()
+                .ok_or_else(|| eyre!("There's no {{command}} to put in file names here"))?,
+            _ => bail!(
+                "Unknown placeholder '{{{}}}' in a file name, expected {{date}}, {{time}}, \
+                 {{host}} or {{command}}",
+                name
+            ),
+        };
+        Ok(value
+            .chars()
+            .map(|c| match c {
+                c if c.is_alphanumeric() || matches!(c, '-' | '_' | '.' | '+') => c,
+                _ => '_',
+            })
+            .collect())
+    }
+}
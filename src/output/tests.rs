@@ -0,0 +1,648 @@
+use std::{
+    fs::File,
+    net::UdpSocket,
+    path::{Path, PathBuf},
+    time::Duration,
+};
+
+use color_eyre::Result;
+use parquet::file::reader::{FileReader, SerializedFileReader};
+
+use crate::process::summary::{RunSummary, SummaryAccumulator};
+
+use super::{
+    aggregate::Aggregator,
+    append::keep_latest,
+    compress::Compression,
+    console::{ConsoleFormat, render_fields, render_records},
+    dialect::{CsvDialect, Quoting, parse_delimiter, sniff_delimiter},
+    format::{ReportFormat, ReportOptions},
+    man::{escape, man_page},
+    notify::Notification,
+    parquet::{ColumnType, ParquetSink},
+    progress::{ProgressEvent, progress_line},
+    rotating::{RotatingCsvWriter, Rotation},
+    statsd::StatsdSink,
+    table::TableSink,
+    template::FileNameValues,
+    units::{SizeUnits, parse_size},
+};
+
+#[test]
+fn test_statsd_gauges_are_prefixed_and_tagged() -> Result<()> {
+    let receiver = UdpSocket::bind("127.0.0.1:0")?;
+    receiver.set_read_timeout(Some(Duration::from_secs(5)))?;
+    let address = receiver.local_addr()?.to_string();
+
+    let sink = StatsdSink::new(
+        &address,
+        "tu.",
+        vec![
+            ("host".into(), "node1".into()),
+            ("label".into(), "train".into()),
+        ],
+    )?;
+    sink.send_gauges(&[("cpu_percent", 150.5), ("ram_mb", 20.0)])?;
+
+    let mut buf = [0u8; 1024];
+    let len = receiver.recv(&mut buf)?;
+    let payload = std::str::from_utf8(&buf[..len])?;
+
+    assert_eq!(
+        "tu.cpu_percent:150.5|g|#host:node1,label:train\ntu.ram_mb:20|g|#host:node1,label:train",
+        payload
+    );
+
+    Ok(())
+}
+
+#[test]
+fn test_table_sink_aligns_and_widens_columns() -> Result<()> {
+    #[derive(serde::Serialize)]
+    struct Row {
+        name: &'static str,
+        cpu_percent: String,
+    }
+
+    let mut out = Vec::new();
+    let mut table = TableSink::new(&mut out);
+    table.write_record(&Row {
+        name: "a",
+        cpu_percent: "1.0".into(),
+    })?;
+    table.write_record(&Row {
+        name: "b",
+        cpu_percent: "12.5".into(),
+    })?;
+    table.write_record(&Row {
+        name: "longer name",
+        cpu_percent: "100.0".into(),
+    })?;
+
+    let expected = "\
+name  cpu_percent
+   a          1.0
+   b         12.5
+       name  cpu_percent
+longer name        100.0
+";
+    assert_eq!(expected, String::from_utf8(out)?);
+
+    Ok(())
+}
+
+#[test]
+fn test_parquet_sink_writes_typed_nullable_columns() -> Result<()> {
+    #[derive(serde::Serialize)]
+    struct Row {
+        timestamp: &'static str,
+        count: String,
+        cpu_percent: String,
+    }
+    fn column_type(name: &str) -> ColumnType {
+        match name {
+            "timestamp" => ColumnType::Text,
+            "count" => ColumnType::Int64,
+            _ => ColumnType::Double,
+        }
+    }
+
+    let path = std::env::temp_dir().join(format!("tu_test_{}.parquet", std::process::id()));
+    let mut sink = ParquetSink::new(&path, Rotation::Never, column_type);
+    sink.serialize(&Row {
+        timestamp: "t1",
+        count: "3".into(),
+        cpu_percent: "12.5".into(),
+    })?;
+    sink.serialize(&Row {
+        timestamp: "t2",
+        count: "NA".into(),
+        cpu_percent: "NA".into(),
+    })?;
+    sink.close()?;
+
+    let reader = SerializedFileReader::new(File::open(&path)?)?;
+    let rows = reader
+        .get_row_iter(None)?
+        .map(|row| Ok(row?.to_string()))
+        .collect::<Result<Vec<_>>>()?;
+    std::fs::remove_file(&path)?;
+
+    assert_eq!(
+        vec![
+            r#"{timestamp: "t1", count: 3, cpu_percent: 12.5}"#,
+            r#"{timestamp: "t2", count: null, cpu_percent: null}"#,
+        ],
+        rows
+    );
+
+    Ok(())
+}
+
+#[test]
+fn test_compressed_csv_round_trips() -> Result<()> {
+    let base = std::env::temp_dir().join(format!("tu_test_{}.csv", std::process::id()));
+    let mut wtr = RotatingCsvWriter::compressed(&base, Rotation::Never, Compression::Zstd)?;
+    assert_eq!(
+        base.with_extension("csv.zst"),
+        wtr.current_path().to_path_buf()
+    );
+
+    wtr.serialize(("cpu_percent", "ram_mb"))?;
+    wtr.serialize((12.5, 100))?;
+    wtr.finish()?;
+
+    let path = wtr.current_path().to_path_buf();
+    let decompressed = zstd::decode_all(File::open(&path)?)?;
+    std::fs::remove_file(&path)?;
+
+    assert_eq!(
+        "cpu_percent,ram_mb\n12.5,100\n",
+        String::from_utf8(decompressed)?
+    );
+    assert_eq!(
+        PathBuf::from("usage.csv.gz"),
+        Compression::Gzip.apply_extension(Path::new("usage.csv.gz"))
+    );
+
+    Ok(())
+}
+
+#[test]
+fn test_csv_dialect_sets_delimiter_and_quoting() -> Result<()> {
+    let base = std::env::temp_dir().join(format!("tu_test_dialect_{}.csv", std::process::id()));
+    let dialect = CsvDialect {
+        delimiter: parse_delimiter(";").map_err(color_eyre::eyre::Report::msg)?,
+        quoting: Quoting::NonNumeric,
+    };
+    let mut wtr = RotatingCsvWriter::create(&base, Rotation::Never, Compression::None, dialect)?;
+    wtr.serialize(("key", "bytes"))?;
+    wtr.serialize(("logs/a,b", 100))?;
+    wtr.finish()?;
+
+    let written = std::fs::read_to_string(&base)?;
+    std::fs::remove_file(&base)?;
+
+    assert_eq!("\"key\";\"bytes\"\n\"logs/a,b\";100\n", written);
+    assert_eq!(b';', sniff_delimiter("key;bytes"));
+    assert_eq!(b',', sniff_delimiter("key,bytes"));
+    assert_eq!(b'\t', sniff_delimiter("key\tbytes"));
+    assert_eq!(Ok(b'\t'), parse_delimiter("tab"));
+    assert!(parse_delimiter("\"").is_err());
+    assert!(parse_delimiter(";;").is_err());
+
+    Ok(())
+}
+
+#[test]
+fn test_console_formats_lay_out_records() -> Result<()> {
+    #[derive(serde::Serialize)]
+    struct Row {
+        prefix: &'static str,
+        bytes: u64,
+    }
+    let rows = [
+        Row {
+            prefix: "logs/",
+            bytes: 10,
+        },
+        Row {
+            prefix: "data\tset/",
+            bytes: 2048,
+        },
+    ];
+
+    assert_eq!(
+        "| prefix | bytes |\n\
+         |--------|-------|\n\
+         | logs/  | 10    |",
+        render_records(ConsoleFormat::Table, &rows[..1])?
+    );
+    // A tab in a field would start another column
+    assert_eq!(
+        "prefix\tbytes\nlogs/\t10\ndata set/\t2048",
+        render_records(ConsoleFormat::Tsv, &rows)?
+    );
+    assert_eq!(
+        "field\tvalue\nprefix\tlogs/\nbytes\t10",
+        render_fields(ConsoleFormat::Tsv, &rows[0])?
+    );
+    Ok(())
+}
+
+#[test]
+fn test_appended_reports_keep_latest_row_per_url() -> Result<()> {
+    #[derive(serde::Serialize)]
+    struct Row {
+        run_time: &'static str,
+        url: &'static str,
+        bytes: u64,
+    }
+    let path = std::env::temp_dir().join(format!("tu_test_append_{}.csv", std::process::id()));
+    let _ = std::fs::remove_file(&path);
+    let options = ReportOptions {
+        append: true,
+        ..Default::default()
+    };
+    let runs = [
+        ("2025-06-01T00:00:00Z", [("s3://a/", 1), ("s3://b/", 2)]),
+        ("2025-06-02T00:00:00Z", [("s3://a/", 3), ("s3://c/", 4)]),
+    ];
+    for (run_time, rows) in runs {
+        let mut sink = ReportFormat::Csv.open::<Row>(&path, &options)?;
+        for (url, bytes) in rows {
+            sink.write(
+                &Row {
+                    run_time,
+                    url,
+                    bytes,
+                },
+                &[],
+            )?;
+        }
+        sink.finish()?;
+    }
+    let appended = std::fs::read_to_string(&path)?;
+
+    let dropped = keep_latest(&path, Default::default(), "url", "run_time")?;
+    let deduplicated = std::fs::read_to_string(&path)?;
+
+    #[derive(serde::Serialize)]
+    struct OtherSchema {
+        url: &'static str,
+    }
+    let mismatch = ReportFormat::Csv
+        .open::<OtherSchema>(&path, &options)?
+        .write(&OtherSchema { url: "s3://a/" }, &[]);
+    std::fs::remove_file(&path)?;
+
+    assert_eq!(
+        "run_time,url,bytes\n\
+         2025-06-01T00:00:00Z,s3://a/,1\n\
+         2025-06-01T00:00:00Z,s3://b/,2\n\
+         2025-06-02T00:00:00Z,s3://a/,3\n\
+         2025-06-02T00:00:00Z,s3://c/,4\n",
+        appended
+    );
+    assert_eq!(1, dropped);
+    assert_eq!(
+        "run_time,url,bytes\n\
+         2025-06-01T00:00:00Z,s3://b/,2\n\
+         2025-06-02T00:00:00Z,s3://a/,3\n\
+         2025-06-02T00:00:00Z,s3://c/,4\n",
+        deduplicated
+    );
+    assert!(mismatch.unwrap_err().to_string().contains("columns differ"));
+    assert!(ReportFormat::Jsonl.open::<Row>(&path, &options).is_err());
+
+    Ok(())
+}
+
+#[test]
+fn test_file_name_placeholders_are_filled_in() -> Result<()> {
+    use chrono::TimeZone;
+
+    let values = FileNameValues {
+        time: chrono::Local
+            .with_ymd_and_hms(2025, 6, 1, 13, 45, 0)
+            .unwrap(),
+        host: Some("node-1".into()),
+        command: Some("train.sh /data".into()),
+    };
+
+    assert_eq!(
+        "usage_2025-06-01_13-45-00_node-1_train.sh__data.csv",
+        values.expand("usage_{date}_{time}_{host}_{command}.csv")?
+    );
+    assert_eq!("{date}.csv", values.expand("{{date}}.csv")?);
+    assert_eq!(
+        PathBuf::from("/runs/{unchanged}"),
+        values.expand_path(Path::new("/runs/{{unchanged}}"))?
+    );
+    for invalid in ["{day}.csv", "{date.csv", "date}.csv"] {
+        assert!(values.expand(invalid).is_err(), "{}", invalid);
+    }
+    let no_command = FileNameValues {
+        command: None,
+        ..values
+    };
+    assert!(no_command.expand("{command}.csv").is_err());
+
+    Ok(())
+}
+
+#[test]
+fn test_stdout_output_is_never_a_file() {
+    let stdout = Path::new("-");
+    assert_eq!(
+        stdout.to_path_buf(),
+        Compression::Zstd.apply_extension(stdout)
+    );
+
+    let rotated = ReportOptions {
+        rotation: Rotation::Daily,
+        ..Default::default()
+    };
+    assert!(ReportFormat::Csv.open::<(u32,)>(stdout, &rotated).is_err());
+    assert!(
+        ReportFormat::Parquet
+            .open::<(u32,)>(stdout, &Default::default())
+            .is_err()
+    );
+}
+
+#[test]
+fn test_size_rotation_keeps_most_recent_files() -> Result<()> {
+    let dir = std::env::temp_dir().join(format!("tu_test_rotation_{}", std::process::id()));
+    std::fs::create_dir_all(&dir)?;
+    let mut wtr =
+        RotatingCsvWriter::new(dir.join("usage.csv"), Rotation::Size(1))?.with_retention(2);
+
+    // Every write fills the current file, so each starts a new one
+    for row in 0..4 {
+        wtr.serialize(("row", row))?;
+        wtr.flush()?;
+    }
+    let last = wtr.current_path().to_path_buf();
+    wtr.finish()?;
+
+    let mut files = std::fs::read_dir(&dir)?
+        .map(|entry| Ok(entry?.path()))
+        .collect::<Result<Vec<_>>>()?;
+    files.sort();
+    let last_contents = std::fs::read_to_string(&last)?;
+    std::fs::remove_dir_all(&dir)?;
+
+    assert_eq!(2, files.len());
+    assert!(files.contains(&last));
+    assert_eq!("row,3\n", last_contents);
+
+    Ok(())
+}
+
+#[test]
+fn test_notification_describes_stopped_run() -> Result<()> {
+    let summary = RunSummary {
+        peak_ram_bytes: 512 * 2u64.pow(20),
+        exit_code: Some(137),
+        stop_reason: Some("limit breached: RAM".to_string()),
+        ..SummaryAccumulator::new().finish(Duration::from_secs(90))
+    };
+
+    let notification = Notification::new("train.sh".into(), Some("node1".into()), &summary);
+    let json: serde_json::Value = serde_json::to_value(&notification)?;
+
+    assert_eq!(
+        "`train.sh` on node1 stopped (limit breached: RAM) after 90s. Peak CPU 0.0%, RAM 512.0 MiB",
+        notification.text
+    );
+    assert_eq!("train.sh", json["command"]);
+    assert_eq!(137, json["exit_code"]);
+    assert_eq!(90.0, json["wall_clock_seconds"]);
+
+    Ok(())
+}
+
+#[test]
+fn test_aggregator_folds_periods_after_raw() -> Result<()> {
+    #[derive(Debug, PartialEq, serde::Serialize, serde::Deserialize)]
+    struct Row {
+        elapsed_seconds: u64,
+        #[serde(skip_serializing_if = "Option::is_none")]
+        aggregate: Option<String>,
+        cpu_percent: String,
+        process_count: String,
+        top_cpu_pid: String,
+    }
+    let row = |elapsed_seconds: u64, cpu_percent: &str, process_count: &str, pid: &str| Row {
+        elapsed_seconds,
+        aggregate: Some("raw".into()),
+        cpu_percent: cpu_percent.into(),
+        process_count: process_count.into(),
+        top_cpu_pid: pid.into(),
+    };
+
+    let mut aggregator = Aggregator::new(
+        Duration::from_secs(10),
+        Duration::from_secs(60),
+        &["elapsed_seconds", "top_cpu_pid"],
+    );
+    let mut written = Vec::new();
+    let samples = [
+        row(5, "12.0", "1", "100"),
+        row(10, "50.0", "2", "101"),
+        row(30, "99.5", "3", "102"),
+        row(40, "NA", "3", "NA"),
+        row(70, "20.0", "1", "103"),
+    ];
+    for sample in samples {
+        let elapsed = Duration::from_secs(sample.elapsed_seconds);
+        written.extend(aggregator.push(sample, elapsed)?);
+    }
+    // The period from 70s is still open
+    assert_eq!(4, written.len());
+    written.extend(aggregator.flush()?);
+
+    let folded = |elapsed_seconds, aggregate: &str, cpu_percent: &str, process_count: &str| Row {
+        aggregate: Some(aggregate.into()),
+        ..row(elapsed_seconds, cpu_percent, process_count, "NA")
+    };
+    let expected = vec![
+        row(5, "12.0", "1", "100"),
+        Row {
+            top_cpu_pid: "101".into(),
+            ..folded(10, "min", "50.0", "2")
+        },
+        Row {
+            top_cpu_pid: "101".into(),
+            ..folded(10, "mean", "74.8", "3")
+        },
+        Row {
+            top_cpu_pid: "101".into(),
+            ..folded(10, "max", "99.5", "3")
+        },
+        Row {
+            top_cpu_pid: "103".into(),
+            ..folded(70, "min", "20.0", "1")
+        },
+        Row {
+            top_cpu_pid: "103".into(),
+            ..folded(70, "mean", "20.0", "1")
+        },
+        Row {
+            top_cpu_pid: "103".into(),
+            ..folded(70, "max", "20.0", "1")
+        },
+    ];
+    assert_eq!(expected, written);
+    Ok(())
+}
+
+#[test]
+fn test_man_page_documents_options_commands_and_environment() {
+    let cmd = clap::Command::new("xu")
+        .about("Does things")
+        .arg(
+            clap::Arg::new("format")
+                .long("log-format")
+                .value_name("FORMAT")
+                .help("How to log")
+                .value_parser(["text", "json"])
+                .default_value("text"),
+        )
+        .arg(
+            clap::Arg::new("quiet")
+                .short('q')
+                .action(clap::ArgAction::SetTrue)
+                .help(".Less output"),
+        )
+        .subcommand(
+            clap::Command::new("size-report")
+                .about("Report sizes")
+                .arg(clap::Arg::new("url").required(true)),
+        );
+
+    let page = man_page(&cmd, &[("XU_HOME", "Where things go")]);
+
+    assert!(page.starts_with(".TH XU 1 "));
+    assert!(page.contains(".SH NAME\nxu \\- Does things\n"));
+    assert!(page.contains(
+        ".TP\n\\fB\\-\\-log\\-format\\fR \\fIFORMAT\\fR\nHow to log [default: text] \
+         [possible values: text, json]\n"
+    ));
+    // Flags don't list true and false, and text starting with a dot is escaped
+    assert!(page.contains(".TP\n\\fB\\-q\\fR\n\\&.Less output\n"));
+    assert!(page.contains(".SS xu size\\-report\nReport sizes\n.TP\n\\fIURL\\fR\n"));
+    assert!(page.contains(".SH ENVIRONMENT\n.TP\n\\fBXU_HOME\\fR\nWhere things go\n"));
+    assert!(page.contains(".SH EXIT STATUS\n.TP\n\\fB0\\fR\nSuccess.\n"));
+
+    assert_eq!(escape("a\\b-c\n'd"), "a\\eb\\-c\n\\&'d");
+}
+
+#[test]
+fn test_json_formats_keep_columns_in_order_with_types() -> Result<()> {
+    #[derive(serde::Serialize)]
+    struct Row {
+        name: &'static str,
+        pid: u32,
+        cpu_percent: String,
+        gpu_percent: String,
+        active: bool,
+    }
+    let rows = [
+        Row {
+            name: "python \"train\"",
+            pid: 42,
+            cpu_percent: "12.5".into(),
+            gpu_percent: "NA".into(),
+            active: true,
+        },
+        Row {
+            name: "007",
+            pid: 43,
+            cpu_percent: "0.0".into(),
+            gpu_percent: "3".into(),
+            active: false,
+        },
+    ];
+    let first = r#"{"name":"python \"train\"","pid":42,"cpu_percent":12.5,"gpu_percent":null,"active":true}"#;
+    // Not a valid JSON number, so left as text
+    let second = r#"{"name":"007","pid":43,"cpu_percent":0.0,"gpu_percent":3,"active":false}"#;
+
+    let dir = std::env::temp_dir().join(format!("tu_test_json_{}", std::process::id()));
+    std::fs::create_dir_all(&dir)?;
+    for (format, expected) in [
+        (
+            ReportFormat::Json,
+            format!("[\n{},\n{}\n]\n", first, second),
+        ),
+        (ReportFormat::Jsonl, format!("{}\n{}\n", first, second)),
+    ] {
+        let path = dir.join(format!("usage.{}", format.extension()));
+        let mut sink = format.open::<Row>(&path, &ReportOptions::default())?;
+        for row in &rows {
+            sink.write(row, &[])?;
+        }
+        sink.finish()?;
+        let written = std::fs::read_to_string(&path)?;
+        assert_eq!(expected, written);
+        if format == ReportFormat::Json {
+            let parsed: serde_json::Value = serde_json::from_str(&written)?;
+            assert_eq!(2, parsed.as_array().map_or(0, Vec::len));
+        }
+    }
+
+    assert_eq!(Ok(ReportFormat::Jsonl), "jsonl".parse());
+    assert!("xml".parse::<ReportFormat>().is_err());
+    let compressed = ReportOptions {
+        compression: Compression::Gzip,
+        ..Default::default()
+    };
+    assert!(
+        ReportFormat::Parquet
+            .open::<Row>(&dir.join("usage.parquet"), &compressed)
+            .is_err()
+    );
+
+    std::fs::remove_dir_all(&dir)?;
+    Ok(())
+}
+
+#[test]
+fn test_sizes_in_si_or_binary_units() {
+    assert_eq!("1.5 GiB", SizeUnits::Binary.format(1_610_612_736));
+    assert_eq!("1.6 GB", SizeUnits::Si.format(1_610_612_736));
+    assert_eq!("999 B", SizeUnits::Si.format(999));
+    assert_eq!("1.0 kB", SizeUnits::Si.format(1000));
+    assert_eq!("1000 B", SizeUnits::Binary.format(1000));
+
+    assert_eq!(Ok(SizeUnits::Si), "si".parse());
+    assert_eq!(Ok(SizeUnits::Binary), "binary".parse());
+    assert!("metric".parse::<SizeUnits>().is_err());
+}
+
+#[test]
+fn test_parse_human_sizes() {
+    let bytes = |s: &str| parse_size(s).map(|size| size.as_u64());
+    assert_eq!(Ok(100_000_000), bytes("100MB"));
+    assert_eq!(Ok(2_684_354_560), bytes("2.5GiB"));
+    assert_eq!(Ok(1_536), bytes("1.5 kib"));
+    assert_eq!(Ok(32_000_000_000), bytes(" 32gb "));
+    assert_eq!(Ok(1_048_576), bytes("1048576"));
+    for invalid in ["", "MB", "-1GB", "12 parsecs"] {
+        assert!(parse_size(invalid).is_err(), "{}", invalid);
+    }
+}
+
+#[test]
+fn test_progress_events_are_single_json_lines() -> Result<()> {
+    let line = progress_line(&ProgressEvent::VersionsDeleted {
+        bucket: "my-bucket",
+        page: 2,
+        deleted: 998,
+        failed: 2,
+        bytes: 1_500_000,
+        total_deleted: 2998,
+        total_bytes: 4_500_000,
+    })?;
+    assert!(!line.contains('\n'));
+
+    let mut value: serde_json::Value = serde_json::from_str(&line)?;
+    let time = value["time"].take();
+    assert!(time.as_str().is_some_and(|time| time.ends_with('Z')));
+    assert_eq!(
+        serde_json::json!({
+            "time": null,
+            "event": "versions_deleted",
+            "bucket": "my-bucket",
+            "page": 2,
+            "deleted": 998,
+            "failed": 2,
+            "bytes": 1_500_000,
+            "total_deleted": 2998,
+            "total_bytes": 4_500_000,
+        }),
+        value
+    );
+    Ok(())
+}
@@ -0,0 +1,82 @@
+use std::{fmt::Display, str::FromStr, sync::OnceLock};
+
+use bytesize::ByteSize;
+
+/// Units chosen for the run, see [`SizeUnits::set_default`]
+static DEFAULT_UNITS: OnceLock<SizeUnits> = OnceLock::new();
+
+/// Units for sizes written for people to read, rather than in bytes or MiB columns
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default)]
+pub enum SizeUnits {
+    /// Powers of 1024: KiB, MiB, GiB
+    #[default]
+    Binary,
+    /// Powers of 1000: kB, MB, GB
+    Si,
+}
+
+impl FromStr for SizeUnits {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s {
+            "binary" => Ok(SizeUnits::Binary),
+            "si" => Ok(SizeUnits::Si),
+            _ => Err(format!("expected si or binary, not '{}'", s)),
+        }
+    }
+}
+
+impl Display for SizeUnits {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            SizeUnits::Binary => f.write_str("binary"),
+            SizeUnits::Si => f.write_str("si"),
+        }
+    }
+}
+
+impl SizeUnits {
+    /**
+     * `bytes` in these units, e.g. `1.5 GiB` or `1.6 GB`
+     */
+    pub fn format(self, bytes: u64) -> String {
+        let display = ByteSize::b(bytes).display();
+        match self {
+            SizeUnits::Binary => display.iec().to_string(),
+            SizeUnits::Si => display.si().to_string(),
+        }
+    }
+
+    /**
+     * Use these units for every size formatted by [`format_size`].  Only the first call
+     * counts, as the units shouldn't change part way through a run.
+     */
+    pub fn set_default(self) {
+        let _ = DEFAULT_UNITS.set(self);
+    }
+
+    pub fn default_units() -> Self {
+        DEFAULT_UNITS.get().copied().unwrap_or_default()
+    }
+}
+
+/**
+ * `bytes` for people to read, in the units chosen with `--units`
+ */
+pub fn format_size(bytes: u64) -> String {
+    SizeUnits::default_units().format(bytes)
+}
+
+/**
+ * Parse a size given on the command line, e.g. `100MB`, `2.5GiB` or a number of bytes.
+ * Units of 1000 and 1024 are both accepted, whatever `--units` says, and case is ignored.
+ */
+pub fn parse_size(s: &str) -> Result<ByteSize, String> {
+    s.trim().parse::<ByteSize>().map_err(|_| {
+        format!(
+            "expected a size such as 100MB, 2.5GiB or 1048576, not '{}'",
+            s
+        )
+    })
+}
@@ -0,0 +1,36 @@
+//! The stable library API.  Everything here keeps its path and signature until the next
+//! breaking release, wherever it moves to inside the crate, so depend on these rather than
+//! on module paths:
+//!
+//! ```
+//! use tools::prelude::*;
+//! ```
+//!
+//! Anything reached only through a module path may change in any release.
+
+pub use crate::{
+    exit::{Failure, exit_code},
+    output::{
+        format::{ReportFormat, ReportOptions},
+        sink::Sink,
+        units::{SizeUnits, format_size},
+    },
+    process::{
+        gpu::{GpuBackend, NoGpu},
+        monitor::{Measurements, Monitor, Target, UsageSample},
+        summary::RunSummary,
+        system::{CpuRamUsage, ProcessUsage, System},
+        trace::UsageTrace,
+    },
+};
+
+#[cfg(feature = "nvml")]
+pub use crate::process::gpu::GpuSession;
+
+#[cfg(feature = "s3")]
+pub use crate::s3::{
+    client::S3Api,
+    size::{SizeReport, Stats, build_size_report},
+    types::S3Location,
+    wrapper::S3Wrapper,
+};
@@ -0,0 +1,149 @@
+use std::{
+    collections::{HashMap, HashSet},
+    process::Command,
+    str::from_utf8,
+    time::Duration,
+};
+
+use color_eyre::{
+    Result,
+    eyre::{Context, OptionExt, bail},
+};
+use regex::Regex;
+use sysinfo::Pid;
+
+use crate::process::system::System;
+
+/// GPU monitoring on Apple Silicon, using `powermetrics` (which must be run as root)
+pub struct AppleGpu {
+    sample_window: Duration,
+}
+
+impl AppleGpu {
+    /**
+     * `sample_window` is how long each call to `powermetrics` spends measuring
+     */
+    pub fn new(sample_window: Duration) -> Result<Self> {
+        if !cfg!(target_os = "macos") {
+            bail!("Apple GPU monitoring is only available on macOS")
+        }
+
+        // Take one short sample up front to check we have permission
+        let status = Command::new("powermetrics")
+            .args(["-n", "1", "-i", "1", "--samplers", "gpu_power"])
+            .output()
+            .wrap_err("Failed to run `powermetrics`")?
+            .status;
+        if !status.success() {
+            bail!(
+                "`powermetrics` failed ({}), it needs to be run with sudo",
+                status
+            )
+        }
+
+        Ok(Self { sample_window })
+    }
+
+    fn sample(&self) -> Result<String> {
+        let output = Command::new("powermetrics")
+            .args([
+                "-n",
+                "1",
+                "-i",
+                &self.sample_window.as_millis().to_string(),
+                "--samplers",
+                "gpu_power,tasks",
+                "--show-process-gpu",
+            ])
+            .output()
+            .wrap_err("Failed to run `powermetrics`")?;
+        if !output.status.success() {
+            bail!(
+                "`powermetrics` failed: {}",
+                String::from_utf8_lossy(&output.stderr)
+            )
+        }
+        Ok(from_utf8(&output.stdout)?.to_string())
+    }
+
+    /**
+     * Overall utilisation of the GPU, regardless of which processes are using it
+     */
+    pub fn get_device_utilisation(&self) -> Result<u32> {
+        parse_active_residency(&self.sample()?)
+    }
+
+    /**
+     * Utilisation of each process in the trees rooted at `roots` which used the GPU
+     */
+    pub fn get_pid_trees_utilisation_by_pid(
+        &self,
+        roots: &[Pid],
+        system: &mut System,
+    ) -> Result<HashMap<Pid, u32>> {
+        self.get_tree_utilisation_by_pid(&system.get_pid_trees(roots))
+    }
+
+    /**
+     * As [`Self::get_pid_trees_utilisation_by_pid`], for the processes already found in `tree`
+     */
+    pub fn get_tree_utilisation_by_pid(&self, tree: &HashSet<Pid>) -> Result<HashMap<Pid, u32>> {
+        let by_pid = parse_process_gpu(&self.sample()?)?
+            .into_iter()
+            .filter(|(pid, _)| tree.contains(pid))
+            .collect();
+        log::info!("Apple GPU by pid: {:?}", by_pid);
+        Ok(by_pid)
+    }
+}
+
+/**
+ * Overall GPU busy percentage from the `gpu_power` sampler
+ */
+pub(crate) fn parse_active_residency(output: &str) -> Result<u32> {
+    // Older releases omit the "HW"
+    let re = Regex::new(r"GPU (?:HW )?active residency:\s+([\d.]+)%").unwrap();
+    let percent: f64 = re
+        .captures(output)
+        .map(|caps| caps[1].parse())
+        .transpose()?
+        .ok_or_eyre("No GPU active residency in `powermetrics` output")?;
+    Ok(percent.round() as u32)
+}
+
+/**
+ * Per-process GPU percentage from the `tasks` sampler.  GPU time is reported in
+ * ms/s, so dividing by ten gives the percentage of one GPU.
+ */
+pub(crate) fn parse_process_gpu(output: &str) -> Result<HashMap<Pid, u32>> {
+    let mut lines = output.lines();
+    let Some(header) = lines.find(|line| line.starts_with("Name") && line.contains("GPU ms/s"))
+    else {
+        bail!("No per-process GPU column in `powermetrics` output")
+    };
+    // Columns are aligned, and names can contain spaces, so locate values by position
+    let column = header.find("GPU ms/s").unwrap();
+    let row = Regex::new(r"^.+?\s+(\d+)\s").unwrap();
+
+    let mut by_pid = HashMap::new();
+    for line in lines.take_while(|line| !line.trim().is_empty()) {
+        let Some(pid) = row.captures(line).and_then(|caps| caps[1].parse().ok()) else {
+            continue;
+        };
+        let Some(gpu_ms) = token_at(line, column).and_then(|token| token.parse::<f64>().ok())
+        else {
+            continue;
+        };
+        if gpu_ms > 0.0 {
+            by_pid.insert(Pid::from_u32(pid), (gpu_ms / 10.0).round() as u32);
+        }
+    }
+
+    Ok(by_pid)
+}
+
+/// Whitespace delimited token overlapping the given byte offset, or the next one after it
+fn token_at(line: &str, offset: usize) -> Option<&str> {
+    let start = line.get(..offset)?.rfind(' ').map(|i| i + 1).unwrap_or(0);
+    line[start..].split_whitespace().next()
+}
@@ -0,0 +1,76 @@
+use super::summary::RunSummary;
+
+/// Headline figures from one run of a benchmarked command
+#[derive(Debug, Clone, PartialEq)]
+pub struct BenchRun {
+    pub wall_clock_seconds: f64,
+    pub peak_ram_bytes: u64,
+    pub mean_cpu_percent: f32,
+    pub mean_gpu_percent: Option<f32>,
+    pub exit_code: Option<i32>,
+}
+
+impl From<&RunSummary> for BenchRun {
+    fn from(summary: &RunSummary) -> Self {
+        Self {
+            wall_clock_seconds: summary.wall_clock_seconds,
+            peak_ram_bytes: summary.peak_ram_bytes,
+            mean_cpu_percent: summary.mean_cpu_percent,
+            mean_gpu_percent: summary.mean_gpu_percent,
+            exit_code: summary.exit_code,
+        }
+    }
+}
+
+/// Mean and sample standard deviation of a figure across runs
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct Spread {
+    pub mean: f64,
+    pub stddev: f64,
+}
+
+impl Spread {
+    /**
+     * `None` if there are no values.  A single value has no spread.
+     */
+    pub fn of(values: impl IntoIterator<Item = f64>) -> Option<Self> {
+        let values: Vec<f64> = values.into_iter().collect();
+        if values.is_empty() {
+            return None;
+        }
+        let n = values.len() as f64;
+        let mean = values.iter().sum::<f64>() / n;
+        let stddev = if values.len() > 1 {
+            let squares: f64 = values.iter().map(|v| (v - mean).powi(2)).sum();
+            (squares / (n - 1.0)).sqrt()
+        } else {
+            0.0
+        };
+        Some(Self { mean, stddev })
+    }
+}
+
+/// Spread of each figure across the runs of a benchmark
+#[derive(Debug, Clone, PartialEq)]
+pub struct BenchStats {
+    pub wall_clock_seconds: Option<Spread>,
+    pub peak_ram_bytes: Option<Spread>,
+    pub mean_cpu_percent: Option<Spread>,
+    /// Over the runs which measured GPU utilisation
+    pub mean_gpu_percent: Option<Spread>,
+}
+
+impl BenchStats {
+    pub fn new(runs: &[BenchRun]) -> Self {
+        Self {
+            wall_clock_seconds: Spread::of(runs.iter().map(|r| r.wall_clock_seconds)),
+            peak_ram_bytes: Spread::of(runs.iter().map(|r| r.peak_ram_bytes as f64)),
+            mean_cpu_percent: Spread::of(runs.iter().map(|r| r.mean_cpu_percent as f64)),
+            mean_gpu_percent: Spread::of(
+                runs.iter()
+                    .filter_map(|r| r.mean_gpu_percent)
+                    .map(|gpu| gpu as f64),
+            ),
+        }
+    }
+}
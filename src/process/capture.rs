@@ -0,0 +1,144 @@
+use std::{
+    fs::File,
+    io::{BufRead, BufReader, LineWriter, Read, Write},
+    path::Path,
+    process::{Child, Command, Stdio},
+    sync::{Arc, Mutex},
+    thread::{self, JoinHandle},
+};
+
+use chrono::{DateTime, Local};
+use color_eyre::{Result, eyre::Context};
+
+use crate::process::dashboard::LogBuffer;
+
+type SharedLog = Arc<Mutex<LineWriter<File>>>;
+type Timestamper = Arc<dyn Fn(DateTime<Local>) -> String + Send + Sync>;
+
+/// Captures a command's stdout and stderr, copying each line to a timestamped log file and
+/// passing it on to the terminal (or a dashboard's log pane)
+pub struct OutputCapture {
+    stdout_log: Option<SharedLog>,
+    stderr_log: Option<SharedLog>,
+    /// Stream names are included when both go to the same file
+    combined: bool,
+    /// Pass the command's stdout on to stderr, e.g. when stdout carries the report
+    stdout_to_stderr: bool,
+    dashboard: Option<LogBuffer>,
+    timestamp: Timestamper,
+    threads: Mutex<Vec<JoinHandle<()>>>,
+}
+
+impl OutputCapture {
+    /**
+     * Log stdout and stderr to the given files, which may be the same.  If `dashboard` is
+     * given, lines go there instead of the terminal.
+     */
+    pub fn new(
+        stdout_path: Option<&Path>,
+        stderr_path: Option<&Path>,
+        dashboard: Option<LogBuffer>,
+        timestamp: impl Fn(DateTime<Local>) -> String + Send + Sync + 'static,
+    ) -> Result<Self> {
+        let stdout_log = stdout_path.map(open_log).transpose()?;
+        let combined = stdout_path.is_some() && stdout_path == stderr_path;
+        let stderr_log = match &stdout_log {
+            Some(log) if combined => Some(Arc::clone(log)),
+            _ => stderr_path.map(open_log).transpose()?,
+        };
+
+        Ok(Self {
+            stdout_log,
+            stderr_log,
+            combined,
+            stdout_to_stderr: false,
+            dashboard,
+            timestamp: Arc::new(timestamp),
+            threads: Mutex::new(Vec::new()),
+        })
+    }
+
+    /**
+     * Pass the command's stdout on to stderr rather than stdout
+     */
+    pub fn with_stdout_to_stderr(mut self) -> Self {
+        self.stdout_to_stderr = true;
+        self
+    }
+
+    pub fn pipe_output(&self, cmd: &mut Command) {
+        cmd.stdout(Stdio::piped()).stderr(Stdio::piped());
+    }
+
+    /**
+     * Start copying the output of a child spawned with [`Self::pipe_output`]
+     */
+    pub fn follow(&self, child: &mut Child) {
+        let mut threads = self.threads.lock().unwrap();
+        if let Some(stdout) = child.stdout.take() {
+            threads.push(self.copy_lines(
+                stdout,
+                "stdout",
+                self.stdout_log.clone(),
+                self.stdout_to_stderr,
+            ));
+        }
+        if let Some(stderr) = child.stderr.take() {
+            threads.push(self.copy_lines(stderr, "stderr", self.stderr_log.clone(), true));
+        }
+    }
+
+    /**
+     * Wait for the command's output to be fully copied.  Only call once it has exited, or
+     * this blocks until it does.
+     */
+    pub fn finish(&self) {
+        for thread in self.threads.lock().unwrap().drain(..) {
+            if thread.join().is_err() {
+                log::warn!("Output capture thread panicked");
+            }
+        }
+    }
+
+    fn copy_lines<R: Read + Send + 'static>(
+        &self,
+        source: R,
+        stream: &'static str,
+        log: Option<SharedLog>,
+        to_stderr: bool,
+    ) -> JoinHandle<()> {
+        let dashboard = self.dashboard.clone();
+        let timestamp = Arc::clone(&self.timestamp);
+        let combined = self.combined;
+
+        thread::spawn(move || {
+            for line in BufReader::new(source).lines().map_while(Result::ok) {
+                if let Some(log) = &log {
+                    let mut log = log.lock().unwrap();
+                    let time = timestamp(Local::now());
+                    let written = if combined {
+                        writeln!(log, "{}\t{}\t{}", time, stream, line)
+                    } else {
+                        writeln!(log, "{}\t{}", time, line)
+                    };
+                    if let Err(e) = written {
+                        log::warn!("Failed to log command {}: {}", stream, e);
+                    }
+                }
+
+                match &dashboard {
+                    Some(dashboard) => dashboard.push(line),
+                    // The terminal going away shouldn't stop the log being written
+                    None if to_stderr => _ = writeln!(std::io::stderr(), "{}", line),
+                    None => _ = writeln!(std::io::stdout(), "{}", line),
+                }
+            }
+        })
+    }
+}
+
+fn open_log(path: &Path) -> Result<SharedLog> {
+    let file = File::create(path)
+        .wrap_err_with(|| format!("Failed to create output log {}", path.display()))?;
+    Ok(Arc::new(Mutex::new(LineWriter::new(file))))
+}
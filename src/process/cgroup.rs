@@ -0,0 +1,393 @@
+use std::{
+    fs,
+    path::{Path, PathBuf},
+    process::Command,
+    time::Instant,
+};
+
+use color_eyre::{
+    Result,
+    eyre::{Context, OptionExt, bail, eyre},
+};
+use sysinfo::Pid;
+
+use crate::{
+    exit::Failure,
+    process::system::{CpuRamUsage, CpuTimes, NumaBytes},
+};
+
+static CGROUP_ROOT: &str = "/sys/fs/cgroup";
+
+/// A cgroup v2 group, measured as a whole rather than by walking the process tree.  Processes
+/// which double-fork or otherwise leave the tree stay in the cgroup, so are still counted.
+pub struct Cgroup {
+    path: PathBuf,
+    /// Whether we made the group, so should tidy it up
+    created: bool,
+    last_cpu: (u64, Instant),
+}
+
+impl Cgroup {
+    /**
+     * Use the cgroup at `path` (absolute, or relative to the cgroup root), creating it if
+     * necessary.
+     */
+    pub fn open_or_create(path: &Path) -> Result<Self> {
+        check_v2()?;
+        let path = Path::new(CGROUP_ROOT).join(path);
+
+        let created = !path.exists();
+        if created {
+            fs::create_dir(&path)
+                .wrap_err_with(|| format!("Failed to create cgroup {}", path.display()))?;
+            log::info!("Created cgroup {}", path.display());
+        }
+
+        Self::new(path, created)
+    }
+
+    /**
+     * Use the cgroup of a running Docker/containerd/Podman container, given its ID (or a
+     * unique prefix of it)
+     */
+    pub fn for_container(id: &str) -> Result<Self> {
+        check_v2()?;
+        let path = find_container_cgroup(id)?;
+        log::info!("Container {} is in cgroup {}", id, path.display());
+        Self::new(Path::new(CGROUP_ROOT).join(path), false)
+    }
+
+    fn new(path: PathBuf, created: bool) -> Result<Self> {
+        let last_cpu = (read_cpu_usage_usec(&path)?, Instant::now());
+        Ok(Self {
+            path,
+            created,
+            last_cpu,
+        })
+    }
+
+    pub fn path(&self) -> &Path {
+        &self.path
+    }
+
+    /**
+     * Make `cmd` join this cgroup before it execs, so none of its descendants can escape
+     */
+    #[cfg(unix)]
+    pub fn enter_on_spawn(&self, cmd: &mut Command) -> Result<()> {
+        use std::{fs::OpenOptions, io::Write, os::unix::process::CommandExt};
+
+        let procs = self.path.join("cgroup.procs");
+        let file = OpenOptions::new()
+            .write(true)
+            .open(&procs)
+            .wrap_err_with(|| format!("Failed to open {}", procs.display()))?;
+        // Writing 0 moves the writing process.  Nothing here may allocate, as we're post-fork.
+        unsafe {
+            cmd.pre_exec(move || (&file).write_all(b"0"));
+        }
+        Ok(())
+    }
+
+    #[cfg(not(unix))]
+    pub fn enter_on_spawn(&self, _cmd: &mut Command) -> Result<()> {
+        bail!("cgroups are only supported on Linux")
+    }
+
+    /**
+     * Processes directly in the cgroup (not its descendant groups)
+     */
+    pub fn pids(&self) -> Result<Vec<Pid>> {
+        let procs = self.read("cgroup.procs")?;
+        procs
+            .lines()
+            .map(|line| {
+                line.parse::<u32>()
+                    .map(Pid::from_u32)
+                    .wrap_err_with(|| format!("Bad pid '{}' in cgroup.procs", line))
+            })
+            .collect()
+    }
+
+    /**
+     * Whether any process remains in the cgroup or its descendants
+     */
+    pub fn is_populated(&self) -> Result<bool> {
+        let events = self.read("cgroup.events")?;
+        let populated = events
+            .lines()
+            .find_map(|line| line.strip_prefix("populated "))
+            .ok_or_eyre("No 'populated' entry in cgroup.events")?;
+        Ok(populated.trim() == "1")
+    }
+
+    /**
+     * CPU since the last call (or since the group was opened), and current memory
+     */
+    pub fn cpu_ram(&mut self) -> Result<CpuRamUsage> {
+        let usage_usec = read_cpu_usage_usec(&self.path)?;
+        let now = Instant::now();
+        let (last_usec, last_time) = self.last_cpu;
+        let elapsed_usec = now.duration_since(last_time).as_micros() as f64;
+        let cpu_percent = if elapsed_usec > 0.0 {
+            100.0 * usage_usec.saturating_sub(last_usec) as f64 / elapsed_usec
+        } else {
+            0.0
+        };
+        self.last_cpu = (usage_usec, now);
+
+        let memory = self.read("memory.current").wrap_err(
+            "Is the memory controller enabled?  It must be listed in the parent's cgroup.subtree_control",
+        )?;
+        let memory_bytes = memory
+            .trim()
+            .parse()
+            .wrap_err_with(|| format!("Bad memory.current '{}'", memory.trim()))?;
+
+        Ok(CpuRamUsage {
+            cpu_percent: cpu_percent as f32,
+            memory_bytes,
+            // The cgroup's memory isn't broken down per mapping
+            virtual_memory_bytes: None,
+            shared_memory_bytes: None,
+        })
+    }
+
+    /**
+     * User and system CPU time used by the group since it was created
+     */
+    pub fn cpu_times(&self) -> Result<CpuTimes> {
+        parse_cpu_times_usec(&self.read("cpu.stat")?)
+    }
+
+    /**
+     * Total bytes read and written by the group, summed over devices.  Zero if the io
+     * controller isn't enabled.
+     */
+    pub fn io_bytes(&self) -> Result<(u64, u64)> {
+        if !self.path.join("io.stat").exists() {
+            log::debug!("No io.stat in {}", self.path.display());
+            return Ok((0, 0));
+        }
+        parse_io_stat(&self.read("io.stat")?)
+    }
+
+    /**
+     * Anonymous and file-backed memory of the group on each NUMA node
+     */
+    pub fn numa_bytes(&self) -> Result<NumaBytes> {
+        parse_numa_stat(&self.read("memory.numa_stat")?)
+    }
+
+    fn read(&self, file: &str) -> Result<String> {
+        let path = self.path.join(file);
+        fs::read_to_string(&path).wrap_err_with(|| format!("Failed to read {}", path.display()))
+    }
+}
+
+impl Drop for Cgroup {
+    fn drop(&mut self) {
+        // Only succeeds once the group is empty, which it should be if the command finished
+        if self.created
+            && let Err(e) = fs::remove_dir(&self.path)
+        {
+            log::warn!("Failed to remove cgroup {}: {}", self.path.display(), e);
+        }
+    }
+}
+
+/**
+ * The cgroup v2 group `pid` is in, if it can be read
+ */
+pub(crate) fn cgroup_of(pid: Pid) -> Option<PathBuf> {
+    let proc_cgroup = fs::read_to_string(format!("/proc/{}/cgroup", pid)).ok()?;
+    let path = proc_cgroup
+        .lines()
+        .find_map(|line| line.strip_prefix("0::"))?;
+    Some(Path::new(CGROUP_ROOT).join(path.trim_start_matches('/')))
+}
+
+/**
+ * How many cores' worth of CPU time the processes in the group at `path` may use: the
+ * tightest `cpu.max` quota on the way up to the root, or the number of CPUs in its cpuset
+ * if that's fewer.  `None` if neither limits it to fewer than `cpu_count` cores.
+ */
+pub(crate) fn cpu_limit_cores(path: &Path, cpu_count: usize) -> Option<f64> {
+    let cpuset_cores = fs::read_to_string(path.join("cpuset.cpus.effective"))
+        .ok()
+        .and_then(|list| parse_cpu_list(&list))
+        .map(|count| count as f64);
+    let quota_cores = path
+        .ancestors()
+        .take_while(|dir| dir.starts_with(CGROUP_ROOT) && *dir != Path::new(CGROUP_ROOT))
+        .filter_map(|dir| fs::read_to_string(dir.join("cpu.max")).ok())
+        .filter_map(|cpu_max| parse_cpu_max(&cpu_max))
+        .reduce(f64::min);
+
+    [cpuset_cores, quota_cores]
+        .into_iter()
+        .flatten()
+        .reduce(f64::min)
+        .filter(|&cores| cores < cpu_count as f64)
+}
+
+/**
+ * Cores allowed by a `cpu.max` of `$MAX $PERIOD`, `None` if unlimited (`max`)
+ */
+pub(crate) fn parse_cpu_max(cpu_max: &str) -> Option<f64> {
+    let mut fields = cpu_max.split_whitespace();
+    let quota: f64 = fields.next()?.parse().ok()?;
+    let period: f64 = fields.next()?.parse().ok()?;
+    (period > 0.0).then(|| quota / period)
+}
+
+/**
+ * Number of CPUs in a list such as `0-3,8,10-11`
+ */
+pub(crate) fn parse_cpu_list(list: &str) -> Option<usize> {
+    let list = list.trim();
+    if list.is_empty() {
+        return None;
+    }
+    list.split(',')
+        .map(|range| match range.split_once('-') {
+            Some((first, last)) => {
+                let (first, last) = (first.parse::<usize>().ok()?, last.parse::<usize>().ok()?);
+                last.checked_sub(first).map(|n| n + 1)
+            }
+            None => range.parse::<usize>().ok().map(|_| 1),
+        })
+        .sum()
+}
+
+fn check_v2() -> Result<()> {
+    if !Path::new(CGROUP_ROOT).join("cgroup.controllers").exists() {
+        bail!("No cgroup v2 hierarchy mounted at {}", CGROUP_ROOT)
+    }
+    Ok(())
+}
+
+/**
+ * Container runtimes name the cgroup after the container ID (e.g. `docker-<id>.scope` or
+ * `cri-containerd-<id>.scope`), so look for a process whose cgroup includes it.
+ */
+fn find_container_cgroup(id: &str) -> Result<PathBuf> {
+    if id.len() < 4 || !id.chars().all(|c| c.is_ascii_hexdigit()) {
+        return Err(
+            Failure::InvalidTarget.because(format!("'{}' doesn't look like a container ID", id))
+        );
+    }
+
+    let mut found: Option<PathBuf> = None;
+    for entry in fs::read_dir("/proc")?.flatten() {
+        // Processes can exit while we're looking
+        let Ok(cgroup) = fs::read_to_string(entry.path().join("cgroup")) else {
+            continue;
+        };
+        let Some(path) = container_cgroup_path(&cgroup, id) else {
+            continue;
+        };
+        match &found {
+            Some(existing) if *existing != path => bail!(
+                "Container ID '{}' is ambiguous, matching {} and {}",
+                id,
+                existing.display(),
+                path.display()
+            ),
+            _ => found = Some(path),
+        }
+    }
+
+    found.ok_or_else(|| {
+        Failure::InvalidTarget.because(format!("No running container with ID '{}'", id))
+    })
+}
+
+/**
+ * Given the contents of `/proc/PID/cgroup`, the cgroup v2 path up to and including the
+ * component naming the container, if any
+ */
+pub(crate) fn container_cgroup_path(proc_cgroup: &str, id: &str) -> Option<PathBuf> {
+    let path = proc_cgroup
+        .lines()
+        .find_map(|line| line.strip_prefix("0::"))?;
+
+    let mut container_path = PathBuf::new();
+    for component in path.trim_start_matches('/').split('/') {
+        container_path.push(component);
+        let is_container = component
+            .split(['-', '.', ':'])
+            .any(|part| part.len() == 64 && part.starts_with(id));
+        if is_container {
+            return Some(container_path);
+        }
+    }
+    None
+}
+
+fn read_cpu_usage_usec(path: &Path) -> Result<u64> {
+    let cpu_stat_path = path.join("cpu.stat");
+    let cpu_stat = fs::read_to_string(&cpu_stat_path)
+        .wrap_err_with(|| format!("Failed to read {}", cpu_stat_path.display()))?;
+    parse_cpu_usage_usec(&cpu_stat)
+}
+
+pub(crate) fn parse_cpu_usage_usec(cpu_stat: &str) -> Result<u64> {
+    cpu_stat
+        .lines()
+        .find_map(|line| line.strip_prefix("usage_usec "))
+        .ok_or_eyre("No usage_usec in cpu.stat")?
+        .trim()
+        .parse()
+        .wrap_err("Bad usage_usec in cpu.stat")
+}
+
+pub(crate) fn parse_cpu_times_usec(cpu_stat: &str) -> Result<CpuTimes> {
+    let field = |name: &str| -> Result<f64> {
+        let usec: u64 = cpu_stat
+            .lines()
+            .find_map(|line| line.strip_prefix(name))
+            .ok_or_else(|| eyre!("No {} in cpu.stat", name.trim()))?
+            .trim()
+            .parse()
+            .wrap_err_with(|| format!("Bad {} in cpu.stat", name.trim()))?;
+        Ok(usec as f64 / 1e6)
+    };
+    Ok(CpuTimes {
+        user_seconds: field("user_usec ")?,
+        system_seconds: field("system_usec ")?,
+    })
+}
+
+/**
+ * Sum the `anon` and `file` lines of `memory.numa_stat`, which give bytes as `N<node>=<bytes>`
+ */
+pub(crate) fn parse_numa_stat(numa_stat: &str) -> Result<NumaBytes> {
+    let mut nodes = NumaBytes::new();
+    let lines = numa_stat
+        .lines()
+        .filter(|line| line.starts_with("anon ") || line.starts_with("file "));
+    for field in lines.flat_map(|line| line.split_whitespace().skip(1)) {
+        let (node, bytes) = field
+            .strip_prefix('N')
+            .and_then(|f| f.split_once('='))
+            .ok_or_else(|| eyre!("Bad field '{}' in memory.numa_stat", field))?;
+        *nodes.entry(node.parse()?).or_default() += bytes.parse::<u64>()?;
+    }
+    Ok(nodes)
+}
+
+/**
+ * Sum `rbytes` and `wbytes` across the devices listed in `io.stat`
+ */
+pub(crate) fn parse_io_stat(io_stat: &str) -> Result<(u64, u64)> {
+    let mut totals = (0, 0);
+    for field in io_stat.split_whitespace() {
+        if let Some(value) = field.strip_prefix("rbytes=") {
+            totals.0 += value.parse::<u64>()?;
+        } else if let Some(value) = field.strip_prefix("wbytes=") {
+            totals.1 += value.parse::<u64>()?;
+        }
+    }
+    Ok(totals)
+}
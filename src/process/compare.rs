@@ -0,0 +1,196 @@
+use std::fmt::Display;
+
+use crate::output::console::{Colour, paint};
+
+use super::trace::{Column, UsageTrace};
+
+/// How a figure moved between two runs.  Every figure compared is a cost, so lower is better.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Verdict {
+    Better,
+    Worse,
+    /// Changed by less than the threshold
+    Same,
+    /// Missing from one or both runs
+    Unknown,
+}
+
+impl Display for Verdict {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        let text = match self {
+            Verdict::Better => "better",
+            Verdict::Worse => "worse",
+            Verdict::Same => "same",
+            Verdict::Unknown => "NA",
+        };
+        f.write_str(text)
+    }
+}
+
+/// One figure from each of two runs
+#[derive(Debug, Clone, PartialEq)]
+pub struct MetricComparison {
+    pub name: &'static str,
+    pub old: Option<f64>,
+    pub new: Option<f64>,
+    pub verdict: Verdict,
+}
+
+impl MetricComparison {
+    /**
+     * `threshold_percent` is the smallest relative change which isn't [`Verdict::Same`]
+     */
+    fn new(name: &'static str, old: Option<f64>, new: Option<f64>, threshold_percent: f64) -> Self {
+        let verdict = match (old, new) {
+            (Some(old), Some(new)) => {
+                let change = match relative_change(old, new) {
+                    Some(change) => change,
+                    None if new == old => 0.0,
+                    None => f64::INFINITY,
+                };
+                if change.abs() < threshold_percent {
+                    Verdict::Same
+                } else if change < 0.0 {
+                    Verdict::Better
+                } else {
+                    Verdict::Worse
+                }
+            }
+            _ => Verdict::Unknown,
+        };
+        Self {
+            name,
+            old,
+            new,
+            verdict,
+        }
+    }
+
+    pub fn delta(&self) -> Option<f64> {
+        Some(self.new? - self.old?)
+    }
+
+    /**
+     * Change as a percentage of the old figure, `None` if that was zero
+     */
+    pub fn change_percent(&self) -> Option<f64> {
+        relative_change(self.old?, self.new?)
+    }
+}
+
+/// Headline figures of two runs side by side, e.g. before and after an optimisation
+#[derive(Debug, Clone, PartialEq)]
+pub struct TraceComparison {
+    pub metrics: Vec<MetricComparison>,
+}
+
+impl TraceComparison {
+    pub fn new(old: &UsageTrace, new: &UsageTrace, threshold_percent: f64) -> Self {
+        let columns: [(_, _, Column); 3] = [
+            ("peak CPU (%)", "mean CPU (%)", |row| row.cpu_percent),
+            ("peak RAM (MiB)", "mean RAM (MiB)", |row| row.ram_mb),
+            ("peak GPU (%)", "mean GPU (%)", |row| row.gpu_percent),
+        ];
+
+        let mut metrics = vec![MetricComparison::new(
+            "wall clock (s)",
+            old.duration_seconds(),
+            new.duration_seconds(),
+            threshold_percent,
+        )];
+        for (peak_name, mean_name, column) in columns {
+            metrics.push(MetricComparison::new(
+                peak_name,
+                old.peak(column),
+                new.peak(column),
+                threshold_percent,
+            ));
+            metrics.push(MetricComparison::new(
+                mean_name,
+                old.mean(column),
+                new.mean(column),
+                threshold_percent,
+            ));
+        }
+        Self { metrics }
+    }
+}
+
+impl Display for TraceComparison {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        f.write_str(&self.render(false))
+    }
+}
+
+impl TraceComparison {
+    /**
+     * An aligned table of the figures, with better verdicts in green and worse in red if
+     * `color` is set
+     */
+    pub fn render(&self, color: bool) -> String {
+        let format = |value: Option<f64>, sign: bool| match value {
+            Some(value) if sign => format!("{:+.1}", value),
+            Some(value) => format!("{:.1}", value),
+            None => "NA".to_string(),
+        };
+        let rows: Vec<[String; 6]> = self
+            .metrics
+            .iter()
+            .map(|metric| {
+                [
+                    metric.name.to_string(),
+                    format(metric.old, false),
+                    format(metric.new, false),
+                    format(metric.delta(), true),
+                    match metric.change_percent() {
+                        Some(change) => format!("{:+.1}%", change),
+                        None => "NA".to_string(),
+                    },
+                    metric.verdict.to_string(),
+                ]
+            })
+            .collect();
+
+        let header = ["metric", "old", "new", "delta", "change", "verdict"].map(String::from);
+        let widths: Vec<usize> = (0..header.len())
+            .map(|i| {
+                rows.iter()
+                    .chain([&header])
+                    .map(|row| row[i].len())
+                    .max()
+                    .unwrap_or(0)
+            })
+            .collect();
+        let verdicts = std::iter::once(None).chain(self.metrics.iter().map(|m| Some(m.verdict)));
+        let mut table = String::new();
+        for (row, verdict) in [&header].into_iter().chain(&rows).zip(verdicts) {
+            let line = row
+                .iter()
+                .zip(&widths)
+                .enumerate()
+                .map(|(i, (field, &width))| match i {
+                    0 => format!("{:<width$}", field),
+                    // The verdict is last, and coloured after padding as escape codes take no
+                    // room on screen
+                    _ if i == header.len() - 1 => {
+                        let field = format!("{:>width$}", field);
+                        match verdict {
+                            Some(Verdict::Better) => paint(&field, Colour::Green, color),
+                            Some(Verdict::Worse) => paint(&field, Colour::Red, color),
+                            _ => field,
+                        }
+                    }
+                    _ => format!("{:>width$}", field),
+                })
+                .collect::<Vec<_>>()
+                .join("  ");
+            table.push_str(line.trim_end());
+            table.push('\n');
+        }
+        table
+    }
+}
+
+fn relative_change(old: f64, new: f64) -> Option<f64> {
+    (old != 0.0).then(|| (new - old) / old.abs() * 100.0)
+}
@@ -0,0 +1,230 @@
+use std::{
+    collections::VecDeque,
+    sync::{Arc, Mutex},
+    time::Duration,
+};
+
+use color_eyre::Result;
+use ratatui::{
+    DefaultTerminal, Frame,
+    crossterm::event::{self, Event, KeyCode, KeyEventKind, KeyModifiers},
+    layout::{Constraint, Layout},
+    style::{Color, Style},
+    text::Line,
+    widgets::{Block, Borders, Gauge, Paragraph, Sparkline},
+};
+
+use crate::output::{console, units::format_size};
+
+/// Number of samples kept for the sparklines
+const HISTORY: usize = 300;
+/// Number of output lines kept from the monitored command
+const LOG_LINES: usize = 200;
+
+/// Recent output lines from the monitored command, shared with the threads reading it
+#[derive(Clone, Default)]
+pub struct LogBuffer {
+    lines: Arc<Mutex<VecDeque<String>>>,
+}
+
+impl LogBuffer {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    pub fn push(&self, line: String) {
+        let mut lines = self.lines.lock().unwrap();
+        if lines.len() == LOG_LINES {
+            lines.pop_front();
+        }
+        lines.push_back(line);
+    }
+
+    fn last(&self, n: usize) -> Vec<String> {
+        let lines = self.lines.lock().unwrap();
+        lines
+            .iter()
+            .skip(lines.len().saturating_sub(n))
+            .cloned()
+            .collect()
+    }
+}
+
+/// What the user asked for while the dashboard was waiting
+#[derive(Debug, PartialEq, Eq)]
+pub enum DashboardAction {
+    Continue,
+    Quit,
+}
+
+/// Live terminal view of the monitored tree's usage
+pub struct Dashboard {
+    terminal: DefaultTerminal,
+    title: String,
+    logs: LogBuffer,
+    cpu: VecDeque<u64>,
+    gpu: VecDeque<u64>,
+    ram_percent: f64,
+    ram_bytes: u64,
+    latest_cpu: f32,
+    latest_gpu: Option<u32>,
+}
+
+impl Dashboard {
+    pub fn new(title: String, logs: LogBuffer) -> Self {
+        Self {
+            terminal: ratatui::init(),
+            title,
+            logs,
+            cpu: VecDeque::new(),
+            gpu: VecDeque::new(),
+            ram_percent: 0.0,
+            ram_bytes: 0,
+            latest_cpu: 0.0,
+            latest_gpu: None,
+        }
+    }
+
+    pub fn update(
+        &mut self,
+        cpu_percent: f32,
+        ram_bytes: u64,
+        ram_percent: f64,
+        gpu_percent: Option<u32>,
+    ) -> Result<()> {
+        push_bounded(&mut self.cpu, cpu_percent.max(0.0).round() as u64);
+        if let Some(gpu_percent) = gpu_percent {
+            push_bounded(&mut self.gpu, gpu_percent as u64);
+        }
+        self.latest_cpu = cpu_percent;
+        self.latest_gpu = gpu_percent;
+        self.ram_bytes = ram_bytes;
+        self.ram_percent = ram_percent;
+
+        self.draw()
+    }
+
+    /**
+     * Wait for up to `timeout`, redrawing on resize.  Returns early if the user presses
+     * `q` or Ctrl-C (which doesn't raise SIGINT while the terminal is in raw mode).
+     */
+    pub fn wait(&mut self, timeout: Duration) -> Result<DashboardAction> {
+        let deadline = std::time::Instant::now() + timeout;
+        loop {
+            let remaining = deadline.saturating_duration_since(std::time::Instant::now());
+            if remaining.is_zero() || !event::poll(remaining)? {
+                return Ok(DashboardAction::Continue);
+            }
+            match event::read()? {
+                Event::Key(key) if key.kind == KeyEventKind::Press => {
+                    let ctrl_c = key.code == KeyCode::Char('c')
+                        && key.modifiers.contains(KeyModifiers::CONTROL);
+                    if ctrl_c || key.code == KeyCode::Char('q') {
+                        return Ok(DashboardAction::Quit);
+                    }
+                }
+                Event::Resize(_, _) => self.draw()?,
+                _ => {}
+            }
+        }
+    }
+
+    fn draw(&mut self) -> Result<()> {
+        let view = View {
+            title: &self.title,
+            cpu: self.cpu.iter().copied().collect(),
+            gpu: self.gpu.iter().copied().collect(),
+            latest_cpu: self.latest_cpu,
+            latest_gpu: self.latest_gpu,
+            ram_bytes: self.ram_bytes,
+            ram_percent: self.ram_percent,
+            logs: &self.logs,
+        };
+        self.terminal.draw(|frame| view.render(frame))?;
+        Ok(())
+    }
+}
+
+impl Drop for Dashboard {
+    fn drop(&mut self) {
+        ratatui::restore();
+    }
+}
+
+struct View<'a> {
+    title: &'a str,
+    cpu: Vec<u64>,
+    gpu: Vec<u64>,
+    latest_cpu: f32,
+    latest_gpu: Option<u32>,
+    ram_bytes: u64,
+    ram_percent: f64,
+    logs: &'a LogBuffer,
+}
+
+impl View<'_> {
+    fn render(&self, frame: &mut Frame) {
+        let style = |colour| match console::color_allowed() {
+            true => Style::default().fg(colour),
+            false => Style::default(),
+        };
+        let outer = Block::default()
+            .title(Line::from(format!(" {} (q to quit) ", self.title)))
+            .borders(Borders::ALL);
+        let inner = outer.inner(frame.area());
+        frame.render_widget(outer, frame.area());
+
+        let [cpu_area, ram_area, gpu_area, log_area] = Layout::vertical([
+            Constraint::Length(6),
+            Constraint::Length(3),
+            Constraint::Length(6),
+            Constraint::Min(3),
+        ])
+        .areas(inner);
+
+        let cpu = Sparkline::default()
+            .block(Block::bordered().title(format!("CPU {:.1}%", self.latest_cpu)))
+            .data(&self.cpu)
+            .style(style(Color::Cyan));
+        frame.render_widget(cpu, cpu_area);
+
+        let ram = Gauge::default()
+            .block(Block::bordered().title("RAM"))
+            .gauge_style(style(Color::Yellow))
+            .ratio((self.ram_percent / 100.0).clamp(0.0, 1.0))
+            .label(format!(
+                "{} ({:.1}%)",
+                format_size(self.ram_bytes),
+                self.ram_percent
+            ));
+        frame.render_widget(ram, ram_area);
+
+        let gpu_title = match self.latest_gpu {
+            Some(gpu) => format!("GPU {}%", gpu),
+            None => "GPU NA".to_string(),
+        };
+        let gpu = Sparkline::default()
+            .block(Block::bordered().title(gpu_title))
+            .data(&self.gpu)
+            .max(100)
+            .style(style(Color::Green));
+        frame.render_widget(gpu, gpu_area);
+
+        let visible = log_area.height.saturating_sub(2) as usize;
+        let lines: Vec<Line> = self
+            .logs
+            .last(visible)
+            .into_iter()
+            .map(Line::from)
+            .collect();
+        let logs = Paragraph::new(lines).block(Block::bordered().title("Output"));
+        frame.render_widget(logs, log_area);
+    }
+}
+
+fn push_bounded(values: &mut VecDeque<u64>, value: u64) {
+    if values.len() == HISTORY {
+        values.pop_front();
+    }
+    values.push_back(value);
+}
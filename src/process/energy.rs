@@ -0,0 +1,124 @@
+use std::{
+    fs,
+    path::{Path, PathBuf},
+};
+
+use color_eyre::{
+    Result,
+    eyre::{Context, bail},
+};
+
+static POWERCAP_ROOT: &str = "/sys/class/powercap";
+
+/// Energy counters from Intel/AMD RAPL (Running Average Power Limit), via the Linux powercap
+/// interface.  These cover the whole machine, not just the monitored processes.
+pub struct Rapl {
+    zones: Vec<RaplZone>,
+}
+
+struct RaplZone {
+    is_dram: bool,
+    energy_path: PathBuf,
+    /// The counter wraps back to zero beyond this
+    max_energy_uj: u64,
+    last_energy_uj: u64,
+}
+
+/// Energy used since the previous reading
+#[derive(Debug, Clone, Copy, Default, PartialEq)]
+pub struct RaplEnergy {
+    pub package_joules: f64,
+    /// `None` if the CPU doesn't report DRAM separately (e.g. most AMD parts)
+    pub dram_joules: Option<f64>,
+}
+
+impl Rapl {
+    pub fn new() -> Result<Self> {
+        let root = Path::new(POWERCAP_ROOT);
+        if !root.exists() {
+            bail!("No RAPL energy counters: {} doesn't exist", POWERCAP_ROOT)
+        }
+
+        let mut zones = Vec::new();
+        for entry in fs::read_dir(root)?.flatten() {
+            let path = entry.path();
+            // Sub-zones are also listed at the top level, e.g. `intel-rapl:0:1`
+            let is_rapl = entry
+                .file_name()
+                .to_str()
+                .map(|name| name.starts_with("intel-rapl:"))
+                .unwrap_or(false);
+            if !is_rapl {
+                continue;
+            }
+
+            let name = read_trimmed(&path.join("name"))?;
+            // Package zones already include the cores and integrated graphics, and `psys`
+            // covers the whole platform, so only packages and DRAM are summed
+            let is_dram = name == "dram";
+            if !is_dram && !name.starts_with("package") {
+                log::debug!("Skipping RAPL zone {} ({})", path.display(), name);
+                continue;
+            }
+
+            let energy_path = path.join("energy_uj");
+            let last_energy_uj = read_u64(&energy_path)
+                .wrap_err("RAPL counters are usually only readable by root")?;
+            zones.push(RaplZone {
+                is_dram,
+                energy_path,
+                max_energy_uj: read_u64(&path.join("max_energy_range_uj"))?,
+                last_energy_uj,
+            });
+        }
+
+        if zones.is_empty() {
+            bail!("No RAPL package zones found in {}", POWERCAP_ROOT)
+        }
+        Ok(Self { zones })
+    }
+
+    /**
+     * Energy used since the last call (or since the counters were opened)
+     */
+    pub fn energy_since_last(&mut self) -> Result<RaplEnergy> {
+        let mut energy = RaplEnergy::default();
+        for zone in self.zones.iter_mut() {
+            let energy_uj = read_u64(&zone.energy_path)?;
+            let joules =
+                counter_delta(zone.last_energy_uj, energy_uj, zone.max_energy_uj) as f64 / 1e6;
+            zone.last_energy_uj = energy_uj;
+
+            if zone.is_dram {
+                *energy.dram_joules.get_or_insert(0.0) += joules;
+            } else {
+                energy.package_joules += joules;
+            }
+        }
+        Ok(energy)
+    }
+}
+
+/**
+ * Increase in a counter which wraps to zero after `max`
+ */
+pub(crate) fn counter_delta(last: u64, now: u64, max: u64) -> u64 {
+    if now >= last {
+        now - last
+    } else {
+        max.saturating_sub(last) + now
+    }
+}
+
+fn read_trimmed(path: &Path) -> Result<String> {
+    fs::read_to_string(path)
+        .map(|s| s.trim().to_string())
+        .wrap_err_with(|| format!("Failed to read {}", path.display()))
+}
+
+fn read_u64(path: &Path) -> Result<u64> {
+    let value = read_trimmed(path)?;
+    value
+        .parse()
+        .wrap_err_with(|| format!("Bad value '{}' in {}", value, path.display()))
+}
@@ -1,121 +1,959 @@
-use std::{process::Command, str::from_utf8};
-
-use color_eyre::{
-    Result,
-    eyre::{Context, bail},
+use std::{
+    collections::{HashMap, HashSet},
+    fmt::Display,
+    ops::AddAssign,
 };
+#[cfg(feature = "nvml")]
+use std::{process::Command, ptr, str::from_utf8};
+
+use color_eyre::Result;
+#[cfg(feature = "nvml")]
+use color_eyre::eyre::{Context, bail};
+#[cfg(feature = "nvml")]
 use nvml_wrapper::{
-    Device, Nvml, error::NvmlError, struct_wrappers::device::ProcessUtilizationSample,
+    Device, Nvml, cuda_driver_version_major, cuda_driver_version_minor,
+    enum_wrappers::device::{PcieUtilCounter, TemperatureSensor},
+    enums::device::UsedGpuMemory,
+    error::{NvmlError, nvml_try},
+    struct_wrappers::device::ProcessUtilizationSample,
 };
+#[cfg(feature = "nvml")]
+use nvml_wrapper_sys::bindings::{NVML_DEVICE_MIG_ENABLE, NvmlLib};
 use sysinfo::Pid;
 
+use crate::process::metadata::GpuDevice;
+#[cfg(feature = "nvml")]
 use crate::process::system::System;
 
-pub struct Gpu<'a>{
-    devices: Vec<Device<'a>>,
-    last_sample_time: Option<u64>,
-}
-impl<'a> Gpu<'a> {
-    pub fn new(api: &'a GpuApi) -> Result<Self> {
-        let num_devices = api.nvml.device_count()?;
-        let devices = (0..num_devices)
-            .map(|idx| {
-                api.nvml
-                    .device_by_index(idx)
-                    .wrap_err("Device initialisation failure")
+#[cfg(all(feature = "nvml", windows))]
+const NVML_LIB: &str = "nvml.dll";
+#[cfg(all(feature = "nvml", not(windows)))]
+const NVML_LIB: &str = "libnvidia-ml.so.1";
+
+/// NVML and the devices being sampled.  Remembers the timestamp of the latest process
+/// sample seen on each device, so each query only covers what happened since the last.
+///
+/// Devices partitioned with MIG only report processes through their MIG instances, so
+/// per-process queries cover each instance of those devices in place of the device itself.
+#[cfg(feature = "nvml")]
+pub struct GpuSession {
+    nvml: Nvml,
+    indices: Vec<u32>,
+    /// MIG instances of each device, empty where MIG is disabled
+    mig_instances: Vec<Vec<MigInstance>>,
+    /// nvml-wrapper doesn't expose MIG device handles, so they come from the raw library,
+    /// which is only loaded if a device has MIG enabled
+    mig_lib: Option<NvmlLib>,
+    /// For each process device, in the order of [`Self::process_devices`]
+    last_seen: Vec<Option<u64>>,
+}
+
+/// A MIG slice of a device, shown as `DEVICE:GPU_INSTANCE.COMPUTE_INSTANCE`, e.g. `0:1.0`
+#[derive(Debug, Clone, Copy, PartialEq, Eq, PartialOrd, Ord, Hash)]
+pub struct MigInstance {
+    /// NVML index of the parent device
+    pub device_index: u32,
+    pub gpu_instance_id: u32,
+    pub compute_instance_id: u32,
+    /// Position among the parent's MIG devices, to look its handle up by
+    pub(crate) mig_index: u32,
+}
+
+impl Display for MigInstance {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        write!(
+            f,
+            "{}:{}.{}",
+            self.device_index, self.gpu_instance_id, self.compute_instance_id
+        )
+    }
+}
+
+/// Utilisation and memory of a single device, or of one of its MIG instances
+#[derive(Debug, Clone, PartialEq)]
+pub struct DeviceUsage {
+    pub index: u32,
+    pub mig_instance: Option<MigInstance>,
+    pub gpu_percent: u32,
+    pub memory_bytes: u64,
+}
+
+/// How busy each of a device's engines was, as a % of the sample period.  Summed over
+/// processes or devices, as `gpu_percent` is.
+#[derive(Debug, Clone, Copy, Default, PartialEq)]
+pub struct GpuEngines {
+    /// Streaming multiprocessors, i.e. 3D and compute work
+    pub sm_percent: u32,
+    /// Memory controller, i.e. reading or writing device memory
+    pub memory_percent: u32,
+    /// Video encoder
+    pub encoder_percent: u32,
+    /// Video decoder
+    pub decoder_percent: u32,
+}
+
+impl AddAssign for GpuEngines {
+    fn add_assign(&mut self, other: Self) {
+        self.sm_percent += other.sm_percent;
+        self.memory_percent += other.memory_percent;
+        self.encoder_percent += other.encoder_percent;
+        self.decoder_percent += other.decoder_percent;
+    }
+}
+
+/// Physical state of a single device
+#[derive(Debug, Clone, PartialEq)]
+pub struct DeviceThermals {
+    pub temperature_c: u32,
+    /// Not all devices report power draw
+    pub power_watts: Option<f64>,
+    /// Passively cooled devices have no fan to report
+    pub fan_percent: Option<u32>,
+}
+
+/// Data movement on and off a single device
+#[derive(Debug, Clone, PartialEq)]
+pub struct DeviceBandwidth {
+    /// PCIe throughput from the device to the host, where reported
+    pub pcie_tx_bytes_per_sec: Option<u64>,
+    /// PCIe throughput from the host to the device, where reported
+    pub pcie_rx_bytes_per_sec: Option<u64>,
+    /// % of the sample period the memory controller was busy
+    pub memory_percent: u32,
+}
+
+/// A source of GPU measurements, so GPUs other than NVIDIA's (or a fake one, in tests) can
+/// be measured by a [`Monitor`](crate::process::monitor::Monitor).  Per-process results are
+/// kept separate for each of [`GpuBackend::process_devices`], in that order, and whole-device
+/// results follow the order of [`GpuBackend::device_names`].
+pub trait GpuBackend {
+    /// Device index, and MIG instance if partitioned, of each device processes are reported on
+    fn process_devices(&self) -> Vec<(u32, Option<MigInstance>)>;
+
+    /// Whether any device is partitioned with MIG
+    fn has_mig(&self) -> bool {
+        false
+    }
+
+    /// Model name of each device
+    fn device_names(&self) -> Result<Vec<String>>;
+
+    /**
+     * Name, total memory and UUID of each device.  By default just the names, for GPUs
+     * which don't report the rest.
+     */
+    fn device_details(&self) -> Result<Vec<GpuDevice>> {
+        let mut indices: Vec<u32> = self.process_devices().iter().map(|&(i, _)| i).collect();
+        indices.dedup();
+        Ok(indices
+            .into_iter()
+            .zip(self.device_names()?)
+            .map(|(index, name)| GpuDevice {
+                index,
+                name,
+                memory_bytes: None,
+                uuid: None,
+            })
+            .collect())
+    }
+
+    /// Version of the GPU driver, if known
+    fn driver_version(&self) -> Option<String> {
+        None
+    }
+
+    /// Newest CUDA version the driver supports, e.g. `12.4`, if known
+    fn cuda_version(&self) -> Option<String> {
+        None
+    }
+
+    /// Overall utilisation and memory used on each device, whoever is using it
+    fn device_usage(&self) -> Result<Vec<DeviceUsage>>;
+
+    /**
+     * Overall engine utilisation of each device.  By default everything counts as SM
+     * utilisation, for GPUs which don't tell their engines apart.
+     */
+    fn device_engines(&self) -> Result<Vec<GpuEngines>> {
+        let usage = self.device_usage()?;
+        Ok(usage
+            .iter()
+            .map(|device| GpuEngines {
+                sm_percent: device.gpu_percent,
+                ..Default::default()
             })
-            .collect::<Result<Vec<Device<'a>>>>()?;
+            .collect())
+    }
 
-        log::debug!("Found devices: {:?}", &devices);
+    /// Temperature, power draw and fan speed of each device
+    fn device_thermals(&self) -> Result<Vec<DeviceThermals>>;
 
-        Ok(Gpu{
-            devices,
-            last_sample_time: None,
-        })
+    /// PCIe throughput and memory controller utilisation of each device, if available
+    fn device_bandwidth(&self) -> Result<Option<Vec<DeviceBandwidth>>> {
+        Ok(None)
     }
+
+    /**
+     * Engine utilisation of each process in `tree`, as found by
+     * [`System::get_pid_trees`](crate::process::system::System::get_pid_trees), since the
+     * last call, for processes with any samples
+     */
+    fn pid_trees_engines_by_device(
+        &mut self,
+        tree: &HashSet<Pid>,
+    ) -> Result<Vec<HashMap<Pid, GpuEngines>>>;
+
+    /**
+     * GPU memory of each process in `tree` which is on a device, even if it's idle
+     */
+    fn pid_trees_memory_by_device(&self, tree: &HashSet<Pid>) -> Result<Vec<HashMap<Pid, u64>>>;
 }
 
-pub struct GpuApi {
-    nvml: Nvml,
+/// So a backend chosen at runtime can be passed where one is expected
+impl<T: GpuBackend + ?Sized> GpuBackend for Box<T> {
+    fn process_devices(&self) -> Vec<(u32, Option<MigInstance>)> {
+        (**self).process_devices()
+    }
+
+    fn has_mig(&self) -> bool {
+        (**self).has_mig()
+    }
+
+    fn device_names(&self) -> Result<Vec<String>> {
+        (**self).device_names()
+    }
+
+    fn device_details(&self) -> Result<Vec<GpuDevice>> {
+        (**self).device_details()
+    }
+
+    fn driver_version(&self) -> Option<String> {
+        (**self).driver_version()
+    }
+
+    fn cuda_version(&self) -> Option<String> {
+        (**self).cuda_version()
+    }
+
+    fn device_usage(&self) -> Result<Vec<DeviceUsage>> {
+        (**self).device_usage()
+    }
+
+    fn device_engines(&self) -> Result<Vec<GpuEngines>> {
+        (**self).device_engines()
+    }
+
+    fn device_thermals(&self) -> Result<Vec<DeviceThermals>> {
+        (**self).device_thermals()
+    }
+
+    fn device_bandwidth(&self) -> Result<Option<Vec<DeviceBandwidth>>> {
+        (**self).device_bandwidth()
+    }
+
+    fn pid_trees_engines_by_device(
+        &mut self,
+        tree: &HashSet<Pid>,
+    ) -> Result<Vec<HashMap<Pid, GpuEngines>>> {
+        (**self).pid_trees_engines_by_device(tree)
+    }
+
+    fn pid_trees_memory_by_device(&self, tree: &HashSet<Pid>) -> Result<Vec<HashMap<Pid, u64>>> {
+        (**self).pid_trees_memory_by_device(tree)
+    }
 }
 
-impl GpuApi {
+/// No GPUs at all, for where a [`GpuBackend`] is needed but there's nothing to measure
+pub struct NoGpu;
+
+impl GpuBackend for NoGpu {
+    fn process_devices(&self) -> Vec<(u32, Option<MigInstance>)> {
+        Vec::new()
+    }
+
+    fn device_names(&self) -> Result<Vec<String>> {
+        Ok(Vec::new())
+    }
+
+    fn device_usage(&self) -> Result<Vec<DeviceUsage>> {
+        Ok(Vec::new())
+    }
+
+    fn device_thermals(&self) -> Result<Vec<DeviceThermals>> {
+        Ok(Vec::new())
+    }
+
+    fn pid_trees_engines_by_device(
+        &mut self,
+        _tree: &HashSet<Pid>,
+    ) -> Result<Vec<HashMap<Pid, GpuEngines>>> {
+        Ok(Vec::new())
+    }
+
+    fn pid_trees_memory_by_device(&self, _tree: &HashSet<Pid>) -> Result<Vec<HashMap<Pid, u64>>> {
+        Ok(Vec::new())
+    }
+}
+
+#[cfg(feature = "nvml")]
+impl GpuSession {
+    /**
+     * Sample every device NVML can see
+     */
     pub fn new() -> Result<Self> {
-        let bytes = Command::new("lspci")
-            .output()
-            .wrap_err("Failed to run `lspci`")?
-            .stdout;
-        let stdout = from_utf8(&bytes)?;
-        if stdout.contains("NVIDIA") {
-            log::debug!("`lspci`, confirms existence of a GPU");
+        let nvml = init_nvml(true)?;
+        let num_devices = nvml.device_count()?;
+        Self::open(nvml, (0..num_devices).collect())
+    }
+
+    /**
+     * Only open the devices with the given NVML indices, leaving the others untouched
+     */
+    pub fn with_indices(indices: &[u32]) -> Result<Self> {
+        Self::open(init_nvml(true)?, indices.to_vec())
+    }
+
+    /**
+     * As [`GpuSession::new`], or [`GpuSession::with_indices`] if `indices` isn't empty, but
+     * `None` if NVML can't be initialised or finds no GPUs, e.g. on a node without NVIDIA
+     * drivers.  Other failures, such as a bad index, are still errors.
+     */
+    pub fn detect(indices: &[u32]) -> Result<Option<Self>> {
+        // Running `lspci` on every node without NVIDIA hardware would be wasted, unless asked
+        let nvml = match init_nvml(log::log_enabled!(log::Level::Debug)) {
+            Ok(nvml) => nvml,
+            Err(e) => {
+                log::info!("Not monitoring NVIDIA GPUs: {:#}", e);
+                return Ok(None);
+            }
+        };
+        let indices = match indices {
+            [] => (0..nvml.device_count()?).collect(),
+            indices => indices.to_vec(),
+        };
+        Self::open(nvml, indices).map(Some)
+    }
+
+    fn open(nvml: Nvml, indices: Vec<u32>) -> Result<Self> {
+        let num_devices = nvml.device_count()?;
+        if let Some(bad) = indices.iter().find(|&&idx| idx >= num_devices) {
+            bail!("GPU index {} requested, but only {} device(s) found", bad, num_devices)
+        }
+
+        let devices = open_devices(&nvml, &indices)?;
+        log::debug!("Found devices: {:?}", &devices);
+
+        let mut mig_enabled = Vec::new();
+        for device in &devices {
+            let mode = optional(device.mig_mode())?;
+            mig_enabled.push(mode.is_some_and(|mode| mode.current == NVML_DEVICE_MIG_ENABLE));
+        }
+        let mig_lib = if mig_enabled.contains(&true) {
+            // Already loaded by nvml-wrapper, so this shares its initialised state
+            Some(unsafe { NvmlLib::new(NVML_LIB) }.wrap_err("Failed to load NVML for MIG")?)
         } else {
-            bail!("`lspci` did not confirm the presence of a GPU")
+            None
+        };
+
+        let mut mig_instances = Vec::new();
+        for ((device, &index), enabled) in devices.iter().zip(&indices).zip(mig_enabled) {
+            mig_instances.push(match &mig_lib {
+                Some(lib) if enabled => find_mig_instances(lib, device, index)?,
+                _ => Vec::new(),
+            });
         }
+        log::debug!("MIG instances: {:?}", &mig_instances);
+        drop(devices);
 
-        Ok(Self {
-            nvml: Nvml::init()?,
-        })
+        let mut session = GpuSession {
+            nvml,
+            indices,
+            mig_instances,
+            mig_lib,
+            last_seen: Vec::new(),
+        };
+        session.last_seen = vec![None; session.process_devices().len()];
+        Ok(session)
     }
 
-    fn get_all_utilisation(
-        &self,
-        gpu: &Gpu,
-    ) -> Result<Vec<ProcessUtilizationSample>> {
-        gpu.devices
+    /**
+     * NVML index of each device, in the order per-device results are returned
+     */
+    pub fn indices(&self) -> &[u32] {
+        &self.indices
+    }
+
+    /**
+     * Whether any device is partitioned with MIG
+     */
+    pub fn has_mig(&self) -> bool {
+        self.mig_instances.iter().any(|instances| !instances.is_empty())
+    }
+
+    /**
+     * NVML index and MIG instance of each device processes are reported on, in the order
+     * per-device process results are returned.  This is each MIG instance of a device
+     * with MIG enabled, otherwise the device itself.
+     */
+    pub fn process_devices(&self) -> Vec<(u32, Option<MigInstance>)> {
+        self.indices
+            .iter()
+            .zip(&self.mig_instances)
+            .flat_map(|(&index, instances)| {
+                if instances.is_empty() {
+                    vec![(index, None)]
+                } else {
+                    instances.iter().map(|&i| (index, Some(i))).collect()
+                }
+            })
+            .collect()
+    }
+
+    /**
+     * Process samples from each process device since the last call
+     */
+    fn get_all_utilisation(&mut self) -> Result<Vec<Vec<ProcessUtilizationSample>>> {
+        let devices = open_process_devices(
+            &self.nvml,
+            &self.indices,
+            &self.mig_instances,
+            self.mig_lib.as_ref(),
+        )?;
+        let mut all_utilisation = Vec::new();
+        for (device, last_seen) in devices.iter().zip(self.last_seen.iter_mut()) {
+            let samples = match device.process_utilization_stats(*last_seen) {
+                Ok(samples) => samples,
+                // It's ok if we don't find the PID, just assume zero usage
+                Err(NvmlError::NotFound) => Vec::new(),
+                // Drivers may not report process utilisation on MIG instances
+                Err(NvmlError::NotSupported) if self.mig_lib.is_some() => Vec::new(),
+                // But if we get another error, that's serious enough to propagate
+                Err(e) => Err(e).wrap_err("Unexpected NvmlError when querying usage")?,
+            };
+
+            // Each device has its own clock, so keep track of where we got to on each
+            *last_seen = advance_last_seen(*last_seen, samples.iter().map(|s| s.timestamp));
+            all_utilisation.push(samples);
+        }
+        Ok(all_utilisation)
+    }
+
+    /**
+     * Overall utilisation of each device, regardless of which processes are using it
+     */
+    pub fn get_device_utilisation(&self) -> Result<Vec<u32>> {
+        open_devices(&self.nvml, &self.indices)?
+            .iter()
+            .map(|d| {
+                let rates = d
+                    .utilization_rates()
+                    .wrap_err("Unexpected NvmlError when querying device utilisation")?;
+                log::info!("{:?} -> {:?}", d.index(), rates);
+                Ok(rates.gpu)
+            })
+            .collect()
+    }
+
+    /**
+     * Overall utilisation and memory used on each device, regardless of which processes are using it
+     */
+    pub fn get_device_usage(&self) -> Result<Vec<DeviceUsage>> {
+        open_devices(&self.nvml, &self.indices)?
             .iter()
-            .map(|d|
-                d.process_utilization_stats(gpu.last_sample_time).or_else(|e|{
-                    match e {
-                        // It's ok if we don't find the PID, just assume zero usage
-                        NvmlError::NotFound => Ok(Vec::new()), 
-                        // But if we get another error, that's serious enough to propagate
-                        _ => Err(e).wrap_err_with(||"Unexpected NvmlError when querying usage")
-                    }
+            .zip(&self.indices)
+            .map(|(d, &index)| {
+                let rates = d
+                    .utilization_rates()
+                    .wrap_err("Unexpected NvmlError when querying device utilisation")?;
+                let memory = d
+                    .memory_info()
+                    .wrap_err("Unexpected NvmlError when querying device memory")?;
+                log::info!("{:?} -> {:?}, {:?}", index, rates, memory);
+                Ok(DeviceUsage {
+                    index,
+                    mig_instance: None,
+                    gpu_percent: rates.gpu,
+                    memory_bytes: memory.used,
                 })
-            )
-            .try_fold(
-                Vec::<ProcessUtilizationSample>::new(),
-                |mut acc, res_samples| {
-                    acc.extend(res_samples?);
-                    Result::Ok(acc)
-                },
-            )
+            })
+            .collect()
     }
 
+    /**
+     * Overall engine utilisation of each device, regardless of which processes are using it
+     */
+    pub fn get_device_engines(&self) -> Result<Vec<GpuEngines>> {
+        open_devices(&self.nvml, &self.indices)?
+            .iter()
+            .map(|d| {
+                let rates = d
+                    .utilization_rates()
+                    .wrap_err("Unexpected NvmlError when querying device utilisation")?;
+                // Devices without video engines count as idle
+                let encoder = optional(d.encoder_utilization())?;
+                let decoder = optional(d.decoder_utilization())?;
+                let engines = GpuEngines {
+                    sm_percent: rates.gpu,
+                    memory_percent: rates.memory,
+                    encoder_percent: encoder.map_or(0, |e| e.utilization),
+                    decoder_percent: decoder.map_or(0, |d| d.utilization),
+                };
+                log::info!("{:?} -> {:?}", d.index(), engines);
+                Ok(engines)
+            })
+            .collect()
+    }
 
-    pub fn get_pid_utilisation(
-        &self,
-        gpu: &mut Gpu,
-        pid: Pid,
-        system: &mut System,
-    ) -> Result<u32> {
-        let children = system.get_pid_tree(pid, false);
-        log::trace!("Process {} has Children {:?}", pid, children);
+    /**
+     * PCIe throughput and memory controller utilisation of each device.  NVML measures
+     * PCIe throughput over a short window (about 20ms) when asked, rather than since the
+     * last call.
+     */
+    pub fn get_device_bandwidth(&self) -> Result<Vec<DeviceBandwidth>> {
+        open_devices(&self.nvml, &self.indices)?
+            .iter()
+            .map(|d| {
+                let rates = d
+                    .utilization_rates()
+                    .wrap_err("Unexpected NvmlError when querying device utilisation")?;
+                let bandwidth = DeviceBandwidth {
+                    pcie_tx_bytes_per_sec: optional(d.pcie_throughput(PcieUtilCounter::Send))?
+                        .map(|kb| kb as u64 * 1024),
+                    pcie_rx_bytes_per_sec: optional(d.pcie_throughput(PcieUtilCounter::Receive))?
+                        .map(|kb| kb as u64 * 1024),
+                    memory_percent: rates.memory,
+                };
+                log::info!("{:?} -> {:?}", d.index(), bandwidth);
+                Ok(bandwidth)
+            })
+            .collect()
+    }
 
-        let all_utilisation = self.get_all_utilisation(gpu)?;
+    /**
+     * Model name of each device
+     */
+    pub fn get_device_names(&self) -> Result<Vec<String>> {
+        open_devices(&self.nvml, &self.indices)?
+            .iter()
+            .map(|d| d.name().wrap_err("Unexpected NvmlError when querying device name"))
+            .collect()
+    }
 
-        // Needed to keep track of when we last looked at GPU utilisation
-        let max_timestamp: Option<u64> = all_utilisation
+    /**
+     * Name, total memory and UUID of each device
+     */
+    pub fn get_device_details(&self) -> Result<Vec<GpuDevice>> {
+        open_devices(&self.nvml, &self.indices)?
             .iter()
-            .max_by_key(|sample| sample.timestamp)
-            .map(|sample| sample.timestamp);
+            .zip(&self.indices)
+            .map(|(d, &index)| {
+                Ok(GpuDevice {
+                    index,
+                    name: d.name().wrap_err("Unexpected NvmlError when querying device name")?,
+                    memory_bytes: optional(d.memory_info())?.map(|memory| memory.total),
+                    uuid: optional(d.uuid())?,
+                })
+            })
+            .collect()
+    }
+
+    /**
+     * Temperature, power draw and fan speed of each device
+     */
+    pub fn get_device_thermals(&self) -> Result<Vec<DeviceThermals>> {
+        open_devices(&self.nvml, &self.indices)?
+            .iter()
+            .map(|d| {
+                let thermals = DeviceThermals {
+                    temperature_c: d
+                        .temperature(TemperatureSensor::Gpu)
+                        .wrap_err("Unexpected NvmlError when querying temperature")?,
+                    power_watts: optional(d.power_usage())?.map(|mw| mw as f64 / 1000.0),
+                    fan_percent: optional(d.fan_speed(0))?,
+                };
+                log::info!("{:?} -> {:?}", d.index(), thermals);
+                Ok(thermals)
+            })
+            .collect()
+    }
+
+    pub fn get_pid_utilisation(&mut self, pid: Pid, system: &mut System) -> Result<u32> {
+        let by_pid = self.get_pid_trees_utilisation_by_pid(&[pid], system)?;
+        Ok(by_pid.values().sum())
+    }
+
+    /**
+     * SM utilisation of each process in the trees rooted at `roots` which has GPU samples
+     */
+    pub fn get_pid_trees_utilisation_by_pid(
+        &mut self,
+        roots: &[Pid],
+        system: &mut System,
+    ) -> Result<HashMap<Pid, u32>> {
+        let by_device = self.get_pid_trees_utilisation_by_device(roots, system)?;
+        Ok(sum_by_pid(&by_device))
+    }
+
+    /**
+     * As [`Self::get_pid_trees_utilisation_by_pid`], but kept separate for each of
+     * [`Self::process_devices`]
+     */
+    pub fn get_pid_trees_utilisation_by_device(
+        &mut self,
+        roots: &[Pid],
+        system: &mut System,
+    ) -> Result<Vec<HashMap<Pid, u32>>> {
+        let by_device = self.get_pid_trees_engines_by_device(roots, system)?;
+        Ok(sm_by_device(&by_device))
+    }
+
+    /**
+     * As [`Self::get_pid_trees_utilisation_by_device`], but with every engine's
+     * utilisation rather than only the SMs'
+     */
+    pub fn get_pid_trees_engines_by_device(
+        &mut self,
+        roots: &[Pid],
+        system: &mut System,
+    ) -> Result<Vec<HashMap<Pid, GpuEngines>>> {
+        let children = system.get_pid_trees(roots);
+        log::trace!("Processes {:?} have Children {:?}", roots, children);
+        self.tree_engines_by_device(&children)
+    }
 
-        gpu.last_sample_time = max_timestamp;
+    /**
+     * As [`Self::get_pid_trees_engines_by_device`], for the processes already found in `tree`
+     */
+    fn tree_engines_by_device(
+        &mut self,
+        tree: &HashSet<Pid>,
+    ) -> Result<Vec<HashMap<Pid, GpuEngines>>> {
+        let all_utilisation = self.get_all_utilisation()?;
 
         //TODO sum is a percentage?
-        let sum = all_utilisation
+        let by_device = all_utilisation
             .iter()
-            .filter_map(
-                |p_sample| match children.contains(&Pid::from_u32(p_sample.pid)) {
-                    true => {
+            .map(|device_samples| {
+                device_samples
+                    .iter()
+                    .filter(|p_sample| tree.contains(&Pid::from_u32(p_sample.pid)))
+                    .fold(HashMap::new(), |mut acc, p_sample| {
                         log::info!("{} -> {:?}", p_sample.pid, p_sample);
-                        Some(p_sample.sm_util)
-                    }
-                    false => None,
-                },
-            )
-            .sum();
-
-        Ok(sum)
+                        *acc.entry(Pid::from_u32(p_sample.pid))
+                            .or_insert_with(GpuEngines::default) += GpuEngines {
+                            sm_percent: p_sample.sm_util,
+                            memory_percent: p_sample.mem_util,
+                            encoder_percent: p_sample.enc_util,
+                            decoder_percent: p_sample.dec_util,
+                        };
+                        acc
+                    })
+            })
+            .collect();
+
+        Ok(by_device)
+    }
+
+    /**
+     * GPU memory allocated by each process in the trees rooted at `roots`, summed across devices
+     */
+    pub fn get_pid_trees_memory_by_pid(
+        &self,
+        roots: &[Pid],
+        system: &mut System,
+    ) -> Result<HashMap<Pid, u64>> {
+        let by_device = self.get_pid_trees_memory_by_device(roots, system)?;
+        Ok(sum_by_pid(&by_device))
+    }
+
+    /**
+     * As [`Self::get_pid_trees_memory_by_pid`], but kept separate for each of
+     * [`Self::process_devices`].  Every process in the trees on a device is included, with
+     * zero bytes if the driver can't say how much it has allocated.
+     */
+    pub fn get_pid_trees_memory_by_device(
+        &self,
+        roots: &[Pid],
+        system: &mut System,
+    ) -> Result<Vec<HashMap<Pid, u64>>> {
+        self.tree_memory_by_device(&system.get_pid_trees(roots))
+    }
+
+    /**
+     * As [`Self::get_pid_trees_memory_by_device`], for the processes already found in `tree`
+     */
+    fn tree_memory_by_device(&self, tree: &HashSet<Pid>) -> Result<Vec<HashMap<Pid, u64>>> {
+        let mut by_device = Vec::new();
+        for device in open_process_devices(
+            &self.nvml,
+            &self.indices,
+            &self.mig_instances,
+            self.mig_lib.as_ref(),
+        )? {
+            let processes = match device.running_compute_processes() {
+                Ok(processes) => processes,
+                Err(NvmlError::NotFound) => Vec::new(),
+                Err(e) => Err(e).wrap_err("Unexpected NvmlError when querying memory")?,
+            };
+
+            let mut by_pid = HashMap::new();
+            for process in processes {
+                let pid = Pid::from_u32(process.pid);
+                if tree.contains(&pid) {
+                    let bytes = match process.used_gpu_memory {
+                        UsedGpuMemory::Used(bytes) => bytes,
+                        UsedGpuMemory::Unavailable => 0,
+                    };
+                    *by_pid.entry(pid).or_insert(0) += bytes;
+                }
+            }
+            by_device.push(by_pid);
+        }
+        log::info!("GPU memory by device and pid: {:?}", by_device);
+
+        Ok(by_device)
+    }
+}
+
+#[cfg(feature = "nvml")]
+impl GpuBackend for GpuSession {
+    fn process_devices(&self) -> Vec<(u32, Option<MigInstance>)> {
+        GpuSession::process_devices(self)
+    }
+
+    fn has_mig(&self) -> bool {
+        GpuSession::has_mig(self)
+    }
+
+    fn device_names(&self) -> Result<Vec<String>> {
+        self.get_device_names()
+    }
+
+    fn device_details(&self) -> Result<Vec<GpuDevice>> {
+        self.get_device_details()
+    }
+
+    fn driver_version(&self) -> Option<String> {
+        self.nvml.sys_driver_version().ok()
+    }
+
+    fn cuda_version(&self) -> Option<String> {
+        self.nvml.sys_cuda_driver_version().ok().map(format_cuda_version)
+    }
+
+    fn device_usage(&self) -> Result<Vec<DeviceUsage>> {
+        self.get_device_usage()
+    }
+
+    fn device_engines(&self) -> Result<Vec<GpuEngines>> {
+        self.get_device_engines()
+    }
+
+    fn device_thermals(&self) -> Result<Vec<DeviceThermals>> {
+        self.get_device_thermals()
+    }
+
+    fn device_bandwidth(&self) -> Result<Option<Vec<DeviceBandwidth>>> {
+        self.get_device_bandwidth().map(Some)
+    }
+
+    fn pid_trees_engines_by_device(
+        &mut self,
+        tree: &HashSet<Pid>,
+    ) -> Result<Vec<HashMap<Pid, GpuEngines>>> {
+        self.tree_engines_by_device(tree)
+    }
+
+    fn pid_trees_memory_by_device(&self, tree: &HashSet<Pid>) -> Result<Vec<HashMap<Pid, u64>>> {
+        self.tree_memory_by_device(tree)
+    }
+}
+
+/// Initialise NVML, checking it finds a GPU.  If not, the error says what `lspci` makes of
+/// it if `diagnose`.
+#[cfg(feature = "nvml")]
+fn init_nvml(diagnose: bool) -> Result<Nvml> {
+    let diagnostic = || match diagnose {
+        true => format!(" {}", lspci_diagnostic()),
+        false => String::new(),
+    };
+    let nvml = Nvml::init()
+        .wrap_err_with(|| format!("Failed to initialise NVML.{}", diagnostic()))?;
+
+    let num_devices = nvml
+        .device_count()
+        .wrap_err("Failed to count NVML devices")?;
+    if num_devices == 0 {
+        bail!("NVML found no GPUs.{}", diagnostic())
+    }
+    log::debug!("NVML found {} device(s)", num_devices);
+
+    Ok(nvml)
+}
+
+/// Device handles are cheap to look up, so they aren't kept between samples
+#[cfg(feature = "nvml")]
+fn open_devices<'a>(nvml: &'a Nvml, indices: &[u32]) -> Result<Vec<Device<'a>>> {
+    indices
+        .iter()
+        .map(|&idx| {
+            nvml.device_by_index(idx)
+                .wrap_err_with(|| format!("Device initialisation failure for GPU {}", idx))
+        })
+        .collect()
+}
+
+/// Handles for each of [`GpuSession::process_devices`]
+#[cfg(feature = "nvml")]
+fn open_process_devices<'a>(
+    nvml: &'a Nvml,
+    indices: &[u32],
+    mig_instances: &[Vec<MigInstance>],
+    mig_lib: Option<&NvmlLib>,
+) -> Result<Vec<Device<'a>>> {
+    let devices = open_devices(nvml, indices)?;
+    let mut process_devices = Vec::new();
+    for (device, instances) in devices.into_iter().zip(mig_instances) {
+        match mig_lib {
+            Some(lib) if !instances.is_empty() => {
+                for instance in instances {
+                    process_devices.push(open_mig_device(lib, nvml, &device, instance)?);
+                }
+            }
+            _ => process_devices.push(device),
+        }
+    }
+    Ok(process_devices)
+}
+
+/// The MIG instances which currently exist on a device with MIG enabled
+#[cfg(feature = "nvml")]
+fn find_mig_instances(
+    lib: &NvmlLib,
+    device: &Device,
+    device_index: u32,
+) -> Result<Vec<MigInstance>> {
+    let handle = unsafe { device.handle() };
+    let mut max_count = 0;
+    nvml_try(unsafe { lib.nvmlDeviceGetMaxMigDeviceCount(handle, &mut max_count) })
+        .wrap_err_with(|| format!("Failed to count MIG devices on GPU {}", device_index))?;
+
+    let mut instances = Vec::new();
+    for mig_index in 0..max_count {
+        let mut mig_handle = ptr::null_mut();
+        match nvml_try(unsafe {
+            lib.nvmlDeviceGetMigDeviceHandleByIndex(handle, mig_index, &mut mig_handle)
+        }) {
+            Ok(()) => (),
+            // Slots which haven't been partitioned into an instance
+            Err(NvmlError::NotFound) => continue,
+            Err(e) => Err(e).wrap_err_with(|| {
+                format!("Failed to open MIG device {} on GPU {}", mig_index, device_index)
+            })?,
+        }
+
+        let (mut gpu_instance_id, mut compute_instance_id) = (0, 0);
+        nvml_try(unsafe { lib.nvmlDeviceGetGpuInstanceId(mig_handle, &mut gpu_instance_id) })
+            .and_then(|_| {
+                nvml_try(unsafe {
+                    lib.nvmlDeviceGetComputeInstanceId(mig_handle, &mut compute_instance_id)
+                })
+            })
+            .wrap_err_with(|| {
+                format!("Failed to identify MIG device {} on GPU {}", mig_index, device_index)
+            })?;
+        instances.push(MigInstance {
+            device_index,
+            gpu_instance_id,
+            compute_instance_id,
+            mig_index,
+        });
+    }
+    Ok(instances)
+}
+
+#[cfg(feature = "nvml")]
+fn open_mig_device<'a>(
+    lib: &NvmlLib,
+    nvml: &'a Nvml,
+    device: &Device<'a>,
+    instance: &MigInstance,
+) -> Result<Device<'a>> {
+    let handle = unsafe { device.handle() };
+    let mut mig_handle = ptr::null_mut();
+    nvml_try(unsafe {
+        lib.nvmlDeviceGetMigDeviceHandleByIndex(handle, instance.mig_index, &mut mig_handle)
+    })
+    .wrap_err_with(|| format!("Failed to open MIG instance {}", instance))?;
+    // The handle stays valid for as long as NVML is initialised
+    Ok(unsafe { Device::new(mig_handle, nvml) })
+}
+
+/// Combine per-device results for each process
+pub(crate) fn sum_by_pid<T: Copy + Default + std::ops::AddAssign>(
+    by_device: &[HashMap<Pid, T>],
+) -> HashMap<Pid, T> {
+    let mut by_pid = HashMap::new();
+    for (&pid, &value) in by_device.iter().flatten() {
+        *by_pid.entry(pid).or_insert_with(T::default) += value;
+    }
+    by_pid
+}
+
+/// Timestamp to pass a device's next `process_utilization_stats` call, so only newer samples
+/// come back.  Never moves backwards, even if a device reports nothing new.
+#[cfg(feature = "nvml")]
+pub(crate) fn advance_last_seen(
+    last_seen: Option<u64>,
+    timestamps: impl IntoIterator<Item = u64>,
+) -> Option<u64> {
+    timestamps.into_iter().chain(last_seen).max()
+}
+
+/// CUDA version as NVML encodes it, e.g. 12040, in the usual form, e.g. `12.4`
+#[cfg(feature = "nvml")]
+pub(crate) fn format_cuda_version(version: i32) -> String {
+    format!(
+        "{}.{}",
+        cuda_driver_version_major(version),
+        cuda_driver_version_minor(version)
+    )
+}
+
+/// Only the SM utilisation from per-device engine results
+pub(crate) fn sm_by_device(by_device: &[HashMap<Pid, GpuEngines>]) -> Vec<HashMap<Pid, u32>> {
+    by_device
+        .iter()
+        .map(|by_pid| {
+            by_pid
+                .iter()
+                .map(|(&pid, engines)| (pid, engines.sm_percent))
+                .collect()
+        })
+        .collect()
+}
+
+/// Hint from `lspci` about whether there is any NVIDIA hardware, for error messages.
+/// Many systems don't have `lspci`, so it is never required.
+#[cfg(feature = "nvml")]
+pub(crate) fn lspci_diagnostic() -> String {
+    match Command::new("lspci").output() {
+        Ok(output) => match from_utf8(&output.stdout) {
+            Ok(stdout) if stdout.contains("NVIDIA") => {
+                "`lspci` lists an NVIDIA device, so check the driver is installed and loaded".into()
+            }
+            _ => "`lspci` lists no NVIDIA devices".into(),
+        },
+        Err(_) => "`lspci` is unavailable to check for NVIDIA hardware".into(),
+    }
+}
+
+/// Treat metrics the device doesn't support as absent, but propagate other errors
+#[cfg(feature = "nvml")]
+fn optional<T>(result: Result<T, NvmlError>) -> Result<Option<T>> {
+    match result {
+        Ok(value) => Ok(Some(value)),
+        Err(NvmlError::NotSupported) => Ok(None),
+        Err(e) => Err(e).wrap_err("Unexpected NvmlError when querying device"),
     }
 }
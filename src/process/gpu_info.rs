@@ -0,0 +1,230 @@
+use std::fmt::Display;
+
+use nvml_wrapper::{Device, Nvml, error::NvmlError};
+use nvml_wrapper_sys::bindings::NVML_DEVICE_MIG_ENABLE;
+
+use super::gpu::{format_cuda_version, lspci_diagnostic};
+
+/// Whether a device answers one of the NVML queries `tu` relies on
+#[derive(Debug, Clone, PartialEq)]
+pub enum Capability {
+    Supported,
+    NotSupported,
+    /// Supported by the device, but not available to this user
+    NoPermission,
+    Failed(String),
+}
+
+impl Capability {
+    /**
+     * NVML returns `NotFound` when there is nothing to report yet, e.g. no process has used
+     * the device recently, so that counts as supported
+     */
+    pub fn of<T>(result: Result<T, NvmlError>) -> Self {
+        match result {
+            Ok(_) | Err(NvmlError::NotFound) => Capability::Supported,
+            Err(NvmlError::NotSupported) => Capability::NotSupported,
+            Err(NvmlError::NoPermission) => Capability::NoPermission,
+            Err(e) => Capability::Failed(e.to_string()),
+        }
+    }
+}
+
+impl Display for Capability {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            Capability::Supported => f.write_str("yes"),
+            Capability::NotSupported => f.write_str("not supported"),
+            Capability::NoPermission => f.write_str("no permission"),
+            Capability::Failed(e) => write!(f, "failed ({})", e),
+        }
+    }
+}
+
+/// What NVML reports about a single device
+#[derive(Debug, Clone, PartialEq)]
+pub struct DeviceInfo {
+    pub index: u32,
+    pub name: Option<String>,
+    pub uuid: Option<String>,
+    /// `None` where the device can't be partitioned with MIG
+    pub mig_enabled: Option<bool>,
+    /// Overall utilisation of the device, used by `--system` and `--per-device`
+    pub device_utilisation: Capability,
+    /// Utilisation by each process, used for `gpu_percent`
+    pub process_utilisation: Capability,
+    /// Processes using the device, used for `gpu_memory_mb` and to find processes on it
+    pub compute_processes: Capability,
+    /// Used for `gpu_energy_j` and `gpu_power_w`
+    pub power: Capability,
+}
+
+impl DeviceInfo {
+    fn query(index: u32, device: &Device) -> Self {
+        Self {
+            index,
+            name: device.name().ok(),
+            uuid: device.uuid().ok(),
+            mig_enabled: device
+                .mig_mode()
+                .ok()
+                .map(|mode| mode.current == NVML_DEVICE_MIG_ENABLE),
+            device_utilisation: Capability::of(device.utilization_rates()),
+            process_utilisation: Capability::of(device.process_utilization_stats(None)),
+            compute_processes: Capability::of(device.running_compute_processes()),
+            power: Capability::of(device.power_usage()),
+        }
+    }
+
+    /**
+     * A line of advice for each query `tu` needs which the device didn't answer
+     */
+    pub fn problems(&self) -> Vec<String> {
+        let checks = [
+            (
+                &self.process_utilisation,
+                "per-process utilisation",
+                "`gpu_percent`",
+            ),
+            (
+                &self.compute_processes,
+                "the processes using it",
+                "`gpu_memory_mb`",
+            ),
+            (
+                &self.device_utilisation,
+                "overall utilisation",
+                "`--system` and `--per-device`",
+            ),
+            (&self.power, "power draw", "`gpu_power_w`"),
+        ];
+        checks
+            .into_iter()
+            .filter_map(|(capability, what, column)| match capability {
+                Capability::Supported => None,
+                Capability::NotSupported => Some(format!(
+                    "GPU {} doesn't report {}, so its {} figures will be NA",
+                    self.index, what, column
+                )),
+                Capability::NoPermission => Some(format!(
+                    "No permission to read {} from GPU {}, so its {} figures will be NA.  \
+                     Try running as root, or ask an administrator whether the driver restricts \
+                     profiling to admin users.",
+                    what, self.index, column
+                )),
+                Capability::Failed(e) => Some(format!(
+                    "Reading {} from GPU {} failed ({}), so its {} figures will be NA",
+                    what, self.index, e, column
+                )),
+            })
+            .collect()
+    }
+}
+
+/// Versions, devices and capabilities found by NVML, for diagnosing why GPU columns are NA
+#[derive(Debug, Clone, Default, PartialEq)]
+pub struct GpuInfo {
+    pub nvml_version: Option<String>,
+    pub driver_version: Option<String>,
+    /// Newest CUDA version the driver supports, e.g. `12.4`
+    pub cuda_version: Option<String>,
+    pub devices: Vec<DeviceInfo>,
+    /// Anything likely to stop GPUs being monitored, with advice on fixing it
+    pub problems: Vec<String>,
+}
+
+impl GpuInfo {
+    /**
+     * Query NVML and each device it can see.  Failures are recorded as problems rather than
+     * returned, so everything which can be checked is.
+     */
+    pub fn detect() -> Self {
+        let mut info = GpuInfo::default();
+        let nvml = match Nvml::init() {
+            Ok(nvml) => nvml,
+            Err(e) => {
+                info.problems.push(format!(
+                    "Failed to initialise NVML ({}).  {}",
+                    e,
+                    lspci_diagnostic()
+                ));
+                return info;
+            }
+        };
+        info.nvml_version = nvml.sys_nvml_version().ok();
+        info.driver_version = nvml.sys_driver_version().ok();
+        info.cuda_version = nvml.sys_cuda_driver_version().ok().map(format_cuda_version);
+
+        let count = match nvml.device_count() {
+            Ok(count) => count,
+            Err(e) => {
+                info.problems
+                    .push(format!("Failed to count NVML devices ({})", e));
+                return info;
+            }
+        };
+        if count == 0 {
+            info.problems
+                .push(format!("NVML found no GPUs.  {}", lspci_diagnostic()));
+        }
+        for index in 0..count {
+            match nvml.device_by_index(index) {
+                Ok(device) => info.devices.push(DeviceInfo::query(index, &device)),
+                Err(NvmlError::NoPermission) => info.problems.push(format!(
+                    "No permission to open GPU {}.  Check the current user can read and write \
+                     its /dev/nvidia* device files.",
+                    index
+                )),
+                Err(e) => info
+                    .problems
+                    .push(format!("Failed to open GPU {} ({})", index, e)),
+            }
+        }
+        let device_problems: Vec<String> =
+            info.devices.iter().flat_map(DeviceInfo::problems).collect();
+        info.problems.extend(device_problems);
+
+        info
+    }
+}
+
+impl Display for GpuInfo {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        let optional = |value: &Option<String>| value.clone().unwrap_or_else(|| "NA".to_string());
+        writeln!(f, "NVML version:    {}", optional(&self.nvml_version))?;
+        writeln!(f, "Driver version:  {}", optional(&self.driver_version))?;
+        writeln!(f, "CUDA version:    {}", optional(&self.cuda_version))?;
+
+        for device in &self.devices {
+            let mig = match device.mig_enabled {
+                Some(true) => "enabled",
+                Some(false) => "disabled",
+                None => "not supported",
+            };
+            writeln!(
+                f,
+                "\nGPU {}: {}\n  UUID:                   {}\n  MIG:                    {}\n  \
+                 Device utilisation:     {}\n  Process utilisation:    {}\n  \
+                 Process list:           {}\n  Power draw:             {}",
+                device.index,
+                optional(&device.name),
+                optional(&device.uuid),
+                mig,
+                device.device_utilisation,
+                device.process_utilisation,
+                device.compute_processes,
+                device.power
+            )?;
+        }
+
+        if self.problems.is_empty() {
+            writeln!(f, "\nNo problems found")
+        } else {
+            writeln!(f, "\nProblems:")?;
+            for problem in &self.problems {
+                writeln!(f, "- {}", problem)?;
+            }
+            Ok(())
+        }
+    }
+}
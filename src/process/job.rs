@@ -0,0 +1,91 @@
+//! Windows job objects, used to track every process a spawned command creates.  Unlike the
+//! parent/child tree, membership survives intermediate processes exiting.
+
+use std::{mem::size_of, os::windows::io::AsRawHandle, process::Child, ptr};
+
+use color_eyre::{Result, eyre::bail};
+use sysinfo::Pid;
+use windows_sys::Win32::{
+    Foundation::{CloseHandle, ERROR_MORE_DATA, GetLastError, HANDLE},
+    System::JobObjects::{
+        AssignProcessToJobObject, CreateJobObjectW, JOBOBJECT_BASIC_PROCESS_ID_LIST,
+        JobObjectBasicProcessIdList, QueryInformationJobObject,
+    },
+};
+
+pub struct JobObject {
+    handle: HANDLE,
+}
+
+impl JobObject {
+    pub fn new() -> Result<Self> {
+        let handle = unsafe { CreateJobObjectW(ptr::null(), ptr::null()) };
+        if handle.is_null() {
+            bail!("Failed to create job object (error {})", unsafe {
+                GetLastError()
+            });
+        }
+        Ok(Self { handle })
+    }
+
+    /**
+     * Add a child to the job.  Processes it creates from then on join the job too.
+     */
+    pub fn assign(&self, child: &Child) -> Result<()> {
+        let ok = unsafe { AssignProcessToJobObject(self.handle, child.as_raw_handle()) };
+        if ok == 0 {
+            bail!(
+                "Failed to assign process {} to job object (error {})",
+                child.id(),
+                unsafe { GetLastError() }
+            );
+        }
+        Ok(())
+    }
+
+    /**
+     * Every process currently in the job
+     */
+    pub fn pids(&self) -> Result<Vec<Pid>> {
+        let header = size_of::<JOBOBJECT_BASIC_PROCESS_ID_LIST>() - size_of::<usize>();
+        let mut capacity = 64;
+        loop {
+            // Backed by usizes so the list is suitably aligned
+            let mut buffer = vec![0usize; header / size_of::<usize>() + capacity];
+            let ok = unsafe {
+                QueryInformationJobObject(
+                    self.handle,
+                    JobObjectBasicProcessIdList,
+                    buffer.as_mut_ptr().cast(),
+                    (buffer.len() * size_of::<usize>()) as u32,
+                    ptr::null_mut(),
+                )
+            };
+            if ok == 0 {
+                let error = unsafe { GetLastError() };
+                if error == ERROR_MORE_DATA {
+                    capacity *= 2;
+                    continue;
+                }
+                bail!("Failed to list processes in job object (error {})", error);
+            }
+
+            let list = unsafe { &*buffer.as_ptr().cast::<JOBOBJECT_BASIC_PROCESS_ID_LIST>() };
+            let ids = unsafe {
+                std::slice::from_raw_parts(
+                    list.ProcessIdList.as_ptr(),
+                    list.NumberOfProcessIdsInList as usize,
+                )
+            };
+            return Ok(ids.iter().map(|&id| Pid::from(id)).collect());
+        }
+    }
+}
+
+impl Drop for JobObject {
+    fn drop(&mut self) {
+        unsafe {
+            CloseHandle(self.handle);
+        }
+    }
+}
@@ -0,0 +1,100 @@
+use std::{
+    collections::BTreeMap,
+    fs::File,
+    path::{Path, PathBuf},
+};
+
+use color_eyre::{Result, eyre::Context};
+use serde::{Deserialize, Serialize};
+
+/// Most processes recorded, so a tree of thousands of varied processes doesn't bloat the file
+pub const MAX_PROCESSES: usize = 50;
+
+/// Where and how a usage trace was recorded, so it can be interpreted long after the fact
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct RunMetadata {
+    /// Arguments `tu` was run with, including any command
+    pub command_line: Vec<String>,
+    pub hostname: Option<String>,
+    pub username: Option<String>,
+    /// RFC 3339
+    pub start_time: String,
+    pub cpu_model: Option<String>,
+    pub cpu_count: usize,
+    pub total_memory_bytes: u64,
+    /// CPU the monitored tree's cgroup allows it, if fewer cores than the machine has
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub cpu_limit_cores: Option<f64>,
+    pub gpu_models: Vec<String>,
+    /// NVIDIA driver version, when GPUs are monitored
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub gpu_driver_version: Option<String>,
+    /// Newest CUDA version the GPU driver supports
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub cuda_version: Option<String>,
+    /// Each monitored GPU, in more detail than `gpu_models`
+    #[serde(default, skip_serializing_if = "Vec::is_empty")]
+    pub gpu_devices: Vec<GpuDevice>,
+    /// Distinct processes seen in the monitored tree, with `--capture-env`
+    #[serde(default, skip_serializing_if = "Vec::is_empty")]
+    pub processes: Vec<ProcessSnapshot>,
+}
+
+/// A monitored GPU, as identified by its driver
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+pub struct GpuDevice {
+    /// NVML index, as used by `--gpu-index`
+    pub index: u32,
+    pub name: String,
+    /// Total memory on the device
+    pub memory_bytes: Option<u64>,
+    /// Stable across reboots and renumbering, unlike the index
+    pub uuid: Option<String>,
+}
+
+/// How a process in the monitored tree was started
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+pub struct ProcessSnapshot {
+    pub pid: u32,
+    pub command_line: Vec<String>,
+    /// Just the variables asked for which were set
+    pub environment: BTreeMap<String, String>,
+}
+
+impl RunMetadata {
+    /**
+     * Sidecar file for a CSV, e.g. `task_usage.meta.json` for `task_usage.csv`
+     */
+    pub fn path_for(csv_path: &Path) -> PathBuf {
+        csv_path.with_extension("meta.json")
+    }
+
+    /**
+     * Record a process, unless it's a copy of one already recorded (e.g. one of many
+     * identical workers) or [`MAX_PROCESSES`] have been.  Returns whether it was added.
+     */
+    pub fn add_process(&mut self, snapshot: ProcessSnapshot) -> bool {
+        let seen = self.processes.iter().any(|p| {
+            p.command_line == snapshot.command_line && p.environment == snapshot.environment
+        });
+        if seen || self.processes.len() >= MAX_PROCESSES {
+            return false;
+        }
+        self.processes.push(snapshot);
+        true
+    }
+
+    pub fn read_json(path: &Path) -> Result<Self> {
+        let file = File::open(path)
+            .wrap_err_with(|| format!("Failed to open metadata file {}", path.display()))?;
+        serde_json::from_reader(file)
+            .wrap_err_with(|| format!("Failed to parse metadata in {}", path.display()))
+    }
+
+    pub fn write_json(&self, path: &Path) -> Result<()> {
+        let file = File::create(path)
+            .wrap_err_with(|| format!("Failed to create metadata file {}", path.display()))?;
+        serde_json::to_writer_pretty(file, self)
+            .wrap_err_with(|| format!("Failed to write metadata to {}", path.display()))
+    }
+}
@@ -1,2 +1,25 @@
+pub mod apple_gpu;
+pub mod bench;
+pub mod capture;
+pub mod cgroup;
+pub mod compare;
+pub mod dashboard;
+pub mod energy;
 pub mod gpu;
+#[cfg(feature = "nvml")]
+pub mod gpu_info;
+#[cfg(windows)]
+pub mod job;
+pub mod metadata;
+pub mod monitor;
+pub mod nvidia_smi;
+pub mod plot;
+pub mod report;
+pub mod sampling;
+pub mod signals;
+pub mod summary;
 pub mod system;
+pub mod trace;
+
+#[cfg(test)]
+mod tests;
@@ -0,0 +1,987 @@
+use std::{
+    collections::{HashMap, HashSet},
+    ops::ControlFlow,
+    path::{Path, PathBuf},
+    process::{Child, Command, ExitStatus},
+    time::{Duration, Instant},
+};
+
+use chrono::{DateTime, Local};
+use color_eyre::{
+    Report, Result,
+    eyre::{Context, bail},
+};
+use regex::Regex;
+use sysinfo::{Pid, Signal};
+
+use crate::exit::Failure;
+#[cfg(feature = "nvml")]
+use crate::process::gpu::GpuSession;
+#[cfg(windows)]
+use crate::process::job::JobObject;
+use crate::process::{
+    apple_gpu::AppleGpu,
+    capture::OutputCapture,
+    cgroup::{Cgroup, cgroup_of, cpu_limit_cores},
+    energy::Rapl,
+    gpu::{
+        DeviceBandwidth, DeviceThermals, DeviceUsage, GpuBackend, GpuEngines, MigInstance,
+        sm_by_device, sum_by_pid,
+    },
+    sampling::Schedule,
+    system::{
+        CounterDeltas, CpuRamUsage, CpuTimes, NumaBytes, ProcessCounters, ProcessUsage, System,
+        ThrottleCounts, has_exited_unreaped, read_memory_pressure, read_numa_bytes,
+        read_throttle_counts,
+    },
+};
+
+/// Time a terminated process tree is given to exit before it is killed
+const KILL_GRACE: Duration = Duration::from_secs(10);
+/// How often the target is checked for exit between samples
+const EXIT_POLL: Duration = Duration::from_millis(100);
+
+/// What is being monitored
+pub enum Target {
+    /// A command we started, which we are responsible for reaping
+    Spawned {
+        child: Child,
+        command: Vec<String>,
+        /// Also catches processes which escape the parent/child tree
+        #[cfg(windows)]
+        job: Option<JobObject>,
+    },
+    /// A process which was already running when we started
+    Attached(Pid),
+    /// Whichever processes match a pattern, which may come and go over time
+    Matching {
+        pattern: Regex,
+        current: HashSet<Pid>,
+    },
+    /// Everything in a cgroup, including a command we started inside it, if any
+    InCgroup {
+        cgroup: Cgroup,
+        child: Option<Child>,
+    },
+    /// Everything running on the machine
+    WholeSystem,
+}
+
+impl Target {
+    /// Run `command` and monitor its process tree
+    pub fn spawn(command: &[String], capture: Option<&OutputCapture>) -> Result<Self> {
+        let child = spawn_child(command, capture, None)?;
+
+        // Tree monitoring still works without the job, so don't give up if it can't be set up
+        #[cfg(windows)]
+        let job = JobObject::new()
+            .and_then(|job| job.assign(&child).map(|_| job))
+            .inspect_err(|e| log::warn!("Not tracking command with a job object: {:#}", e))
+            .ok();
+
+        Ok(Target::Spawned {
+            child,
+            command: command.to_vec(),
+            #[cfg(windows)]
+            job,
+        })
+    }
+
+    /// If `command` is empty, monitor what's already in the cgroup
+    pub fn in_cgroup(
+        path: &Path,
+        command: &[String],
+        capture: Option<&OutputCapture>,
+    ) -> Result<Self> {
+        let cgroup = Cgroup::open_or_create(path)?;
+        let child = if command.is_empty() {
+            log::info!("Monitoring cgroup {}", cgroup.path().display());
+            None
+        } else {
+            Some(spawn_child(command, capture, Some(&cgroup))?)
+        };
+
+        Ok(Target::InCgroup { cgroup, child })
+    }
+
+    /// Everything in a running container
+    pub fn container(id: &str) -> Result<Self> {
+        Ok(Target::InCgroup {
+            cgroup: Cgroup::for_container(id)?,
+            child: None,
+        })
+    }
+
+    /// The process must still be running when the [`Monitor`] is created
+    pub fn attach(pid: Pid) -> Self {
+        log::info!("Attaching to pid {}", pid);
+        Target::Attached(pid)
+    }
+
+    pub fn matching(pattern: Regex) -> Self {
+        log::info!("Monitoring processes matching '{}'", pattern);
+
+        Target::Matching {
+            pattern,
+            current: HashSet::new(),
+        }
+    }
+
+    /// Whether we started the command being monitored
+    pub fn is_spawned(&self) -> bool {
+        matches!(
+            self,
+            Target::Spawned { .. } | Target::InCgroup { child: Some(_), .. }
+        )
+    }
+
+    /// Cgroup whose CPU limits apply to the whole target, if there's one
+    fn cgroup_path(&self) -> Option<PathBuf> {
+        match self {
+            Target::Spawned { child, .. } => cgroup_of(Pid::from_u32(child.id())),
+            Target::Attached(pid) => cgroup_of(*pid),
+            Target::InCgroup { cgroup, .. } => Some(cgroup.path().to_path_buf()),
+            Target::Matching { .. } | Target::WholeSystem => None,
+        }
+    }
+
+    /// Root processes of the tree(s) to be sampled
+    fn roots(&mut self, system: &mut System) -> Vec<Pid> {
+        match self {
+            #[cfg(windows)]
+            Target::Spawned {
+                child,
+                job: Some(job),
+                ..
+            } => {
+                let mut roots = job.pids().unwrap_or_else(|e| {
+                    log::warn!("{:#}", e);
+                    Vec::new()
+                });
+                let child_pid = Pid::from_u32(child.id());
+                if !roots.contains(&child_pid) {
+                    roots.push(child_pid);
+                }
+                roots
+            }
+            Target::Spawned { child, .. } => vec![Pid::from_u32(child.id())],
+            Target::Attached(pid) => vec![*pid],
+            Target::Matching { pattern, current } => {
+                let found = system.find_matching_pids(pattern);
+                let found_set: HashSet<Pid> = found.iter().copied().collect();
+                for pid in found_set.difference(current) {
+                    log::info!("Process {} now matches '{}'", pid, pattern);
+                }
+                for pid in current.difference(&found_set) {
+                    log::info!("Process {} no longer matches '{}'", pid, pattern);
+                }
+                if found.is_empty() && !current.is_empty() {
+                    log::warn!("No processes currently match '{}'", pattern);
+                }
+                *current = found_set;
+                found
+            }
+            Target::InCgroup { cgroup, .. } => cgroup.pids().unwrap_or_else(|e| {
+                log::warn!("{:#}", e);
+                Vec::new()
+            }),
+            Target::WholeSystem => Vec::new(),
+        }
+    }
+
+    fn has_exited(&mut self, system: &mut System) -> Result<bool> {
+        match self {
+            Target::Spawned { child, command, .. } => {
+                // Left unreaped where possible, so the tree can be sampled one last time
+                if let Some(exited) = has_exited_unreaped(Pid::from_u32(child.id())) {
+                    return Ok(exited);
+                }
+                let exit_status = child.try_wait().wrap_err_with(|| {
+                    format!("Abnormal User command status ({})", command.join(" "))
+                })?;
+                Ok(exit_status.is_some())
+            }
+            Target::Attached(pid) => {
+                if let Some(exited) = has_exited_unreaped(*pid) {
+                    return Ok(exited);
+                }
+                system.refresh_pids(&[*pid]);
+                Ok(!system.pid_is_alive(*pid))
+            }
+            Target::InCgroup {
+                child: Some(child), ..
+            } => match has_exited_unreaped(Pid::from_u32(child.id())) {
+                Some(exited) => Ok(exited),
+                None => Ok(child.try_wait()?.is_some()),
+            },
+            Target::InCgroup { cgroup, .. } => Ok(!cgroup.is_populated()?),
+            // Matching processes may reappear at any time, so run until interrupted
+            Target::Matching { .. } | Target::WholeSystem => Ok(false),
+        }
+    }
+
+    /// Exit status of the command, if we started one
+    fn wait(&mut self) -> Result<Option<ExitStatus>> {
+        if let Target::Spawned { child, .. }
+        | Target::InCgroup {
+            child: Some(child), ..
+        } = self
+        {
+            log::info!("Waiting for command to complete...");
+            return Ok(Some(child.wait()?));
+        }
+        Ok(None)
+    }
+}
+
+/// Shell convention of 128 + N for a process killed by signal N
+pub fn exit_status_code(status: ExitStatus) -> i32 {
+    #[cfg(unix)]
+    {
+        use std::os::unix::process::ExitStatusExt;
+        if let Some(signal) = status.signal() {
+            return 128 + signal;
+        }
+    }
+    status.code().unwrap_or(1)
+}
+
+/// If `capture` is given, the command's output goes through it rather than being inherited
+fn spawn_child(
+    command: &[String],
+    capture: Option<&OutputCapture>,
+    cgroup: Option<&Cgroup>,
+) -> Result<Child> {
+    if command.is_empty() {
+        bail!("No command given to run");
+    }
+    let mut cmd = Command::new(&command[0]);
+    cmd.args(&command[1..]);
+    if let Some(capture) = capture {
+        capture.pipe_output(&mut cmd);
+    }
+    if let Some(cgroup) = cgroup {
+        cgroup.enter_on_spawn(&mut cmd)?;
+    }
+
+    let mut child = cmd
+        .spawn()
+        .map_err(|e| match Failure::from_io(&e) {
+            Some(failure) => Report::new(e).wrap_err(failure),
+            None => Report::new(e),
+        })
+        .wrap_err_with(|| format!("Failed to run command ({})", command.join(" ")))?;
+
+    if let Some(capture) = capture {
+        capture.follow(&mut child);
+    }
+
+    Ok(child)
+}
+
+/// Optional measurements, which cost extra to take or aren't available everywhere
+#[derive(Debug, Clone, Copy, Default)]
+pub struct Measurements {
+    /// Linux memory pressure (PSI)
+    pub memory_pressure: bool,
+    /// CPU clock speed and throttling
+    pub cpu_frequency: bool,
+    /// CPU, DRAM and GPU energy
+    pub energy: bool,
+    /// Temperature, power draw and fan speed of NVIDIA devices
+    pub gpu_thermals: bool,
+    /// Memory controller, video encoder and decoder utilisation of NVIDIA devices
+    pub gpu_engines: bool,
+    /// PCIe throughput and memory controller utilisation of whole NVIDIA devices
+    pub gpu_bandwidth: bool,
+    /// Whole-machine CPU, RAM and load alongside the monitored tree's
+    pub system_baseline: bool,
+    /// Resident memory on each NUMA node
+    pub numa: bool,
+}
+
+/// One aggregated measurement of the monitored target
+#[derive(Debug)]
+pub struct UsageSample {
+    pub time: DateTime<Local>,
+    pub cpu_ram: CpuRamUsage,
+    pub gpu_percent: Option<u32>,
+    pub swap_bytes: Option<u64>,
+    pub fd_count: Option<usize>,
+    /// Processes in the tree.  `None` when monitoring the whole system.
+    pub process_count: Option<usize>,
+    /// Threads in the tree's processes, where reported
+    pub thread_count: Option<u64>,
+    /// User and system CPU time used by the tree so far, including processes which have
+    /// finished.  Linux only, and `None` when monitoring the whole system.
+    pub cpu_times: Option<CpuTimes>,
+    /// Cores' worth of CPU the tree's cgroup allows it, if fewer than the machine has
+    pub cpu_limit_cores: Option<f64>,
+    /// Since the previous sample
+    pub counters: Option<ProcessCounters>,
+    /// Outer `None` if not being recorded
+    pub memory_pressure: Option<Option<f64>>,
+    /// Resident memory of the tree on each NUMA node.  `None` if not being recorded (or
+    /// when monitoring the whole system), and empty if it couldn't be read.
+    pub numa_bytes: Option<NumaBytes>,
+    pub cpu_clock: Option<CpuClock>,
+    pub energy: Option<Energy>,
+    pub gpu_thermals: Option<Vec<DeviceThermals>>,
+    /// Per-device, as other processes share the bus and memory
+    pub gpu_bandwidth: Option<Vec<DeviceBandwidth>>,
+    /// Attributed to the monitored tree, or whole devices when monitoring the system, and
+    /// summed over devices
+    pub gpu_engines: Option<GpuEngines>,
+    /// Attributed to the monitored tree, or whole devices when monitoring the system.
+    /// Always present with NVIDIA devices.
+    pub gpu_devices: Option<Vec<DeviceUsage>>,
+    /// Each process in the tree.  Empty when monitoring the whole system.
+    pub processes: Vec<ProcessUsage>,
+    /// GPU utilisation of each process in the tree which is on one, including those which
+    /// are idle
+    pub gpu_by_pid: Option<HashMap<Pid, u32>>,
+    /// GPU memory of each process in the tree which is on an NVIDIA device
+    pub gpu_memory_by_pid: Option<HashMap<Pid, u64>>,
+    /// MIG instances the tree has processes on, if any device is partitioned with MIG
+    pub gpu_mig_instances: Option<Vec<MigInstance>>,
+    /// Whole-machine usage, to show whether the tree was competing for resources
+    pub system_baseline: Option<SystemBaseline>,
+    /// Lifetime disk I/O (read, written) of a cgroup, which accounts for processes which
+    /// have since left the tree
+    pub group_io: Option<(u64, u64)>,
+}
+
+#[derive(Debug)]
+pub struct SystemBaseline {
+    pub cpu_ram: CpuRamUsage,
+    pub load_average: Option<f64>,
+}
+
+#[derive(Debug)]
+pub struct CpuClock {
+    pub mhz: Option<u64>,
+    /// Throttling events since the previous sample
+    pub thermal_throttles: Option<u64>,
+    pub power_throttles: Option<u64>,
+}
+
+/// Used since the previous sample
+#[derive(Debug)]
+pub struct Energy {
+    pub cpu_joules: Option<f64>,
+    pub dram_joules: Option<f64>,
+    pub gpu_joules: Option<f64>,
+}
+
+impl Energy {
+    /**
+     * `None` if nothing could be measured
+     */
+    pub fn total_joules(&self) -> Option<f64> {
+        [self.cpu_joules, self.dram_joules, self.gpu_joules]
+            .into_iter()
+            .flatten()
+            .reduce(|a, b| a + b)
+    }
+}
+
+impl UsageSample {
+    /// CPU as a percentage of what the tree's cgroup allows, rather than of one core
+    pub fn cpu_percent_of_limit(&self) -> Option<f64> {
+        Some(self.cpu_ram.cpu_percent as f64 / self.cpu_limit_cores?)
+    }
+
+    /// Process (or thread, if they're accounted separately) in the tree using the most CPU,
+    /// if any is using some
+    pub fn top_cpu_process(&self) -> Option<&ProcessUsage> {
+        self.processes
+            .iter()
+            .filter(|process| process.usage.cpu_percent > 0.0)
+            .max_by(|a, b| a.usage.cpu_percent.total_cmp(&b.usage.cpu_percent))
+    }
+
+    /// Process in the tree using the most memory
+    pub fn top_ram_process(&self) -> Option<&ProcessUsage> {
+        self.processes
+            .iter()
+            .filter(|process| process.usage.memory_bytes > 0)
+            .max_by_key(|process| process.usage.memory_bytes)
+    }
+
+    /// Total GPU memory of the devices, or of the monitored processes on them
+    pub fn gpu_memory_bytes(&self) -> Option<u64> {
+        self.gpu_devices
+            .as_ref()
+            .map(|devices| devices.iter().map(|d| d.memory_bytes).sum())
+    }
+
+    /// Total over the devices which report it
+    pub fn gpu_pcie_tx_bytes_per_sec(&self) -> Option<u64> {
+        self.gpu_bandwidth
+            .as_ref()?
+            .iter()
+            .filter_map(|b| b.pcie_tx_bytes_per_sec)
+            .reduce(|a, b| a + b)
+    }
+
+    /// Total over the devices which report it
+    pub fn gpu_pcie_rx_bytes_per_sec(&self) -> Option<u64> {
+        self.gpu_bandwidth
+            .as_ref()?
+            .iter()
+            .filter_map(|b| b.pcie_rx_bytes_per_sec)
+            .reduce(|a, b| a + b)
+    }
+
+    /// Busiest memory controller
+    pub fn gpu_memory_bandwidth_percent(&self) -> Option<u32> {
+        self.gpu_bandwidth
+            .as_ref()?
+            .iter()
+            .map(|b| b.memory_percent)
+            .max()
+    }
+
+    /// Hottest device
+    pub fn gpu_temp_c(&self) -> Option<u32> {
+        self.gpu_thermals
+            .as_ref()?
+            .iter()
+            .map(|t| t.temperature_c)
+            .max()
+    }
+
+    /// Total draw of the devices which report it
+    pub fn gpu_power_w(&self) -> Option<f64> {
+        self.gpu_thermals
+            .as_ref()?
+            .iter()
+            .filter_map(|t| t.power_watts)
+            .reduce(|a, b| a + b)
+    }
+
+    /// Fastest fan
+    pub fn gpu_fan_percent(&self) -> Option<u32> {
+        self.gpu_thermals
+            .as_ref()?
+            .iter()
+            .filter_map(|t| t.fan_percent)
+            .max()
+    }
+}
+
+/// Samples the usage of a [`Target`], for embedding monitoring in other programs.  Either
+/// call [`Monitor::run`] with a callback, or drive it with [`Monitor::sample`] on a
+/// schedule of your own.
+pub struct Monitor {
+    target: Target,
+    system: System,
+    interval: Duration,
+    measurements: Measurements,
+    gpu: Option<Box<dyn GpuBackend>>,
+    apple_gpu: Option<AppleGpu>,
+    last_throttle: Option<ThrottleCounts>,
+    rapl: Option<Rapl>,
+    last_energy_instant: Instant,
+    counter_deltas: CounterDeltas,
+    exit_time: Option<DateTime<Local>>,
+    cpu_limit_cores: Option<f64>,
+    /// Highest seen, as the tree's total drops when processes leave it
+    cpu_times: Option<CpuTimes>,
+}
+
+impl Monitor {
+    /**
+     * Samples every second until configured otherwise
+     */
+    pub fn new(target: Target) -> Result<Self> {
+        let mut system = System::new();
+        system.refresh_process_stats();
+        if let Target::Attached(pid) = target
+            && !system.pid_is_alive(pid)
+        {
+            return Err(
+                Failure::InvalidTarget.because(format!("No running process with pid {}", pid))
+            );
+        }
+        // Read once, as a limit changing part way through a run is rare
+        let cpu_limit_cores = target
+            .cgroup_path()
+            .and_then(|path| cpu_limit_cores(&path, system.cpu_count()));
+        if let Some(cores) = cpu_limit_cores {
+            log::info!(
+                "Tree is limited to {:.2} cores' worth of CPU by its cgroup",
+                cores
+            );
+        }
+
+        Ok(Self {
+            target,
+            system,
+            interval: Duration::from_secs(1),
+            measurements: Measurements::default(),
+            gpu: None,
+            apple_gpu: None,
+            last_throttle: None,
+            rapl: None,
+            last_energy_instant: Instant::now(),
+            counter_deltas: CounterDeltas::new(),
+            exit_time: None,
+            cpu_limit_cores,
+            cpu_times: None,
+        })
+    }
+
+    /// Run `command` and monitor its process tree
+    pub fn spawn(command: &[String]) -> Result<Self> {
+        Self::new(Target::spawn(command, None)?)
+    }
+
+    /// Monitor the tree of an already running process
+    pub fn attach(pid: Pid) -> Result<Self> {
+        Self::new(Target::attach(pid))
+    }
+
+    /**
+     * Pause between samples taken by [`Monitor::run`]
+     */
+    pub fn with_interval(mut self, interval: Duration) -> Self {
+        self.interval = interval;
+        self
+    }
+
+    pub fn with_measurements(mut self, measurements: Measurements) -> Self {
+        if measurements.cpu_frequency {
+            self.last_throttle = read_throttle_counts();
+        }
+        self.rapl = match measurements.energy.then(Rapl::new) {
+            Some(Ok(rapl)) => Some(rapl),
+            Some(Err(e)) => {
+                log::warn!("CPU energy won't be recorded: {:#}", e);
+                None
+            }
+            None => None,
+        };
+        self.last_energy_instant = Instant::now();
+        self.measurements = measurements;
+        self
+    }
+
+    /**
+     * Measure NVIDIA GPU usage on the given devices
+     */
+    #[cfg(feature = "nvml")]
+    pub fn with_nvidia_gpu(self, gpu: GpuSession) -> Self {
+        self.with_gpu(gpu)
+    }
+
+    /**
+     * Measure GPU usage through any [`GpuBackend`]
+     */
+    pub fn with_gpu(self, gpu: impl GpuBackend + 'static) -> Self {
+        self.with_boxed_gpu(Box::new(gpu))
+    }
+
+    /**
+     * As [`Monitor::with_gpu`], for a backend chosen at runtime, without boxing it again
+     */
+    pub fn with_boxed_gpu(mut self, gpu: Box<dyn GpuBackend>) -> Self {
+        self.gpu = Some(gpu);
+        self
+    }
+
+    /**
+     * Hand back the GPU backend, e.g. to reuse its session for the next command
+     */
+    pub fn take_gpu(&mut self) -> Option<Box<dyn GpuBackend>> {
+        self.gpu.take()
+    }
+
+    /**
+     * Measure Apple Silicon GPU usage
+     */
+    pub fn with_apple_gpu(mut self, apple_gpu: AppleGpu) -> Self {
+        self.apple_gpu = Some(apple_gpu);
+        self
+    }
+
+    pub fn target(&self) -> &Target {
+        &self.target
+    }
+
+    pub fn system(&mut self) -> &mut System {
+        &mut self.system
+    }
+
+    /// Whether the target is done, so there's nothing more to monitor
+    pub fn has_exited(&mut self) -> Result<bool> {
+        if self.exit_time.is_some() {
+            return Ok(true);
+        }
+        let exited = self.target.has_exited(&mut self.system)?;
+        if exited {
+            self.exit_time = Some(Local::now());
+        }
+        Ok(exited)
+    }
+
+    /**
+     * Pause for up to `timeout`, returning early (with `true`) if the target exits.  On
+     * Linux a command we started isn't reaped yet, so a final sample still sees its tree.
+     */
+    pub fn wait_for_exit(&mut self, timeout: Duration) -> Result<bool> {
+        let deadline = Instant::now() + timeout;
+        loop {
+            if self.has_exited()? {
+                return Ok(true);
+            }
+            let remaining = deadline.saturating_duration_since(Instant::now());
+            if remaining.is_zero() {
+                return Ok(false);
+            }
+            std::thread::sleep(remaining.min(EXIT_POLL));
+        }
+    }
+
+    /// Cores' worth of CPU the target's cgroup allows it, if fewer than the machine has
+    pub fn cpu_limit_cores(&self) -> Option<f64> {
+        self.cpu_limit_cores
+    }
+
+    /// When the target was first seen to have exited
+    pub fn exit_time(&self) -> Option<DateTime<Local>> {
+        self.exit_time
+    }
+
+    /**
+     * Send `signal` to the tree of a command we started, killing whatever is left after a
+     * grace period.  Processes which were already running are left alone.
+     */
+    pub fn terminate(&mut self, signal: Signal) {
+        if self.target.is_spawned() {
+            let roots = self.target.roots(&mut self.system);
+            self.system.terminate_pid_trees(&roots, signal, KILL_GRACE);
+        }
+    }
+
+    /**
+     * Terminate the monitored tree, even if it was already running
+     */
+    pub fn kill(&mut self) {
+        let roots = self.target.roots(&mut self.system);
+        self.system
+            .terminate_pid_trees(&roots, Signal::Term, KILL_GRACE);
+    }
+
+    /**
+     * Exit status of the command, once it finishes, if we started one
+     */
+    pub fn wait(&mut self) -> Result<Option<ExitStatus>> {
+        self.target.wait()
+    }
+
+    /**
+     * Sample every interval until the target exits, or `on_sample` breaks.  Samples keep to
+     * a fixed cadence however long each takes, skipping any missed through overrunning.  A
+     * final sample is taken as soon as the target exits, covering the part interval since
+     * the last.  A command we started is then terminated if necessary, and its exit status
+     * returned.
+     */
+    pub fn run(
+        &mut self,
+        mut on_sample: impl FnMut(&UsageSample) -> Result<ControlFlow<()>>,
+    ) -> Result<Option<ExitStatus>> {
+        let mut schedule = Schedule::new(Instant::now(), self.interval);
+        loop {
+            let exited = self.wait_for_exit(schedule.remaining(Instant::now()))?;
+            let sample = self.sample()?;
+            if on_sample(&sample)?.is_break() {
+                self.terminate(Signal::Term);
+                break;
+            }
+            if exited {
+                break;
+            }
+            let missed = schedule.advance(self.interval, Instant::now());
+            if missed > 0 {
+                log::debug!("Sampling overran, skipped {} samples", missed);
+            }
+        }
+        self.wait()
+    }
+
+    /**
+     * Measure the target now.  Rates and counters cover the time since the previous sample.
+     */
+    pub fn sample(&mut self) -> Result<UsageSample> {
+        let roots = self.target.roots(&mut self.system);
+        let _span = tracing::debug_span!("sample", pids = ?roots).entered();
+
+        let gpu_thermals_opt = match &self.gpu {
+            Some(gpu) if self.measurements.gpu_thermals => Some(gpu.device_thermals()?),
+            _ => None,
+        };
+        let gpu_bandwidth_opt = match &self.gpu {
+            Some(gpu) if self.measurements.gpu_bandwidth => gpu.device_bandwidth()?,
+            _ => None,
+        };
+        let memory_pressure_opt = self.measurements.memory_pressure.then(read_memory_pressure);
+        let energy_opt = self
+            .measurements
+            .energy
+            .then(|| self.energy_since_last())
+            .transpose()?;
+        let cpu_clock_opt = self.measurements.cpu_frequency.then(|| self.cpu_clock());
+
+        if let Target::WholeSystem = self.target {
+            let gpu_devices_opt = self
+                .gpu
+                .as_ref()
+                .map(|gpu| gpu.device_usage())
+                .transpose()?;
+            let gpu_usage_opt = match &self.apple_gpu {
+                Some(apple_gpu) => Some(apple_gpu.get_device_utilisation()?),
+                None => gpu_devices_opt
+                    .as_ref()
+                    .map(|devices| devices.iter().map(|d| d.gpu_percent).sum()),
+            };
+            let gpu_engines_opt = match &self.gpu {
+                Some(gpu) if self.measurements.gpu_engines => {
+                    Some(total_engines(gpu.device_engines()?))
+                }
+                _ => None,
+            };
+
+            let usage = self.system.get_system_utilisation();
+
+            return Ok(UsageSample {
+                time: Local::now(),
+                cpu_ram: usage.cpu_ram,
+                gpu_percent: gpu_usage_opt,
+                swap_bytes: Some(usage.swap_bytes),
+                fd_count: None,
+                process_count: None,
+                thread_count: None,
+                cpu_times: None,
+                cpu_limit_cores: None,
+                counters: None,
+                memory_pressure: memory_pressure_opt,
+                numa_bytes: None,
+                cpu_clock: cpu_clock_opt,
+                energy: energy_opt,
+                gpu_thermals: gpu_thermals_opt,
+                gpu_bandwidth: gpu_bandwidth_opt,
+                gpu_engines: gpu_engines_opt,
+                gpu_devices: gpu_devices_opt,
+                processes: Vec::new(),
+                gpu_by_pid: None,
+                gpu_memory_by_pid: None,
+                gpu_mig_instances: None,
+                system_baseline: None,
+                group_io: None,
+            });
+        }
+
+        // Found once, as each refresh restarts the window sysinfo measures CPU over
+        let tree = self.system.get_pid_trees(&roots);
+        let gpu_engines_by_device_opt = self
+            .gpu
+            .as_mut()
+            .map(|gpu| gpu.pid_trees_engines_by_device(&tree))
+            .transpose()?;
+        let gpu_by_device_opt = gpu_engines_by_device_opt.as_deref().map(sm_by_device);
+        let gpu_engines_opt = gpu_engines_by_device_opt
+            .filter(|_| self.measurements.gpu_engines)
+            .map(|by_device| total_engines(by_device.iter().flat_map(HashMap::values).copied()));
+        // Processes holding GPU memory but momentarily idle have no utilisation samples, so
+        // which processes are on a device comes from the running compute processes instead.
+        // With MIG, it's also what shows which instances the tree is on, as they may not
+        // report utilisation at all.
+        let gpu_memory_by_device_opt = self
+            .gpu
+            .as_ref()
+            .map(|gpu| gpu.pid_trees_memory_by_device(&tree))
+            .transpose()?;
+        let gpu_memory_by_pid_opt = gpu_memory_by_device_opt.as_deref().map(sum_by_pid);
+
+        let gpu_by_pid_opt = match &self.apple_gpu {
+            Some(apple_gpu) => {
+                Some(apple_gpu.get_tree_utilisation_by_pid(&tree)?)
+            }
+            None => gpu_by_device_opt.as_deref().map(|by_device| {
+                let mut by_pid = sum_by_pid(by_device);
+                for &pid in gpu_memory_by_pid_opt.iter().flat_map(HashMap::keys) {
+                    by_pid.entry(pid).or_insert(0);
+                }
+                by_pid
+            }),
+        };
+        let gpu_usage_opt = gpu_by_pid_opt.as_ref().map(|by_pid| by_pid.values().sum());
+
+        let (gpu_devices_opt, gpu_mig_instances_opt) =
+            match (&self.gpu, &gpu_by_device_opt, gpu_memory_by_device_opt) {
+                (Some(gpu), Some(by_device), Some(memory_by_device)) => {
+                    let mut devices = Vec::new();
+                    let mut mig_instances = Vec::new();
+                    for (((index, mig_instance), utilisation), memory) in gpu
+                        .process_devices()
+                        .into_iter()
+                        .zip(by_device)
+                        .zip(memory_by_device)
+                    {
+                        if let Some(instance) = mig_instance
+                            && (!utilisation.is_empty() || !memory.is_empty())
+                        {
+                            mig_instances.push(instance);
+                        }
+                        devices.push(DeviceUsage {
+                            index,
+                            mig_instance,
+                            gpu_percent: utilisation.values().sum(),
+                            memory_bytes: memory.values().sum(),
+                        });
+                    }
+                    (Some(devices), gpu.has_mig().then_some(mig_instances))
+                }
+                _ => (None, None),
+            };
+
+        let processes = self.system.get_tree_process_usage(&tree);
+        let now = Local::now();
+
+        // A cgroup accounts for everything in it, even processes which left the tree
+        let (cgroup_cpu_ram_opt, group_io) = match &mut self.target {
+            Target::InCgroup { cgroup, .. } => (Some(cgroup.cpu_ram()?), Some(cgroup.io_bytes()?)),
+            _ => (None, None),
+        };
+        let cpu_times_opt = match &self.target {
+            Target::InCgroup { cgroup, .. } => Some(cgroup.cpu_times()?),
+            _ => processes
+                .iter()
+                .filter(|p| p.thread_of.is_none())
+                .filter_map(|p| p.cpu_times)
+                .reduce(|a, b| a + b)
+                .map(|live| match self.cpu_times {
+                    Some(last) => live.max(last),
+                    None => live,
+                }),
+        };
+        if cpu_times_opt.is_some() {
+            self.cpu_times = cpu_times_opt;
+        }
+        let numa_bytes_opt = match &self.target {
+            _ if !self.measurements.numa => None,
+            Target::InCgroup { cgroup, .. } => Some(cgroup.numa_bytes()?),
+            _ => Some(tree_numa_bytes(&processes)),
+        };
+
+        let fd_count = processes
+            .iter()
+            .filter_map(|p| p.fd_count)
+            .reduce(|a, b| a + b);
+        let swap_bytes = processes
+            .iter()
+            .filter_map(|p| p.swap_bytes)
+            .reduce(|a, b| a + b);
+        let process_count = processes.iter().filter(|p| p.thread_of.is_none()).count();
+        let thread_count = processes
+            .iter()
+            .filter_map(|p| p.thread_count)
+            .reduce(|a, b| a + b);
+        let counters = self.counter_deltas.update(&processes);
+        // After the processes, as refreshing machine-wide CPU shortens their next CPU window
+        let system_baseline = self.measurements.system_baseline.then(|| SystemBaseline {
+            cpu_ram: self.system.get_system_utilisation().cpu_ram,
+            load_average: self.system.load_average(),
+        });
+
+        Ok(UsageSample {
+            time: now,
+            fd_count,
+            process_count: Some(process_count),
+            thread_count,
+            cpu_times: self.cpu_times,
+            cpu_limit_cores: self.cpu_limit_cores,
+            cpu_ram: cgroup_cpu_ram_opt
+                .unwrap_or_else(|| processes.iter().map(|p| p.usage.clone()).sum()),
+            gpu_percent: gpu_usage_opt,
+            swap_bytes,
+            counters,
+            memory_pressure: memory_pressure_opt,
+            numa_bytes: numa_bytes_opt,
+            cpu_clock: cpu_clock_opt,
+            energy: energy_opt,
+            gpu_thermals: gpu_thermals_opt,
+            gpu_bandwidth: gpu_bandwidth_opt,
+            gpu_engines: gpu_engines_opt,
+            gpu_devices: gpu_devices_opt,
+            processes,
+            gpu_by_pid: gpu_by_pid_opt,
+            gpu_memory_by_pid: gpu_memory_by_pid_opt,
+            gpu_mig_instances: gpu_mig_instances_opt,
+            system_baseline,
+            group_io,
+        })
+    }
+
+    fn energy_since_last(&mut self) -> Result<Energy> {
+        let rapl_energy_opt = self
+            .rapl
+            .as_mut()
+            .map(|rapl| rapl.energy_since_last())
+            .transpose()?;
+        let gpu_power_opt = match &self.gpu {
+            Some(gpu) => gpu
+                .device_thermals()?
+                .iter()
+                .filter_map(|t| t.power_watts)
+                .reduce(|a, b| a + b),
+            None => None,
+        };
+        let seconds = self.last_energy_instant.elapsed().as_secs_f64();
+        self.last_energy_instant = Instant::now();
+
+        Ok(Energy {
+            cpu_joules: rapl_energy_opt.map(|e| e.package_joules),
+            dram_joules: rapl_energy_opt.and_then(|e| e.dram_joules),
+            gpu_joules: gpu_power_opt.map(|watts| watts * seconds),
+        })
+    }
+
+    fn cpu_clock(&mut self) -> CpuClock {
+        let throttle_opt = read_throttle_counts();
+        let clock = CpuClock {
+            mhz: self.system.cpu_frequency_mhz(),
+            thermal_throttles: throttle_opt
+                .zip(self.last_throttle)
+                .map(|(now, last)| now.thermal_since(&last)),
+            power_throttles: throttle_opt
+                .zip(self.last_throttle)
+                .map(|(now, last)| now.power_since(&last)),
+        };
+        self.last_throttle = throttle_opt;
+        clock
+    }
+}
+
+/// Sum over processes or devices
+fn total_engines(engines: impl IntoIterator<Item = GpuEngines>) -> GpuEngines {
+    let mut total = GpuEngines::default();
+    for e in engines {
+        total += e;
+    }
+    total
+}
+
+/// Sum over the tree's processes.  Threads share their process's memory, so are skipped.
+fn tree_numa_bytes(processes: &[ProcessUsage]) -> NumaBytes {
+    let mut total = NumaBytes::new();
+    let per_process = processes
+        .iter()
+        .filter(|p| p.thread_of.is_none())
+        .filter_map(|p| read_numa_bytes(p.pid));
+    for (node, bytes) in per_process.flatten() {
+        *total.entry(node).or_default() += bytes;
+    }
+    total
+}
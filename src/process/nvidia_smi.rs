@@ -0,0 +1,338 @@
+use std::{
+    collections::{HashMap, HashSet},
+    process::Command,
+    str::from_utf8,
+};
+
+use color_eyre::{
+    Result,
+    eyre::{Context, OptionExt, bail, eyre},
+};
+use sysinfo::Pid;
+
+use crate::process::{
+    gpu::{DeviceThermals, DeviceUsage, GpuBackend, GpuEngines, MigInstance},
+    metadata::GpuDevice,
+};
+
+/// A device as listed by `nvidia-smi --query-gpu`
+#[derive(Debug, Clone, PartialEq)]
+pub struct SmiDevice {
+    pub index: u32,
+    pub uuid: String,
+    pub name: String,
+}
+
+/// NVIDIA GPU monitoring by parsing `nvidia-smi` output, for when NVML can't be loaded,
+/// e.g. in containers where the driver library doesn't match what the bindings expect.
+/// Lower fidelity than [`GpuSession`](crate::process::gpu::GpuSession): per-process
+/// utilisation comes from a single `nvidia-smi pmon` sample taken as each interval ends,
+/// rather than everything since the last, and MIG instances aren't told apart.
+pub struct NvidiaSmi {
+    devices: Vec<SmiDevice>,
+}
+
+impl NvidiaSmi {
+    /**
+     * Every device `nvidia-smi` lists, or only those with the given indices if `indices`
+     * isn't empty.  `None` if `nvidia-smi` can't be run or finds no GPUs.
+     */
+    pub fn detect(indices: &[u32]) -> Result<Option<Self>> {
+        let output = match nvidia_smi(&["--query-gpu=index,uuid,name", "--format=csv,noheader"]) {
+            Ok(output) => output,
+            Err(e) => {
+                log::info!("Not monitoring GPUs with nvidia-smi: {:#}", e);
+                return Ok(None);
+            }
+        };
+        let mut devices = parse_devices(&output)?;
+        if devices.is_empty() {
+            log::info!("nvidia-smi found no GPUs");
+            return Ok(None);
+        }
+
+        if !indices.is_empty() {
+            if let Some(bad) = indices
+                .iter()
+                .find(|&&idx| !devices.iter().any(|device| device.index == idx))
+            {
+                bail!(
+                    "GPU index {} requested, but nvidia-smi only found {} device(s)",
+                    bad,
+                    devices.len()
+                )
+            }
+            devices.retain(|device| indices.contains(&device.index));
+        }
+        log::debug!("nvidia-smi found devices: {:?}", &devices);
+
+        Ok(Some(Self { devices }))
+    }
+
+    /**
+     * Position of the device with NVML index `index` in per-device results
+     */
+    fn position(&self, index: u32) -> Option<usize> {
+        self.devices.iter().position(|device| device.index == index)
+    }
+
+    /**
+     * Rows of a `--query-gpu` for the monitored devices, in order, without the leading index
+     */
+    fn query_gpus(&self, fields: &str) -> Result<Vec<Vec<String>>> {
+        let output = nvidia_smi(&[
+            &format!("--query-gpu=index,{}", fields),
+            "--format=csv,noheader,nounits",
+        ])?;
+        let mut rows = vec![None; self.devices.len()];
+        for line in output.lines().filter(|line| !line.trim().is_empty()) {
+            let mut values = line.split(',').map(|value| value.trim().to_string());
+            let index: u32 = values
+                .next()
+                .unwrap_or_default()
+                .parse()
+                .wrap_err_with(|| format!("Unexpected nvidia-smi output: {}", line))?;
+            if let Some(position) = self.position(index) {
+                rows[position] = Some(values.collect());
+            }
+        }
+        rows.into_iter()
+            .zip(&self.devices)
+            .map(|(row, device)| {
+                row.ok_or_else(|| eyre!("nvidia-smi didn't report GPU {}", device.index))
+            })
+            .collect()
+    }
+}
+
+impl GpuBackend for NvidiaSmi {
+    fn process_devices(&self) -> Vec<(u32, Option<MigInstance>)> {
+        self.devices
+            .iter()
+            .map(|device| (device.index, None))
+            .collect()
+    }
+
+    fn device_names(&self) -> Result<Vec<String>> {
+        Ok(self
+            .devices
+            .iter()
+            .map(|device| device.name.clone())
+            .collect())
+    }
+
+    fn device_details(&self) -> Result<Vec<GpuDevice>> {
+        self.query_gpus("memory.total")?
+            .iter()
+            .zip(&self.devices)
+            .map(|(row, device)| {
+                Ok(GpuDevice {
+                    index: device.index,
+                    name: device.name.clone(),
+                    memory_bytes: number::<u64>(row.first()).map(|mib| mib * 2u64.pow(20)),
+                    uuid: Some(device.uuid.clone()),
+                })
+            })
+            .collect()
+    }
+
+    fn driver_version(&self) -> Option<String> {
+        let rows = self.query_gpus("driver_version").ok()?;
+        rows.into_iter().next()?.into_iter().next()
+    }
+
+    fn device_usage(&self) -> Result<Vec<DeviceUsage>> {
+        self.query_gpus("utilization.gpu,memory.used")?
+            .iter()
+            .zip(&self.devices)
+            .map(|(row, device)| {
+                Ok(DeviceUsage {
+                    index: device.index,
+                    mig_instance: None,
+                    gpu_percent: number(row.first()).unwrap_or(0),
+                    memory_bytes: number::<u64>(row.get(1)).unwrap_or(0) * 2u64.pow(20),
+                })
+            })
+            .collect()
+    }
+
+    fn device_thermals(&self) -> Result<Vec<DeviceThermals>> {
+        self.query_gpus("temperature.gpu,power.draw,fan.speed")?
+            .iter()
+            .zip(&self.devices)
+            .map(|(row, device)| {
+                Ok(DeviceThermals {
+                    temperature_c: number(row.first()).ok_or_else(|| {
+                        eyre!(
+                            "nvidia-smi reported no temperature for GPU {}",
+                            device.index
+                        )
+                    })?,
+                    power_watts: number(row.get(1)),
+                    fan_percent: number(row.get(2)),
+                })
+            })
+            .collect()
+    }
+
+    fn pid_trees_engines_by_device(
+        &mut self,
+        tree: &HashSet<Pid>,
+    ) -> Result<Vec<HashMap<Pid, GpuEngines>>> {
+        let output = nvidia_smi(&["pmon", "--count", "1", "--select", "u"])?;
+
+        let mut by_device = vec![HashMap::new(); self.devices.len()];
+        for (index, pid, engines) in parse_pmon(&output)? {
+            if let Some(position) = self.position(index)
+                && tree.contains(&pid)
+            {
+                *by_device[position].entry(pid).or_default() += engines;
+            }
+        }
+        log::info!(
+            "GPU engines by device and pid (nvidia-smi): {:?}",
+            by_device
+        );
+
+        Ok(by_device)
+    }
+
+    fn pid_trees_memory_by_device(&self, tree: &HashSet<Pid>) -> Result<Vec<HashMap<Pid, u64>>> {
+        let output = nvidia_smi(&[
+            "--query-compute-apps=gpu_uuid,pid,used_memory",
+            "--format=csv,noheader,nounits",
+        ])?;
+
+        let mut by_device = vec![HashMap::new(); self.devices.len()];
+        for (uuid, pid, bytes) in parse_compute_apps(&output)? {
+            let position = self.devices.iter().position(|device| device.uuid == uuid);
+            if let Some(position) = position
+                && tree.contains(&pid)
+            {
+                *by_device[position].entry(pid).or_insert(0) += bytes;
+            }
+        }
+        log::info!("GPU memory by device and pid (nvidia-smi): {:?}", by_device);
+
+        Ok(by_device)
+    }
+}
+
+fn nvidia_smi(args: &[&str]) -> Result<String> {
+    let output = Command::new("nvidia-smi")
+        .args(args)
+        .output()
+        .wrap_err("Failed to run `nvidia-smi`")?;
+    if !output.status.success() {
+        // nvidia-smi explains most failures on stdout
+        bail!(
+            "`nvidia-smi` failed ({}): {}",
+            output.status,
+            String::from_utf8_lossy(&output.stdout).trim()
+        )
+    }
+    Ok(from_utf8(&output.stdout)?.to_string())
+}
+
+/// A number from `nvidia-smi` CSV output, `None` for `[N/A]`, `[Not Supported]` and the like
+fn number<T: std::str::FromStr>(value: Option<&String>) -> Option<T> {
+    let value = value?.trim();
+    // Fractional where only whole numbers are expected, e.g. power in watts
+    value
+        .parse()
+        .ok()
+        .or_else(|| value.split('.').next()?.parse().ok())
+}
+
+/**
+ * Output of `nvidia-smi --query-gpu=index,uuid,name --format=csv,noheader`
+ */
+pub(crate) fn parse_devices(output: &str) -> Result<Vec<SmiDevice>> {
+    output
+        .lines()
+        .filter(|line| !line.trim().is_empty())
+        .map(|line| {
+            // Names are last, as they could contain commas
+            let mut fields = line.splitn(3, ',').map(str::trim);
+            let index = fields.next().and_then(|index| index.parse().ok());
+            match (index, fields.next(), fields.next()) {
+                (Some(index), Some(uuid), Some(name)) => Ok(SmiDevice {
+                    index,
+                    uuid: uuid.to_string(),
+                    name: name.to_string(),
+                }),
+                _ => bail!("Unexpected nvidia-smi output: {}", line),
+            }
+        })
+        .collect()
+}
+
+/**
+ * Device index, process and engine utilisation from each row of
+ * `nvidia-smi pmon --count 1 --select u`.  Columns are found from the header, which varies
+ * between driver versions, and rows for devices with no processes are skipped.
+ */
+pub(crate) fn parse_pmon(output: &str) -> Result<Vec<(u32, Pid, GpuEngines)>> {
+    let header: Vec<&str> = output
+        .lines()
+        .find(|line| line.starts_with('#') && line.contains("pid"))
+        .ok_or_eyre("No header in nvidia-smi pmon output")?
+        .trim_start_matches('#')
+        .split_whitespace()
+        .collect();
+    let column = |name: &str| header.iter().position(|&field| field == name);
+    let (Some(gpu), Some(pid)) = (column("gpu"), column("pid")) else {
+        bail!("Unexpected nvidia-smi pmon header: {}", header.join(" "))
+    };
+    let (sm, mem, enc, dec) = (column("sm"), column("mem"), column("enc"), column("dec"));
+
+    let mut rows = Vec::new();
+    for line in output.lines().filter(|line| !line.starts_with('#')) {
+        let fields: Vec<String> = line.split_whitespace().map(str::to_string).collect();
+        if fields.is_empty() {
+            continue;
+        }
+        let (Some(index), Some(process)) = (
+            number::<u32>(fields.get(gpu)),
+            number::<u32>(fields.get(pid)),
+        ) else {
+            continue;
+        };
+        // "-" where an engine wasn't used
+        let percent = |column: Option<usize>| column.and_then(|c| number(fields.get(c)));
+        rows.push((
+            index,
+            Pid::from_u32(process),
+            GpuEngines {
+                sm_percent: percent(sm).unwrap_or(0),
+                memory_percent: percent(mem).unwrap_or(0),
+                encoder_percent: percent(enc).unwrap_or(0),
+                decoder_percent: percent(dec).unwrap_or(0),
+            },
+        ));
+    }
+    Ok(rows)
+}
+
+/**
+ * Device UUID, process and memory in bytes from each row of
+ * `nvidia-smi --query-compute-apps=gpu_uuid,pid,used_memory --format=csv,noheader,nounits`
+ */
+pub(crate) fn parse_compute_apps(output: &str) -> Result<Vec<(String, Pid, u64)>> {
+    output
+        .lines()
+        .filter(|line| !line.trim().is_empty())
+        .map(|line| {
+            let fields: Vec<String> = line.split(',').map(|f| f.trim().to_string()).collect();
+            match (fields.first(), number::<u32>(fields.get(1))) {
+                (Some(uuid), Some(pid)) => Ok((
+                    uuid.clone(),
+                    Pid::from_u32(pid),
+                    // Memory isn't always available, e.g. without permission
+                    number::<u64>(fields.get(2)).unwrap_or(0) * 2u64.pow(20),
+                )),
+                _ => bail!("Unexpected nvidia-smi output: {}", line),
+            }
+        })
+        .collect()
+}
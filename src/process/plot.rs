@@ -0,0 +1,145 @@
+use std::path::Path;
+
+use color_eyre::{Result, eyre::eyre};
+use plotters::prelude::*;
+
+use super::trace::{Column, UsageTrace};
+
+const PANEL_HEIGHT: u32 = 300;
+const WIDTH: u32 = 1000;
+/// Colour of the earlier run when two are overlaid
+const BASELINE_COLOUR: RGBColor = RGBColor(150, 150, 150);
+
+/// A chart of one usage column
+pub(crate) struct Panel {
+    pub(crate) title: &'static str,
+    pub(crate) column: Column,
+    pub(crate) colour: RGBColor,
+}
+
+pub(crate) const PANELS: [Panel; 3] = [
+    Panel {
+        title: "CPU (%)",
+        column: |row| row.cpu_percent,
+        colour: RGBColor(31, 119, 180),
+    },
+    Panel {
+        title: "RAM (MiB)",
+        column: |row| row.ram_mb,
+        colour: RGBColor(255, 127, 14),
+    },
+    Panel {
+        title: "GPU (%)",
+        column: |row| row.gpu_percent,
+        colour: RGBColor(44, 160, 44),
+    },
+];
+
+/**
+ * Render CPU, RAM and (if recorded) GPU time series as stacked SVG charts
+ */
+pub fn plot_usage<P: AsRef<Path>>(trace: &UsageTrace, out_file: P) -> Result<()> {
+    plot_traces(&[("", trace)], out_file.as_ref())
+}
+
+/**
+ * Render two runs' time series on the same stacked SVG charts, aligned by elapsed time, with
+ * the old run in grey
+ */
+pub fn plot_comparison<P: AsRef<Path>>(
+    old: (&str, &UsageTrace),
+    new: (&str, &UsageTrace),
+    out_file: P,
+) -> Result<()> {
+    plot_traces(&[old, new], out_file.as_ref())
+}
+
+/**
+ * The last trace is drawn in each panel's colour and any earlier ones in grey.  A legend is
+ * added if there's more than one.
+ */
+fn plot_traces(traces: &[(&str, &UsageTrace)], out_file: &Path) -> Result<()> {
+    let is_svg = out_file
+        .extension()
+        .map(|ext| ext.eq_ignore_ascii_case("svg"))
+        .unwrap_or(false);
+    if !is_svg {
+        return Err(eyre!(
+            "Only SVG output is supported: {}",
+            out_file.display()
+        ));
+    }
+
+    let panels = PANELS
+        .iter()
+        .map(|panel| {
+            let series = traces
+                .iter()
+                .map(|(_, trace)| trace.series(panel.column))
+                .collect::<Vec<_>>();
+            (panel, series)
+        })
+        .filter(|(_, series)| series.iter().any(|s| !s.is_empty()))
+        .collect::<Vec<_>>();
+    if panels.is_empty() {
+        return Err(eyre!("No usage data to plot"));
+    }
+
+    let max_elapsed = traces
+        .iter()
+        .flat_map(|(_, trace)| &trace.rows)
+        .map(|row| row.elapsed_seconds)
+        .fold(1.0, f64::max);
+
+    let root =
+        SVGBackend::new(out_file, (WIDTH, PANEL_HEIGHT * panels.len() as u32)).into_drawing_area();
+    root.fill(&WHITE).map_err(plot_error)?;
+
+    for ((panel, series), area) in panels.iter().zip(root.split_evenly((panels.len(), 1))) {
+        let max_value = series.iter().flatten().map(|(_, v)| *v).fold(1.0, f64::max) * 1.1;
+
+        let mut chart = ChartBuilder::on(&area)
+            .caption(panel.title, ("sans-serif", 20))
+            .margin(10)
+            .x_label_area_size(35)
+            .y_label_area_size(60)
+            .build_cartesian_2d(0.0..max_elapsed, 0.0..max_value)
+            .map_err(plot_error)?;
+
+        chart
+            .configure_mesh()
+            .x_desc("elapsed seconds")
+            .draw()
+            .map_err(plot_error)?;
+
+        for (i, ((label, _), series)) in traces.iter().zip(series).enumerate() {
+            let colour = if i + 1 == traces.len() {
+                panel.colour
+            } else {
+                BASELINE_COLOUR
+            };
+            chart
+                .draw_series(LineSeries::new(series.iter().copied(), &colour))
+                .map_err(plot_error)?
+                .label(*label)
+                .legend(move |(x, y)| PathElement::new([(x, y), (x + 20, y)], colour));
+        }
+        if traces.len() > 1 {
+            chart
+                .configure_series_labels()
+                .background_style(WHITE.mix(0.8))
+                .border_style(BLACK)
+                .draw()
+                .map_err(plot_error)?;
+        }
+    }
+
+    root.present().map_err(plot_error)?;
+    log::info!("Plot written to {}", out_file.display());
+
+    Ok(())
+}
+
+fn plot_error<E: std::error::Error + Send + Sync + 'static>(e: E) -> color_eyre::eyre::Error {
+    eyre!("Failed to draw plot: {}", e)
+}
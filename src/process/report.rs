@@ -0,0 +1,255 @@
+use std::{fmt::Write as _, fs, path::Path};
+
+use color_eyre::{
+    Result,
+    eyre::{Context, eyre},
+};
+use plotters::style::RGBColor;
+
+use crate::output::units::format_size;
+
+use super::{
+    metadata::RunMetadata,
+    plot::{PANELS, Panel},
+    trace::UsageTrace,
+};
+
+const CHART_WIDTH: f64 = 960.0;
+const CHART_HEIGHT: f64 = 240.0;
+/// Space left of the plot area for the value axis labels
+const LEFT_MARGIN: f64 = 60.0;
+/// Space below the plot area for the elapsed time axis labels
+const BOTTOM_MARGIN: f64 = 30.0;
+const TICKS: usize = 5;
+
+const STYLE: &str = "
+body { font-family: sans-serif; margin: 2em auto; max-width: 1000px; color: #222; }
+table { border-collapse: collapse; margin-bottom: 1.5em; }
+th, td { padding: 0.25em 1em 0.25em 0; text-align: left; vertical-align: top; }
+td.number { text-align: right; }
+code { word-break: break-all; }
+.chart { position: relative; }
+.chart svg { font-size: 12px; }
+.tooltip { position: absolute; display: none; background: #fff; border: 1px solid #888;
+  padding: 2px 6px; pointer-events: none; font-size: 12px; white-space: nowrap; }
+";
+
+/// Shows the value of the nearest sample under the pointer
+const SCRIPT: &str = "
+document.querySelectorAll('.chart').forEach(chart => {
+  const svg = chart.querySelector('svg');
+  const marker = svg.querySelector('.marker');
+  const tooltip = chart.querySelector('.tooltip');
+  const points = JSON.parse(chart.dataset.points);
+  const unit = chart.dataset.unit;
+  svg.addEventListener('mousemove', event => {
+    const box = svg.getBoundingClientRect();
+    const x = (event.clientX - box.left) * svg.viewBox.baseVal.width / box.width;
+    let nearest = points[0];
+    for (const point of points) {
+      if (Math.abs(point[0] - x) < Math.abs(nearest[0] - x)) nearest = point;
+    }
+    marker.setAttribute('cx', nearest[0]);
+    marker.setAttribute('cy', nearest[1]);
+    marker.style.display = 'inline';
+    tooltip.textContent = nearest[2] + ' s: ' + nearest[3] + ' ' + unit;
+    tooltip.style.left = (nearest[0] * box.width / svg.viewBox.baseVal.width + 10) + 'px';
+    tooltip.style.top = (nearest[1] * box.height / svg.viewBox.baseVal.height - 10) + 'px';
+    tooltip.style.display = 'block';
+  });
+  svg.addEventListener('mouseleave', () => {
+    marker.style.display = 'none';
+    tooltip.style.display = 'none';
+  });
+});
+";
+
+/**
+ * Write a single HTML file with the run's metadata, summary statistics and an interactive
+ * chart for each recorded column.  It has no external dependencies, so can be attached to a
+ * merge request or experiment tracker as is.
+ */
+pub fn write_report<P: AsRef<Path>>(
+    title: &str,
+    trace: &UsageTrace,
+    metadata: Option<&RunMetadata>,
+    out_file: P,
+) -> Result<()> {
+    let out_file = out_file.as_ref();
+    if trace.rows.is_empty() {
+        return Err(eyre!("No usage data to report"));
+    }
+
+    let mut html = String::new();
+    writeln!(
+        html,
+        "<!DOCTYPE html>\n<html lang=\"en\">\n<head>\n<meta charset=\"utf-8\">\n\
+         <title>{0}</title>\n<style>{1}</style>\n</head>\n<body>\n<h1>{0}</h1>",
+        escape(title),
+        STYLE
+    )?;
+
+    if let Some(metadata) = metadata {
+        html.push_str("<h2>Run</h2>\n<table>\n");
+        let mut row = |name: &str, value: String| {
+            writeln!(html, "<tr><th>{}</th><td>{}</td></tr>", name, value)
+        };
+        row(
+            "Command line",
+            format!("<code>{}</code>", escape(&metadata.command_line.join(" "))),
+        )?;
+        row("Started", escape(&metadata.start_time))?;
+        row("Host", optional(metadata.hostname.as_deref()))?;
+        row("User", optional(metadata.username.as_deref()))?;
+        row(
+            "CPU",
+            format!(
+                "{} ({} logical cores)",
+                optional(metadata.cpu_model.as_deref()),
+                metadata.cpu_count
+            ),
+        )?;
+        row("Memory", format_size(metadata.total_memory_bytes))?;
+        if !metadata.gpu_models.is_empty() {
+            row("GPUs", escape(&metadata.gpu_models.join(", ")))?;
+        }
+        if let Some(driver) = &metadata.gpu_driver_version {
+            let cuda = metadata
+                .cuda_version
+                .as_deref()
+                .map(|cuda| format!(" (CUDA {})", escape(cuda)))
+                .unwrap_or_default();
+            row("GPU driver", format!("{}{}", escape(driver), cuda))?;
+        }
+        html.push_str("</table>\n");
+    }
+
+    let panels: Vec<&Panel> = PANELS
+        .iter()
+        .filter(|panel| !trace.series(panel.column).is_empty())
+        .collect();
+
+    html.push_str("<h2>Summary</h2>\n<table>\n");
+    let format = |value: Option<f64>| match value {
+        Some(value) => format!("{:.1}", value),
+        None => "NA".to_string(),
+    };
+    writeln!(
+        html,
+        "<tr><th>Samples</th><td class=\"number\">{}</td></tr>\n\
+         <tr><th>Wall clock (s)</th><td class=\"number\">{}</td></tr>",
+        trace.rows.len(),
+        format(trace.duration_seconds())
+    )?;
+    html.push_str("</table>\n<table>\n<tr><th></th><th>Peak</th><th>Mean</th></tr>\n");
+    for panel in &panels {
+        writeln!(
+            html,
+            "<tr><th>{}</th><td class=\"number\">{}</td><td class=\"number\">{}</td></tr>",
+            escape(panel.title),
+            format(trace.peak(panel.column)),
+            format(trace.mean(panel.column))
+        )?;
+    }
+    html.push_str("</table>\n<h2>Usage</h2>\n");
+
+    let max_elapsed = trace
+        .rows
+        .iter()
+        .map(|row| row.elapsed_seconds)
+        .fold(1.0, f64::max);
+    for panel in &panels {
+        chart(&mut html, panel, &trace.series(panel.column), max_elapsed)?;
+    }
+
+    writeln!(html, "<script>{}</script>\n</body>\n</html>", SCRIPT)?;
+
+    fs::write(out_file, html)
+        .wrap_err_with(|| format!("Failed to write report to {}", out_file.display()))?;
+    log::info!("Report written to {}", out_file.display());
+
+    Ok(())
+}
+
+/**
+ * Append an SVG line chart, carrying its points for the hover script
+ */
+fn chart(
+    html: &mut String,
+    panel: &Panel,
+    series: &[(f64, f64)],
+    max_elapsed: f64,
+) -> std::fmt::Result {
+    let max_value = series.iter().map(|(_, v)| *v).fold(1.0, f64::max) * 1.1;
+    let plot_width = CHART_WIDTH - LEFT_MARGIN;
+    let plot_height = CHART_HEIGHT - BOTTOM_MARGIN;
+    let x = |elapsed: f64| LEFT_MARGIN + elapsed / max_elapsed * plot_width;
+    let y = |value: f64| plot_height - value / max_value * plot_height;
+
+    let points: Vec<String> = series
+        .iter()
+        .map(|&(elapsed, value)| {
+            format!(
+                "[{:.1},{:.1},{},{:.1}]",
+                x(elapsed),
+                y(value),
+                elapsed,
+                value
+            )
+        })
+        .collect();
+    let unit = match panel.title.split_once('(') {
+        Some((_, unit)) => unit.trim_end_matches(')'),
+        None => "",
+    };
+    let RGBColor(r, g, b) = panel.colour;
+
+    writeln!(
+        html,
+        "<h3>{}</h3>\n<div class=\"chart\" data-unit=\"{}\" data-points=\"[{}]\">\n\
+         <svg viewBox=\"0 0 {} {}\" width=\"100%\">",
+        escape(panel.title),
+        escape(unit),
+        points.join(","),
+        CHART_WIDTH,
+        CHART_HEIGHT
+    )?;
+    for tick in 0..=TICKS {
+        let fraction = tick as f64 / TICKS as f64;
+        let value_y = plot_height - fraction * plot_height;
+        let elapsed_x = LEFT_MARGIN + fraction * plot_width;
+        writeln!(
+            html,
+            "<line x1=\"{LEFT_MARGIN}\" x2=\"{CHART_WIDTH}\" y1=\"{value_y:.1}\" \
+             y2=\"{value_y:.1}\" stroke=\"#ddd\"/>\
+             <text x=\"{:.1}\" y=\"{:.1}\" text-anchor=\"end\">{:.1}</text>\
+             <text x=\"{elapsed_x:.1}\" y=\"{CHART_HEIGHT}\" text-anchor=\"middle\">{:.0}</text>",
+            LEFT_MARGIN - 5.0,
+            value_y + 4.0,
+            fraction * max_value,
+            fraction * max_elapsed
+        )?;
+    }
+    let line: Vec<String> = series
+        .iter()
+        .map(|&(elapsed, value)| format!("{:.1},{:.1}", x(elapsed), y(value)))
+        .collect();
+    writeln!(
+        html,
+        "<polyline fill=\"none\" stroke=\"rgb({r},{g},{b})\" stroke-width=\"2\" points=\"{}\"/>\n\
+         <circle class=\"marker\" r=\"4\" fill=\"rgb({r},{g},{b})\" style=\"display: none\"/>\n\
+         </svg>\n<div class=\"tooltip\"></div>\n</div>",
+        line.join(" ")
+    )
+}
+
+fn optional(value: Option<&str>) -> String {
+    value.map(escape).unwrap_or_else(|| "NA".to_string())
+}
+
+fn escape(text: &str) -> String {
+    text.replace('&', "&amp;")
+        .replace('<', "&lt;")
+        .replace('>', "&gt;")
+        .replace('"', "&quot;")
+}
@@ -0,0 +1,111 @@
+use std::time::{Duration, Instant};
+
+use crate::process::system::CpuRamUsage;
+
+/// CPU swing, in percentage points, which counts as a rapid change
+const CPU_CHANGE_THRESHOLD: f32 = 25.0;
+/// Relative RAM swing which counts as a rapid change
+const RAM_CHANGE_THRESHOLD: f64 = 0.1;
+
+/// Sampling interval which is short while a job starts up or its usage is changing, and
+/// doubles (up to a limit) through steady phases, keeping long runs' output manageable.
+#[derive(Debug)]
+pub struct AdaptiveInterval {
+    min: Duration,
+    max: Duration,
+    warmup: Duration,
+    current: Duration,
+    last: Option<CpuRamUsage>,
+}
+
+impl AdaptiveInterval {
+    /**
+     * Sample every `min` for the first `warmup`, backing off towards `max` afterwards
+     */
+    pub fn new(min: Duration, max: Duration, warmup: Duration) -> Self {
+        Self {
+            min,
+            max: max.max(min),
+            warmup,
+            current: min,
+            last: None,
+        }
+    }
+
+    pub fn current(&self) -> Duration {
+        self.current
+    }
+
+    /**
+     * Pause before the next sample, given the latest and how long we've been running
+     */
+    pub fn next(&mut self, elapsed: Duration, usage: &CpuRamUsage) -> Duration {
+        let changing = self
+            .last
+            .as_ref()
+            .map(|last| is_rapid_change(last, usage))
+            .unwrap_or(false);
+        self.last = Some(usage.clone());
+
+        self.current = if elapsed < self.warmup || changing {
+            self.min
+        } else {
+            (self.current * 2).min(self.max)
+        };
+        self.current
+    }
+}
+
+fn is_rapid_change(last: &CpuRamUsage, now: &CpuRamUsage) -> bool {
+    let cpu_change = (now.cpu_percent - last.cpu_percent).abs();
+    let ram_change = now.memory_bytes.abs_diff(last.memory_bytes) as f64;
+    let ram_base = last.memory_bytes.max(1) as f64;
+
+    cpu_change >= CPU_CHANGE_THRESHOLD || ram_change / ram_base >= RAM_CHANGE_THRESHOLD
+}
+
+/// Deadlines for samples at a fixed cadence.  Each is set from the one before rather than
+/// from when sampling finished, so the time spent sampling doesn't make the interval drift.
+#[derive(Debug, Clone)]
+pub struct Schedule {
+    next: Instant,
+}
+
+impl Schedule {
+    /**
+     * First sample due `interval` after `start`
+     */
+    pub fn new(start: Instant, interval: Duration) -> Self {
+        Self {
+            next: start + interval,
+        }
+    }
+
+    /// When the next sample is due
+    pub fn deadline(&self) -> Instant {
+        self.next
+    }
+
+    /**
+     * Pause until the next sample is due, zero if it's overdue
+     */
+    pub fn remaining(&self, now: Instant) -> Duration {
+        self.next.saturating_duration_since(now)
+    }
+
+    /**
+     * Move on to the sample `interval` after the one just taken.  If sampling overran so that
+     * deadline has passed too, those missed are skipped rather than taken in a burst, keeping
+     * to the cadence.  Returns how many were skipped.
+     */
+    pub fn advance(&mut self, interval: Duration, now: Instant) -> u32 {
+        self.next += interval;
+        if interval.is_zero() || self.next >= now {
+            return 0;
+        }
+        let missed = (now - self.next).as_nanos() / interval.as_nanos() + 1;
+        let missed = u32::try_from(missed).unwrap_or(u32::MAX);
+        self.next += interval * missed;
+        missed
+    }
+}
@@ -0,0 +1,43 @@
+use std::sync::{
+    Arc,
+    atomic::{AtomicBool, AtomicUsize, Ordering},
+};
+
+use color_eyre::{Result, eyre::Context};
+use signal_hook::consts::{SIGINT, SIGTERM};
+use sysinfo::Signal;
+
+/// Catches SIGINT/SIGTERM so they can be passed on to the monitored command and monitoring
+/// wound up cleanly.  A second signal exits immediately, in case winding up gets stuck.
+pub struct SignalCatcher {
+    received: Arc<AtomicUsize>,
+}
+
+impl SignalCatcher {
+    pub fn install() -> Result<Self> {
+        let received = Arc::new(AtomicUsize::new(0));
+        let caught_one = Arc::new(AtomicBool::new(false));
+        for signal in [SIGINT, SIGTERM] {
+            // Registered first, so it only acts on a second signal
+            signal_hook::flag::register_conditional_shutdown(signal, 1, Arc::clone(&caught_one))
+                .wrap_err("Failed to install signal handler")?;
+            signal_hook::flag::register(signal, Arc::clone(&caught_one))
+                .wrap_err("Failed to install signal handler")?;
+            signal_hook::flag::register_usize(signal, Arc::clone(&received), signal as usize)
+                .wrap_err("Failed to install signal handler")?;
+        }
+
+        Ok(Self { received })
+    }
+
+    /**
+     * The signal caught, if any
+     */
+    pub fn received(&self) -> Option<Signal> {
+        match self.received.load(Ordering::Relaxed) as i32 {
+            0 => None,
+            SIGINT => Some(Signal::Interrupt),
+            _ => Some(Signal::Term),
+        }
+    }
+}
@@ -0,0 +1,213 @@
+use std::{
+    collections::{BTreeSet, HashMap},
+    fmt::Display,
+    time::Duration,
+};
+
+use sysinfo::Pid;
+
+use crate::output::units::format_size;
+
+use super::{
+    gpu::MigInstance,
+    system::{CpuRamUsage, ProcessUsage},
+};
+
+const JOULES_PER_KWH: f64 = 3.6e6;
+
+/// Headline figures for a monitoring run
+#[derive(Debug, Clone, PartialEq, serde::Serialize)]
+pub struct RunSummary {
+    pub wall_clock_seconds: f64,
+    pub samples: usize,
+    pub peak_cpu_percent: f32,
+    pub mean_cpu_percent: f32,
+    pub peak_ram_bytes: u64,
+    pub peak_gpu_percent: Option<u32>,
+    pub mean_gpu_percent: Option<f32>,
+    /// MIG instances the tree used, space separated
+    pub mig_instances: Option<String>,
+    pub io_read_bytes: u64,
+    pub io_written_bytes: u64,
+    /// Machine-wide CPU, DRAM and GPU energy, if measured
+    pub energy_kwh: Option<f64>,
+    /// Of the monitored command, if we started it
+    pub exit_code: Option<i32>,
+    /// When the monitored process tree was seen to exit, if it did
+    pub exit_time: Option<String>,
+    /// Why monitoring ended early (e.g. a timeout), if it did
+    pub stop_reason: Option<String>,
+}
+
+impl Display for RunSummary {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        let wall_clock = Duration::from_secs(self.wall_clock_seconds.round() as u64);
+        writeln!(f, "Run summary ({} samples):", self.samples)?;
+        writeln!(
+            f,
+            "  wall clock: {}",
+            humantime::format_duration(wall_clock)
+        )?;
+        writeln!(
+            f,
+            "  CPU: peak {:.1}%, mean {:.1}%",
+            self.peak_cpu_percent, self.mean_cpu_percent
+        )?;
+        writeln!(f, "  RAM: peak {}", format_size(self.peak_ram_bytes))?;
+        match (self.peak_gpu_percent, self.mean_gpu_percent) {
+            (Some(peak), Some(mean)) => writeln!(f, "  GPU: peak {}%, mean {:.1}%", peak, mean)?,
+            _ => writeln!(f, "  GPU: NA")?,
+        }
+        if let Some(mig_instances) = &self.mig_instances {
+            writeln!(f, "  MIG instances: {}", mig_instances)?;
+        }
+        if let Some(energy_kwh) = self.energy_kwh {
+            writeln!(f, "  energy: {:.4} kWh", energy_kwh)?;
+        }
+        write!(
+            f,
+            "  I/O: read {}, written {}",
+            format_size(self.io_read_bytes),
+            format_size(self.io_written_bytes)
+        )?;
+        if let Some(exit_code) = self.exit_code {
+            write!(f, "\n  exit code: {}", exit_code)?;
+        }
+        if let Some(exit_time) = &self.exit_time {
+            write!(f, "\n  exited at: {}", exit_time)?;
+        }
+        if let Some(stop_reason) = &self.stop_reason {
+            write!(f, "\n  stopped: {}", stop_reason)?;
+        }
+        Ok(())
+    }
+}
+
+/// Accumulates samples into a [`RunSummary`]
+#[derive(Debug, Default)]
+pub struct SummaryAccumulator {
+    samples: usize,
+    cpu_sum: f64,
+    peak_cpu_percent: f32,
+    peak_ram_bytes: u64,
+    gpu_samples: usize,
+    gpu_sum: f64,
+    peak_gpu_percent: Option<u32>,
+    mig_instances: BTreeSet<MigInstance>,
+    energy_joules: Option<f64>,
+    io_baseline: HashMap<Pid, (u64, u64)>,
+    io_latest: HashMap<Pid, (u64, u64)>,
+    /// Baseline and latest totals for a whole group, which replace the per-process figures
+    group_io: Option<((u64, u64), (u64, u64))>,
+}
+
+impl SummaryAccumulator {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    pub fn add_sample(&mut self, cpu_ram: &CpuRamUsage, gpu_percent: Option<u32>) {
+        self.samples += 1;
+        self.cpu_sum += cpu_ram.cpu_percent as f64;
+        self.peak_cpu_percent = self.peak_cpu_percent.max(cpu_ram.cpu_percent);
+        self.peak_ram_bytes = self.peak_ram_bytes.max(cpu_ram.memory_bytes);
+
+        if let Some(gpu_percent) = gpu_percent {
+            self.gpu_samples += 1;
+            self.gpu_sum += gpu_percent as f64;
+            self.peak_gpu_percent = self.peak_gpu_percent.max(Some(gpu_percent));
+        }
+    }
+
+    pub fn add_mig_instances(&mut self, mig_instances: &[MigInstance]) {
+        self.mig_instances.extend(mig_instances);
+    }
+
+    pub fn add_energy(&mut self, joules: f64) {
+        *self.energy_joules.get_or_insert(0.0) += joules;
+    }
+
+    /**
+     * Track disk I/O of the processes in the tree.  I/O done before monitoring began is
+     * excluded when `exclude_prior_io` is set, otherwise a process's lifetime I/O counts.
+     */
+    pub fn add_processes(&mut self, processes: &[ProcessUsage], exclude_prior_io: bool) {
+        let first_sample = self.io_latest.is_empty();
+        for process in processes {
+            let totals = (process.total_read_bytes, process.total_written_bytes);
+            if first_sample && exclude_prior_io {
+                self.io_baseline.entry(process.pid).or_insert(totals);
+            }
+            self.io_latest.insert(process.pid, totals);
+        }
+    }
+
+    /**
+     * Track disk I/O totals of a whole group of processes, such as a cgroup, instead of
+     * individual processes.
+     */
+    pub fn add_group_io(&mut self, read_bytes: u64, written_bytes: u64, exclude_prior_io: bool) {
+        let totals = (read_bytes, written_bytes);
+        let baseline = match self.group_io {
+            Some((baseline, _)) => baseline,
+            None if exclude_prior_io => totals,
+            None => (0, 0),
+        };
+        self.group_io = Some((baseline, totals));
+    }
+
+    pub fn finish(&self, wall_clock: Duration) -> RunSummary {
+        let (io_read_bytes, io_written_bytes) = match self.group_io {
+            Some(((base_read, base_written), (read, written))) => (
+                read.saturating_sub(base_read),
+                written.saturating_sub(base_written),
+            ),
+            None => self.process_io(),
+        };
+
+        RunSummary {
+            wall_clock_seconds: wall_clock.as_secs_f64(),
+            samples: self.samples,
+            peak_cpu_percent: self.peak_cpu_percent,
+            mean_cpu_percent: mean(self.cpu_sum, self.samples),
+            peak_ram_bytes: self.peak_ram_bytes,
+            peak_gpu_percent: self.peak_gpu_percent,
+            mean_gpu_percent: (self.gpu_samples > 0).then(|| mean(self.gpu_sum, self.gpu_samples)),
+            mig_instances: (!self.mig_instances.is_empty()).then(|| {
+                self.mig_instances
+                    .iter()
+                    .map(MigInstance::to_string)
+                    .collect::<Vec<_>>()
+                    .join(" ")
+            }),
+            io_read_bytes,
+            io_written_bytes,
+            energy_kwh: self.energy_joules.map(|joules| joules / JOULES_PER_KWH),
+            exit_code: None,
+            exit_time: None,
+            stop_reason: None,
+        }
+    }
+
+    fn process_io(&self) -> (u64, u64) {
+        self.io_latest
+            .iter()
+            .map(|(pid, (read, written))| {
+                let (base_read, base_written) =
+                    self.io_baseline.get(pid).copied().unwrap_or_default();
+                (
+                    read.saturating_sub(base_read),
+                    written.saturating_sub(base_written),
+                )
+            })
+            .fold((0, 0), |(acc_r, acc_w), (r, w)| (acc_r + r, acc_w + w))
+    }
+}
+
+fn mean(sum: f64, count: usize) -> f32 {
+    if count == 0 {
+        0.0
+    } else {
+        (sum / count as f64) as f32
+    }
+}
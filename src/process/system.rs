@@ -1,104 +1,827 @@
-use std::collections::HashSet;
+use std::{
+    collections::{BTreeMap, HashMap, HashSet},
+    iter::Sum,
+    ops::Add,
+    time::{Duration, Instant},
+};
 
+use regex::Regex;
 use sysinfo::{
-    Pid, Process, ProcessRefreshKind, ProcessesToUpdate, System as SysInfoSystem, ThreadKind,
+    Pid, Process, ProcessRefreshKind, ProcessStatus, ProcessesToUpdate, Signal,
+    System as SysInfoSystem, ThreadKind, UpdateKind,
 };
 
+use crate::process::metadata::ProcessSnapshot;
+
+/// How often process trees are found by scanning every process, rather than only refreshing
+/// the processes already known to be in them
+const DEFAULT_FULL_REFRESH_INTERVAL: Duration = Duration::from_secs(5);
+/// Units of the CPU times in `/proc/PID/stat` (`USER_HZ`), which Linux fixes at 100
+const CLOCK_TICKS_PER_SECOND: f64 = 100.0;
+
+/// How a process's memory is counted
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default)]
+pub enum MemoryMode {
+    /// Resident set size.  Pages shared between processes count towards each of them.
+    #[default]
+    Rss,
+    /// Proportional set size: shared pages are divided between the processes sharing them,
+    /// so summing over a tree doesn't double count (Linux only)
+    Pss,
+    /// Unique set size: only pages private to the process (Linux only)
+    Uss,
+}
+
+/// Which tasks in a tree are reported.  A thread shares its process's memory, and the
+/// process's CPU time already includes its threads', so counting both double counts.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default)]
+pub enum ThreadAccounting {
+    /// One entry per process, its CPU including that of all its threads
+    #[default]
+    Processes,
+    /// One entry per thread (Linux only).  A process's own entry keeps its memory and I/O
+    /// but only the CPU of its main thread, so tree totals match [`Processes`].
+    ///
+    /// [`Processes`]: ThreadAccounting::Processes
+    Threads,
+    /// Every process and thread exactly as the OS reports them (Linux only), so the CPU and
+    /// memory of multi-threaded processes are counted several times over.  Only useful to
+    /// match tools which do the same.
+    Tasks,
+}
+
 pub struct System {
     sys_info: SysInfoSystem,
+    /// Processes in the trees last found, refreshed on their own between full scans
+    tracked: Vec<Pid>,
+    last_full_refresh: Option<Instant>,
+    full_refresh_interval: Duration,
+    memory_mode: MemoryMode,
+    thread_accounting: ThreadAccounting,
 }
 
 impl System {
     pub fn new() -> Self {
         let mut instance = Self {
             sys_info: SysInfoSystem::new(),
+            tracked: Vec::new(),
+            last_full_refresh: None,
+            full_refresh_interval: DEFAULT_FULL_REFRESH_INTERVAL,
+            memory_mode: MemoryMode::default(),
+            thread_accounting: ThreadAccounting::default(),
         };
         instance.sys_info.refresh_all();
         instance
     }
 
+    /**
+     * Between full scans of every process, only processes already in a monitored tree are
+     * refreshed, which is much cheaper on busy machines.  Children started in between are
+     * picked up at the next full scan.  Zero scans everything every time.
+     */
+    pub fn set_full_refresh_interval(&mut self, interval: Duration) {
+        self.full_refresh_interval = interval;
+    }
+
+    /**
+     * How process memory is counted.  PSS and USS are read from `/proc/PID/smaps_rollup`,
+     * falling back to RSS where that can't be read.
+     */
+    pub fn set_memory_mode(&mut self, memory_mode: MemoryMode) {
+        self.memory_mode = memory_mode;
+    }
+
+    /**
+     * Whether threads get their own entries in per-process usage.  Process trees, as used for
+     * GPU attribution and signalling, are made of processes whatever this is set to.
+     */
+    pub fn set_thread_accounting(&mut self, thread_accounting: ThreadAccounting) {
+        self.thread_accounting = thread_accounting;
+    }
+
     pub fn refresh_process_stats(&mut self) {
+        self.refresh_processes(ProcessesToUpdate::All);
+    }
+
+    /**
+     * Refresh only the given processes, forgetting any which have exited
+     */
+    pub fn refresh_pids(&mut self, pids: &[Pid]) {
+        self.refresh_processes(ProcessesToUpdate::Some(pids));
+    }
+
+    fn refresh_processes(&mut self, processes: ProcessesToUpdate) {
         self.sys_info.refresh_processes_specifics(
-            ProcessesToUpdate::All,
+            processes,
             true,
             ProcessRefreshKind::nothing()
                 .with_memory()
                 .with_cpu()
-                .with_tasks(),
+                .with_tasks()
+                .with_disk_usage(),
         );
     }
 
+    /**
+     * Refresh enough to find the trees rooted at `roots`: everything if a full scan is due,
+     * otherwise just the roots and the processes found in the trees last time
+     */
+    fn refresh_for_trees(&mut self, roots: &[Pid]) {
+        let full_refresh_due = self
+            .last_full_refresh
+            .is_none_or(|last| last.elapsed() >= self.full_refresh_interval);
+        if full_refresh_due {
+            self.refresh_process_stats();
+            self.last_full_refresh = Some(Instant::now());
+        } else {
+            // sysinfo forgets a process listed twice, as the second look finds it already updated
+            let mut pids = self.tracked.clone();
+            pids.extend(roots.iter().filter(|pid| !self.tracked.contains(pid)));
+            self.refresh_pids(&pids);
+        }
+    }
+
     pub fn total_memory(&self) -> u64 {
         self.sys_info.total_memory()
     }
 
+    pub fn cpu_count(&self) -> usize {
+        self.sys_info.cpus().len()
+    }
+
+    /**
+     * Name of the user running this process
+     */
+    pub fn current_username(&self) -> Option<String> {
+        let pid = sysinfo::get_current_pid().ok()?;
+        let user_id = self.sys_info.process(pid)?.user_id()?;
+        let users = sysinfo::Users::new_with_refreshed_list();
+        users
+            .get_user_by_id(user_id)
+            .map(|user| user.name().to_string())
+    }
+
+    pub fn cpu_model(&self) -> Option<String> {
+        self.sys_info
+            .cpus()
+            .first()
+            .map(|cpu| cpu.brand().trim().to_string())
+    }
+
+    /**
+     * Mean current clock speed across cores, if the platform reports it
+     */
+    pub fn cpu_frequency_mhz(&mut self) -> Option<u64> {
+        self.sys_info.refresh_cpu_frequency();
+        let cpus = self.sys_info.cpus();
+        let total: u64 = cpus.iter().map(|cpu| cpu.frequency()).sum();
+        // Reported as zero where unavailable, e.g. some VMs
+        (total > 0).then(|| total / cpus.len() as u64)
+    }
+
+    /**
+     * One minute load average, where the platform has one
+     */
+    pub fn load_average(&self) -> Option<f64> {
+        (!cfg!(windows)).then(|| SysInfoSystem::load_average().one)
+    }
+
+    /**
+     * Whole-machine usage.  CPU is scaled so that 100% is one fully used core, matching the
+     * per-process figures.
+     */
+    pub fn get_system_utilisation(&mut self) -> SystemUsage {
+        self.sys_info.refresh_cpu_usage();
+        self.sys_info.refresh_memory();
+
+        let usage = SystemUsage {
+            cpu_ram: CpuRamUsage {
+                cpu_percent: self.sys_info.global_cpu_usage() * self.sys_info.cpus().len() as f32,
+                memory_bytes: self.sys_info.used_memory(),
+                virtual_memory_bytes: None,
+                shared_memory_bytes: None,
+            },
+            swap_bytes: self.sys_info.used_swap(),
+        };
+        log::info!("System -> {:?}", usage);
+
+        usage
+    }
+
     pub fn get_pid_tree_utilisation(&mut self, pid: Pid) -> CpuRamUsage {
-        let children = self.get_pid_tree(pid, true);
-        log::trace!("Descendants of {}: {:#?}", pid, &children);
+        self.get_pid_tree_process_usage(pid)
+            .into_iter()
+            .map(|p| p.usage)
+            .sum()
+    }
 
-        children
-            .iter()
+    /**
+     * Usage of each individual process in the tree rooted at `pid`
+     */
+    pub fn get_pid_tree_process_usage(&mut self, pid: Pid) -> Vec<ProcessUsage> {
+        self.get_pid_trees_process_usage(&[pid])
+    }
+
+    /**
+     * Usage of each individual process in the trees rooted at `roots`, each counted once,
+     * along with their threads as set by [`System::set_thread_accounting`]
+     */
+    pub fn get_pid_trees_process_usage(&mut self, roots: &[Pid]) -> Vec<ProcessUsage> {
+        let children = self.get_pid_trees(roots);
+        log::trace!("Descendants of {:?}: {:#?}", roots, &children);
+        self.get_tree_process_usage(&children)
+    }
+
+    /**
+     * As [`System::get_pid_trees_process_usage`], for the processes already found in `tree`
+     * by [`System::get_pid_trees`], without refreshing them again.  sysinfo measures CPU since
+     * the last refresh, so refreshing twice in one sample would shorten that to the gap between.
+     */
+    pub fn get_tree_process_usage(&self, tree: &HashSet<Pid>) -> Vec<ProcessUsage> {
+        tree.iter()
             .filter_map(|pid| {
                 let proc_opt = self.sys_info.process(*pid);
                 log::trace!("Found child: {:?}", proc_opt.map(|p| p.pid()));
                 proc_opt
             })
-            .map(|proc| {
-                let usage = CpuRamUsage {
-                    cpu_percent: proc.cpu_usage(),
-                    memory_bytes: proc.memory(),
-                };
-                log::info!("{} -> {:?}", proc.pid(), usage);
-                usage
+            .flat_map(|proc| {
+                let mut usage = self.process_usage(proc);
+                let threads = proc
+                    .tasks()
+                    .into_iter()
+                    .flatten()
+                    .filter_map(|tid| self.sys_info.process(*tid));
+                match self.thread_accounting {
+                    ThreadAccounting::Processes => vec![usage],
+                    ThreadAccounting::Threads => {
+                        let threads: Vec<ProcessUsage> = threads
+                            .map(|thread| thread_usage(thread, proc.pid()))
+                            .collect();
+                        let thread_cpu: f32 = threads.iter().map(|t| t.usage.cpu_percent).sum();
+                        usage.usage.cpu_percent = (usage.usage.cpu_percent - thread_cpu).max(0.0);
+                        std::iter::once(usage).chain(threads).collect()
+                    }
+                    ThreadAccounting::Tasks => std::iter::once(usage)
+                        .chain(threads.map(|thread| ProcessUsage {
+                            thread_of: Some(proc.pid()),
+                            thread_count: None,
+                            ..self.process_usage(thread)
+                        }))
+                        .collect(),
+                }
             })
-            .sum()
+            .collect()
+    }
+
+    fn process_usage(&self, proc: &Process) -> ProcessUsage {
+        let status = read_proc_file(proc.pid(), "status");
+        let stat = read_proc_file(proc.pid(), "stat");
+        let usage = CpuRamUsage {
+            cpu_percent: proc.cpu_usage(),
+            memory_bytes: process_memory(proc, self.memory_mode),
+            virtual_memory_bytes: Some(proc.virtual_memory()),
+            shared_memory_bytes: status.as_deref().and_then(parse_shared_memory),
+        };
+        log::info!("{} -> {:?}", proc.pid(), usage);
+        let disk = proc.disk_usage();
+        ProcessUsage {
+            pid: proc.pid(),
+            // sysinfo keeps the name a process had when first seen, i.e. before any exec
+            name: status
+                .as_deref()
+                .and_then(parse_status_name)
+                .map(String::from)
+                .unwrap_or_else(|| proc.name().to_string_lossy().into_owned()),
+            usage,
+            total_read_bytes: disk.total_read_bytes,
+            total_written_bytes: disk.total_written_bytes,
+            fd_count: proc.open_files(),
+            swap_bytes: status.as_deref().and_then(parse_vm_swap),
+            counters: read_counters(status.as_deref(), stat.as_deref()),
+            cpu_times: stat.as_deref().and_then(parse_cpu_times),
+            thread_of: None,
+            thread_count: status.as_deref().and_then(parse_thread_count),
+        }
+    }
+
+    pub fn get_pid_tree(&mut self, root_pid: Pid) -> HashSet<Pid> {
+        self.get_pid_trees(&[root_pid])
     }
 
-    pub fn get_pid_tree(&mut self, root_pid: Pid, exclude_userland: bool) -> HashSet<Pid> {
-        self.refresh_process_stats();
+    /**
+     * Processes in the trees rooted at `roots`.  Threads are never included: they appear in
+     * sysinfo as children of their process, but are accounted for through it.
+     */
+    pub fn get_pid_trees(&mut self, roots: &[Pid]) -> HashSet<Pid> {
+        self.refresh_for_trees(roots);
 
-        fn find_children(
-            pid: Pid,
-            sys_info: &SysInfoSystem,
-            exclude_userland: bool,
-        ) -> HashSet<Pid> {
-            let children_it = sys_info
+        let sys_info = &self.sys_info;
+        let acc = walk_pid_trees(roots, || {
+            sys_info
                 .processes()
                 .iter()
-                .filter(|(_pid, proc)| proc.parent().map(|ppid| ppid == pid).unwrap_or(false));
-
-            let children_it: Box<dyn Iterator<Item = (&Pid, &Process)>> = if exclude_userland {
-                // Filter out processes in userland
-                Box::new(children_it.filter(|(_, proc)| {
+                .filter(|(_, proc)| {
                     proc.thread_kind()
                         .map(|k| k != ThreadKind::Userland)
                         .unwrap_or(true)
-                }))
-            } else {
-                // Keep all child processes
-                Box::new(children_it)
-            };
+                })
+                .map(|(&pid, proc)| (pid, proc.parent()))
+        });
 
-            children_it.map(|(&pid, _)| pid).collect()
+        self.tracked = acc.iter().copied().collect();
+        if self.thread_accounting != ThreadAccounting::Processes {
+            // Threads are only refreshed between full scans if they're asked for by TID
+            let threads = acc
+                .iter()
+                .filter_map(|pid| self.sys_info.process(*pid))
+                .flat_map(|proc| proc.tasks().into_iter().flatten());
+            self.tracked.extend(threads);
         }
+        acc
+    }
 
-        let mut to_visit: Vec<Pid> = vec![root_pid];
-        let mut acc: HashSet<Pid> = HashSet::new();
+    /**
+     * Processes whose command line (or name, if the command line is unavailable) matches
+     * `pattern`.  Threads, the current process and its ancestors are excluded.
+     */
+    pub fn find_matching_pids(&mut self, pattern: &Regex) -> Vec<Pid> {
+        // Command lines change on exec, so always re-read them.  CPU isn't refreshed here
+        // to avoid shortening the window over which the next CPU sample is measured.
+        self.sys_info.refresh_processes_specifics(
+            ProcessesToUpdate::All,
+            true,
+            ProcessRefreshKind::nothing()
+                .with_tasks()
+                .with_cmd(UpdateKind::Always),
+        );
 
-        while let Some(pid) = to_visit.pop() {
-            acc.insert(pid);
-            to_visit.extend(find_children(pid, &self.sys_info, exclude_userland));
+        let mut excluded: HashSet<Pid> = HashSet::new();
+        let mut next = sysinfo::get_current_pid().ok();
+        while let Some(pid) = next {
+            if !excluded.insert(pid) {
+                break;
+            }
+            next = self.sys_info.process(pid).and_then(|proc| proc.parent());
         }
 
-        acc
+        let mut pids: Vec<Pid> = self
+            .sys_info
+            .processes()
+            .values()
+            .filter(|proc| !excluded.contains(&proc.pid()))
+            .filter(|proc| {
+                proc.thread_kind()
+                    .map(|k| k != ThreadKind::Userland)
+                    .unwrap_or(true)
+            })
+            .filter(|proc| {
+                let cmd = proc
+                    .cmd()
+                    .iter()
+                    .map(|arg| arg.to_string_lossy())
+                    .collect::<Vec<_>>()
+                    .join(" ");
+                if cmd.is_empty() {
+                    pattern.is_match(&proc.name().to_string_lossy())
+                } else {
+                    pattern.is_match(&cmd)
+                }
+            })
+            .map(|proc| proc.pid())
+            .collect();
+        pids.sort();
+
+        pids
     }
 
     /**
-     * Assumes process stats were recently refreshed
+     * Command line of `pid` and those of the `env_names` environment variables it has set.
+     * Both are read afresh, as they change on exec.  `None` if the process has gone.
+     */
+    pub fn process_snapshot(&mut self, pid: Pid, env_names: &[String]) -> Option<ProcessSnapshot> {
+        self.sys_info.refresh_processes_specifics(
+            ProcessesToUpdate::Some(&[pid]),
+            false,
+            ProcessRefreshKind::nothing()
+                .with_cmd(UpdateKind::Always)
+                .with_environ(UpdateKind::Always),
+        );
+        let proc = self.sys_info.process(pid)?;
+        let environment = proc
+            .environ()
+            .iter()
+            .filter_map(|var| var.to_str()?.split_once('='))
+            .filter(|(name, _)| env_names.iter().any(|n| n == name))
+            .map(|(name, value)| (name.to_string(), value.to_string()))
+            .collect();
+        Some(ProcessSnapshot {
+            pid: pid.as_u32(),
+            command_line: proc
+                .cmd()
+                .iter()
+                .map(|arg| arg.to_string_lossy().into_owned())
+                .collect(),
+            environment,
+        })
+    }
+
+    /**
+     * Send `signal` to each of `pids`, returning how many were successfully signalled.
+     */
+    pub fn signal_pids(&self, pids: &HashSet<Pid>, signal: Signal) -> usize {
+        pids.iter()
+            .filter_map(|pid| self.sys_info.process(*pid))
+            .filter(|proc| {
+                let sent = proc.kill_with(signal).unwrap_or(false);
+                log::debug!("Sent {:?} to {}: {}", signal, proc.pid(), sent);
+                sent
+            })
+            .count()
+    }
+
+    /**
+     * Send `signal` (e.g. SIGTERM) to every process in the trees rooted at `roots`, then
+     * SIGKILL any which are still alive after `grace`.
+     */
+    pub fn terminate_pid_trees(&mut self, roots: &[Pid], signal: Signal, grace: Duration) {
+        // Collect the tree up front, as descendants are reparented once their parent exits
+        let pids = self.get_pid_trees(roots);
+
+        // e.g. on Windows, where there's no equivalent of SIGTERM
+        if !sysinfo::SUPPORTED_SIGNALS.contains(&signal) {
+            let sent = self.signal_pids(&pids, Signal::Kill);
+            log::warn!("Killed {} of {} processes", sent, pids.len());
+            return;
+        }
+
+        let sent = self.signal_pids(&pids, signal);
+        log::warn!("Sent {:?} to {} of {} processes", signal, sent, pids.len());
+
+        let deadline = Instant::now() + grace;
+        let pid_list: Vec<Pid> = pids.iter().copied().collect();
+        loop {
+            self.refresh_pids(&pid_list);
+            let remaining: HashSet<Pid> = pids
+                .iter()
+                .copied()
+                .filter(|pid| self.pid_is_alive(*pid))
+                .collect();
+
+            if remaining.is_empty() {
+                return;
+            }
+            if Instant::now() >= deadline {
+                let sent = self.signal_pids(&remaining, Signal::Kill);
+                log::warn!(
+                    "Sent SIGKILL to {} processes still running after {:?}",
+                    sent,
+                    grace
+                );
+                return;
+            }
+            std::thread::sleep(Duration::from_millis(100));
+        }
+    }
+
+    /**
+     * Assumes process stats were recently refreshed.  Zombies are treated as dead.
      */
     pub fn pid_is_alive(&mut self, pid: Pid) -> bool {
         let t = self.sys_info.process(pid);
-        t.is_some()
+        t.map(|proc| proc.status() != ProcessStatus::Zombie)
+            .unwrap_or(false)
+    }
+}
+
+/**
+ * PIDs in the trees rooted at `roots`, given an iterator over every process and its parent
+ */
+pub fn walk_pid_trees<I>(roots: &[Pid], processes: impl Fn() -> I) -> HashSet<Pid>
+where
+    I: Iterator<Item = (Pid, Option<Pid>)>,
+{
+    let mut to_visit: Vec<Pid> = roots.to_vec();
+    let mut acc: HashSet<Pid> = HashSet::new();
+
+    while let Some(pid) = to_visit.pop() {
+        if acc.insert(pid) {
+            to_visit.extend(
+                processes()
+                    .filter(|(_, parent)| *parent == Some(pid))
+                    .map(|(child, _)| child),
+            );
+        }
+    }
+    acc
+}
+
+/**
+ * A thread's own CPU.  Its memory, I/O and descriptors belong to its process, so aren't
+ * repeated.
+ */
+fn thread_usage(thread: &Process, pid: Pid) -> ProcessUsage {
+    ProcessUsage {
+        pid: thread.pid(),
+        name: thread.name().to_string_lossy().into_owned(),
+        usage: CpuRamUsage {
+            cpu_percent: thread.cpu_usage(),
+            ..Default::default()
+        },
+        total_read_bytes: 0,
+        total_written_bytes: 0,
+        fd_count: None,
+        swap_bytes: None,
+        counters: None,
+        cpu_times: None,
+        thread_of: Some(pid),
+        thread_count: None,
+    }
+}
+
+fn read_proc_file(pid: Pid, file: &str) -> Option<String> {
+    std::fs::read_to_string(format!("/proc/{}/{}", pid, file)).ok()
+}
+
+fn process_memory(proc: &Process, memory_mode: MemoryMode) -> u64 {
+    let smaps_opt = match memory_mode {
+        MemoryMode::Rss => return proc.memory(),
+        MemoryMode::Pss | MemoryMode::Uss => read_proc_file(proc.pid(), "smaps_rollup"),
+    };
+    let rollup_opt = smaps_opt.as_deref().and_then(parse_smaps_rollup);
+    match (memory_mode, rollup_opt) {
+        (MemoryMode::Pss, Some(rollup)) => rollup.pss_bytes,
+        (MemoryMode::Uss, Some(rollup)) => rollup.uss_bytes,
+        _ => {
+            log::debug!("No smaps_rollup for {}, using RSS", proc.pid());
+            proc.memory()
+        }
+    }
+}
+
+/**
+ * Value of a `Name:   123 kB` line, as found in `/proc/PID/status` and `smaps_rollup`
+ */
+fn parse_kb_field(text: &str, name: &str) -> Option<u64> {
+    let kb: u64 = text
+        .lines()
+        .find_map(|line| line.strip_prefix(name)?.strip_prefix(':'))?
+        .trim()
+        .strip_suffix("kB")?
+        .trim()
+        .parse()
+        .ok()?;
+    Some(kb * 1024)
+}
+
+/**
+ * `Name` from `/proc/PID/status`
+ */
+pub(crate) fn parse_status_name(status: &str) -> Option<&str> {
+    status
+        .lines()
+        .find_map(|line| line.strip_prefix("Name:"))
+        .map(str::trim)
+}
+
+/**
+ * `Threads` from `/proc/PID/status`
+ */
+pub(crate) fn parse_thread_count(status: &str) -> Option<u64> {
+    status
+        .lines()
+        .find_map(|line| line.strip_prefix("Threads:"))?
+        .trim()
+        .parse()
+        .ok()
+}
+
+/**
+ * `VmSwap` from `/proc/PID/status`, which sysinfo doesn't report
+ */
+pub(crate) fn parse_vm_swap(status: &str) -> Option<u64> {
+    parse_kb_field(status, "VmSwap")
+}
+
+/**
+ * Resident file-backed and shared memory (`RssFile` + `RssShmem`) from `/proc/PID/status`
+ */
+pub(crate) fn parse_shared_memory(status: &str) -> Option<u64> {
+    Some(parse_kb_field(status, "RssFile")? + parse_kb_field(status, "RssShmem")?)
+}
+
+/// Memory figures from `/proc/PID/smaps_rollup`
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct SmapsRollup {
+    pub pss_bytes: u64,
+    /// Private clean and dirty pages
+    pub uss_bytes: u64,
+}
+
+pub(crate) fn parse_smaps_rollup(smaps: &str) -> Option<SmapsRollup> {
+    Some(SmapsRollup {
+        pss_bytes: parse_kb_field(smaps, "Pss")?,
+        uss_bytes: parse_kb_field(smaps, "Private_Clean")?
+            + parse_kb_field(smaps, "Private_Dirty")?,
+    })
+}
+
+fn read_counters(status: Option<&str>, stat: Option<&str>) -> Option<ProcessCounters> {
+    let (voluntary_switches, involuntary_switches) = parse_context_switches(status?)?;
+    let major_faults = parse_major_faults(stat?)?;
+    Some(ProcessCounters {
+        voluntary_switches,
+        involuntary_switches,
+        major_faults,
+    })
+}
+
+/**
+ * Voluntary and involuntary context switches from `/proc/PID/status`
+ */
+pub(crate) fn parse_context_switches(status: &str) -> Option<(u64, u64)> {
+    let field = |name: &str| -> Option<u64> {
+        status
+            .lines()
+            .find_map(|line| line.strip_prefix(name))?
+            .trim()
+            .parse()
+            .ok()
+    };
+    Some((
+        field("voluntary_ctxt_switches:")?,
+        field("nonvoluntary_ctxt_switches:")?,
+    ))
+}
+
+/**
+ * `majflt` from `/proc/PID/stat`.  The command name may contain spaces or brackets, so
+ * fields are counted from the last `)`.
+ */
+pub(crate) fn parse_major_faults(stat: &str) -> Option<u64> {
+    let (_, fields) = stat.rsplit_once(')')?;
+    fields.split_whitespace().nth(9)?.parse().ok()
+}
+
+/**
+ * `utime` and `stime` from `/proc/PID/stat`, plus `cutime` and `cstime`: the time of
+ * children which have exited and been waited for, so short-lived children still count
+ */
+pub(crate) fn parse_cpu_times(stat: &str) -> Option<CpuTimes> {
+    let (_, fields) = stat.rsplit_once(')')?;
+    let ticks: Vec<u64> = fields
+        .split_whitespace()
+        .skip(11)
+        .take(4)
+        .map(|field| field.parse().ok())
+        .collect::<Option<_>>()?;
+    let [user, system, children_user, children_system] = ticks[..] else {
+        return None;
+    };
+    Some(CpuTimes {
+        user_seconds: (user + children_user) as f64 / CLOCK_TICKS_PER_SECOND,
+        system_seconds: (system + children_system) as f64 / CLOCK_TICKS_PER_SECOND,
+    })
+}
+
+/**
+ * Single letter state (e.g. `R`, `S`, `Z`) from `/proc/PID/stat`
+ */
+pub(crate) fn parse_process_state(stat: &str) -> Option<char> {
+    let (_, fields) = stat.rsplit_once(')')?;
+    fields.split_whitespace().next()?.chars().next()
+}
+
+/**
+ * Whether a process has exited, judged without reaping it, so a child can still be sampled
+ * as a zombie.  `None` where this can't be told (anywhere but Linux).
+ */
+pub(crate) fn has_exited_unreaped(pid: Pid) -> Option<bool> {
+    if !cfg!(target_os = "linux") {
+        return None;
+    }
+    match read_proc_file(pid, "stat") {
+        Some(stat) => Some(parse_process_state(&stat) == Some('Z')),
+        None => Some(true),
+    }
+}
+
+/// Bytes resident on each NUMA node, by node number
+pub type NumaBytes = BTreeMap<u32, u64>;
+
+/**
+ * Resident memory of `pid` on each NUMA node, from `/proc/PID/numa_maps`.  `None` where
+ * that can't be read, e.g. on kernels built without NUMA support.
+ */
+pub(crate) fn read_numa_bytes(pid: Pid) -> Option<NumaBytes> {
+    read_proc_file(pid, "numa_maps")
+        .as_deref()
+        .map(parse_numa_maps)
+}
+
+/**
+ * Sum the `N<node>=<pages>` counts of each mapping, scaled by the mapping's page size
+ */
+pub(crate) fn parse_numa_maps(numa_maps: &str) -> NumaBytes {
+    let mut nodes = NumaBytes::new();
+    for line in numa_maps.lines() {
+        let mut page_bytes = 4096;
+        let mut pages = Vec::new();
+        for field in line.split_whitespace() {
+            if let Some(kb) = field.strip_prefix("kernelpagesize_kB=") {
+                page_bytes = kb.parse::<u64>().map_or(page_bytes, |kb| kb * 1024);
+            } else if let Some((node, count)) =
+                field.strip_prefix('N').and_then(|f| f.split_once('='))
+                && let (Ok(node), Ok(count)) = (node.parse::<u32>(), count.parse::<u64>())
+            {
+                pages.push((node, count));
+            }
+        }
+        for (node, count) in pages {
+            *nodes.entry(node).or_default() += count * page_bytes;
+        }
+    }
+    nodes
+}
+
+/**
+ * Share of the last 10 seconds in which some tasks were stalled waiting for memory, from
+ * Linux pressure stall information.  A rising figure means the machine is thrashing.
+ */
+pub(crate) fn read_memory_pressure() -> Option<f64> {
+    let pressure = std::fs::read_to_string("/proc/pressure/memory").ok()?;
+    parse_pressure_avg10(&pressure)
+}
+
+pub(crate) fn parse_pressure_avg10(pressure: &str) -> Option<f64> {
+    pressure
+        .lines()
+        .find(|line| line.starts_with("some "))?
+        .split_whitespace()
+        .find_map(|field| field.strip_prefix("avg10="))?
+        .parse()
+        .ok()
+}
+
+/**
+ * Running totals of how often the CPUs have been throttled, from the Intel
+ * `thermal_throttle` counters Linux exposes in sysfs.  `None` where they aren't available.
+ */
+pub(crate) fn read_throttle_counts() -> Option<ThrottleCounts> {
+    let mut counts: Option<ThrottleCounts> = None;
+    for entry in std::fs::read_dir("/sys/devices/system/cpu").ok()?.flatten() {
+        let dir = entry.path().join("thermal_throttle");
+        if !dir.is_dir() {
+            continue;
+        }
+        let read = |file: &str| -> u64 {
+            std::fs::read_to_string(dir.join(file))
+                .ok()
+                .and_then(|count| count.trim().parse().ok())
+                .unwrap_or(0)
+        };
+
+        let counts = counts.get_or_insert_default();
+        // Package counters are repeated for every core in the package, so take the maximum
+        counts.thermal += read("core_throttle_count");
+        counts.package_thermal = counts.package_thermal.max(read("package_throttle_count"));
+        counts.power += read("core_power_limit_count");
+        counts.package_power = counts.package_power.max(read("package_power_limit_count"));
+    }
+    counts
+}
+
+#[derive(Debug, Default, Clone, Copy, PartialEq)]
+pub struct ThrottleCounts {
+    thermal: u64,
+    package_thermal: u64,
+    power: u64,
+    package_power: u64,
+}
+
+impl ThrottleCounts {
+    pub fn new(thermal: u64, package_thermal: u64, power: u64, package_power: u64) -> Self {
+        Self {
+            thermal,
+            package_thermal,
+            power,
+            package_power,
+        }
+    }
+
+    /**
+     * Thermal throttling events since `earlier`
+     */
+    pub fn thermal_since(&self, earlier: &ThrottleCounts) -> u64 {
+        self.thermal.saturating_sub(earlier.thermal)
+            + self.package_thermal.saturating_sub(earlier.package_thermal)
+    }
+
+    /**
+     * Power limit throttling events since `earlier`
+     */
+    pub fn power_since(&self, earlier: &ThrottleCounts) -> u64 {
+        self.power.saturating_sub(earlier.power)
+            + self.package_power.saturating_sub(earlier.package_power)
     }
 }
 
@@ -108,8 +831,155 @@ impl Default for System {
     }
 }
 
-#[derive(derive_more::Add, derive_more::Sum, serde::Serialize, Debug)]
+#[derive(serde::Serialize, Debug, Default, Clone)]
 pub struct CpuRamUsage {
     pub cpu_percent: f32,
+    /// Resident memory, counted as set by [`MemoryMode`]
     pub memory_bytes: u64,
+    /// Address space mapped, whether or not it's resident
+    pub virtual_memory_bytes: Option<u64>,
+    /// Resident pages backed by files or shared memory, which other processes may also
+    /// map (Linux only)
+    pub shared_memory_bytes: Option<u64>,
+}
+
+impl Add for CpuRamUsage {
+    type Output = Self;
+
+    fn add(self, other: Self) -> Self {
+        // Processes which don't report a figure are left out of the total, rather than
+        // making it unknown
+        let add_optional = |a: Option<u64>, b: Option<u64>| match (a, b) {
+            (Some(a), Some(b)) => Some(a + b),
+            (a, b) => a.or(b),
+        };
+        Self {
+            cpu_percent: self.cpu_percent + other.cpu_percent,
+            memory_bytes: self.memory_bytes + other.memory_bytes,
+            virtual_memory_bytes: add_optional(
+                self.virtual_memory_bytes,
+                other.virtual_memory_bytes,
+            ),
+            shared_memory_bytes: add_optional(self.shared_memory_bytes, other.shared_memory_bytes),
+        }
+    }
+}
+
+impl Sum for CpuRamUsage {
+    fn sum<I: Iterator<Item = Self>>(iter: I) -> Self {
+        iter.fold(Self::default(), Add::add)
+    }
+}
+
+#[derive(Debug)]
+pub struct ProcessUsage {
+    pub pid: Pid,
+    pub name: String,
+    pub usage: CpuRamUsage,
+    /// Disk I/O over the lifetime of the process
+    pub total_read_bytes: u64,
+    pub total_written_bytes: u64,
+    /// Open file descriptors, where the platform reports them
+    pub fd_count: Option<usize>,
+    /// Swapped out memory (Linux only)
+    pub swap_bytes: Option<u64>,
+    /// Linux only
+    pub counters: Option<ProcessCounters>,
+    /// Since the process started, including children it has waited for (Linux only)
+    pub cpu_times: Option<CpuTimes>,
+    /// The process this is a thread of, if it's a thread rather than a process
+    pub thread_of: Option<Pid>,
+    /// Threads in the process, including its main thread (Linux only)
+    pub thread_count: Option<u64>,
+}
+
+/// CPU time used, split between user and kernel mode
+#[derive(derive_more::Add, derive_more::Sum, Debug, Default, Clone, Copy, PartialEq)]
+pub struct CpuTimes {
+    pub user_seconds: f64,
+    pub system_seconds: f64,
+}
+
+impl CpuTimes {
+    /**
+     * The larger of each figure.  Totals over a tree can dip when a process leaves it, so
+     * this keeps them cumulative.
+     */
+    pub fn max(self, other: Self) -> Self {
+        Self {
+            user_seconds: self.user_seconds.max(other.user_seconds),
+            system_seconds: self.system_seconds.max(other.system_seconds),
+        }
+    }
+}
+
+/// Cumulative scheduling and paging counts for a process
+#[derive(derive_more::Add, derive_more::Sum, Debug, Default, Clone, Copy, PartialEq)]
+pub struct ProcessCounters {
+    pub voluntary_switches: u64,
+    pub involuntary_switches: u64,
+    pub major_faults: u64,
+}
+
+/// Turns per-process cumulative counters into totals for the interval between samples
+#[derive(Debug)]
+pub struct CounterDeltas {
+    last: HashMap<Pid, ProcessCounters>,
+    first_sample: bool,
+}
+
+impl CounterDeltas {
+    pub fn new() -> Self {
+        Self {
+            last: HashMap::new(),
+            first_sample: true,
+        }
+    }
+
+    /**
+     * Increase in the tree's counters since the last call.  Processes seen for the first
+     * time count in full, except on the first call, which only sets the baseline.  `None`
+     * if no process reports counters.
+     */
+    pub fn update(&mut self, processes: &[ProcessUsage]) -> Option<ProcessCounters> {
+        let mut latest = HashMap::new();
+        let mut total: Option<ProcessCounters> = None;
+        for process in processes {
+            let Some(counters) = process.counters else {
+                continue;
+            };
+            let baseline = match self.last.get(&process.pid) {
+                Some(last) => *last,
+                None if self.first_sample => counters,
+                None => ProcessCounters::default(),
+            };
+            let delta = ProcessCounters {
+                voluntary_switches: counters
+                    .voluntary_switches
+                    .saturating_sub(baseline.voluntary_switches),
+                involuntary_switches: counters
+                    .involuntary_switches
+                    .saturating_sub(baseline.involuntary_switches),
+                major_faults: counters.major_faults.saturating_sub(baseline.major_faults),
+            };
+            total = Some(total.unwrap_or_default() + delta);
+            latest.insert(process.pid, counters);
+        }
+
+        self.last = latest;
+        self.first_sample = false;
+        total
+    }
+}
+
+impl Default for CounterDeltas {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+#[derive(Debug)]
+pub struct SystemUsage {
+    pub cpu_ram: CpuRamUsage,
+    pub swap_bytes: u64,
 }
@@ -0,0 +1,1113 @@
+use std::{
+    collections::{BTreeMap, HashMap, HashSet},
+    path::PathBuf,
+    time::{Duration, Instant},
+};
+
+use sysinfo::Pid;
+
+use crate::output::schema::{UNVERSIONED, USAGE_SCHEMA};
+
+use super::{
+    apple_gpu::{parse_active_residency, parse_process_gpu},
+    bench::{BenchRun, BenchStats, Spread},
+    cgroup::{
+        container_cgroup_path, parse_cpu_list, parse_cpu_max, parse_cpu_times_usec,
+        parse_cpu_usage_usec, parse_io_stat, parse_numa_stat,
+    },
+    compare::{TraceComparison, Verdict},
+    energy::counter_delta,
+    gpu::{
+        DeviceThermals, DeviceUsage, GpuBackend, GpuEngines, MigInstance, sm_by_device, sum_by_pid,
+    },
+    nvidia_smi::{SmiDevice, parse_compute_apps, parse_devices, parse_pmon},
+    report::write_report,
+    sampling::{AdaptiveInterval, Schedule},
+    summary::SummaryAccumulator,
+    system::{
+        CounterDeltas, CpuRamUsage, CpuTimes, ProcessCounters, ProcessUsage, SmapsRollup,
+        ThreadAccounting, ThrottleCounts, parse_context_switches, parse_cpu_times,
+        parse_major_faults, parse_numa_maps, parse_pressure_avg10, parse_process_state,
+        parse_shared_memory, parse_smaps_rollup, parse_status_name, parse_thread_count,
+        parse_vm_swap,
+    },
+    trace::{UsageRow, UsageTrace},
+};
+#[cfg(feature = "nvml")]
+use super::{
+    gpu::{advance_last_seen, format_cuda_version},
+    gpu_info::{Capability, DeviceInfo},
+};
+
+fn process(pid: u32, total_read_bytes: u64, total_written_bytes: u64) -> ProcessUsage {
+    ProcessUsage {
+        pid: Pid::from_u32(pid),
+        name: format!("proc{}", pid),
+        usage: CpuRamUsage {
+            cpu_percent: 0.0,
+            memory_bytes: 0,
+            ..Default::default()
+        },
+        total_read_bytes,
+        total_written_bytes,
+        fd_count: None,
+        swap_bytes: None,
+        counters: None,
+        cpu_times: None,
+        thread_of: None,
+        thread_count: None,
+    }
+}
+
+#[test]
+fn test_summary_peaks_and_means() {
+    let mut acc = SummaryAccumulator::new();
+    acc.add_sample(
+        &CpuRamUsage {
+            cpu_percent: 100.0,
+            memory_bytes: 300,
+            ..Default::default()
+        },
+        Some(20),
+    );
+    acc.add_sample(
+        &CpuRamUsage {
+            cpu_percent: 50.0,
+            memory_bytes: 500,
+            ..Default::default()
+        },
+        Some(80),
+    );
+
+    let summary = acc.finish(Duration::from_secs(2));
+
+    assert_eq!(2, summary.samples);
+    assert_eq!(100.0, summary.peak_cpu_percent);
+    assert_eq!(75.0, summary.mean_cpu_percent);
+    assert_eq!(500, summary.peak_ram_bytes);
+    assert_eq!(Some(80), summary.peak_gpu_percent);
+    assert_eq!(Some(50.0), summary.mean_gpu_percent);
+}
+
+#[test]
+fn test_summary_io_excludes_prior_io_of_initial_processes() {
+    let mut acc = SummaryAccumulator::new();
+    acc.add_processes(&[process(1, 1000, 100)], true);
+    acc.add_processes(&[process(1, 1500, 100), process(2, 10, 20)], true);
+    // Process 1 has exited, but its I/O still counts
+    acc.add_processes(&[process(2, 30, 40)], true);
+
+    let summary = acc.finish(Duration::from_secs(3));
+
+    assert_eq!(500 + 30, summary.io_read_bytes);
+    assert_eq!(40, summary.io_written_bytes);
+    assert_eq!(None, summary.peak_gpu_percent);
+}
+
+#[test]
+fn test_trace_treats_na_as_missing() -> color_eyre::Result<()> {
+    let path = std::env::temp_dir().join("tools_test_trace_treats_na_as_missing.csv");
+    std::fs::write(
+        &path,
+        "timestamp,elapsed_seconds,cpu_percent,ram_percent,ram_mb,gpu_percent\n\
+         2025-05-12 20:06:27,1,0.0,9,470.5,NA\n\
+         2025-05-12 20:06:28,2,180.7,8.8,700.5,12\n",
+    )?;
+
+    let trace = UsageTrace::from_csv(&path)?;
+    std::fs::remove_file(&path)?;
+
+    assert_eq!(UNVERSIONED, trace.schema_version);
+    assert_eq!(2, trace.rows.len());
+    assert_eq!(None, trace.rows[0].swap_mb);
+    assert_eq!(vec![(2.0, 12.0)], trace.series(|row| row.gpu_percent));
+    assert_eq!(
+        vec![(1.0, 0.0), (2.0, 180.7)],
+        trace.series(|row| row.cpu_percent)
+    );
+
+    Ok(())
+}
+
+#[test]
+fn test_trace_reads_semicolon_delimited_csv() -> color_eyre::Result<()> {
+    let path = std::env::temp_dir().join("tools_test_trace_reads_semicolon_delimited_csv.csv");
+    std::fs::write(
+        &path,
+        "schema_version;timestamp;elapsed_seconds;cpu_percent;ram_percent;ram_mb\n\
+         2;2025-05-12 20:06:27;1;50.0;9;470.5\n",
+    )?;
+
+    let trace = UsageTrace::from_csv(&path)?;
+    std::fs::remove_file(&path)?;
+
+    assert_eq!(vec![(1.0, 50.0)], trace.series(|row| row.cpu_percent));
+    Ok(())
+}
+
+#[test]
+fn test_trace_reads_versioned_schemas() -> color_eyre::Result<()> {
+    let path = std::env::temp_dir().join("tools_test_trace_reads_versioned_schemas.csv");
+    std::fs::write(
+        &path,
+        "schema_version,timestamp,elapsed_seconds,cpu_percent,ram_percent,ram_mb,swap_mb,\
+         gpu_percent,fd_count\n\
+         2,2025-05-12 20:06:27,1,50.0,9,470.5,1.5,NA,12\n",
+    )?;
+    let trace = UsageTrace::from_csv(&path);
+
+    std::fs::write(
+        &path,
+        "schema_version,timestamp,elapsed_seconds,cpu_percent,ram_percent,ram_mb\n\
+         99,2025-05-12 20:06:27,1,50.0,9,470.5\n",
+    )?;
+    let newer = UsageTrace::from_csv(&path);
+    std::fs::remove_file(&path)?;
+
+    let trace = trace?;
+    assert_eq!(USAGE_SCHEMA, trace.schema_version);
+    assert_eq!(Some(1.5), trace.rows[0].swap_mb);
+    assert!(newer.unwrap_err().to_string().contains("newer tu"));
+    Ok(())
+}
+
+#[test]
+fn test_compare_weights_samples_by_elapsed_time() {
+    let trace = |samples: &[(f64, f64)]| UsageTrace {
+        schema_version: USAGE_SCHEMA,
+        rows: samples
+            .iter()
+            .map(|&(elapsed_seconds, cpu)| UsageRow {
+                timestamp: String::new(),
+                elapsed_seconds,
+                aggregate: None,
+                cpu_percent: Some(cpu),
+                ram_percent: None,
+                ram_mb: Some(100.0),
+                swap_mb: None,
+                gpu_percent: None,
+            })
+            .collect(),
+    };
+    // Sampled every second, then every 2s
+    let old = trace(&[(1.0, 100.0), (2.0, 100.0), (3.0, 100.0), (4.0, 100.0)]);
+    let new = trace(&[(2.0, 50.0), (4.0, 100.0)]);
+
+    let comparison = TraceComparison::new(&old, &new, 5.0);
+    let metric = |name| {
+        comparison
+            .metrics
+            .iter()
+            .find(|metric| metric.name == name)
+            .unwrap()
+    };
+
+    assert_eq!(Some(75.0), metric("mean CPU (%)").new);
+    assert_eq!(Some(-25.0), metric("mean CPU (%)").change_percent());
+    assert_eq!(Verdict::Better, metric("mean CPU (%)").verdict);
+    assert_eq!(Verdict::Same, metric("peak CPU (%)").verdict);
+    assert_eq!(Verdict::Same, metric("wall clock (s)").verdict);
+    assert_eq!(Verdict::Unknown, metric("peak GPU (%)").verdict);
+
+    // Only the verdict is coloured, so columns still line up
+    let plain = comparison.to_string();
+    let coloured = comparison.render(true);
+    assert!(coloured.contains("\x1b[32m better\x1b[0m"));
+    assert_eq!(plain, coloured.replace("\x1b[32m", "").replace("\x1b[0m", ""));
+}
+
+#[test]
+fn test_report_is_self_contained() -> color_eyre::Result<()> {
+    let path = std::env::temp_dir().join("tools_test_report_is_self_contained.html");
+    let trace = UsageTrace {
+        schema_version: USAGE_SCHEMA,
+        rows: vec![UsageRow {
+            timestamp: String::new(),
+            elapsed_seconds: 1.0,
+            aggregate: None,
+            cpu_percent: Some(50.0),
+            ram_percent: None,
+            ram_mb: Some(100.0),
+            swap_mb: None,
+            gpu_percent: None,
+        }],
+    };
+
+    write_report("<run>", &trace, None, &path)?;
+    let html = std::fs::read_to_string(&path)?;
+    std::fs::remove_file(&path)?;
+
+    assert!(html.contains("<h1>&lt;run&gt;</h1>"));
+    // CPU and RAM charts, but no GPU as none was recorded
+    assert_eq!(2, html.matches("<polyline").count());
+    assert!(!html.contains("src="));
+    assert!(!html.contains("href="));
+
+    Ok(())
+}
+
+#[test]
+fn test_sum_by_pid_combines_devices() {
+    let by_device = vec![
+        HashMap::from([(Pid::from_u32(1), 10u32), (Pid::from_u32(2), 5)]),
+        HashMap::new(),
+        HashMap::from([(Pid::from_u32(1), 30u32)]),
+    ];
+
+    let by_pid = sum_by_pid(&by_device);
+
+    assert_eq!(
+        HashMap::from([(Pid::from_u32(1), 40), (Pid::from_u32(2), 5)]),
+        by_pid
+    );
+}
+
+static POWERMETRICS_OUTPUT: &str = "\
+*** Sampled system activity (Tue Jun  3 10:15:02 2025 +0100) (201.42ms elapsed) ***
+
+*** Running tasks ***
+
+Name                               ID     CPU ms/s  User%  Deadlines (<2 ms, 2-5 ms)  Wakeups (Intr, Pkg idle)  GPU ms/s
+WindowServer                       155    41.43     58.30  0.00    0.00               64.55   9.93              12.41
+python3 train.py                   4242   812.20    97.10  0.00    0.00               3.97    0.00              503.70
+mds_stores                         390    2.11      41.95  0.00    0.00               0.00    0.00              0.00
+ALL_TASKS                          -2     901.10    90.02  0.00    0.00               121.11  17.12             516.11
+
+**** GPU usage ****
+
+GPU HW active frequency: 1296 MHz
+GPU HW active residency:  52.34% (389 MHz:   0% 486 MHz:   0% 648 MHz: 3.1%)
+GPU idle residency:  47.66%
+";
+
+#[test]
+fn test_engines_split_into_sm_and_sum_by_pid() {
+    let engines = |sm_percent, encoder_percent| GpuEngines {
+        sm_percent,
+        memory_percent: 1,
+        encoder_percent,
+        decoder_percent: 0,
+    };
+    let by_device = vec![
+        HashMap::from([(Pid::from_u32(1), engines(10, 40))]),
+        HashMap::from([(Pid::from_u32(1), engines(5, 20))]),
+    ];
+
+    assert_eq!(
+        vec![
+            HashMap::from([(Pid::from_u32(1), 10)]),
+            HashMap::from([(Pid::from_u32(1), 5)]),
+        ],
+        sm_by_device(&by_device)
+    );
+    assert_eq!(
+        GpuEngines {
+            sm_percent: 15,
+            memory_percent: 2,
+            encoder_percent: 60,
+            decoder_percent: 0,
+        },
+        sum_by_pid(&by_device)[&Pid::from_u32(1)]
+    );
+}
+
+#[test]
+fn test_powermetrics_device_utilisation() -> color_eyre::Result<()> {
+    assert_eq!(52, parse_active_residency(POWERMETRICS_OUTPUT)?);
+    Ok(())
+}
+
+#[test]
+fn test_powermetrics_process_utilisation() -> color_eyre::Result<()> {
+    let by_pid = parse_process_gpu(POWERMETRICS_OUTPUT)?;
+
+    assert_eq!(
+        HashMap::from([(Pid::from_u32(155), 1), (Pid::from_u32(4242), 50)]),
+        by_pid
+    );
+    Ok(())
+}
+
+#[test]
+fn test_cgroup_stat_parsing() -> color_eyre::Result<()> {
+    let cpu_stat = "usage_usec 1234567\nuser_usec 1000000\nsystem_usec 234567\n";
+    let io_stat = "\
+8:0 rbytes=1024 wbytes=2048 rios=1 wios=2 dbytes=0 dios=0
+259:0 rbytes=100 wbytes=0 rios=3 wios=0 dbytes=0 dios=0
+";
+
+    assert_eq!(1234567, parse_cpu_usage_usec(cpu_stat)?);
+    assert_eq!((1124, 2048), parse_io_stat(io_stat)?);
+    Ok(())
+}
+
+#[test]
+fn test_container_cgroup_path() {
+    let id = "3f2a9c0b7d1e4f5a6b7c8d9e0f1a2b3c4d5e6f7a8b9c0d1e2f3a4b5c6d7e8f9a";
+    let docker = "0::/system.slice/docker-3f2a9c0b7d1e4f5a6b7c8d9e0f1a2b3c4d5e6f7a8b9c0d1e2f3a4b5c6d7e8f9a.scope\n";
+    let nested = "0::/kubepods/burstable/pod1234/3f2a9c0b7d1e4f5a6b7c8d9e0f1a2b3c4d5e6f7a8b9c0d1e2f3a4b5c6d7e8f9a/init\n";
+
+    assert_eq!(
+        Some(PathBuf::from(format!("system.slice/docker-{}.scope", id))),
+        container_cgroup_path(docker, "3f2a9c")
+    );
+    assert_eq!(
+        Some(PathBuf::from(format!("kubepods/burstable/pod1234/{}", id))),
+        container_cgroup_path(nested, id)
+    );
+    assert_eq!(None, container_cgroup_path(docker, "3f2a9d"));
+    assert_eq!(None, container_cgroup_path("0::/user.slice\n", "3f2a9c"));
+}
+
+#[test]
+fn test_numa_parsing() -> color_eyre::Result<()> {
+    let numa_maps = "\
+55f1d000 default file=/usr/bin/python3 mapped=6 N0=6 kernelpagesize_kB=4
+7f3a0000 interleave:0-1 anon=1024 dirty=1024 N0=512 N1=512 kernelpagesize_kB=4
+7f400000 default anon=2 dirty=2 N1=2 kernelpagesize_kB=2048
+7ffd1000 default stack anon=3 dirty=3 N0=3 kernelpagesize_kB=4
+";
+    assert_eq!(
+        BTreeMap::from([(0, (6 + 512 + 3) * 4096), (1, 512 * 4096 + 2 * 2048 * 1024)]),
+        parse_numa_maps(numa_maps)
+    );
+
+    let numa_stat = "\
+anon N0=1048576 N1=2097152
+file N0=4096 N1=0
+kernel_stack N0=16384 N1=8192
+shmem N0=0 N1=0
+";
+    assert_eq!(
+        BTreeMap::from([(0, 1048576 + 4096), (1, 2097152)]),
+        parse_numa_stat(numa_stat)?
+    );
+    Ok(())
+}
+
+#[test]
+fn test_cpu_limit_parsing() {
+    assert_eq!(Some(4.0), parse_cpu_max("400000 100000\n"));
+    assert_eq!(Some(0.5), parse_cpu_max("50000 100000\n"));
+    assert_eq!(None, parse_cpu_max("max 100000\n"));
+
+    assert_eq!(Some(7), parse_cpu_list("0-3,8,10-11\n"));
+    assert_eq!(Some(1), parse_cpu_list("5"));
+    assert_eq!(None, parse_cpu_list("\n"));
+    assert_eq!(None, parse_cpu_list("3-1"));
+}
+
+#[test]
+fn test_summary_group_io_replaces_process_io() {
+    let mut acc = SummaryAccumulator::new();
+    acc.add_processes(&[process(1, 5000, 5000)], false);
+    acc.add_group_io(100, 200, true);
+    acc.add_group_io(400, 1200, true);
+
+    let summary = acc.finish(Duration::from_secs(1));
+
+    assert_eq!(300, summary.io_read_bytes);
+    assert_eq!(1000, summary.io_written_bytes);
+}
+
+#[test]
+fn test_adaptive_interval_backs_off_and_resets() {
+    let usage = |cpu_percent, memory_bytes| CpuRamUsage {
+        cpu_percent,
+        memory_bytes,
+        ..Default::default()
+    };
+    let secs = Duration::from_secs;
+    let mut adaptive = AdaptiveInterval::new(secs(1), secs(8), secs(60));
+
+    // Fast throughout warm-up
+    assert_eq!(secs(1), adaptive.next(secs(30), &usage(50.0, 1000)));
+    // Then backs off while steady
+    assert_eq!(secs(2), adaptive.next(secs(61), &usage(52.0, 1010)));
+    assert_eq!(secs(4), adaptive.next(secs(63), &usage(51.0, 1000)));
+    assert_eq!(secs(8), adaptive.next(secs(67), &usage(50.0, 1000)));
+    assert_eq!(secs(8), adaptive.next(secs(75), &usage(50.0, 1000)));
+    // Back to fast when things change
+    assert_eq!(secs(1), adaptive.next(secs(83), &usage(95.0, 1000)));
+    assert_eq!(secs(2), adaptive.next(secs(84), &usage(95.0, 1000)));
+    assert_eq!(secs(1), adaptive.next(secs(86), &usage(95.0, 2000)));
+}
+
+#[test]
+fn test_swap_and_pressure_parsing() {
+    let status = "Name:\tpython3\nVmRSS:\t  204800 kB\nVmSwap:\t    1024 kB\nThreads:\t4\n";
+    let pressure = "\
+some avg10=12.50 avg60=3.10 avg300=0.70 total=2862185
+full avg10=4.00 avg60=1.00 avg300=0.20 total=1614211
+";
+
+    assert_eq!(Some(1024 * 1024), parse_vm_swap(status));
+    assert_eq!(None, parse_vm_swap("Name:\tkthreadd\n"));
+    assert_eq!(Some(12.5), parse_pressure_avg10(pressure));
+}
+
+#[test]
+fn test_status_name_and_thread_count_parsing() {
+    let status = "Name:\tpython3\nUmask:\t0022\nState:\tS (sleeping)\nThreads:\t12\n";
+
+    assert_eq!(Some("python3"), parse_status_name(status));
+    assert_eq!(Some(12), parse_thread_count(status));
+    assert_eq!(None, parse_thread_count("Name:\tkthreadd\n"));
+}
+
+#[test]
+fn test_memory_breakdown_sums_figures_which_are_reported() {
+    let status = "VmRSS:\t  204800 kB\nRssAnon:\t  150000 kB\nRssFile:\t   50000 kB\nRssShmem:\t    4800 kB\n";
+    let usage = |virtual_memory_bytes, shared_memory_bytes| CpuRamUsage {
+        cpu_percent: 10.0,
+        memory_bytes: 100,
+        virtual_memory_bytes,
+        shared_memory_bytes,
+    };
+
+    let total: CpuRamUsage = [usage(Some(1000), Some(40)), usage(Some(500), None)]
+        .into_iter()
+        .sum();
+
+    assert_eq!(Some(54800 * 1024), parse_shared_memory(status));
+    assert_eq!(None, parse_shared_memory("Name:\tkthreadd\n"));
+    assert_eq!(20.0, total.cpu_percent);
+    assert_eq!(200, total.memory_bytes);
+    assert_eq!(Some(1500), total.virtual_memory_bytes);
+    assert_eq!(Some(40), total.shared_memory_bytes);
+}
+
+#[test]
+fn test_smaps_rollup_parsing() {
+    let smaps = "\
+55d0c0a00000-7ffc8b5f2000 ---p 00000000 00:00 0                          [rollup]
+Rss:              204800 kB
+Pss:              120000 kB
+Pss_Anon:          90000 kB
+Pss_File:          30000 kB
+Shared_Clean:      80000 kB
+Shared_Dirty:       4800 kB
+Private_Clean:     20000 kB
+Private_Dirty:    100000 kB
+";
+
+    assert_eq!(
+        Some(SmapsRollup {
+            pss_bytes: 120000 * 1024,
+            uss_bytes: 120000 * 1024,
+        }),
+        parse_smaps_rollup(smaps)
+    );
+    assert_eq!(None, parse_smaps_rollup("Rss:  4 kB\n"));
+}
+
+#[test]
+fn test_context_switch_and_fault_parsing() {
+    let status =
+        "State:\tS (sleeping)\nvoluntary_ctxt_switches:\t150\nnonvoluntary_ctxt_switches:\t7\n";
+    let stat = "1234 (my (odd) prog) S 1 1234 1234 0 -1 4194560 2210 0 12 0 5 3 0 0 20 0";
+
+    assert_eq!(Some((150, 7)), parse_context_switches(status));
+    assert_eq!(Some(12), parse_major_faults(stat));
+    assert_eq!(Some('S'), parse_process_state(stat));
+}
+
+#[test]
+fn test_cpu_time_parsing() -> color_eyre::Result<()> {
+    let stat = "1234 (my (odd) prog) S 1 1234 1234 0 -1 4194560 2210 0 12 0 250 30 1200 45 20 0";
+    assert_eq!(
+        Some(CpuTimes {
+            user_seconds: 14.5,
+            system_seconds: 0.75,
+        }),
+        parse_cpu_times(stat)
+    );
+    assert_eq!(None, parse_cpu_times("1234 (prog) S 1 1234"));
+
+    let cpu_stat = "usage_usec 3500000\nuser_usec 3000000\nsystem_usec 500000\nnr_periods 0\n";
+    assert_eq!(
+        CpuTimes {
+            user_seconds: 3.0,
+            system_seconds: 0.5,
+        },
+        parse_cpu_times_usec(cpu_stat)?
+    );
+
+    // Totals never go down, even if a process leaves the tree
+    let earlier = CpuTimes {
+        user_seconds: 5.0,
+        system_seconds: 1.0,
+    };
+    let later = CpuTimes {
+        user_seconds: 4.0,
+        system_seconds: 1.5,
+    };
+    assert_eq!(
+        CpuTimes {
+            user_seconds: 5.0,
+            system_seconds: 1.5,
+        },
+        later.max(earlier)
+    );
+    Ok(())
+}
+
+#[test]
+fn test_counter_deltas() {
+    let with_counters = |pid: u32, switches: u64| ProcessUsage {
+        counters: Some(ProcessCounters {
+            voluntary_switches: switches,
+            involuntary_switches: 1,
+            major_faults: 0,
+        }),
+        ..process(pid, 0, 0)
+    };
+    let mut deltas = CounterDeltas::new();
+
+    // First sample is only the baseline
+    let first = deltas.update(&[with_counters(1, 100)]);
+    assert_eq!(Some(0), first.map(|c| c.voluntary_switches));
+
+    // New processes count in full
+    let second = deltas.update(&[with_counters(1, 130), with_counters(2, 5)]);
+    assert_eq!(Some(35), second.map(|c| c.voluntary_switches));
+    assert_eq!(Some(1), second.map(|c| c.involuntary_switches));
+
+    assert_eq!(None, deltas.update(&[process(3, 0, 0)]));
+}
+
+#[test]
+fn test_throttle_counts_since() {
+    let earlier = ThrottleCounts::new(10, 4, 0, 7);
+    let now = ThrottleCounts::new(13, 5, 0, 7);
+
+    assert_eq!(4, now.thermal_since(&earlier));
+    assert_eq!(0, now.power_since(&earlier));
+    // Counters reset, e.g. by a CPU going offline
+    assert_eq!(0, earlier.thermal_since(&now));
+}
+
+#[test]
+fn test_energy_counter_wraps_and_sums_to_kwh() {
+    assert_eq!(500, counter_delta(1_000, 1_500, 10_000));
+    assert_eq!(700, counter_delta(9_500, 200, 10_000));
+
+    let mut acc = SummaryAccumulator::new();
+    assert_eq!(None, acc.finish(Duration::from_secs(1)).energy_kwh);
+    acc.add_energy(1.8e6);
+    acc.add_energy(1.8e6);
+    assert_eq!(Some(1.0), acc.finish(Duration::from_secs(1)).energy_kwh);
+}
+
+#[cfg(windows)]
+#[test]
+fn test_job_object_lists_assigned_process() -> color_eyre::Result<()> {
+    let job = super::job::JobObject::new()?;
+    let mut child = std::process::Command::new("cmd")
+        .args(["/C", "ping -n 3 127.0.0.1 > NUL"])
+        .spawn()?;
+    job.assign(&child)?;
+
+    let pids = job.pids();
+    child.kill()?;
+    child.wait()?;
+
+    assert!(pids?.contains(&Pid::from_u32(child.id())));
+    Ok(())
+}
+
+#[cfg(unix)]
+#[test]
+fn test_monitor_samples_spawned_command_until_it_exits() -> color_eyre::Result<()> {
+    use std::ops::ControlFlow;
+
+    let command = ["sleep".to_string(), "1".to_string()];
+    let mut monitor =
+        super::monitor::Monitor::spawn(&command)?.with_interval(Duration::from_millis(100));
+
+    let mut samples = Vec::new();
+    let status = monitor.run(|sample| {
+        samples.push(sample.processes.len());
+        Ok(ControlFlow::Continue(()))
+    })?;
+
+    assert!(samples.iter().any(|&processes| processes > 0));
+    // On Linux the final sample is taken before the command is reaped, so still sees it
+    if cfg!(target_os = "linux") {
+        assert_eq!(Some(&1), samples.last());
+    }
+    assert!(monitor.exit_time().is_some());
+    assert!(status.is_some_and(|status| status.success()));
+    Ok(())
+}
+
+/// Two devices: every process in the tree busy on the first, and holding memory on the second
+struct FakeGpu;
+
+impl GpuBackend for FakeGpu {
+    fn process_devices(&self) -> Vec<(u32, Option<MigInstance>)> {
+        vec![(0, None), (1, None)]
+    }
+
+    fn device_names(&self) -> color_eyre::Result<Vec<String>> {
+        Ok(vec!["Fake A".into(), "Fake B".into()])
+    }
+
+    fn device_usage(&self) -> color_eyre::Result<Vec<DeviceUsage>> {
+        Ok(Vec::new())
+    }
+
+    fn device_thermals(&self) -> color_eyre::Result<Vec<DeviceThermals>> {
+        Ok(Vec::new())
+    }
+
+    fn pid_trees_engines_by_device(
+        &mut self,
+        tree: &HashSet<Pid>,
+    ) -> color_eyre::Result<Vec<HashMap<Pid, GpuEngines>>> {
+        let busy = GpuEngines {
+            sm_percent: 10,
+            ..Default::default()
+        };
+        Ok(vec![
+            tree.iter().map(|&pid| (pid, busy)).collect(),
+            HashMap::new(),
+        ])
+    }
+
+    fn pid_trees_memory_by_device(
+        &self,
+        tree: &HashSet<Pid>,
+    ) -> color_eyre::Result<Vec<HashMap<Pid, u64>>> {
+        Ok(vec![
+            HashMap::new(),
+            tree.iter().map(|&pid| (pid, 1 << 20)).collect(),
+        ])
+    }
+}
+
+#[cfg(unix)]
+#[test]
+fn test_monitor_measures_gpu_through_backend() -> color_eyre::Result<()> {
+    let command = ["sleep".to_string(), "2".to_string()];
+    let mut monitor = super::monitor::Monitor::spawn(&command)?.with_gpu(FakeGpu);
+    let sample = monitor.sample();
+    monitor.kill();
+    monitor.wait()?;
+    let sample = sample?;
+
+    let pid = sample.processes[0].pid;
+    assert_eq!(Some(10), sample.gpu_percent);
+    assert_eq!(
+        Some(&HashMap::from([(pid, 10)])),
+        sample.gpu_by_pid.as_ref()
+    );
+    assert_eq!(Some(1 << 20), sample.gpu_memory_bytes());
+    let devices = sample.gpu_devices.expect("Devices should be reported");
+    // Holding memory while idle still counts as being on the second device
+    assert_eq!(
+        vec![(0, 10, 0), (1, 0, 1 << 20)],
+        devices
+            .iter()
+            .map(|d| (d.index, d.gpu_percent, d.memory_bytes))
+            .collect::<Vec<_>>()
+    );
+    Ok(())
+}
+
+#[cfg(unix)]
+#[test]
+fn test_pid_tree_apis_agree() -> color_eyre::Result<()> {
+    let mut system = super::system::System::new();
+    // Scan everything each time, so the grandchild is found as soon as it starts
+    system.set_full_refresh_interval(Duration::ZERO);
+    // A grandchild, so the tree is more than one level deep
+    let mut child = std::process::Command::new("sh")
+        .args(["-c", "sleep 10 & wait"])
+        .spawn()?;
+    let pid = Pid::from_u32(child.id());
+    // Wait for the shell to start its child, rather than guessing how long that takes
+    let deadline = std::time::Instant::now() + Duration::from_secs(5);
+    while system.get_pid_tree(pid).len() < 2 && std::time::Instant::now() < deadline {
+        std::thread::sleep(Duration::from_millis(20));
+    }
+
+    let tree = system.get_pid_tree(pid);
+    let trees = system.get_pid_trees(&[pid, pid]);
+    let processes = system.get_pid_tree_process_usage(pid);
+    let total = system.get_pid_tree_utilisation(pid);
+
+    child.kill()?;
+    child.wait()?;
+    system.terminate_pid_trees(&[pid], sysinfo::Signal::Kill, Duration::from_secs(1));
+
+    // Listing a root twice doesn't count its tree twice
+    assert_eq!(tree, trees);
+    assert_eq!(2, tree.len());
+    let listed: std::collections::HashSet<Pid> = processes.iter().map(|p| p.pid).collect();
+    assert_eq!(tree, listed);
+    assert!(total.memory_bytes > 0);
+    Ok(())
+}
+
+#[cfg(unix)]
+#[test]
+fn test_targeted_refresh_notices_tree_exit() -> color_eyre::Result<()> {
+    let mut system = super::system::System::new();
+    system.set_full_refresh_interval(Duration::from_secs(3600));
+    let mut child = std::process::Command::new("sleep").arg("10").spawn()?;
+    let pid = Pid::from_u32(child.id());
+
+    // The first call scans everything, later ones only refresh the tree
+    assert!(system.get_pid_trees(&[pid]).contains(&pid));
+    system.get_pid_trees(&[pid]);
+    assert!(system.pid_is_alive(pid));
+
+    child.kill()?;
+    child.wait()?;
+    system.get_pid_trees(&[pid]);
+
+    assert!(!system.pid_is_alive(pid));
+    Ok(())
+}
+
+#[cfg(target_os = "linux")]
+#[test]
+fn test_thread_accounting_gives_threads_rows() -> color_eyre::Result<()> {
+    let (tid_tx, tid_rx) = std::sync::mpsc::channel();
+    let (stop_tx, stop_rx) = std::sync::mpsc::channel::<()>();
+    let thread = std::thread::spawn(move || {
+        // e.g. "1234/task/1240"
+        let thread_self = std::fs::read_link("/proc/thread-self").unwrap();
+        let tid: u32 = thread_self
+            .file_name()
+            .unwrap()
+            .to_str()
+            .unwrap()
+            .parse()
+            .unwrap();
+        tid_tx.send(Pid::from_u32(tid)).unwrap();
+        stop_rx.recv().ok();
+    });
+    let tid = tid_rx.recv()?;
+    let pid = sysinfo::get_current_pid().map_err(color_eyre::eyre::Error::msg)?;
+
+    let mut system = super::system::System::new();
+    let has_thread_row = |system: &mut super::system::System| {
+        system
+            .get_pid_tree_process_usage(pid)
+            .iter()
+            .any(|process| process.pid == tid)
+    };
+    let processes_only = has_thread_row(&mut system);
+    system.set_thread_accounting(ThreadAccounting::Threads);
+    let with_threads = has_thread_row(&mut system);
+    let tree = system.get_pid_tree(pid);
+
+    stop_tx.send(())?;
+    thread.join().ok();
+
+    assert!(!processes_only);
+    assert!(with_threads);
+    assert!(!tree.contains(&tid));
+    Ok(())
+}
+
+#[test]
+fn test_summary_lists_each_mig_instance_once() {
+    let instance = |gpu_instance_id| MigInstance {
+        device_index: 0,
+        gpu_instance_id,
+        compute_instance_id: 0,
+        mig_index: gpu_instance_id,
+    };
+    let mut acc = SummaryAccumulator::new();
+    acc.add_mig_instances(&[instance(2)]);
+    acc.add_mig_instances(&[instance(1), instance(2)]);
+
+    let summary = acc.finish(Duration::from_secs(2));
+
+    assert_eq!(Some("0:1.0 0:2.0".to_string()), summary.mig_instances);
+}
+
+#[test]
+fn test_bench_stats_spread_over_runs() {
+    let run = |wall_clock_seconds, mean_gpu_percent| BenchRun {
+        wall_clock_seconds,
+        peak_ram_bytes: 1024,
+        mean_cpu_percent: 50.0,
+        mean_gpu_percent,
+        exit_code: Some(0),
+    };
+
+    let stats = BenchStats::new(&[run(2.0, None), run(4.0, Some(30.0)), run(6.0, None)]);
+
+    assert_eq!(
+        Some(Spread {
+            mean: 4.0,
+            stddev: 2.0
+        }),
+        stats.wall_clock_seconds
+    );
+    assert_eq!(
+        Some(Spread {
+            mean: 1024.0,
+            stddev: 0.0
+        }),
+        stats.peak_ram_bytes
+    );
+    // Only one run measured the GPU
+    assert_eq!(
+        Some(Spread {
+            mean: 30.0,
+            stddev: 0.0
+        }),
+        stats.mean_gpu_percent
+    );
+    assert_eq!(None, Spread::of([]));
+}
+
+#[test]
+fn test_schedule_keeps_cadence_and_skips_missed_samples() {
+    let start = Instant::now();
+    let second = Duration::from_secs(1);
+    let mut schedule = Schedule::new(start, second);
+    assert_eq!(start + second, schedule.deadline());
+
+    // Sampling late doesn't move later deadlines
+    assert_eq!(
+        0,
+        schedule.advance(second, start + Duration::from_millis(1300))
+    );
+    assert_eq!(start + 2 * second, schedule.deadline());
+    assert_eq!(
+        Duration::from_millis(700),
+        schedule.remaining(start + Duration::from_millis(1300))
+    );
+
+    // Overrunning by more than an interval skips those missed, staying on the cadence
+    assert_eq!(
+        2,
+        schedule.advance(second, start + Duration::from_millis(4500))
+    );
+    assert_eq!(start + 5 * second, schedule.deadline());
+    assert_eq!(Duration::ZERO, schedule.remaining(start + 6 * second));
+
+    // Reaching the next deadline exactly means sampling straight away
+    assert_eq!(0, schedule.advance(second, start + 6 * second));
+    assert_eq!(start + 6 * second, schedule.deadline());
+}
+
+#[cfg(unix)]
+#[test]
+fn test_process_snapshots_keep_requested_variables() -> color_eyre::Result<()> {
+    use super::metadata::{ProcessSnapshot, RunMetadata};
+
+    let mut system = super::system::System::new();
+    let mut child = std::process::Command::new("sleep")
+        .arg("10")
+        .env("TU_TEST_THREADS", "4")
+        .env("TU_TEST_IGNORED", "x")
+        .spawn()?;
+    let pid = Pid::from_u32(child.id());
+    let env_names = ["TU_TEST_THREADS".to_string(), "TU_TEST_UNSET".to_string()];
+    let snapshot = system.process_snapshot(pid, &env_names);
+    child.kill()?;
+    child.wait()?;
+
+    let snapshot = snapshot.expect("Child should be running");
+    assert_eq!(vec!["sleep", "10"], snapshot.command_line);
+    assert_eq!(
+        HashMap::from([("TU_TEST_THREADS".to_string(), "4".to_string())]),
+        snapshot.environment.clone().into_iter().collect()
+    );
+
+    let mut metadata = RunMetadata {
+        command_line: Vec::new(),
+        hostname: None,
+        username: None,
+        start_time: String::new(),
+        cpu_model: None,
+        cpu_count: 1,
+        total_memory_bytes: 0,
+        cpu_limit_cores: None,
+        gpu_models: Vec::new(),
+        gpu_driver_version: None,
+        cuda_version: None,
+        gpu_devices: Vec::new(),
+        processes: Vec::new(),
+    };
+    assert!(metadata.add_process(snapshot.clone()));
+    // An identical worker isn't worth recording again
+    assert!(!metadata.add_process(ProcessSnapshot {
+        pid: snapshot.pid + 1,
+        ..snapshot.clone()
+    }));
+    assert_eq!(1, metadata.processes.len());
+    Ok(())
+}
+
+#[cfg(feature = "nvml")]
+#[test]
+fn test_last_seen_tracked_per_device() {
+    // Two devices on unrelated clocks, the second slow to report
+    let mut last_seen = [None, None];
+    let polls: [[&[u64]; 2]; 3] = [[&[1_000, 1_500], &[90]], [&[2_000], &[]], [&[], &[80, 120]]];
+    for poll in polls {
+        for (last, timestamps) in last_seen.iter_mut().zip(poll) {
+            *last = advance_last_seen(*last, timestamps.iter().copied());
+        }
+    }
+    // A stale sample doesn't rewind a device, and one device's clock doesn't affect another
+    assert_eq!([Some(2_000), Some(120)], last_seen);
+    assert_eq!(None, advance_last_seen(None, []));
+}
+
+#[cfg(feature = "nvml")]
+#[test]
+fn test_gpu_info_explains_missing_capabilities() {
+    use nvml_wrapper::error::NvmlError;
+
+    assert_eq!(Capability::of(Ok(())), Capability::Supported);
+    // Nothing to report yet still means the query works
+    assert_eq!(
+        Capability::of::<()>(Err(NvmlError::NotFound)),
+        Capability::Supported
+    );
+    assert_eq!(
+        Capability::of::<()>(Err(NvmlError::NoPermission)),
+        Capability::NoPermission
+    );
+
+    let device = DeviceInfo {
+        index: 1,
+        name: Some("Tesla T4".to_string()),
+        uuid: None,
+        mig_enabled: None,
+        device_utilisation: Capability::Supported,
+        process_utilisation: Capability::NoPermission,
+        compute_processes: Capability::Supported,
+        power: Capability::NotSupported,
+    };
+    let problems = device.problems();
+
+    assert_eq!(problems.len(), 2);
+    assert!(problems[0].starts_with("No permission to read per-process utilisation from GPU 1"));
+    assert!(problems[0].contains("`gpu_percent`"));
+    assert!(problems[1].contains("`gpu_power_w`"));
+}
+
+#[test]
+fn test_nvidia_smi_parsing() {
+    let devices = "0, GPU-5f1c, NVIDIA A100-SXM4-80GB\n1, GPU-9e2a, Tesla T4, rev b\n";
+    assert_eq!(
+        parse_devices(devices).unwrap(),
+        vec![
+            SmiDevice {
+                index: 0,
+                uuid: "GPU-5f1c".to_string(),
+                name: "NVIDIA A100-SXM4-80GB".to_string(),
+            },
+            SmiDevice {
+                index: 1,
+                uuid: "GPU-9e2a".to_string(),
+                name: "Tesla T4, rev b".to_string(),
+            },
+        ]
+    );
+
+    let pmon = "\
+# gpu         pid   type     sm    mem    enc    dec    jpg    ofa    command
+# Idx           #    C/G      %      %      %      %      %      %    name
+    0       4211     C     87     34      -      -      -      -    python
+    1          -     -      -      -      -      -      -      -    -
+";
+    assert_eq!(
+        parse_pmon(pmon).unwrap(),
+        vec![(
+            0,
+            Pid::from_u32(4211),
+            GpuEngines {
+                sm_percent: 87,
+                memory_percent: 34,
+                encoder_percent: 0,
+                decoder_percent: 0,
+            }
+        )]
+    );
+    assert!(parse_pmon("no header").is_err());
+
+    let apps = "GPU-5f1c, 4211, 4410\nGPU-9e2a, 4212, [N/A]\n";
+    assert_eq!(
+        parse_compute_apps(apps).unwrap(),
+        vec![
+            (
+                "GPU-5f1c".to_string(),
+                Pid::from_u32(4211),
+                4410 * 2u64.pow(20)
+            ),
+            ("GPU-9e2a".to_string(), Pid::from_u32(4212), 0),
+        ]
+    );
+}
+
+#[test]
+fn test_gpu_details_for_metadata() -> color_eyre::Result<()> {
+    use super::metadata::GpuDevice;
+
+    #[cfg(feature = "nvml")]
+    {
+        assert_eq!("12.4", format_cuda_version(12040));
+        assert_eq!("11.8", format_cuda_version(11080));
+    }
+
+    // Backends which only know device names still identify each device
+    let details = FakeGpu.device_details()?;
+    assert_eq!(
+        vec![
+            GpuDevice {
+                index: 0,
+                name: "Fake A".into(),
+                memory_bytes: None,
+                uuid: None,
+            },
+            GpuDevice {
+                index: 1,
+                name: "Fake B".into(),
+                memory_bytes: None,
+                uuid: None,
+            },
+        ],
+        details
+    );
+    assert_eq!(None, FakeGpu.driver_version());
+    Ok(())
+}
+
+#[test]
+fn test_exit_codes_tell_missing_targets_from_failed_commands() -> color_eyre::Result<()> {
+    use color_eyre::eyre::{Context, eyre};
+
+    use crate::exit::{Failure, command_exit_code, exit_code};
+
+    let missing_pid = super::monitor::Monitor::attach(Pid::from_u32(u32::MAX)).err();
+    assert_eq!(Some(4), missing_pid.as_ref().map(exit_code));
+
+    let missing_command =
+        super::monitor::Monitor::spawn(&["no-such-command-for-tu-tests".to_string()]).err();
+    assert_eq!(Some(4), missing_command.as_ref().map(exit_code));
+
+    // Only tagged failures get their own codes
+    assert_eq!(1, exit_code(&eyre!("Disk full")));
+    let partial = Err::<(), _>(Failure::Partial.because("1 of 2 failed"))
+        .wrap_err("Size report incomplete")
+        .unwrap_err();
+    assert_eq!(2, exit_code(&partial));
+
+    assert_eq!(0, command_exit_code(0));
+    assert_eq!(11, command_exit_code(1));
+    // Killed by SIGKILL
+    assert_eq!(147, command_exit_code(137));
+    assert_eq!(255, command_exit_code(250));
+    Ok(())
+}
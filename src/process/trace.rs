@@ -0,0 +1,153 @@
+use std::{fs, path::Path};
+
+use color_eyre::{
+    Result,
+    eyre::{Context, bail},
+};
+use serde::{Deserialize, Deserializer};
+
+use crate::output::{
+    dialect::sniff_delimiter,
+    schema::{SCHEMA_COLUMN, UNVERSIONED, USAGE_SCHEMA},
+};
+
+/// One row of a `tu` usage CSV
+#[derive(Debug, Clone, PartialEq, Deserialize)]
+pub struct UsageRow {
+    pub timestamp: String,
+    pub elapsed_seconds: f64,
+    /// With `tu --aggregate`, `raw` or the statistic a folded row holds
+    #[serde(default)]
+    pub aggregate: Option<String>,
+    #[serde(deserialize_with = "na_as_none")]
+    pub cpu_percent: Option<f64>,
+    #[serde(deserialize_with = "na_as_none")]
+    pub ram_percent: Option<f64>,
+    #[serde(deserialize_with = "na_as_none")]
+    pub ram_mb: Option<f64>,
+    #[serde(default, deserialize_with = "na_as_none")]
+    pub swap_mb: Option<f64>,
+    #[serde(default, deserialize_with = "na_as_none")]
+    pub gpu_percent: Option<f64>,
+}
+
+/// Reads one figure from a row, if it was recorded
+pub type Column = fn(&UsageRow) -> Option<f64>;
+
+/// A usage time series previously written by `tu`
+#[derive(Debug, Clone, PartialEq)]
+pub struct UsageTrace {
+    /// Schema the file was written with, see [`crate::output::schema`]
+    pub schema_version: u32,
+    pub rows: Vec<UsageRow>,
+}
+
+impl UsageTrace {
+    /**
+     * Read a usage CSV written by this or any earlier version of `tu`.  Columns an older
+     * schema lacks are read as missing.  The delimiter, e.g. from `tu --delimiter ';'`, is
+     * worked out from the header.
+     */
+    pub fn from_csv<P: AsRef<Path>>(path: P) -> Result<Self> {
+        let path = path.as_ref();
+        let csv = fs::read(path)
+            .wrap_err_with(|| format!("Failed to open usage CSV {}", path.display()))?;
+        let header = csv.split(|&b| b == b'\n').next().unwrap_or_default();
+        let mut reader = csv::ReaderBuilder::new()
+            .delimiter(sniff_delimiter(&String::from_utf8_lossy(header)))
+            .from_reader(csv.as_slice());
+        let headers = reader
+            .headers()
+            .wrap_err_with(|| format!("Failed to read header of {}", path.display()))?
+            .clone();
+        let records = reader
+            .records()
+            .collect::<Result<Vec<_>, _>>()
+            .wrap_err_with(|| format!("Failed to parse usage CSV {}", path.display()))?;
+
+        let schema_version = match headers.iter().position(|name| name == SCHEMA_COLUMN) {
+            Some(column) => records
+                .iter()
+                .filter_map(|record| record.get(column)?.parse::<u32>().ok())
+                .max()
+                .unwrap_or(USAGE_SCHEMA),
+            None => UNVERSIONED,
+        };
+        if schema_version > USAGE_SCHEMA {
+            bail!(
+                "{} has schema version {}, from a newer tu, but this one only reads up to {}",
+                path.display(),
+                schema_version,
+                USAGE_SCHEMA
+            );
+        }
+
+        let rows = records
+            .iter()
+            .map(|record| record.deserialize::<UsageRow>(Some(&headers)))
+            .collect::<Result<Vec<_>, _>>()
+            .wrap_err_with(|| format!("Failed to parse usage CSV {}", path.display()))?
+            .into_iter()
+            // Folded periods are represented by their means
+            .filter(|row| !matches!(row.aggregate.as_deref(), Some("min" | "max")))
+            .collect();
+
+        Ok(Self {
+            schema_version,
+            rows,
+        })
+    }
+
+    /// Points for one column, skipping samples where it is unavailable
+    pub fn series(&self, column: Column) -> Vec<(f64, f64)> {
+        self.rows
+            .iter()
+            .filter_map(|row| column(row).map(|value| (row.elapsed_seconds, value)))
+            .collect()
+    }
+
+    /// Elapsed time of the last sample
+    pub fn duration_seconds(&self) -> Option<f64> {
+        self.rows.last().map(|row| row.elapsed_seconds)
+    }
+
+    pub fn peak(&self, column: Column) -> Option<f64> {
+        self.series(column)
+            .into_iter()
+            .map(|(_, value)| value)
+            .reduce(f64::max)
+    }
+
+    /**
+     * Mean weighting each sample by the time since the previous one, so runs sampled at
+     * different intervals are compared over the same elapsed time rather than per sample
+     */
+    pub fn mean(&self, column: Column) -> Option<f64> {
+        let series = self.series(column);
+        if series.is_empty() {
+            return None;
+        }
+        let mut previous = 0.0;
+        let mut weighted = 0.0;
+        let mut total = 0.0;
+        for &(elapsed, value) in &series {
+            let weight = (elapsed - previous).max(0.0);
+            weighted += value * weight;
+            total += weight;
+            previous = elapsed;
+        }
+        if total > 0.0 {
+            Some(weighted / total)
+        } else {
+            Some(series.iter().map(|(_, value)| value).sum::<f64>() / series.len() as f64)
+        }
+    }
+}
+
+fn na_as_none<'de, D: Deserializer<'de>>(deserializer: D) -> Result<Option<f64>, D::Error> {
+    let value = String::deserialize(deserializer)?;
+    match value.trim() {
+        "" | "NA" => Ok(None),
+        other => other.parse().map(Some).map_err(serde::de::Error::custom),
+    }
+}
@@ -0,0 +1,140 @@
+use std::{collections::HashMap, fs, path::Path};
+
+use chrono::{DateTime, NaiveDateTime, Utc};
+use color_eyre::{
+    Result,
+    eyre::{Context, OptionExt},
+};
+
+use crate::output::dialect::sniff_delimiter;
+
+use super::size::RUN_TIME_FORMAT;
+
+/// Sizes from an earlier `bu size-report`, to work out how much each location has grown
+#[derive(Debug, Clone, Default)]
+pub struct Baseline {
+    /// The newest row for each URL
+    rows: HashMap<String, BaselineRow>,
+}
+
+#[derive(Debug, Clone, PartialEq)]
+struct BaselineRow {
+    /// Missing from reports before `run_time` was added, in schema 3
+    run_time: Option<DateTime<Utc>>,
+    total_b: u64,
+    total_qty: u64,
+}
+
+/// How much a location has grown since the baseline
+#[derive(Debug, Clone, PartialEq)]
+pub struct SizeDelta {
+    /// When the baseline was reported, if it says
+    pub baseline_run_time: Option<DateTime<Utc>>,
+    pub bytes: i64,
+    pub objects: i64,
+    /// Growth in bytes as a percentage of the baseline, unless that was empty
+    pub percent: Option<f64>,
+    /// Growth in bytes per day since the baseline, if its time is known
+    pub bytes_per_day: Option<f64>,
+}
+
+impl Baseline {
+    /**
+     * Read an earlier report, of any schema with `url`, `total_b` and `total_qty` columns,
+     * in any delimiter.  Of several rows for a URL, e.g. in an appended report, the newest
+     * is used.
+     */
+    pub fn from_csv(path: &Path) -> Result<Self> {
+        let csv = fs::read(path)
+            .wrap_err_with(|| format!("Failed to open baseline {}", path.display()))?;
+        let header = csv.split(|&b| b == b'\n').next().unwrap_or_default();
+        let mut reader = csv::ReaderBuilder::new()
+            .delimiter(sniff_delimiter(&String::from_utf8_lossy(header)))
+            .from_reader(csv.as_slice());
+        let headers = reader
+            .headers()
+            .wrap_err_with(|| format!("Failed to read header of {}", path.display()))?
+            .clone();
+        let column = |name: &str| {
+            headers
+                .iter()
+                .position(|column| column == name)
+                .ok_or_eyre(format!(
+                    "Baseline {} has no {} column",
+                    path.display(),
+                    name
+                ))
+        };
+        let (url, total_b, total_qty) = (column("url")?, column("total_b")?, column("total_qty")?);
+        let run_time = column("run_time").ok();
+
+        let mut rows: HashMap<String, BaselineRow> = HashMap::new();
+        for (line, record) in reader.records().enumerate() {
+            let record =
+                record.wrap_err_with(|| format!("Failed to parse baseline {}", path.display()))?;
+            let number = |column: usize| {
+                record[column].parse::<u64>().wrap_err_with(|| {
+                    format!(
+                        "Row {} of baseline {} has a bad size",
+                        line + 1,
+                        path.display()
+                    )
+                })
+            };
+            let row = BaselineRow {
+                run_time: run_time.and_then(|column| parse_run_time(&record[column])),
+                total_b: number(total_b)?,
+                total_qty: number(total_qty)?,
+            };
+            let newer = rows
+                .get(&record[url])
+                .is_none_or(|existing| row.run_time >= existing.run_time);
+            if newer {
+                rows.insert(record[url].to_string(), row);
+            }
+        }
+        Ok(Baseline { rows })
+    }
+
+    /**
+     * How much `url` has grown to `total_b` bytes in `total_qty` objects at `now`, or `None`
+     * if the baseline doesn't have it
+     */
+    pub fn delta(
+        &self,
+        url: &str,
+        total_b: u64,
+        total_qty: usize,
+        now: DateTime<Utc>,
+    ) -> Option<SizeDelta> {
+        let baseline = self.rows.get(url)?;
+        let bytes = total_b as i64 - baseline.total_b as i64;
+        let days = baseline
+            .run_time
+            .map(|then| (now - then).num_seconds() as f64 / 86_400.0)
+            .filter(|&days| days > 0.0);
+        Some(SizeDelta {
+            baseline_run_time: baseline.run_time,
+            bytes,
+            objects: total_qty as i64 - baseline.total_qty as i64,
+            percent: (baseline.total_b > 0).then(|| bytes as f64 / baseline.total_b as f64 * 100.0),
+            bytes_per_day: days.map(|days| bytes as f64 / days),
+        })
+    }
+
+    /**
+     * Whether there's nothing in the baseline
+     */
+    pub fn is_empty(&self) -> bool {
+        self.rows.is_empty()
+    }
+}
+
+/**
+ * A `run_time` written in [`RUN_TIME_FORMAT`]
+ */
+fn parse_run_time(text: &str) -> Option<DateTime<Utc>> {
+    NaiveDateTime::parse_from_str(text, RUN_TIME_FORMAT)
+        .ok()
+        .map(|time| time.and_utc())
+}
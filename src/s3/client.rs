@@ -0,0 +1,212 @@
+use std::future::Future;
+
+use aws_sdk_s3::{
+    Client, Error,
+    operation::{
+        delete_objects::DeleteObjectsOutput,
+        get_bucket_lifecycle_configuration::GetBucketLifecycleConfigurationOutput,
+        get_bucket_versioning::GetBucketVersioningOutput, list_buckets::ListBucketsOutput,
+        list_object_versions::ListObjectVersionsOutput, list_objects_v2::ListObjectsV2Output,
+    },
+    types::Delete,
+};
+
+/// The S3 operations [`S3Wrapper`](super::wrapper::S3Wrapper) needs, a request at a time, so
+/// they can be faked in tests
+pub trait S3Api {
+    /**
+     * A page of the current objects under `prefix`, continuing from `continuation_token`
+     */
+    fn list_objects_v2(
+        &self,
+        bucket: &str,
+        prefix: &str,
+        continuation_token: Option<String>,
+    ) -> impl Future<Output = Result<ListObjectsV2Output, Error>> + Send;
+
+    /**
+     * A page of the versions and delete markers under `prefix`, continuing after the given
+     * key and version
+     */
+    fn list_object_versions(
+        &self,
+        bucket: &str,
+        prefix: &str,
+        key_marker: Option<String>,
+        version_id_marker: Option<String>,
+    ) -> impl Future<Output = Result<ListObjectVersionsOutput, Error>> + Send;
+
+    /**
+     * As [`Self::list_objects_v2`], but only the objects directly in the folder `prefix`,
+     * with the folders in it as common prefixes
+     */
+    fn list_folder_objects(
+        &self,
+        bucket: &str,
+        prefix: &str,
+        continuation_token: Option<String>,
+    ) -> impl Future<Output = Result<ListObjectsV2Output, Error>> + Send;
+
+    /**
+     * As [`Self::list_object_versions`], but only the versions directly in the folder
+     * `prefix`, with the folders in it as common prefixes
+     */
+    fn list_folder_versions(
+        &self,
+        bucket: &str,
+        prefix: &str,
+        key_marker: Option<String>,
+        version_id_marker: Option<String>,
+    ) -> impl Future<Output = Result<ListObjectVersionsOutput, Error>> + Send;
+
+    /**
+     * Delete up to 1000 object versions or delete markers in one request
+     */
+    fn delete_objects(
+        &self,
+        bucket: &str,
+        delete: Delete,
+    ) -> impl Future<Output = Result<DeleteObjectsOutput, Error>> + Send;
+
+    fn get_bucket_versioning(
+        &self,
+        bucket: &str,
+    ) -> impl Future<Output = Result<GetBucketVersioningOutput, Error>> + Send;
+
+    /**
+     * A page of the buckets whose names start with `prefix`, with their regions and creation
+     * dates, continuing from `continuation_token`
+     */
+    fn list_buckets(
+        &self,
+        prefix: &str,
+        continuation_token: Option<String>,
+    ) -> impl Future<Output = Result<ListBucketsOutput, Error>> + Send;
+
+    /**
+     * The bucket's lifecycle rules.  S3 answers with a [`NO_LIFECYCLE`] error if there are
+     * none.
+     */
+    fn get_bucket_lifecycle_configuration(
+        &self,
+        bucket: &str,
+    ) -> impl Future<Output = Result<GetBucketLifecycleConfigurationOutput, Error>> + Send;
+}
+
+/// Code of the error S3 gives for a bucket without lifecycle rules
+pub const NO_LIFECYCLE: &str = "NoSuchLifecycleConfiguration";
+
+/// What separates folders in keys, for listing a folder at a time
+pub const DELIMITER: &str = "/";
+
+impl S3Api for Client {
+    async fn list_objects_v2(
+        &self,
+        bucket: &str,
+        prefix: &str,
+        continuation_token: Option<String>,
+    ) -> Result<ListObjectsV2Output, Error> {
+        Ok(self
+            .list_objects_v2()
+            .bucket(bucket)
+            .prefix(prefix)
+            .set_continuation_token(continuation_token)
+            .send()
+            .await?)
+    }
+
+    async fn list_object_versions(
+        &self,
+        bucket: &str,
+        prefix: &str,
+        key_marker: Option<String>,
+        version_id_marker: Option<String>,
+    ) -> Result<ListObjectVersionsOutput, Error> {
+        Ok(self
+            .list_object_versions()
+            .bucket(bucket)
+            .prefix(prefix)
+            .set_key_marker(key_marker)
+            .set_version_id_marker(version_id_marker)
+            .send()
+            .await?)
+    }
+
+    async fn list_folder_objects(
+        &self,
+        bucket: &str,
+        prefix: &str,
+        continuation_token: Option<String>,
+    ) -> Result<ListObjectsV2Output, Error> {
+        Ok(self
+            .list_objects_v2()
+            .bucket(bucket)
+            .prefix(prefix)
+            .delimiter(DELIMITER)
+            .set_continuation_token(continuation_token)
+            .send()
+            .await?)
+    }
+
+    async fn list_folder_versions(
+        &self,
+        bucket: &str,
+        prefix: &str,
+        key_marker: Option<String>,
+        version_id_marker: Option<String>,
+    ) -> Result<ListObjectVersionsOutput, Error> {
+        Ok(self
+            .list_object_versions()
+            .bucket(bucket)
+            .prefix(prefix)
+            .delimiter(DELIMITER)
+            .set_key_marker(key_marker)
+            .set_version_id_marker(version_id_marker)
+            .send()
+            .await?)
+    }
+
+    async fn delete_objects(
+        &self,
+        bucket: &str,
+        delete: Delete,
+    ) -> Result<DeleteObjectsOutput, Error> {
+        Ok(self
+            .delete_objects()
+            .bucket(bucket)
+            .delete(delete)
+            .send()
+            .await?)
+    }
+
+    async fn get_bucket_versioning(
+        &self,
+        bucket: &str,
+    ) -> Result<GetBucketVersioningOutput, Error> {
+        Ok(self.get_bucket_versioning().bucket(bucket).send().await?)
+    }
+
+    async fn list_buckets(
+        &self,
+        prefix: &str,
+        continuation_token: Option<String>,
+    ) -> Result<ListBucketsOutput, Error> {
+        Ok(self
+            .list_buckets()
+            .prefix(prefix)
+            .set_continuation_token(continuation_token)
+            .send()
+            .await?)
+    }
+
+    async fn get_bucket_lifecycle_configuration(
+        &self,
+        bucket: &str,
+    ) -> Result<GetBucketLifecycleConfigurationOutput, Error> {
+        Ok(self
+            .get_bucket_lifecycle_configuration()
+            .bucket(bucket)
+            .send()
+            .await?)
+    }
+}
@@ -0,0 +1,516 @@
+use std::{
+    collections::{BTreeMap, HashSet},
+    sync::Mutex,
+};
+
+use aws_sdk_s3::{
+    Error,
+    error::ErrorMetadata,
+    operation::{
+        delete_objects::DeleteObjectsOutput,
+        get_bucket_lifecycle_configuration::{
+            GetBucketLifecycleConfigurationError, GetBucketLifecycleConfigurationOutput,
+        },
+        get_bucket_versioning::GetBucketVersioningOutput,
+        list_buckets::ListBucketsOutput,
+        list_object_versions::ListObjectVersionsOutput,
+        list_objects_v2::ListObjectsV2Output,
+    },
+    primitives::DateTime,
+    types::{
+        Bucket, BucketVersioningStatus, CommonPrefix, Delete, DeleteMarkerEntry, DeletedObject,
+        ExpirationStatus, LifecycleRule, LifecycleRuleFilter, Object, ObjectVersion,
+        error::NoSuchBucket,
+    },
+};
+
+use super::client::{NO_LIFECYCLE, S3Api};
+
+/// Region of every fake bucket
+pub const FAKE_REGION: &str = "eu-west-2";
+
+/// Creation time of every fake bucket, in seconds since the epoch
+pub const FAKE_CREATED: i64 = 1_700_000_000;
+
+/// Seconds between one write to a fake bucket and the next
+pub const FAKE_WRITE_INTERVAL: i64 = 86_400;
+
+/// An in-memory stand-in for S3, listing a few items a page so paging and batching get
+/// exercised
+pub struct FakeS3 {
+    buckets: Mutex<BTreeMap<String, FakeBucket>>,
+    page_size: usize,
+    /// Keys whose deletion fails, as if access were denied
+    undeletable: HashSet<String>,
+    /// Keys whose versions are listed without a key, as anomalous records
+    keyless: HashSet<String>,
+    /// Keys whose versions are listed without a latest flag, as anomalous records
+    unflagged: HashSet<String>,
+    /// Number of items in each delete request, in order
+    pub delete_requests: Mutex<Vec<usize>>,
+    /// Prefix of each request listing everything under one, rather than a folder, in order
+    pub list_requests: Mutex<Vec<String>>,
+}
+
+#[derive(Default)]
+struct FakeBucket {
+    versioned: bool,
+    /// Versioning was enabled then suspended
+    suspended: bool,
+    lifecycle_rules: bool,
+    /// Versions and delete markers, oldest first
+    entries: Vec<Entry>,
+    next_version: usize,
+}
+
+#[derive(Clone)]
+struct Entry {
+    key: String,
+    version_id: String,
+    /// None for a delete marker
+    size: Option<i64>,
+    /// Seconds since the epoch
+    last_modified: i64,
+}
+
+impl FakeS3 {
+    pub fn new(page_size: usize) -> Self {
+        FakeS3 {
+            buckets: Mutex::new(BTreeMap::new()),
+            page_size,
+            undeletable: HashSet::new(),
+            keyless: HashSet::new(),
+            unflagged: HashSet::new(),
+            delete_requests: Mutex::new(Vec::new()),
+            list_requests: Mutex::new(Vec::new()),
+        }
+    }
+
+    pub fn with_bucket(self, bucket: &str, versioned: bool) -> Self {
+        self.buckets.lock().unwrap().insert(
+            bucket.to_string(),
+            FakeBucket {
+                versioned,
+                ..Default::default()
+            },
+        );
+        self
+    }
+
+    /**
+     * Give `bucket` a lifecycle rule
+     */
+    pub fn with_lifecycle(self, bucket: &str) -> Self {
+        self.buckets
+            .lock()
+            .unwrap()
+            .get_mut(bucket)
+            .expect("No such fake bucket")
+            .lifecycle_rules = true;
+        self
+    }
+
+    /**
+     * Stop keeping new versions in `bucket`, though those already there remain
+     */
+    pub fn suspend_versioning(&self, bucket: &str) {
+        let mut buckets = self.buckets.lock().unwrap();
+        let bucket = buckets.get_mut(bucket).expect("No such fake bucket");
+        bucket.suspended = bucket.versioned;
+        bucket.versioned = false;
+    }
+
+    pub fn with_undeletable(mut self, key: &str) -> Self {
+        self.undeletable.insert(key.to_string());
+        self
+    }
+
+    /**
+     * List the versions of `key` without their key
+     */
+    pub fn with_keyless(mut self, key: &str) -> Self {
+        self.keyless.insert(key.to_string());
+        self
+    }
+
+    /**
+     * List the versions of `key` without a latest flag
+     */
+    pub fn with_unflagged(mut self, key: &str) -> Self {
+        self.unflagged.insert(key.to_string());
+        self
+    }
+
+    /**
+     * Upload `size` bytes to `key`, replacing it unless the bucket is versioned
+     */
+    pub fn put(&self, bucket: &str, key: &str, size: i64) {
+        self.add(bucket, key, Some(size));
+    }
+
+    /**
+     * Delete `key`, leaving a delete marker if the bucket is versioned
+     */
+    pub fn delete(&self, bucket: &str, key: &str) {
+        self.add(bucket, key, None);
+    }
+
+    fn add(&self, bucket: &str, key: &str, size: Option<i64>) {
+        let mut buckets = self.buckets.lock().unwrap();
+        let bucket = buckets.get_mut(bucket).expect("No such fake bucket");
+        if !bucket.versioned {
+            bucket.entries.retain(|e| e.key != key);
+            if size.is_none() {
+                return;
+            }
+        }
+        bucket.next_version += 1;
+        bucket.entries.push(Entry {
+            key: key.to_string(),
+            version_id: format!("v{}", bucket.next_version),
+            size,
+            last_modified: FAKE_CREATED + bucket.next_version as i64 * FAKE_WRITE_INTERVAL,
+        });
+    }
+
+    /**
+     * Versions and delete markers under `prefix`, ordered by key then newest first, as S3
+     * lists them, with whether each is the latest for its key.  None if there's no such bucket.
+     */
+    fn listing(&self, bucket: &str, prefix: &str) -> Option<Vec<(Entry, bool)>> {
+        let buckets = self.buckets.lock().unwrap();
+        let bucket = buckets.get(bucket)?;
+        let mut entries: Vec<(usize, &Entry)> = bucket
+            .entries
+            .iter()
+            .enumerate()
+            .filter(|(_, e)| e.key.starts_with(prefix))
+            .collect();
+        entries.sort_by(|(a_age, a), (b_age, b)| a.key.cmp(&b.key).then(b_age.cmp(a_age)));
+        Some(
+            entries
+                .iter()
+                .enumerate()
+                .map(|(i, (_, e))| {
+                    let latest = i == 0 || entries[i - 1].1.key != e.key;
+                    ((*e).clone(), latest)
+                })
+                .collect(),
+        )
+    }
+}
+
+/// An item directly in a folder: an entry, with whether it's the latest for its key, or a
+/// folder in it
+enum FolderItem {
+    Entry(Entry, bool),
+    Folder(String),
+}
+
+/**
+ * Fold the entries of a [`FakeS3::listing`] under `prefix` which are in folders in it into
+ * one item per folder, as a delimited listing does
+ */
+fn in_folder(prefix: &str, listing: Vec<(Entry, bool)>) -> Vec<FolderItem> {
+    let mut items: Vec<FolderItem> = Vec::new();
+    for (entry, latest) in listing {
+        match entry.key[prefix.len()..].find('/') {
+            Some(end) => {
+                let folder = &entry.key[..prefix.len() + end + 1];
+                // Keys in a folder are listed together
+                if !matches!(items.last(), Some(FolderItem::Folder(last)) if last == folder) {
+                    items.push(FolderItem::Folder(folder.to_string()));
+                }
+            }
+            None => items.push(FolderItem::Entry(entry, latest)),
+        }
+    }
+    items
+}
+
+impl S3Api for FakeS3 {
+    async fn list_objects_v2(
+        &self,
+        bucket: &str,
+        prefix: &str,
+        continuation_token: Option<String>,
+    ) -> Result<ListObjectsV2Output, Error> {
+        self.list_requests.lock().unwrap().push(prefix.to_string());
+        let objects: Vec<Object> = self
+            .listing(bucket, prefix)
+            .ok_or_else(|| no_such_bucket(bucket))?
+            .into_iter()
+            .filter(|(e, latest)| *latest && e.size.is_some())
+            .map(|(e, _)| Object::builder().key(e.key).set_size(e.size).build())
+            .collect();
+        let start: usize = continuation_token.map_or(0, |t| t.parse().unwrap());
+        let end = (start + self.page_size).min(objects.len());
+        Ok(ListObjectsV2Output::builder()
+            .set_contents(Some(objects[start..end].to_vec()))
+            .set_next_continuation_token((end < objects.len()).then(|| end.to_string()))
+            .build())
+    }
+
+    async fn list_object_versions(
+        &self,
+        bucket: &str,
+        prefix: &str,
+        key_marker: Option<String>,
+        version_id_marker: Option<String>,
+    ) -> Result<ListObjectVersionsOutput, Error> {
+        self.list_requests.lock().unwrap().push(prefix.to_string());
+        let listing = self
+            .listing(bucket, prefix)
+            .ok_or_else(|| no_such_bucket(bucket))?;
+        let start = match (key_marker, version_id_marker) {
+            (Some(key), Some(version)) => {
+                listing
+                    .iter()
+                    .position(|(e, _)| e.key == key && e.version_id == version)
+                    .expect("Unknown markers")
+                    + 1
+            }
+            _ => 0,
+        };
+        let end = (start + self.page_size).min(listing.len());
+        let page = &listing[start..end];
+
+        let mut output = ListObjectVersionsOutput::builder();
+        for (entry, latest) in page {
+            output = match entry.size {
+                Some(size) => output.versions(
+                    ObjectVersion::builder()
+                        .set_key((!self.keyless.contains(&entry.key)).then(|| entry.key.clone()))
+                        .version_id(&entry.version_id)
+                        .size(size)
+                        .set_is_latest((!self.unflagged.contains(&entry.key)).then_some(*latest))
+                        .last_modified(DateTime::from_secs(entry.last_modified))
+                        .build(),
+                ),
+                None => output.delete_markers(
+                    DeleteMarkerEntry::builder()
+                        .key(&entry.key)
+                        .version_id(&entry.version_id)
+                        .is_latest(*latest)
+                        .build(),
+                ),
+            };
+        }
+        if let Some((last, _)) = page.last().filter(|_| end < listing.len()) {
+            output = output
+                .next_key_marker(&last.key)
+                .next_version_id_marker(&last.version_id);
+        }
+        Ok(output.build())
+    }
+
+    async fn list_folder_objects(
+        &self,
+        bucket: &str,
+        prefix: &str,
+        continuation_token: Option<String>,
+    ) -> Result<ListObjectsV2Output, Error> {
+        let current = self
+            .listing(bucket, prefix)
+            .ok_or_else(|| no_such_bucket(bucket))?
+            .into_iter()
+            .filter(|(e, latest)| *latest && e.size.is_some())
+            .collect();
+        let items = in_folder(prefix, current);
+        let start: usize = continuation_token.map_or(0, |t| t.parse().unwrap());
+        let end = (start + self.page_size).min(items.len());
+
+        let mut output = ListObjectsV2Output::builder();
+        for item in &items[start..end] {
+            output = match item {
+                FolderItem::Entry(e, _) => {
+                    output.contents(Object::builder().key(&e.key).set_size(e.size).build())
+                }
+                FolderItem::Folder(folder) => {
+                    output.common_prefixes(CommonPrefix::builder().prefix(folder).build())
+                }
+            };
+        }
+        Ok(output
+            .set_next_continuation_token((end < items.len()).then(|| end.to_string()))
+            .build())
+    }
+
+    async fn list_folder_versions(
+        &self,
+        bucket: &str,
+        prefix: &str,
+        key_marker: Option<String>,
+        _version_id_marker: Option<String>,
+    ) -> Result<ListObjectVersionsOutput, Error> {
+        let listing = self
+            .listing(bucket, prefix)
+            .ok_or_else(|| no_such_bucket(bucket))?;
+        let items = in_folder(prefix, listing);
+        // The markers only need to make sense to the fake itself
+        let start: usize = key_marker.map_or(0, |t| t.parse().unwrap());
+        let end = (start + self.page_size).min(items.len());
+
+        let mut output = ListObjectVersionsOutput::builder();
+        for item in &items[start..end] {
+            output = match item {
+                FolderItem::Entry(entry, latest) => match entry.size {
+                    Some(size) => output.versions(
+                        ObjectVersion::builder()
+                            .key(&entry.key)
+                            .version_id(&entry.version_id)
+                            .size(size)
+                            .is_latest(*latest)
+                            .last_modified(DateTime::from_secs(entry.last_modified))
+                            .build(),
+                    ),
+                    None => output.delete_markers(
+                        DeleteMarkerEntry::builder()
+                            .key(&entry.key)
+                            .version_id(&entry.version_id)
+                            .is_latest(*latest)
+                            .build(),
+                    ),
+                },
+                FolderItem::Folder(folder) => {
+                    output.common_prefixes(CommonPrefix::builder().prefix(folder).build())
+                }
+            };
+        }
+        if end < items.len() {
+            output = output.next_key_marker(end.to_string());
+        }
+        Ok(output.build())
+    }
+
+    async fn delete_objects(
+        &self,
+        bucket: &str,
+        delete: Delete,
+    ) -> Result<DeleteObjectsOutput, Error> {
+        let mut buckets = self.buckets.lock().unwrap();
+        let fake_bucket = buckets
+            .get_mut(bucket)
+            .ok_or_else(|| no_such_bucket(bucket))?;
+        assert!(
+            delete.objects().len() <= 1000,
+            "S3 deletes at most 1000 objects at a time"
+        );
+        self.delete_requests
+            .lock()
+            .unwrap()
+            .push(delete.objects().len());
+
+        let mut output = DeleteObjectsOutput::builder();
+        for object in delete.objects() {
+            let version_id = object.version_id().unwrap_or_default();
+            if self.undeletable.contains(object.key()) {
+                output = output.errors(
+                    aws_sdk_s3::types::Error::builder()
+                        .key(object.key())
+                        .version_id(version_id)
+                        .code("AccessDenied")
+                        .build(),
+                );
+                continue;
+            }
+            fake_bucket
+                .entries
+                .retain(|e| !(e.key == object.key() && e.version_id == version_id));
+            output = output.deleted(
+                DeletedObject::builder()
+                    .key(object.key())
+                    .version_id(version_id)
+                    .build(),
+            );
+        }
+        Ok(output.build())
+    }
+
+    async fn get_bucket_versioning(
+        &self,
+        bucket: &str,
+    ) -> Result<GetBucketVersioningOutput, Error> {
+        let buckets = self.buckets.lock().unwrap();
+        let fake_bucket = buckets.get(bucket).ok_or_else(|| no_such_bucket(bucket))?;
+        let status = match (fake_bucket.versioned, fake_bucket.suspended) {
+            (true, _) => Some(BucketVersioningStatus::Enabled),
+            (false, true) => Some(BucketVersioningStatus::Suspended),
+            (false, false) => None,
+        };
+        Ok(GetBucketVersioningOutput::builder()
+            .set_status(status)
+            .build())
+    }
+
+    async fn list_buckets(
+        &self,
+        prefix: &str,
+        continuation_token: Option<String>,
+    ) -> Result<ListBucketsOutput, Error> {
+        let buckets = self.buckets.lock().unwrap();
+        let names: Vec<&String> = buckets
+            .keys()
+            .filter(|name| name.starts_with(prefix))
+            .filter(|name| {
+                continuation_token
+                    .as_ref()
+                    .is_none_or(|after| *name > after)
+            })
+            .collect();
+        let page = &names[..names.len().min(self.page_size)];
+        let mut output = ListBucketsOutput::builder().prefix(prefix);
+        if page.len() < names.len() {
+            output = output.continuation_token(page[page.len() - 1].as_str());
+        }
+        for name in page {
+            output = output.buckets(
+                Bucket::builder()
+                    .name(name.as_str())
+                    .bucket_region(FAKE_REGION)
+                    .creation_date(DateTime::from_secs(FAKE_CREATED))
+                    .build(),
+            );
+        }
+        Ok(output.build())
+    }
+
+    async fn get_bucket_lifecycle_configuration(
+        &self,
+        bucket: &str,
+    ) -> Result<GetBucketLifecycleConfigurationOutput, Error> {
+        let buckets = self.buckets.lock().unwrap();
+        let fake_bucket = buckets.get(bucket).ok_or_else(|| no_such_bucket(bucket))?;
+        if !fake_bucket.lifecycle_rules {
+            let meta = ErrorMetadata::builder()
+                .code(NO_LIFECYCLE)
+                .message("The lifecycle configuration does not exist")
+                .build();
+            return Err(GetBucketLifecycleConfigurationError::generic(meta).into());
+        }
+        let rule = LifecycleRule::builder()
+            .id("expire-old-versions")
+            .filter(LifecycleRuleFilter::builder().prefix("").build())
+            .status(ExpirationStatus::Enabled)
+            .build()
+            .expect("Rule has a status");
+        Ok(GetBucketLifecycleConfigurationOutput::builder()
+            .rules(rule)
+            .build())
+    }
+}
+
+fn no_such_bucket(bucket: &str) -> Error {
+    let message = format!("The specified bucket does not exist: {}", bucket);
+    Error::NoSuchBucket(
+        NoSuchBucket::builder()
+            .message(&message)
+            .meta(
+                ErrorMetadata::builder()
+                    .code("NoSuchBucket")
+                    .message(message)
+                    .build(),
+            )
+            .build(),
+    )
+}
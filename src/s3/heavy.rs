@@ -0,0 +1,314 @@
+use std::{cmp::Ordering, fmt::Display, str::FromStr};
+
+use aws_sdk_s3::types::{CommonPrefix, ObjectVersion};
+use color_eyre::{Result, eyre::bail};
+
+use crate::output::{
+    console::{ConsoleFormat, render},
+    units::format_size,
+};
+
+use super::{
+    client::S3Api, metadata::VersioningStatus, size::split_versions, types::S3Location,
+    wrapper::S3Wrapper,
+};
+
+/// What to rank prefixes by
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default)]
+pub enum HeavyOrder {
+    /// Bytes in every version
+    #[default]
+    Total,
+    /// Bytes in orphaned versions, i.e. what `bu prune` would free
+    Orphaned,
+}
+
+impl FromStr for HeavyOrder {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s {
+            "total" => Ok(HeavyOrder::Total),
+            "orphaned" => Ok(HeavyOrder::Orphaned),
+            _ => Err(format!("expected total or orphaned, not '{}'", s)),
+        }
+    }
+}
+
+impl Display for HeavyOrder {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            HeavyOrder::Total => f.write_str("total"),
+            HeavyOrder::Orphaned => f.write_str("orphaned"),
+        }
+    }
+}
+
+/// How much is stored under a prefix
+#[derive(Debug, Clone, Default, PartialEq, Eq)]
+pub struct PrefixWeight {
+    /// Full key prefix, from the root of the bucket
+    pub prefix: String,
+    pub bytes: u64,
+    /// Objects, or versions in a versioned bucket
+    pub count: usize,
+    pub orphaned_bytes: u64,
+    pub orphaned_count: usize,
+}
+
+impl PrefixWeight {
+    /**
+     * How much is in `versions` under `prefix`, which must include every version of each key
+     * for orphans to be told apart from earlier versions of current objects
+     */
+    fn of(prefix: &str, versions: &[ObjectVersion]) -> PrefixWeight {
+        let size = |v: &ObjectVersion| v.size.unwrap_or_default().max(0) as u64;
+        let (_, _, orphans) = split_versions(versions);
+        PrefixWeight {
+            prefix: prefix.to_string(),
+            bytes: versions.iter().map(size).sum(),
+            count: versions.len(),
+            orphaned_bytes: orphans.iter().map(|v| size(v)).sum(),
+            orphaned_count: orphans.len(),
+        }
+    }
+
+    /**
+     * Whether `self` is heavier than `other` by `order`, which sorts the heavier first
+     */
+    fn heavier(&self, other: &PrefixWeight, order: HeavyOrder) -> Ordering {
+        match order {
+            HeavyOrder::Total => other.bytes.cmp(&self.bytes),
+            HeavyOrder::Orphaned => other.orphaned_bytes.cmp(&self.orphaned_bytes),
+        }
+    }
+}
+
+/// Where a listing carries on from: a continuation token, or key and version id markers
+type Markers = (Option<String>, Option<String>);
+
+/// One page of a listing, with objects in an unversioned bucket as their only version
+struct Page {
+    versions: Vec<ObjectVersion>,
+    /// Folders in the one listed, for a delimited listing
+    folders: Vec<String>,
+    /// Where the next page starts, if there is one
+    next: Option<Markers>,
+}
+
+/**
+ * One page from `from` of the folder `prefix`, i.e. what's directly in it and the folders in
+ * it, or, unless `folder`, of everything under `prefix`
+ */
+async fn list_page<C: S3Api>(
+    client: &C,
+    bucket: &str,
+    prefix: &str,
+    versioned: bool,
+    folder: bool,
+    from: Markers,
+) -> Result<Page> {
+    let folders = |common: Option<Vec<CommonPrefix>>| -> Vec<String> {
+        common
+            .unwrap_or_default()
+            .into_iter()
+            .filter_map(|c| c.prefix)
+            .collect()
+    };
+    let (marker, version_marker) = from;
+    if versioned {
+        let out = match folder {
+            true => {
+                client
+                    .list_folder_versions(bucket, prefix, marker, version_marker)
+                    .await?
+            }
+            false => {
+                client
+                    .list_object_versions(bucket, prefix, marker, version_marker)
+                    .await?
+            }
+        };
+        let next = match (out.next_key_marker, out.next_version_id_marker) {
+            (None, None) => None,
+            markers => Some(markers),
+        };
+        Ok(Page {
+            versions: out.versions.unwrap_or_default(),
+            folders: folders(out.common_prefixes),
+            next,
+        })
+    } else {
+        let out = match folder {
+            true => client.list_folder_objects(bucket, prefix, marker).await?,
+            false => client.list_objects_v2(bucket, prefix, marker).await?,
+        };
+        let versions = out
+            .contents
+            .unwrap_or_default()
+            .into_iter()
+            .map(|o| {
+                ObjectVersion::builder()
+                    .set_key(o.key)
+                    .set_size(o.size)
+                    .is_latest(true)
+                    .build()
+            })
+            .collect();
+        Ok(Page {
+            versions,
+            folders: folders(out.common_prefixes),
+            next: out.next_continuation_token.map(|token| (Some(token), None)),
+        })
+    }
+}
+
+/// A prefix being weighed, and whether there's more under it than has been listed
+struct Candidate {
+    weight: PrefixWeight,
+    sampled: bool,
+}
+
+/**
+ * The `top` heaviest prefixes `depth` folders below `s3_location`, counting objects in
+ * shallower folders under their own folder.
+ *
+ * S3 doesn't say how much is under a prefix, so the folders are walked down with delimited
+ * listings, which weigh what's directly in each one and name the folders in it.  Each prefix
+ * at `depth` is then weighed by the first page listing everything under it, which is all of
+ * it for most.  While any of the heaviest `top` has more than that page, it's listed in full
+ * and everything ranked again.  A prefix whose first page never ranks among them, even in a
+ * tie, isn't listed any further, so its weight is only a lower bound.
+ */
+pub async fn heaviest_prefixes<C: S3Api>(
+    s3_location: &S3Location,
+    s3: &S3Wrapper<C>,
+    depth: usize,
+    top: usize,
+    order: HeavyOrder,
+) -> Result<Vec<PrefixWeight>> {
+    if depth == 0 {
+        bail!("The depth must be at least 1");
+    }
+    let bucket = &s3_location.bucket;
+    let versioned = s3.versioning_status(bucket).await? != VersioningStatus::Never;
+
+    let mut candidates = Vec::new();
+    let mut folders = vec![s3_location.key_prefix()];
+    for _ in 0..depth {
+        let mut below = Vec::new();
+        for folder in folders {
+            let mut versions = Vec::new();
+            let mut from = Markers::default();
+            loop {
+                let mut page =
+                    list_page(&s3.client, bucket, &folder, versioned, true, from).await?;
+                versions.append(&mut page.versions);
+                below.append(&mut page.folders);
+                match page.next {
+                    Some(next) => from = next,
+                    None => break,
+                }
+            }
+            candidates.push(Candidate {
+                weight: PrefixWeight::of(&folder, &versions),
+                sampled: false,
+            });
+        }
+        folders = below;
+    }
+    log::info!("Sampling {} prefixes at depth {}", folders.len(), depth);
+    for prefix in folders {
+        let page = list_page(
+            &s3.client,
+            bucket,
+            &prefix,
+            versioned,
+            false,
+            Markers::default(),
+        )
+        .await?;
+        candidates.push(Candidate {
+            weight: PrefixWeight::of(&prefix, &page.versions),
+            sampled: page.next.is_some(),
+        });
+    }
+    // Folders holding only delete markers, or nothing but other folders
+    candidates.retain(|c| c.weight.count > 0);
+
+    loop {
+        // A sampled prefix is at least as heavy as its first page, so goes first in a tie
+        candidates.sort_by(|a, b| {
+            a.weight
+                .heavier(&b.weight, order)
+                .then(b.sampled.cmp(&a.sampled))
+                .then_with(|| a.weight.prefix.cmp(&b.weight.prefix))
+        });
+        let Some(candidate) = candidates.iter_mut().take(top).find(|c| c.sampled) else {
+            break;
+        };
+        let prefix = &candidate.weight.prefix;
+        log::info!("Listing everything under {} to weigh it", prefix);
+        let versions = match versioned {
+            true => s3.get_object_versions(bucket, prefix, false).await?,
+            false => s3
+                .list_objects_v2(bucket, prefix)
+                .await?
+                .into_iter()
+                .map(|o| {
+                    ObjectVersion::builder()
+                        .set_key(o.key)
+                        .set_size(o.size)
+                        .is_latest(true)
+                        .build()
+                })
+                .collect(),
+        };
+        candidate.weight = PrefixWeight::of(prefix, &versions);
+        candidate.sampled = false;
+    }
+    let sampled = candidates.iter().filter(|c| c.sampled).count();
+    if sampled > 0 {
+        log::info!(
+            "{} prefixes outside the top {} were weighed by their first page only",
+            sampled,
+            top
+        );
+    }
+
+    candidates.truncate(top);
+    Ok(candidates.into_iter().map(|c| c.weight).collect())
+}
+
+/**
+ * A table of prefixes under `bucket`, with how much of each is orphaned
+ */
+pub fn render_heaviest(bucket: &str, weights: &[PrefixWeight]) -> String {
+    let header = [
+        "prefix",
+        "total",
+        "count",
+        "orphaned",
+        "orphaned count",
+        "orphaned share",
+    ]
+    .map(String::from);
+    let rows: Vec<Vec<String>> = weights
+        .iter()
+        .map(|weight| {
+            let share = match weight.bytes {
+                0 => 0.0,
+                bytes => weight.orphaned_bytes as f64 / bytes as f64,
+            };
+            vec![
+                format!("s3://{}/{}", bucket, weight.prefix),
+                format_size(weight.bytes),
+                weight.count.to_string(),
+                format_size(weight.orphaned_bytes),
+                weight.orphaned_count.to_string(),
+                format!("{:.1}%", share * 100.0),
+            ]
+        })
+        .collect();
+    render(ConsoleFormat::Table, &header, &rows)
+}
@@ -0,0 +1,34 @@
+use std::fmt::Display;
+
+use chrono::{DateTime, Utc};
+
+/// Whether a bucket keeps versions of its objects, as S3 reports it
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum VersioningStatus {
+    Enabled,
+    /// Was enabled, so may still hold versions, but new ones aren't kept
+    Suspended,
+    /// Has never been enabled
+    Never,
+}
+
+impl Display for VersioningStatus {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            VersioningStatus::Enabled => f.write_str("Enabled"),
+            VersioningStatus::Suspended => f.write_str("Suspended"),
+            VersioningStatus::Never => f.write_str("None"),
+        }
+    }
+}
+
+/// What an audit asks of a bucket besides its size.  Whatever couldn't be read, e.g. for
+/// lack of permission, is `None`.
+#[derive(Debug, Clone, Default, PartialEq)]
+pub struct BucketMetadata {
+    pub region: Option<String>,
+    pub created: Option<DateTime<Utc>>,
+    /// Whether any lifecycle rules are set, e.g. to expire old versions
+    pub lifecycle_rules: Option<bool>,
+    pub versioning: Option<VersioningStatus>,
+}
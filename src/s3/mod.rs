@@ -1,7 +1,14 @@
+pub mod baseline;
+pub mod client;
+pub mod heavy;
+pub mod metadata;
+pub mod orphans;
+pub mod retention;
 pub mod types;
 pub mod wrapper;
 pub mod size;
-pub mod delete;
 
+#[cfg(test)]
+mod fake;
 #[cfg(test)]
 mod tests;
\ No newline at end of file
@@ -0,0 +1,138 @@
+use std::collections::HashSet;
+
+use aws_sdk_s3::types::ObjectVersion;
+use chrono::{DateTime, Utc};
+use color_eyre::{Result, eyre::bail};
+use serde::Serialize;
+
+use crate::output::{
+    parquet::ColumnType,
+    schema::{ORPHANS_SCHEMA, SCHEMA_COLUMN},
+    units::format_size,
+};
+
+use super::{
+    client::S3Api,
+    metadata::VersioningStatus,
+    size::{RUN_TIME_FORMAT, split_versions, unsafe_to_delete},
+    types::S3Location,
+    wrapper::S3Wrapper,
+};
+
+/// An orphaned version, i.e. of an object whose latest version is a delete marker, as a row
+/// to review before deleting it
+#[derive(Debug, Serialize)]
+pub struct CSVOrphan {
+    schema_version: u32,
+    bucket: String,
+    key: String,
+    version_id: String,
+    size_b: u64,
+    size_human: String,
+    /// When the version was written, in [`RUN_TIME_FORMAT`]
+    last_modified: Option<String>,
+    /// Whole days since the version was written
+    age_days: Option<i64>,
+}
+
+impl CSVOrphan {
+    /**
+     * A row for `version`, in `bucket`, with its age as of `now`
+     */
+    pub fn new(bucket: &str, version: &ObjectVersion, now: DateTime<Utc>) -> Self {
+        let written = version
+            .last_modified()
+            .and_then(|time| DateTime::from_timestamp(time.secs(), 0));
+        let size = version.size().unwrap_or_default().max(0) as u64;
+        CSVOrphan {
+            schema_version: ORPHANS_SCHEMA,
+            bucket: bucket.to_string(),
+            key: version.key().unwrap_or_default().to_string(),
+            version_id: version.version_id().unwrap_or_default().to_string(),
+            size_b: size,
+            size_human: format_size(size),
+            last_modified: written.map(|time| time.format(RUN_TIME_FORMAT).to_string()),
+            age_days: written.map(|time| (now - time).num_days()),
+        }
+    }
+
+    /**
+     * Parquet type of each column: the schema version, size in bytes and age are integers
+     */
+    pub fn column_type(name: &str) -> ColumnType {
+        match name {
+            SCHEMA_COLUMN | "size_b" | "age_days" => ColumnType::Int64,
+            _ => ColumnType::Text,
+        }
+    }
+}
+
+/**
+ * The orphaned versions under `s3_location`, as [`split_versions`] classifies them for
+ * `bu size`, in the order S3 lists them.  Buckets whose versioning is suspended can still hold
+ * orphans, but those which have never had it can't, which is an error as the location was
+ * probably mistyped.
+ */
+pub async fn list_orphans<C: S3Api>(
+    s3_location: &S3Location,
+    s3: &S3Wrapper<C>,
+    verbose: bool,
+) -> Result<Vec<ObjectVersion>> {
+    let versions = list_versions(s3_location, s3, verbose).await?;
+    let (_, _, orphans) = split_versions(&versions);
+    Ok(orphans.into_iter().cloned().collect())
+}
+
+/**
+ * The orphaned versions under `s3_location` that are safe for `bu prune` to delete.  Unlike
+ * [`list_orphans`], records missing a key, version ID or latest flag are left out, as are
+ * the other versions of a key with no latest flag, since its current version isn't known.
+ */
+pub async fn list_prunable_orphans<C: S3Api>(
+    s3_location: &S3Location,
+    s3: &S3Wrapper<C>,
+    verbose: bool,
+) -> Result<Vec<ObjectVersion>> {
+    let versions = list_versions(s3_location, s3, verbose).await?;
+    let url = s3_location.to_string();
+    let unflagged_keys: HashSet<&str> = versions
+        .iter()
+        .filter(|v| v.is_latest().is_none())
+        .filter_map(|v| v.key())
+        .collect();
+    let (_, _, orphans) = split_versions(&versions);
+    let total = orphans.len();
+    let prunable: Vec<ObjectVersion> = orphans
+        .into_iter()
+        .filter(|v| !unsafe_to_delete(&url, v))
+        .filter(|v| v.key().is_none_or(|key| !unflagged_keys.contains(key)))
+        .cloned()
+        .collect();
+    if prunable.len() < total {
+        log::warn!(
+            "Skipping {} orphaned versions under {} with a missing key, version ID or latest flag",
+            total - prunable.len(),
+            url
+        );
+    }
+    Ok(prunable)
+}
+
+/**
+ * Every version under `s3_location`, failing if its bucket has never had versioning and so
+ * can't hold orphans
+ */
+async fn list_versions<C: S3Api>(
+    s3_location: &S3Location,
+    s3: &S3Wrapper<C>,
+    verbose: bool,
+) -> Result<Vec<ObjectVersion>> {
+    if s3.versioning_status(&s3_location.bucket).await? == VersioningStatus::Never {
+        bail!(
+            "Versioning has never been enabled on {}, so it has no orphaned versions",
+            s3_location.bucket
+        );
+    }
+    s3.get_object_versions(&s3_location.bucket, &s3_location.key_prefix(), verbose)
+        .await
+}
@@ -0,0 +1,206 @@
+use std::{collections::HashMap, path::Path};
+
+use aws_sdk_s3::types::ObjectVersion;
+use chrono::{DateTime, TimeDelta, Utc};
+use color_eyre::{
+    Result,
+    eyre::{Context, bail},
+};
+use regex::Regex;
+use serde::{Deserialize, Serialize};
+
+use crate::output::{
+    parquet::ColumnType,
+    schema::{RETENTION_MANIFEST_SCHEMA, SCHEMA_COLUMN},
+    units::format_size,
+};
+
+use super::size::RUN_TIME_FORMAT;
+
+/// Which object versions to keep, as read from a TOML or YAML file.  A version is only deleted if
+/// no rule keeps it, and the current version of an object is always kept.
+#[derive(Debug, Clone, Default, PartialEq, Deserialize)]
+#[serde(deny_unknown_fields)]
+pub struct RetentionPolicy {
+    /// How many versions of each key to keep, newest first, the current one included
+    pub keep_newest: Option<usize>,
+    /// Versions written less than this many days ago are kept
+    pub keep_days: Option<u32>,
+    /// Keys whose versions are all kept, where `*` matches any run of characters, `/`
+    /// included, and `?` any one character
+    #[serde(default)]
+    pub protect: Vec<String>,
+}
+
+impl RetentionPolicy {
+    /**
+     * Read a policy from the file at `path`, which is YAML if it ends in `.yaml` or `.yml`,
+     * otherwise TOML
+     */
+    pub fn from_file(path: &Path) -> Result<Self> {
+        let text = std::fs::read_to_string(path)
+            .wrap_err_with(|| format!("Failed to read {}", path.display()))?;
+        let is_yaml = path.extension().is_some_and(|ext| {
+            ext.eq_ignore_ascii_case("yaml") || ext.eq_ignore_ascii_case("yml")
+        });
+        let policy = match is_yaml {
+            true => Self::parse_yaml(&text),
+            false => Self::parse(&text),
+        };
+        policy.wrap_err_with(|| format!("Invalid retention policy {}", path.display()))
+    }
+
+    /**
+     * Parse a policy from TOML, checking it keeps something, so a typo can't delete every
+     * earlier version
+     */
+    pub fn parse(text: &str) -> Result<Self> {
+        toml::from_str::<RetentionPolicy>(text)?.checked()
+    }
+
+    /**
+     * As [`RetentionPolicy::parse`], from YAML
+     */
+    pub fn parse_yaml(text: &str) -> Result<Self> {
+        serde_yaml::from_str::<RetentionPolicy>(text)?.checked()
+    }
+
+    /**
+     * `self`, if it keeps something
+     */
+    fn checked(self) -> Result<Self> {
+        match (self.keep_newest, self.keep_days) {
+            (None, None) => bail!("Set keep_newest, keep_days or both"),
+            (Some(0), _) => bail!("keep_newest must be at least 1, the current version"),
+            _ => (),
+        }
+        self.protected()?;
+        Ok(self)
+    }
+
+    /**
+     * The versions no rule keeps as of `now`, each with how many newer versions of its key
+     * there are.  `versions` must be as S3 lists them, i.e. the versions of each key
+     * together, newest first.
+     */
+    pub fn excess<'a>(
+        &self,
+        versions: &'a [ObjectVersion],
+        now: DateTime<Utc>,
+    ) -> Result<Vec<(&'a ObjectVersion, usize)>> {
+        let protected = self.protected()?;
+        let mut newer: HashMap<&str, usize> = HashMap::new();
+        let mut excess = Vec::new();
+        for version in versions {
+            // Versions without a key were counted as well as they could be, but can't be
+            // judged, so are left alone
+            let Some(key) = version.key() else { continue };
+            let rank = newer.entry(key).or_default();
+            let newer_versions = *rank;
+            *rank += 1;
+
+            let recent = match (self.keep_days, version.last_modified()) {
+                (Some(days), Some(written)) => {
+                    let written = DateTime::from_timestamp(written.secs(), 0).unwrap_or(now);
+                    now - written < TimeDelta::days(days as i64)
+                }
+                (Some(_), None) => true,
+                (None, _) => false,
+            };
+            // A version without a latest flag might be current, so is kept
+            let kept = version.is_latest().unwrap_or(true)
+                || self.keep_newest.is_some_and(|keep| newer_versions < keep)
+                || recent
+                || protected.as_ref().is_some_and(|p| p.is_match(key));
+            if !kept {
+                excess.push((version, newer_versions));
+            }
+        }
+        Ok(excess)
+    }
+
+    /**
+     * One pattern matching every protected key, if there are any
+     */
+    fn protected(&self) -> Result<Option<Regex>> {
+        if self.protect.is_empty() {
+            return Ok(None);
+        }
+        let patterns: Vec<String> = self
+            .protect
+            .iter()
+            .map(|pattern| {
+                pattern
+                    .split('*')
+                    .map(|part| {
+                        part.split('?')
+                            .map(regex::escape)
+                            .collect::<Vec<_>>()
+                            .join(".")
+                    })
+                    .collect::<Vec<_>>()
+                    .join(".*")
+            })
+            .collect();
+        let pattern = format!("^(?s:{})$", patterns.join("|"));
+        Regex::new(&pattern)
+            .map(Some)
+            .wrap_err("Invalid protect pattern")
+    }
+}
+
+/// A version the retention policy would delete, as a row of the audit manifest
+#[derive(Debug, Serialize)]
+pub struct CSVExcessVersion {
+    schema_version: u32,
+    bucket: String,
+    key: String,
+    version_id: String,
+    /// Newer versions of the same key, so 0 is the newest
+    newer_versions: usize,
+    size_b: u64,
+    size_human: String,
+    /// When the version was written, in [`RUN_TIME_FORMAT`]
+    last_modified: Option<String>,
+    /// Whole days since the version was written
+    age_days: Option<i64>,
+}
+
+impl CSVExcessVersion {
+    /**
+     * A row for `version`, in `bucket`, which has `newer_versions`, with its age as of `now`
+     */
+    pub fn new(
+        bucket: &str,
+        version: &ObjectVersion,
+        newer_versions: usize,
+        now: DateTime<Utc>,
+    ) -> Self {
+        let written = version
+            .last_modified()
+            .and_then(|time| DateTime::from_timestamp(time.secs(), 0));
+        let size = version.size().unwrap_or_default().max(0) as u64;
+        CSVExcessVersion {
+            schema_version: RETENTION_MANIFEST_SCHEMA,
+            bucket: bucket.to_string(),
+            key: version.key().unwrap_or_default().to_string(),
+            version_id: version.version_id().unwrap_or_default().to_string(),
+            newer_versions,
+            size_b: size,
+            size_human: format_size(size),
+            last_modified: written.map(|time| time.format(RUN_TIME_FORMAT).to_string()),
+            age_days: written.map(|time| (now - time).num_days()),
+        }
+    }
+
+    /**
+     * Parquet type of each column: the schema version, counts, size in bytes and age are
+     * integers
+     */
+    pub fn column_type(name: &str) -> ColumnType {
+        match name {
+            SCHEMA_COLUMN | "newer_versions" | "size_b" | "age_days" => ColumnType::Int64,
+            _ => ColumnType::Text,
+        }
+    }
+}
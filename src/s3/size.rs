@@ -5,7 +5,18 @@ use bytesize::ByteSize;
 use serde::Serialize;
 use color_eyre::Result;
 
-use super::{types::S3Location, wrapper::S3Wrapper};
+use crate::output::{
+    console::{Colour, ConsoleFormat, bar, paint, render},
+    parquet::ColumnType,
+    progress::{self, ProgressEvent},
+    schema::{SCHEMA_COLUMN, SIZE_REPORT_SCHEMA},
+    units::format_size,
+};
+
+use super::{
+    baseline::SizeDelta, client::S3Api, metadata::BucketMetadata, types::S3Location,
+    wrapper::S3Wrapper,
+};
 
 
 #[derive(Debug, PartialEq, Eq)]
@@ -15,27 +26,134 @@ pub struct Stats {
 }
 impl Stats {
     pub fn from_object_versions<T: Borrow<ObjectVersion>>(items: &[T]) -> Self {
-        let size = ByteSize::b(items.iter().map(|o|o.borrow().size.expect("Object has no size.")).sum::<i64>() as u64);
-        Stats {
-            num_objects: items.len(),
-            size,
-        }
+        Self::from_sizes(items.len(), items.iter().map(|o| o.borrow().size))
     }
 
     pub fn from_objects<T: Borrow<Object>>(items: &[T]) -> Self {
-        let size = ByteSize::b(items.iter().map(|o|o.borrow().size.expect("Object has no size.")).sum::<i64>() as u64);
+        Self::from_sizes(items.len(), items.iter().map(|o| o.borrow().size))
+    }
+
+    /**
+     * All of `stats` added together
+     */
+    pub fn sum<'a>(stats: impl IntoIterator<Item = &'a Stats>) -> Self {
+        stats.into_iter().fold(Stats { num_objects: 0, size: ByteSize(0) }, |sum, stats| Stats {
+            num_objects: sum.num_objects + stats.num_objects,
+            size: sum.size + stats.size,
+        })
+    }
+
+    /**
+     * Missing sizes count as empty, see [`anomalous`]
+     */
+    fn from_sizes(num_objects: usize, sizes: impl Iterator<Item = Option<i64>>) -> Self {
+        let bytes = sizes.map(|size| size.unwrap_or_default().max(0) as u64).sum();
         Stats {
-            num_objects: items.len(),
-            size,
+            num_objects,
+            size: ByteSize::b(bytes),
         }
     }
 }
 
+/// Width of the bars showing each category's share of a location's total
+const BAR_WIDTH: usize = 20;
+
+/// `url` of the report adding up every location, see [`SizeReport::combined`]
+pub const TOTAL_URL: &str = "total";
+
 #[derive(Debug)]
 pub struct SizeReport {
     pub url: String,
     pub total: Stats,
     pub versions: Option<VersionData>,
+    /// Objects or versions missing a key, size or latest flag, which were counted as well as
+    /// they could be rather than failing the report
+    pub anomalies: usize,
+}
+impl SizeReport {
+    /**
+     * All of `reports` added together, under [`TOTAL_URL`], e.g. for the orphaned bytes across
+     * every bucket.  Versions are added up from the versioned locations, and are `None` if
+     * there weren't any.  Locations which overlap, e.g. a bucket and a prefix in it, are
+     * counted twice.
+     */
+    pub fn combined<'a>(reports: impl IntoIterator<Item = &'a SizeReport>) -> SizeReport {
+        let reports: Vec<&SizeReport> = reports.into_iter().collect();
+        let versions: Vec<&VersionData> = reports.iter()
+            .filter_map(|report| report.versions.as_ref())
+            .collect();
+        let versions = (!versions.is_empty()).then(|| VersionData {
+            current_objects: Stats::sum(versions.iter().map(|v| &v.current_objects)),
+            current_obj_vers: Stats::sum(versions.iter().map(|v| &v.current_obj_vers)),
+            orphaned_vers: Stats::sum(versions.iter().map(|v| &v.orphaned_vers)),
+        });
+        SizeReport {
+            url: TOTAL_URL.to_string(),
+            total: Stats::sum(reports.iter().map(|report| &report.total)),
+            versions,
+            anomalies: reports.iter().map(|report| report.anomalies).sum(),
+        }
+    }
+
+    /**
+     * Aligned lines for reading: the total, then for versioned buckets how it splits between
+     * current objects, earlier versions of them and orphaned versions, with a bar for each
+     * one's share of the total.  Bars and warnings are coloured if `color` is set.
+     */
+    pub fn render(&self, color: bool) -> String {
+        let mut lines = vec![
+            self.url.clone(),
+            format!(
+                "  {:<17}  {:>10}  {} objects",
+                "total",
+                format_size(self.total.size.0),
+                self.total.num_objects
+            ),
+        ];
+        match &self.versions {
+            Some(versions) => {
+                for (name, stats, colour) in [
+                    ("current objects", &versions.current_objects, Colour::Green),
+                    ("earlier versions", &versions.current_obj_vers, Colour::Yellow),
+                    ("orphaned versions", &versions.orphaned_vers, Colour::Red),
+                ] {
+                    let share = self.share(stats);
+                    lines.push(format!(
+                        "  {:<17}  {:>10}  {} {:>5.1}%  {}",
+                        name,
+                        format_size(stats.size.0),
+                        bar(share, BAR_WIDTH, colour, color),
+                        share * 100.0,
+                        stats.num_objects
+                    ));
+                }
+            }
+            None => lines.push(format!("  {}", paint("versioning not active", Colour::Dim, color))),
+        }
+        if self.anomalies > 0 {
+            let anomalies = format!("{} anomalous records", self.anomalies);
+            lines.push(format!("  {}", paint(&anomalies, Colour::Red, color)));
+        }
+        lines.join("\n")
+    }
+
+    /**
+     * Fraction of the total size in `stats`
+     */
+    fn share(&self, stats: &Stats) -> f64 {
+        match self.total.size.0 {
+            0 => 0.0,
+            total => stats.size.0 as f64 / total as f64,
+        }
+    }
+
+    fn emit_progress(&self) {
+        progress::emit(&ProgressEvent::ObjectsCounted {
+            url: &self.url,
+            objects: self.total.num_objects,
+            bytes: self.total.size.0,
+        });
+    }
 }
 impl AsRef<SizeReport> for SizeReport {
     fn as_ref(&self) -> &SizeReport {
@@ -44,29 +162,96 @@ impl AsRef<SizeReport> for SizeReport {
 }
 impl Display for SizeReport {
     fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
-        f.write_fmt(
-            format_args!(
-                "{}:\n  {} (current obj: {}, current vers: {}, orphaned vers: {})", 
-                self.url, 
-                self.total.size, 
-                self.versions.as_ref().expect("No versioning data for current obj.").current_objects.size, 
-                self.versions.as_ref().expect("No versioning data for current vers.").current_obj_vers.size, 
-                self.versions.as_ref().expect("No versioning data for orphaned vers.").orphaned_vers.size
-            )
-        )
+        f.write_str(&self.render(false))
     }
 }
 
+/**
+ * A table comparing several locations, one row each, with how much of each is in earlier or
+ * orphaned versions, i.e. could be reclaimed by expiring old versions
+ */
+pub fn render_summary(reports: &[SizeReport]) -> String {
+    let header = ["url", "total", "objects", "current", "earlier", "orphaned", "reclaimable"]
+        .map(String::from);
+    let rows: Vec<Vec<String>> = reports.iter()
+        .map(|report| {
+            let versioned = |size: fn(&VersionData) -> u64| report.versions.as_ref()
+                .map(|v| format_size(size(v)))
+                .unwrap_or_else(|| "NA".into());
+            let reclaimable = report.versions.as_ref()
+                .map(|v| report.share(&v.current_obj_vers) + report.share(&v.orphaned_vers))
+                .map(|share| format!("{:.1}%", share * 100.0))
+                .unwrap_or_else(|| "NA".into());
+            vec![
+                report.url.clone(),
+                format_size(report.total.size.0),
+                report.total.num_objects.to_string(),
+                versioned(|v| v.current_objects.size.0),
+                versioned(|v| v.current_obj_vers.size.0),
+                versioned(|v| v.orphaned_vers.size.0),
+                reclaimable,
+            ]
+        })
+        .collect();
+    render(ConsoleFormat::Table, &header, &rows)
+}
+
 #[derive(Debug, PartialEq, Eq)]
 pub struct VersionData {
     pub current_objects: Stats,
     pub current_obj_vers: Stats,
     pub orphaned_vers: Stats,
 }
+impl VersionData {
+    /**
+     * Add up `versions` in each of the categories [`split_versions`] sorts them into
+     */
+    pub fn from_versions(versions: &[ObjectVersion]) -> Self {
+        let (current, current_obj_vers, orphaned_vers) = split_versions(versions);
+
+        VersionData {
+            current_objects: Stats::from_object_versions(&current),
+            current_obj_vers: Stats::from_object_versions(&current_obj_vers),
+            orphaned_vers: Stats::from_object_versions(&orphaned_vers),
+        }
+    }
+}
+
+/**
+ * Split `versions` into the latest version of each object, earlier versions of objects which
+ * still exist, and versions of objects whose latest version is a delete marker, i.e. orphans.
+ * A version with no latest flag counts as an earlier one, and one with no key as orphaned.
+ */
+pub fn split_versions(versions: &[ObjectVersion]) -> (
+    Vec<&ObjectVersion>,
+    Vec<&ObjectVersion>,
+    Vec<&ObjectVersion>,
+) {
+    let (current, earlier): (Vec<_>, Vec<_>) = versions.iter()
+        .partition(|v| v.is_latest.unwrap_or(false));
+    let current_object_keys: HashSet<&str> = current.iter()
+        .filter_map(|v| v.key())
+        .collect();
+    let (current_obj_vers, orphaned_vers): (Vec<_>, Vec<_>) = earlier.into_iter()
+        .partition(|v| v.key().is_some_and(|k| current_object_keys.contains(k)));
+    (current, current_obj_vers, orphaned_vers)
+}
+
+/// Format of a report's `run_time`, in UTC so that sorting the text sorts by time
+pub const RUN_TIME_FORMAT: &str = "%Y-%m-%dT%H:%M:%SZ";
 
 #[derive(Debug, Serialize)]
 pub struct CSVSizeReport {
+    schema_version: u32,
+    /// When the report was run, the same for every location in it
+    run_time: String,
     url: String,
+    region: Option<String>,
+    /// When the bucket was created, in [`RUN_TIME_FORMAT`]
+    bucket_created: Option<String>,
+    lifecycle_rules: Option<bool>,
+    /// Enabled, Suspended or None, as S3 reports it
+    versioning_status: Option<String>,
     
     total_human: String,
     total_b: u64,
@@ -85,20 +270,67 @@ pub struct CSVSizeReport {
     current_ver_qty: usize,
     current_obj_qty: usize,
     orphan_ver_qty: usize,
+
+    anomaly_qty: usize,
+
+    /// When the `--baseline` report was run, and the growth since, all empty without one
+    baseline_run_time: Option<String>,
+    delta_total_b: Option<i64>,
+    delta_total_qty: Option<i64>,
+    delta_total_pct: Option<f64>,
+    delta_b_per_day: Option<f64>,
 }
-impl<T: AsRef<SizeReport>> From<T> for CSVSizeReport{
-    fn from(value: T) -> CSVSizeReport {
-        let report = value.as_ref();
+impl CSVSizeReport {
+    /// Names of the columns, in the order they're written
+    pub const COLUMNS: &'static [&'static str] = &[
+        SCHEMA_COLUMN, "run_time", "url",
+        "region", "bucket_created", "lifecycle_rules", "versioning_status",
+        "total_human", "total_b", "total_qty",
+        "versioning_active",
+        "current_obj_human", "current_ver_human", "orphan_ver_human",
+        "current_obj_b", "current_ver_b", "orphan_ver_b",
+        "current_ver_qty", "current_obj_qty", "orphan_ver_qty",
+        "anomaly_qty",
+        "baseline_run_time", "delta_total_b", "delta_total_qty", "delta_total_pct",
+        "delta_b_per_day",
+    ];
+
+    /**
+     * Parquet type of each column: the schema version, sizes in bytes and counts are integers,
+     * and rates of growth are doubles
+     */
+    pub fn column_type(name: &str) -> ColumnType {
+        if name == SCHEMA_COLUMN || name.ends_with("_b") || name.ends_with("_qty") {
+            ColumnType::Int64
+        } else if name == "delta_total_pct" || name == "delta_b_per_day" {
+            ColumnType::Double
+        } else {
+            ColumnType::Text
+        }
+    }
+}
+impl CSVSizeReport {
+    /**
+     * A row for `report`, on a bucket described by `metadata`, from a run at `run_time`,
+     * which [`RUN_TIME_FORMAT`] describes
+     */
+    pub fn new(report: &SizeReport, metadata: &BucketMetadata, run_time: &str) -> CSVSizeReport {
         CSVSizeReport { 
+            schema_version: SIZE_REPORT_SCHEMA,
+            run_time: run_time.to_string(),
             url: report.url.clone(), 
-            total_human: report.total.size.to_string(), 
+            region: metadata.region.clone(),
+            bucket_created: metadata.created.map(|t| t.format(RUN_TIME_FORMAT).to_string()),
+            lifecycle_rules: metadata.lifecycle_rules,
+            versioning_status: metadata.versioning.map(|status| status.to_string()),
+            total_human: format_size(report.total.size.0), 
             total_b: report.total.size.0, 
             total_qty: report.total.num_objects, 
             versioning_active: report.versions.is_some(),
 
-            current_obj_human: report.versions.as_ref().map(|v|v.current_objects.size.to_string()).unwrap_or_default(), 
-            current_ver_human: report.versions.as_ref().map(|v|v.current_obj_vers.size.to_string()).unwrap_or_default(), 
-            orphan_ver_human: report.versions.as_ref().map(|v|v.orphaned_vers.size.to_string()).unwrap_or_default(), 
+            current_obj_human: report.versions.as_ref().map(|v|format_size(v.current_objects.size.0)).unwrap_or_default(), 
+            current_ver_human: report.versions.as_ref().map(|v|format_size(v.current_obj_vers.size.0)).unwrap_or_default(), 
+            orphan_ver_human: report.versions.as_ref().map(|v|format_size(v.orphaned_vers.size.0)).unwrap_or_default(), 
 
             current_obj_b: report.versions.as_ref().map(|v|v.current_objects.size.0).unwrap_or_default(), 
             current_ver_b: report.versions.as_ref().map(|v|v.current_obj_vers.size.0).unwrap_or_default(), 
@@ -107,54 +339,135 @@ impl<T: AsRef<SizeReport>> From<T> for CSVSizeReport{
             current_obj_qty: report.versions.as_ref().map(|v|v.current_objects.num_objects).unwrap_or_default(), 
             current_ver_qty: report.versions.as_ref().map(|v|v.current_obj_vers.num_objects).unwrap_or_default(), 
             orphan_ver_qty: report.versions.as_ref().map(|v|v.orphaned_vers.num_objects).unwrap_or_default(), 
+
+            anomaly_qty: report.anomalies,
+
+            baseline_run_time: None,
+            delta_total_b: None,
+            delta_total_qty: None,
+            delta_total_pct: None,
+            delta_b_per_day: None,
         }
     }
-}
 
-pub async fn build_size_report(s3_location: &S3Location, s3: &S3Wrapper, verbose: bool) -> Result<SizeReport> {
-    if s3.is_versioning_enabled(&s3_location.bucket).await? {
-        let versions = s3.get_object_versions(&s3_location.bucket, &s3_location.prefix, verbose).await?;
-        
-        let total = Stats::from_object_versions(&versions);
-        
-        let current: Vec<_> = versions.iter().filter(|t|{
-            t.is_latest.unwrap_or(false)
-        }).collect();
-        let current_object_keys: HashSet<String> = current.iter().map(|t|{
-            t.key.as_ref().expect("S3 API issue No key for object.").clone()
-        }).collect();
-        let current_objects = Stats::from_object_versions(&current);
-
-        let (current, orphaned): (Vec<_>, Vec<_>) = versions.iter()
-            .filter(|t|!t.is_latest.expect("S3 API issue is_latest unpopulated."))
-            .partition(|t|{
-                t.key().map(|k|current_object_keys.contains(k)).expect("S3 API issue No key for object.")
-            });
-
-        let current_obj_vers = Stats::from_object_versions(&current);
-        let orphaned_vers = Stats::from_object_versions(&orphaned);
-
-        let report = SizeReport {
-            url: s3_location.to_string(),
-            total,
-            versions: Some(VersionData{
-                current_objects,
-                current_obj_vers,
-                orphaned_vers,
-            })
+    /**
+     * This row with its growth since a baseline, if the baseline had its URL
+     */
+    pub fn with_delta(self, delta: Option<SizeDelta>) -> CSVSizeReport {
+        let Some(delta) = delta else { return self };
+        let round = |value: f64, places: i32| {
+            let scale = 10f64.powi(places);
+            (value * scale).round() / scale
         };
+        CSVSizeReport {
+            baseline_run_time: delta.baseline_run_time
+                .map(|time| time.format(RUN_TIME_FORMAT).to_string()),
+            delta_total_b: Some(delta.bytes),
+            delta_total_qty: Some(delta.objects),
+            delta_total_pct: delta.percent.map(|percent| round(percent, 2)),
+            delta_b_per_day: delta.bytes_per_day.map(|rate| round(rate, 0)),
+            ..self
+        }
+    }
+}
 
-        Ok(report)
+/**
+ * Whether a record is missing any of the `fields` flagged, which S3 always fills in but the
+ * SDK leaves optional.  Each one is logged at debug level.
+ */
+fn anomalous(
+    url: &str,
+    key: Option<&str>,
+    version_id: Option<&str>,
+    fields: &[(&str, bool)],
+) -> bool {
+    let missing: Vec<&str> = fields.iter()
+        .filter_map(|&(field, missing)| missing.then_some(field))
+        .collect();
+    if !missing.is_empty() {
+        log::debug!(
+            "In {}, '{}' (version {}) has no {}",
+            url,
+            key.unwrap_or_default(),
+            version_id.unwrap_or("none"),
+            missing.join(" or ")
+        );
+    }
+    !missing.is_empty()
+}
+
+/**
+ * How many of `versions` are missing a key, size or latest flag
+ */
+pub fn version_anomalies(url: &str, versions: &[ObjectVersion]) -> usize {
+    versions.iter()
+        .filter(|v| anomalous(url, v.key(), v.version_id(), &[
+            ("key", v.key.is_none()),
+            ("size", v.size.is_none()),
+            ("latest flag", v.is_latest.is_none()),
+        ]))
+        .count()
+}
+
+/**
+ * Whether `version` is missing a key, version ID or latest flag, so can't be deleted by ID or
+ * might be current
+ */
+pub fn unsafe_to_delete(url: &str, version: &ObjectVersion) -> bool {
+    anomalous(url, version.key(), version.version_id(), &[
+        ("key", version.key.is_none()),
+        ("version ID", version.version_id.is_none()),
+        ("latest flag", version.is_latest.is_none()),
+    ])
+}
+
+/**
+ * How many of `objects` are missing a key or size
+ */
+pub fn object_anomalies(url: &str, objects: &[Object]) -> usize {
+    objects.iter()
+        .filter(|o| anomalous(url, o.key(), None, &[
+            ("key", o.key.is_none()),
+            ("size", o.size.is_none()),
+        ]))
+        .count()
+}
+
+pub async fn build_size_report<C: S3Api>(
+    s3_location: &S3Location,
+    s3: &S3Wrapper<C>,
+    verbose: bool,
+) -> Result<SizeReport> {
+    let url = s3_location.to_string();
+    let report = if s3.is_versioning_enabled(&s3_location.bucket).await? {
+        let versions = s3.get_object_versions(&s3_location.bucket, &s3_location.key_prefix(), verbose).await?;
+
+        SizeReport {
+            total: Stats::from_object_versions(&versions),
+            versions: Some(VersionData::from_versions(&versions)),
+            anomalies: version_anomalies(&url, &versions),
+            url,
+        }
     } else {
         log::warn!("Versioning is NOT active on {}", s3_location);
-        let objects = s3.list_objects_v2(&s3_location.bucket, &s3_location.prefix).await?;
-        let stats = Stats::from_objects(&objects);
+        let objects = s3.list_objects_v2(&s3_location.bucket, &s3_location.key_prefix()).await?;
 
-        Ok(SizeReport{
-            url: s3_location.to_string(),
-            total: stats,
+        SizeReport {
+            total: Stats::from_objects(&objects),
             versions: None,
-        })
+            anomalies: object_anomalies(&url, &objects),
+            url,
+        }
+    };
 
+    if report.anomalies > 0 {
+        log::warn!(
+            "{} records in {} were missing fields S3 should fill in, and were counted as well \
+             as they could be (-vv to list them)",
+            report.anomalies,
+            report.url
+        );
     }
-}
\ No newline at end of file
+    report.emit_progress();
+    Ok(report)
+}
@@ -1,18 +1,34 @@
 use std::{env, path::Path, process::Command};
 
-use aws_sdk_s3::{Client};
+use aws_sdk_s3::{Client, types::{Object, ObjectVersion}};
 use bytesize::ByteSize;
 use tokio::runtime::Runtime;
 use color_eyre::{Result, eyre::{OptionExt, WrapErr}};
 
-use crate::s3::size::{Stats, VersionData};
+use crate::s3::size::{
+    CSVSizeReport, SizeReport, Stats, TOTAL_URL, VersionData, object_anomalies, render_summary,
+    version_anomalies,
+};
+use crate::exit::{Failure, exit_code};
+use crate::output::{
+    console::{ConsoleFormat, render_records},
+    select::{ColumnSelection, sort_by_column},
+    sink::record_fields,
+};
+use crate::s3::fake::{FAKE_CREATED, FAKE_REGION, FAKE_WRITE_INTERVAL, FakeS3};
+use crate::s3::orphans::{CSVOrphan, list_orphans, list_prunable_orphans};
+use crate::s3::retention::RetentionPolicy;
+use crate::s3::heavy::{HeavyOrder, PrefixWeight, heaviest_prefixes, render_heaviest};
+use crate::s3::metadata::{BucketMetadata, VersioningStatus};
+use crate::s3::types::MAX_KEY_BYTES;
+use crate::s3::baseline::Baseline;
+use proptest::prelude::*;
 
 use super::{size::build_size_report, types::S3Location, wrapper::S3Wrapper};
 
 
 struct StorageTestHelper {
     s3_location: S3Location,
-    prefix: String,
     delete_prefix_on_drop: bool,
     s3_wrapper: S3Wrapper,
     runtime: Runtime,
@@ -42,8 +58,7 @@ impl StorageTestHelper {
         };
 
         let instance = StorageTestHelper {
-            s3_location: S3Location { bucket, prefix: prefix.to_string() },
-            prefix: prefix.into(),
+            s3_location: S3Location { bucket, prefix: prefix.to_string(), trailing_slash: false },
             delete_prefix_on_drop,
             s3_wrapper,
             runtime,
@@ -59,7 +74,7 @@ impl StorageTestHelper {
         self.runtime.block_on(
             self.s3_wrapper.purge_all_versions_of_everything(
                 &self.s3_location.bucket, 
-                &self.s3_location.prefix,
+                &self.s3_location.key_prefix(),
                 false
             )
         )
@@ -163,4 +178,594 @@ fn test_with_versions() -> Result<()> {
     assert_eq!(expected_versions, report.versions.ok_or_eyre("Report has no versions.")?);
     
     Ok(())
-}
\ No newline at end of file
+}
+#[test]
+fn test_versions_missing_fields_are_counted_not_fatal() {
+    let version = |key: Option<&str>, size: Option<i64>, is_latest: Option<bool>| {
+        ObjectVersion::builder()
+            .set_key(key.map(str::to_owned))
+            .set_size(size)
+            .set_is_latest(is_latest)
+            .build()
+    };
+    let versions = [
+        version(Some("a"), Some(10), Some(true)),
+        version(Some("a"), Some(5), Some(false)),
+        // Only a delete marker is latest for "b"
+        version(Some("b"), Some(3), Some(false)),
+        version(None, Some(2), Some(false)),
+        version(Some("c"), None, Some(true)),
+        version(Some("a"), Some(1), None),
+    ];
+
+    let expected_versions = VersionData {
+        current_objects: Stats { num_objects: 2, size: ByteSize(10) },
+        current_obj_vers: Stats { num_objects: 2, size: ByteSize(6) },
+        orphaned_vers: Stats { num_objects: 2, size: ByteSize(5) },
+    };
+    assert_eq!(expected_versions, VersionData::from_versions(&versions));
+    assert_eq!(3, version_anomalies("s3://bucket/", &versions));
+
+    let objects = [
+        Object::builder().key("a").size(4).build(),
+        Object::builder().key("b").build(),
+    ];
+    assert_eq!(Stats { num_objects: 2, size: ByteSize(4) }, Stats::from_objects(&objects));
+    assert_eq!(1, object_anomalies("s3://bucket/", &objects));
+}
+
+#[test]
+fn test_parse_s3_locations() -> Result<()> {
+    let cases = [
+        ("s3://bucket/prefix", "bucket", "prefix", false),
+        ("S3://bucket/prefix/", "bucket", "prefix", true),
+        ("bucket", "bucket", "", false),
+        ("s3://bucket", "bucket", "", false),
+        ("bucket/", "bucket", "", false),
+        ("  my.bucket-1/a/b/c.txt", "my.bucket-1", "a/b/c.txt", false),
+        ("bucket/with space/(1)+ü/", "bucket", "with space/(1)+ü", true),
+        ("bucket//leading", "bucket", "/leading", false),
+        ("bucket/a//", "bucket", "a/", true),
+        ("bucket/s3://not-a-scheme", "bucket", "s3://not-a-scheme", false),
+    ];
+    for (url, bucket, prefix, trailing_slash) in cases {
+        let expected = S3Location {
+            bucket: bucket.to_string(),
+            prefix: prefix.to_string(),
+            trailing_slash,
+        };
+        assert_eq!(expected, url.parse::<S3Location>()?, "{}", url);
+    }
+    Ok(())
+}
+
+#[test]
+fn test_reject_invalid_s3_locations() {
+    let too_long = format!("bucket/{}", "k".repeat(MAX_KEY_BYTES + 1));
+    for url in ["", "s3://", "/prefix", "s3:///prefix", "https://bucket/x", "my bucket/x", &too_long] {
+        assert!(S3Location::parse(url).is_err(), "{}", url);
+    }
+}
+
+proptest! {
+    #[test]
+    fn test_s3_location_keeps_any_key(
+        bucket in "[a-z0-9][a-z0-9.-]{1,61}[a-z0-9]",
+        key in "\\PC{0,200}",
+        scheme in prop_oneof![Just(""), Just("s3://"), Just("S3://")],
+    ) {
+        let location = S3Location::parse(&format!("{}{}/{}", scheme, bucket, key)).unwrap();
+        prop_assert_eq!(&location.bucket, &bucket);
+        prop_assert_eq!(location.key_prefix(), key.clone());
+        prop_assert!(!location.trailing_slash || key.ends_with('/'));
+        prop_assert_eq!(S3Location::parse(&location.to_string()).unwrap(), location);
+    }
+}
+
+fn fake_wrapper(fake: FakeS3) -> S3Wrapper<FakeS3> {
+    S3Wrapper { client: fake }
+}
+
+#[test]
+fn test_size_report_from_fake_versions() -> Result<()> {
+    let s3 = fake_wrapper(FakeS3::new(2).with_bucket("bucket", true));
+    s3.client.put("bucket", "a", 10);
+    s3.client.put("bucket", "a", 20);
+    s3.client.put("bucket", "b", 5);
+    s3.client.delete("bucket", "b");
+    s3.client.put("bucket", "other/c", 7);
+
+    let location = S3Location::parse("bucket")?;
+    let report = Runtime::new()?.block_on(build_size_report(&location, &s3, false))?;
+
+    assert_eq!(Stats { num_objects: 4, size: ByteSize(42) }, report.total);
+    let expected_versions = VersionData {
+        current_objects: Stats { num_objects: 2, size: ByteSize(27) },
+        current_obj_vers: Stats { num_objects: 1, size: ByteSize(10) },
+        orphaned_vers: Stats { num_objects: 1, size: ByteSize(5) },
+    };
+    assert_eq!(Some(expected_versions), report.versions);
+    assert_eq!(0, report.anomalies);
+
+    let location = S3Location::parse("bucket/other/")?;
+    let report = Runtime::new()?.block_on(build_size_report(&location, &s3, false))?;
+    assert_eq!(Stats { num_objects: 1, size: ByteSize(7) }, report.total);
+    Ok(())
+}
+
+#[test]
+fn test_size_report_renders_share_of_each_category() {
+    let report = SizeReport {
+        url: "s3://bucket/".into(),
+        total: Stats { num_objects: 4, size: ByteSize(40) },
+        versions: Some(VersionData {
+            current_objects: Stats { num_objects: 2, size: ByteSize(20) },
+            current_obj_vers: Stats { num_objects: 1, size: ByteSize(10) },
+            orphaned_vers: Stats { num_objects: 1, size: ByteSize(10) },
+        }),
+        anomalies: 0,
+    };
+    let unversioned = SizeReport {
+        url: "s3://other/".into(),
+        total: Stats { num_objects: 0, size: ByteSize(0) },
+        versions: None,
+        anomalies: 1,
+    };
+
+    assert_eq!(
+        "s3://bucket/\n\
+        \x20 total                    40 B  4 objects\n\
+        \x20 current objects          20 B  ██████████░░░░░░░░░░  50.0%  2\n\
+        \x20 earlier versions         10 B  █████░░░░░░░░░░░░░░░  25.0%  1\n\
+        \x20 orphaned versions        10 B  █████░░░░░░░░░░░░░░░  25.0%  1",
+        report.to_string()
+    );
+    assert!(report.render(true).contains("\x1b[32m██████████\x1b[0m"));
+    assert_eq!(
+        "s3://other/\n  total                     0 B  0 objects\n  versioning not active\n\
+        \x20 1 anomalous records",
+        unversioned.to_string()
+    );
+    let summary = render_summary(&[report, unversioned]);
+    assert!(summary.contains(
+        "| s3://bucket/ | 40 B  | 4       | 20 B    | 10 B    | 10 B     | 50.0%"
+    ));
+    assert!(summary.contains(
+        "| s3://other/  | 0 B   | 0       | NA      | NA      | NA       | NA"
+    ));
+}
+
+#[test]
+fn test_bucket_metadata_from_fake_buckets() -> Result<()> {
+    let fake = FakeS3::new(1)
+        .with_bucket("audited", true)
+        .with_lifecycle("audited")
+        .with_bucket("audited-too", true)
+        .with_bucket("plain", false);
+    fake.suspend_versioning("audited-too");
+    let s3 = fake_wrapper(fake);
+    let runtime = Runtime::new()?;
+
+    let audited = runtime.block_on(s3.bucket_metadata("audited"))?;
+    assert_eq!(
+        BucketMetadata {
+            region: Some(FAKE_REGION.into()),
+            created: chrono::DateTime::from_timestamp(FAKE_CREATED, 0),
+            lifecycle_rules: Some(true),
+            versioning: Some(VersioningStatus::Enabled),
+        },
+        audited
+    );
+    let suspended = runtime.block_on(s3.bucket_metadata("audited-too"))?;
+    assert_eq!(Some(false), suspended.lifecycle_rules);
+    assert_eq!(Some(FAKE_REGION.into()), suspended.region);
+    assert_eq!("Suspended", suspended.versioning.unwrap().to_string());
+    let plain = runtime.block_on(s3.bucket_metadata("plain"))?;
+    assert_eq!("None", plain.versioning.unwrap().to_string());
+    assert!(runtime.block_on(s3.bucket_metadata("missing")).is_err());
+
+    let report = SizeReport {
+        url: "s3://audited/".into(),
+        total: Stats { num_objects: 0, size: ByteSize(0) },
+        versions: None,
+        anomalies: 0,
+    };
+    let (header, values) =
+        record_fields(&CSVSizeReport::new(&report, &audited, "2025-06-01T00:00:00Z"))?;
+    let field = |name: &str| values[header.iter().position(|h| h == name).unwrap()].clone();
+    assert_eq!(FAKE_REGION, field("region"));
+    assert_eq!("2023-11-14T22:13:20Z", field("bucket_created"));
+    assert_eq!("true", field("lifecycle_rules"));
+    assert_eq!("Enabled", field("versioning_status"));
+    Ok(())
+}
+
+#[test]
+fn test_orphans_are_listed_with_sizes_and_ages() -> Result<()> {
+    let s3 = fake_wrapper(
+        FakeS3::new(2).with_bucket("bucket", true).with_bucket("plain", false)
+    );
+    s3.client.put("bucket", "kept", 10);
+    s3.client.put("bucket", "kept", 20);
+    s3.client.put("bucket", "gone", 5);
+    s3.client.put("bucket", "gone", 6);
+    s3.client.delete("bucket", "gone");
+    let runtime = Runtime::new()?;
+
+    let orphans = runtime.block_on(list_orphans(&S3Location::parse("bucket")?, &s3, false))?;
+    let now = chrono::DateTime::from_timestamp(FAKE_CREATED + 10 * FAKE_WRITE_INTERVAL, 0)
+        .unwrap();
+    let rows = orphans.iter()
+        .map(|orphan| record_fields(&CSVOrphan::new("bucket", orphan, now)).map(|(_, v)| v))
+        .collect::<Result<Vec<_>>>()?;
+
+    assert_eq!(
+        vec![
+            vec!["1", "bucket", "gone", "v4", "6", "6 B", "2023-11-18T22:13:20Z", "6"],
+            vec!["1", "bucket", "gone", "v3", "5", "5 B", "2023-11-17T22:13:20Z", "7"],
+        ],
+        rows
+    );
+    let none_kept = runtime.block_on(list_orphans(&S3Location::parse("bucket/kept")?, &s3, false))?;
+    assert!(none_kept.is_empty());
+    assert!(runtime.block_on(list_orphans(&S3Location::parse("plain")?, &s3, false)).is_err());
+    Ok(())
+}
+
+#[test]
+fn test_prune_deletes_only_orphaned_versions() -> Result<()> {
+    let s3 = fake_wrapper(FakeS3::new(2).with_bucket("bucket", true));
+    s3.client.put("bucket", "kept", 10);
+    s3.client.put("bucket", "kept", 20);
+    s3.client.put("bucket", "gone", 5);
+    s3.client.put("bucket", "gone", 6);
+    s3.client.delete("bucket", "gone");
+    s3.client.put("bucket", "other/gone", 7);
+    s3.client.delete("bucket", "other/gone");
+    let runtime = Runtime::new()?;
+    let before = runtime.block_on(s3.get_object_versions("bucket", "", false))?;
+
+    let orphans = runtime.block_on(list_orphans(&S3Location::parse("bucket/gone")?, &s3, false))?;
+    runtime.block_on(s3.delete_versions("bucket", &orphans))?;
+
+    assert_eq!(vec![2], *s3.client.delete_requests.lock().unwrap());
+    let left = runtime.block_on(s3.get_object_versions("bucket", "", false))?;
+    let expected: Vec<_> = before.into_iter()
+        .filter(|v| v.key() != Some("gone"))
+        .collect();
+    assert_eq!(expected, left);
+    let report = runtime.block_on(build_size_report(&S3Location::parse("bucket")?, &s3, false))?;
+    assert_eq!(Stats { num_objects: 1, size: ByteSize(7) }, report.versions.unwrap().orphaned_vers);
+    Ok(())
+}
+
+#[test]
+fn test_prune_leaves_anomalous_versions_alone() -> Result<()> {
+    let s3 = fake_wrapper(
+        FakeS3::new(2)
+            .with_bucket("bucket", true)
+            .with_keyless("lost")
+            .with_unflagged("solo")
+    );
+    s3.client.put("bucket", "lost", 1);
+    s3.client.put("bucket", "lost", 2);
+    s3.client.put("bucket", "solo", 3);
+    s3.client.put("bucket", "gone", 4);
+    s3.client.delete("bucket", "gone");
+    let runtime = Runtime::new()?;
+    let location = S3Location::parse("bucket")?;
+
+    // Both anomalies count as orphans in `bu size`, but only "gone" is safe to delete
+    assert_eq!(3, runtime.block_on(list_orphans(&location, &s3, false))?.len());
+    let orphans = runtime.block_on(list_prunable_orphans(&location, &s3, false))?;
+    assert_eq!(vec![Some("gone")], orphans.iter().map(|v| v.key()).collect::<Vec<_>>());
+    runtime.block_on(s3.delete_versions("bucket", &orphans))?;
+
+    let left = runtime.block_on(s3.get_object_versions("bucket", "", false))?;
+    let left: Vec<_> = left.iter().map(|v| (v.key(), v.version_id())).collect();
+    assert_eq!(vec![(None, Some("v2")), (None, Some("v1")), (Some("solo"), Some("v3"))], left);
+
+    let keyless = ObjectVersion::builder().version_id("v9").build();
+    assert!(runtime.block_on(s3.delete_versions("bucket", &[keyless])).is_err());
+    Ok(())
+}
+
+#[test]
+fn test_retention_policy_keeps_newest_recent_and_protected_versions() -> Result<()> {
+    let s3 = fake_wrapper(FakeS3::new(3).with_bucket("bucket", true));
+    // Written a day apart, in this order
+    for key in ["a", "a", "a", "a", "logs/b", "logs/b", "logs/b", "c", "c"] {
+        s3.client.put("bucket", key, 1);
+    }
+    s3.client.delete("bucket", "c");
+    let runtime = Runtime::new()?;
+    let versions = runtime.block_on(s3.get_object_versions("bucket", "", false))?;
+    let now = chrono::DateTime::from_timestamp(FAKE_CREATED + 10 * FAKE_WRITE_INTERVAL, 0)
+        .unwrap();
+    let excess = |policy: &str| -> Result<Vec<String>> {
+        let policy = RetentionPolicy::parse(policy)?;
+        Ok(policy.excess(&versions, now)?.into_iter()
+            .map(|(v, newer)| format!("{}@{}+{}", v.key().unwrap(), v.version_id().unwrap(), newer))
+            .collect())
+    };
+
+    assert_eq!(vec!["a@v2+2", "a@v1+3", "logs/b@v5+2"], excess("keep_newest = 2")?);
+    // Versions 1 to 4 are 9 to 6 days old
+    assert_eq!(vec!["a@v2+2", "a@v1+3"], excess("keep_days = 8\nkeep_newest = 2")?);
+    assert_eq!(vec!["a@v3+1", "a@v2+2", "a@v1+3"], excess("keep_days = 7")?);
+    // Deleted keys have no current version, so their newest versions are kept by count
+    assert_eq!(
+        vec!["a@v3+1", "a@v2+2", "a@v1+3", "c@v8+1"],
+        excess("keep_newest = 1\nprotect = [\"logs/*\"]")?
+    );
+    assert!(excess("keep_newest = 1\nprotect = [\"logs/*\", \"?\"]")?.is_empty());
+
+    for invalid in ["", "protect = [\"a\"]", "keep_newest = 0", "keep_newst = 2"] {
+        assert!(RetentionPolicy::parse(invalid).is_err(), "{}", invalid);
+    }
+
+    let yaml = RetentionPolicy::parse_yaml("keep_newest: 1\nprotect: [\"logs/*\"]")?;
+    assert_eq!(RetentionPolicy::parse("keep_newest = 1\nprotect = [\"logs/*\"]")?, yaml);
+    for invalid in ["keep_newest: 0", "keep_newst: 2", "protect: [a]"] {
+        assert!(RetentionPolicy::parse_yaml(invalid).is_err(), "{}", invalid);
+    }
+    let path = std::env::temp_dir().join(format!("bu_test_policy_{}.yml", std::process::id()));
+    std::fs::write(&path, "keep_days: 7\n")?;
+    let from_file = RetentionPolicy::from_file(&path);
+    std::fs::remove_file(&path)?;
+    assert_eq!(Some(7), from_file?.keep_days);
+    Ok(())
+}
+
+#[test]
+fn test_heaviest_prefixes_by_total_and_orphaned_bytes() -> Result<()> {
+    // Pages big enough for a prefix's first page to be all of it
+    let s3 = fake_wrapper(FakeS3::new(10).with_bucket("bucket", true).with_bucket("plain", false));
+    for (key, size) in [
+        ("logs/2024/a", 50), ("logs/2024/a", 60), ("logs/2025/b", 10), ("data/x/big", 100),
+        ("data/y", 5), ("top", 1), ("data/x/old", 30),
+    ] {
+        s3.client.put("bucket", key, size);
+        s3.client.put("plain", key, size);
+    }
+    s3.client.delete("bucket", "data/x/old");
+    let runtime = Runtime::new()?;
+    let heaviest = |url: &str, depth, top, order, bucket_prefixes: &[&str]| -> Result<()> {
+        let location = S3Location::parse(url)?;
+        let weights =
+            runtime.block_on(heaviest_prefixes(&location, &s3, depth, top, order))?;
+        let prefixes: Vec<&str> = weights.iter().map(|w| w.prefix.as_str()).collect();
+        assert_eq!(bucket_prefixes, prefixes, "{} at depth {}", url, depth);
+        Ok(())
+    };
+
+    heaviest("bucket", 1, 10, HeavyOrder::Total, &["data/", "logs/", ""])?;
+    heaviest("bucket", 2, 3, HeavyOrder::Total, &["data/x/", "logs/2024/", "logs/2025/"])?;
+    heaviest("bucket", 2, 1, HeavyOrder::Orphaned, &["data/x/"])?;
+    heaviest("bucket/logs/", 1, 10, HeavyOrder::Total, &["logs/2024/", "logs/2025/"])?;
+    heaviest("plain", 1, 10, HeavyOrder::Total, &["data/", "logs/", ""])?;
+    assert!(runtime.block_on(heaviest_prefixes(
+        &S3Location::parse("bucket")?, &s3, 0, 10, HeavyOrder::Total
+    )).is_err());
+
+    let location = S3Location::parse("bucket")?;
+    let weights =
+        runtime.block_on(heaviest_prefixes(&location, &s3, 1, 1, HeavyOrder::Total))?;
+    assert_eq!(
+        PrefixWeight {
+            prefix: "data/".into(),
+            bytes: 135,
+            count: 3,
+            orphaned_bytes: 30,
+            orphaned_count: 1,
+        },
+        weights[0]
+    );
+    assert!(render_heaviest("bucket", &weights).contains("| s3://bucket/data/ | 135 B"));
+    Ok(())
+}
+
+#[test]
+fn test_heaviest_prefixes_only_lists_the_heaviest_in_full() -> Result<()> {
+    let runtime = Runtime::new()?;
+    for versioned in [false, true] {
+        let s3 = fake_wrapper(FakeS3::new(2).with_bucket("bucket", versioned));
+        for (key, size) in [
+            ("big/1", 10), ("big/2", 10), ("big/3", 10), ("big/4", 10), ("big/5", 10),
+            ("mid/1", 5), ("mid/2", 5), ("mid/3", 5), ("small/a", 1),
+        ] {
+            s3.client.put("bucket", key, size);
+        }
+        let location = S3Location::parse("bucket")?;
+        let weights =
+            runtime.block_on(heaviest_prefixes(&location, &s3, 1, 1, HeavyOrder::Total))?;
+
+        assert_eq!(
+            vec![PrefixWeight { prefix: "big/".into(), bytes: 50, count: 5, ..Default::default() }],
+            weights
+        );
+        // A page of each to start with, then the rest of the heaviest, from the beginning
+        assert_eq!(
+            vec!["big/", "mid/", "small/", "big/", "big/", "big/"],
+            *s3.client.list_requests.lock().unwrap(),
+            "versioned: {}", versioned
+        );
+    }
+    Ok(())
+}
+
+#[test]
+fn test_combined_size_report_adds_up_every_location() {
+    let versioned = |url: &str, orphaned: u64| SizeReport {
+        url: url.into(),
+        total: Stats { num_objects: 3, size: ByteSize(30 + orphaned) },
+        versions: Some(VersionData {
+            current_objects: Stats { num_objects: 1, size: ByteSize(20) },
+            current_obj_vers: Stats { num_objects: 1, size: ByteSize(10) },
+            orphaned_vers: Stats { num_objects: 1, size: ByteSize(orphaned) },
+        }),
+        anomalies: 1,
+    };
+    let unversioned = SizeReport {
+        url: "s3://c/".into(),
+        total: Stats { num_objects: 5, size: ByteSize(500) },
+        versions: None,
+        anomalies: 0,
+    };
+
+    let reports = [versioned("s3://a/", 5), versioned("s3://b/", 7), unversioned];
+    let total = SizeReport::combined(&reports);
+
+    assert_eq!(TOTAL_URL, total.url);
+    assert_eq!(Stats { num_objects: 11, size: ByteSize(572) }, total.total);
+    let expected_versions = VersionData {
+        current_objects: Stats { num_objects: 2, size: ByteSize(40) },
+        current_obj_vers: Stats { num_objects: 2, size: ByteSize(20) },
+        orphaned_vers: Stats { num_objects: 2, size: ByteSize(12) },
+    };
+    assert_eq!(Some(expected_versions), total.versions);
+    assert_eq!(2, total.anomalies);
+    assert_eq!(None, SizeReport::combined(&[]).versions);
+}
+
+#[test]
+fn test_size_report_columns_are_selected_and_sorted() -> Result<()> {
+    let report = |url: &str, orphaned: u64| SizeReport {
+        url: url.into(),
+        total: Stats { num_objects: 2, size: ByteSize(100 + orphaned) },
+        versions: Some(VersionData {
+            current_objects: Stats { num_objects: 1, size: ByteSize(100) },
+            current_obj_vers: Stats { num_objects: 0, size: ByteSize(0) },
+            orphaned_vers: Stats { num_objects: 1, size: ByteSize(orphaned) },
+        }),
+        anomalies: 0,
+    };
+    let csv_row = |url, orphaned| {
+        CSVSizeReport::new(&report(url, orphaned), &Default::default(), "2025-06-01T00:00:00Z")
+    };
+
+    let all = ColumnSelection::new(CSVSizeReport::COLUMNS, &[])?;
+    let (header, _) = record_fields(&csv_row("s3://a/", 1))?;
+    assert_eq!(CSVSizeReport::COLUMNS, header);
+    assert_eq!(header, record_fields(&all.select(&csv_row("s3://a/", 1))?)?.0);
+
+    let wanted = ["orphan_ver_b".to_string(), "url".to_string()];
+    let selection = ColumnSelection::new(CSVSizeReport::COLUMNS, &wanted)?;
+    let mut rows = [("s3://a/", 9), ("s3://b/", 10), ("s3://c/", 200)]
+        .into_iter()
+        .map(|(url, orphaned)| selection.select(&csv_row(url, orphaned)))
+        .collect::<Result<Vec<_>>>()?;
+    sort_by_column(&mut rows, |row| row, "orphan_ver_b", true)?;
+    assert_eq!(
+        "| schema_version | orphan_ver_b | url     |\n\
+         |----------------|--------------|---------|\n\
+         | 5              | 200          | s3://c/ |\n\
+         | 5              | 10           | s3://b/ |\n\
+         | 5              | 9            | s3://a/ |",
+        render_records(ConsoleFormat::Table, &rows)?
+    );
+    sort_by_column(&mut rows, |row| row, "url", false)?;
+    assert_eq!(Some("s3://a/"), rows[0].get("url"));
+
+    assert!(ColumnSelection::new(CSVSizeReport::COLUMNS, &["orphans".to_string()]).is_err());
+    assert!(sort_by_column(&mut rows, |row| row, "total_b", false).is_err());
+    Ok(())
+}
+
+#[test]
+fn test_baseline_deltas_use_the_newest_row_for_each_url() -> Result<()> {
+    let path = env::temp_dir().join(format!("bu_test_baseline_{}.csv", std::process::id()));
+    std::fs::write(
+        &path,
+        "schema_version;run_time;url;total_b;total_qty\n\
+         3;2025-05-01T00:00:00Z;s3://a/;1000;10\n\
+         3;2025-05-21T00:00:00Z;s3://a/;2000;20\n\
+         3;2025-05-21T00:00:00Z;s3://empty/;0;0\n\
+         3;2025-05-21T00:00:00Z;total;2000;20\n",
+    )?;
+    let baseline = Baseline::from_csv(&path);
+    std::fs::remove_file(&path)?;
+    let baseline = baseline?;
+    let now = "2025-05-31T00:00:00Z".parse()?;
+
+    let delta = baseline.delta("s3://a/", 1500, 25, now).ok_or_eyre("No delta for s3://a/")?;
+    assert_eq!(-500, delta.bytes);
+    assert_eq!(5, delta.objects);
+    assert_eq!(Some(-25.0), delta.percent);
+    assert_eq!(Some(-50.0), delta.bytes_per_day);
+    assert!(baseline.delta(TOTAL_URL, 2000, 20, now).is_some());
+    assert_eq!(None, baseline.delta("s3://b/", 1, 1, now));
+    assert_eq!(None, baseline.delta("s3://empty/", 1, 1, now).and_then(|d| d.percent));
+
+    let report = SizeReport {
+        url: "s3://a/".into(),
+        total: Stats { num_objects: 25, size: ByteSize(1500) },
+        versions: None,
+        anomalies: 0,
+    };
+    let row = CSVSizeReport::new(&report, &Default::default(), "2025-05-31T00:00:00Z")
+        .with_delta(baseline.delta("s3://a/", 1500, 25, now));
+    let columns = ["baseline_run_time", "delta_total_b", "delta_total_pct", "delta_b_per_day"]
+        .map(String::from);
+    let row = ColumnSelection::new(CSVSizeReport::COLUMNS, &columns)?.select(&row)?;
+    assert_eq!(Some("2025-05-21T00:00:00Z"), row.get("baseline_run_time"));
+    assert_eq!(Some("-500"), row.get("delta_total_b"));
+    assert_eq!(Some("-25.0"), row.get("delta_total_pct"));
+    assert_eq!(Some("-50.0"), row.get("delta_b_per_day"));
+
+    assert!(Baseline::from_csv(Path::new("no_such_baseline.csv")).is_err());
+    Ok(())
+}
+
+#[test]
+fn test_size_report_from_fake_unversioned_bucket() -> Result<()> {
+    let s3 = fake_wrapper(FakeS3::new(2).with_bucket("bucket", false));
+    for (key, size) in [("a", 3), ("a", 4), ("b", 5), ("c", 6)] {
+        s3.client.put("bucket", key, size);
+    }
+
+    let location = S3Location::parse("bucket")?;
+    let report = Runtime::new()?.block_on(build_size_report(&location, &s3, false))?;
+
+    assert_eq!(Stats { num_objects: 3, size: ByteSize(15) }, report.total);
+    assert_eq!(None, report.versions);
+    Ok(())
+}
+
+#[test]
+fn test_purge_deletes_a_page_at_a_time() -> Result<()> {
+    let s3 = fake_wrapper(FakeS3::new(2).with_bucket("bucket", true));
+    for key in ["a", "a", "b", "c"] {
+        s3.client.put("bucket", key, 1);
+    }
+    s3.client.delete("bucket", "c");
+    s3.client.put("bucket", "keep/d", 1);
+
+    let runtime = Runtime::new()?;
+    runtime.block_on(s3.purge_all_versions_of_everything("bucket", "", false))?;
+
+    assert_eq!(vec![2, 2, 2], *s3.client.delete_requests.lock().unwrap());
+    let left = runtime.block_on(s3.get_object_versions("bucket", "", false))?;
+    assert!(left.is_empty());
+    Ok(())
+}
+
+#[test]
+fn test_purge_reports_undeleted_versions_and_missing_buckets() -> Result<()> {
+    let s3 = fake_wrapper(FakeS3::new(10).with_bucket("bucket", true).with_undeletable("b"));
+    for key in ["a", "b", "b"] {
+        s3.client.put("bucket", key, 1);
+    }
+
+    let runtime = Runtime::new()?;
+    let error = runtime
+        .block_on(s3.purge_all_versions_of_everything("bucket", "", false))
+        .unwrap_err();
+    assert_eq!(Failure::Partial.code(), exit_code(&error));
+    let left = runtime.block_on(s3.get_object_versions("bucket", "", false))?;
+    assert_eq!(vec!["b", "b"], left.iter().filter_map(|v| v.key()).collect::<Vec<_>>());
+
+    let error = runtime.block_on(s3.is_versioning_enabled("missing")).unwrap_err();
+    assert_eq!(Failure::InvalidTarget.code(), exit_code(&error));
+    Ok(())
+}
@@ -1,39 +1,91 @@
-use std::fmt::Display;
+use std::{fmt::Display, str::FromStr};
 
-use color_eyre::{Result, eyre::{OptionExt}};
-use regex::Regex;
+use color_eyre::{Report, Result, eyre::bail};
 
+/// Longest key S3 accepts, in bytes
+pub const MAX_KEY_BYTES: usize = 1024;
+
+/// A bucket, and a prefix of the keys within it, given as `s3://bucket/prefix`, or without the
+/// scheme
+#[derive(Debug, Clone, PartialEq, Eq)]
 pub struct S3Location {
     pub bucket: String,
+    /// Key prefix, without any trailing slash.  Empty for a whole bucket.
     pub prefix: String,
+    /// Whether the prefix ended in a slash, i.e. names a "directory", so `logs/` covers
+    /// `logs/a` but not `logs-old/a`
+    pub trailing_slash: bool,
 }
 impl S3Location {
+    /**
+     * Parse `s3://bucket/prefix`, `bucket/prefix` or just `bucket`.  Everything after the
+     * bucket's slash is the prefix, as keys can hold any character, including spaces and
+     * further slashes.
+     */
     pub fn parse(s3_location: &str) -> Result<S3Location> {
-        let s3_path_re = Regex::new(
-            // https://regex101.com/r/wAmOQU/1
-            r#"^([Ss]3://)?(?P<bucket>[^/]*)(?P<prefix>[\w/.-]*)$"#,
-        )?;
-
-        let captures = s3_path_re
-            .captures(s3_location)
-            .ok_or_eyre("No regex matches.")?;
-        let bucket = captures
-            .name("bucket")
-            .ok_or_eyre("Bucket capture group found no matches.")?
-            .as_str()
-            .to_string();
-        let prefix = captures
-            .name("prefix")
-            .ok_or_eyre("Prefix capture group found no matches.")?
-            .as_str();
-        let prefix = prefix.strip_prefix('/').unwrap_or(prefix);
-        let prefix = prefix.strip_suffix('/').unwrap_or(prefix).to_string();
-
-        Ok(S3Location { bucket, prefix })
+        // Lists such as "a, b" leave leading spaces, which can't be part of a bucket name
+        let location = s3_location.trim_start();
+        // A "://" after the bucket is part of the prefix
+        let location = match location.split_once("://") {
+            Some((scheme, rest)) if scheme.eq_ignore_ascii_case("s3") => rest,
+            Some((scheme, _)) if !scheme.contains('/') => {
+                bail!("Unsupported scheme '{}://', expected s3://", scheme)
+            }
+            _ => location,
+        };
+
+        let (bucket, key) = location.split_once('/').unwrap_or((location, ""));
+        if bucket.is_empty() {
+            bail!("No bucket name");
+        }
+        if let Some(c) = bucket.chars().find(|&c| !is_bucket_char(c)) {
+            bail!(
+                "Bucket names can only have letters, digits, '.', '-' and '_', not {:?}",
+                c
+            );
+        }
+        if key.len() > MAX_KEY_BYTES {
+            bail!("Prefix is {} bytes, longer than S3 keys can be ({})", key.len(), MAX_KEY_BYTES);
+        }
+
+        let (prefix, trailing_slash) = match key.strip_suffix('/') {
+            Some(prefix) if !prefix.is_empty() => (prefix, true),
+            _ => (key, false),
+        };
+        Ok(S3Location {
+            bucket: bucket.to_string(),
+            prefix: prefix.to_string(),
+            trailing_slash,
+        })
+    }
+
+    /**
+     * The prefix to list keys with, including the trailing slash if one was given
+     */
+    pub fn key_prefix(&self) -> String {
+        match self.trailing_slash {
+            true => format!("{}/", self.prefix),
+            false => self.prefix.clone(),
+        }
+    }
+}
+impl FromStr for S3Location {
+    type Err = Report;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        S3Location::parse(s)
     }
 }
 impl Display for S3Location {
     fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
-        f.write_fmt(format_args!("s3://{}/{}", self.bucket, self.prefix))
+        f.write_fmt(format_args!("s3://{}/{}", self.bucket, self.key_prefix()))
     }
 }
+
+/**
+ * Whether `c` can be in a bucket name.  Uppercase and underscores are only allowed in
+ * legacy buckets, but those still exist.
+ */
+fn is_bucket_char(c: char) -> bool {
+    c.is_ascii_alphanumeric() || matches!(c, '.' | '-' | '_')
+}
@@ -1,16 +1,32 @@
-use std::io::Write;
+use std::{collections::HashSet, io::Write};
 
-use aws_sdk_s3::{operation::{list_object_versions::ListObjectVersionsOutput, list_objects_v2::ListObjectsV2Output}, types::{BucketVersioningStatus, Delete, Object, ObjectIdentifier, ObjectVersion}, Client};
+use aws_sdk_s3::{
+    error::ProvideErrorMetadata,
+    operation::list_object_versions::ListObjectVersionsOutput,
+    types::{Bucket, BucketVersioningStatus, Delete, Object, ObjectIdentifier, ObjectVersion},
+    Client,
+};
 use human_format::Formatter;
+use tracing::Instrument;
 
-use color_eyre::{Result, eyre::{Context, OptionExt}};
+use color_eyre::{Result, eyre::Context};
 
+use crate::{exit::Failure, output::{progress::{self, ProgressEvent}, units::format_size}};
 
-pub struct S3Wrapper {
-    pub client: Client
+use super::{
+    client::{NO_LIFECYCLE, S3Api},
+    metadata::{BucketMetadata, VersioningStatus},
+};
+
+
+/// S3 operations spanning many requests, through the SDK's [`Client`] or anything else
+/// implementing [`S3Api`]
+pub struct S3Wrapper<C = Client> {
+    pub client: C
 }
 
-impl S3Wrapper {
+impl<C: S3Api> S3Wrapper<C> {
+    #[tracing::instrument(level = "debug", skip(self, verbose))]
     pub async fn get_object_versions(&self, bucket: &str, prefix: &str, verbose: bool) -> Result<Vec<ObjectVersion>> {
         let pages = self.get_versions(bucket, prefix, verbose).await?;
         let object_versions: Vec<ObjectVersion> = pages.into_iter()
@@ -21,34 +37,24 @@ impl S3Wrapper {
         Ok(object_versions)
     }
 
+    #[tracing::instrument(level = "debug", skip(self))]
     pub async fn list_objects_v2(&self, bucket: &str, prefix: &str) -> Result<Vec<Object>> {
         let mut acc: Vec<Object> = Vec::new();
 
-        async fn next_page(
-            client: &Client,
-            bucket: &str,
-            prefix: &str,
-            c_tok: Option<String>,
-        ) -> Result<ListObjectsV2Output> {
-            client
-                .list_objects_v2()
-                .bucket(bucket)
-                .prefix(prefix)
-                .set_continuation_token(c_tok)
-                .send()
-                .await
-                .map_err(|e| e.into())
-        }
-
         let mut c_token = None;
-        loop {
-            let list_output = next_page(&self.client, bucket, prefix, c_token).await?;
+        for page in 0.. {
+            let list_output = self.client.list_objects_v2(bucket, prefix, c_token)
+                .instrument(tracing::debug_span!("page", page))
+                .await?;
 
             c_token = list_output.next_continuation_token().map(str::to_string);
 
+            let items = list_output.contents().len();
             if let Some(mut items) = list_output.contents {
                 acc.append(&mut items);
             }
+            let total_items = acc.len();
+            progress::emit(&ProgressEvent::PageListed { bucket, prefix, page, items, total_items });
 
             if c_token.is_none() {
                 break;
@@ -58,59 +64,105 @@ impl S3Wrapper {
         Ok(acc)
     }
 
+    #[tracing::instrument(level = "debug", skip(self))]
     pub async fn is_versioning_enabled(&self, bucket: &str) -> Result<bool> {
-        self
-            .client
-            .get_bucket_versioning()
-            .bucket(bucket) 
-            .send()
-            .await?
-            .status
-            .map(|s| s == BucketVersioningStatus::Enabled)
-            .ok_or_eyre("Error during version checking")
+        Ok(self.versioning_status(bucket).await? == VersioningStatus::Enabled)
     }
 
-    // TODO combine with pub above?
-    async fn get_versions(&self, bucket: &str, prefix: &str, verbose: bool) -> Result<Vec<ListObjectVersionsOutput>> {
-        async fn next_page(
-            client: &Client,
-            bucket: &str,
-            prefix: &str,
-            next_key: Option<String>,
-            next_version: Option<String>,
-        ) -> Result<ListObjectVersionsOutput> {
-            client
-                .list_object_versions()
-                .bucket(bucket)
-                .prefix(prefix)
-                .set_key_marker(next_key)
-                .set_version_id_marker(next_version)
-                .send()
-                .await
-                .map_err(|e| e.into())
+    #[tracing::instrument(level = "debug", skip(self))]
+    pub async fn versioning_status(&self, bucket: &str) -> Result<VersioningStatus> {
+        // Buckets which have never had versioning turned on have no status
+        let status = self.client.get_bucket_versioning(bucket).await?.status;
+        Ok(match status {
+            Some(BucketVersioningStatus::Enabled) => VersioningStatus::Enabled,
+            Some(BucketVersioningStatus::Suspended) => VersioningStatus::Suspended,
+            _ => VersioningStatus::Never,
+        })
+    }
+
+    /**
+     * The region, creation date, lifecycle rules and versioning status of `bucket`.  Only
+     * failing to read the versioning status is an error, as that's needed for a report
+     * anyway.  Anything else which can't be read, e.g. for lack of permission, is logged and
+     * left out.
+     */
+    #[tracing::instrument(level = "debug", skip(self))]
+    pub async fn bucket_metadata(&self, bucket: &str) -> Result<BucketMetadata> {
+        let versioning = self.versioning_status(bucket).await?;
+        let listed = self.find_bucket(bucket).await
+            .inspect_err(|e| {
+                log::warn!("Failed to look up region and creation date of {}: {:#}", bucket, e)
+            })
+            .ok()
+            .flatten();
+        let lifecycle_rules = match self.client.get_bucket_lifecycle_configuration(bucket).await {
+            Ok(lifecycle) => Some(!lifecycle.rules().is_empty()),
+            Err(e) if e.code() == Some(NO_LIFECYCLE) => Some(false),
+            Err(e) => {
+                log::warn!("Failed to read lifecycle rules of {}: {}", bucket, e);
+                None
+            }
+        };
+
+        Ok(BucketMetadata {
+            region: listed.as_ref().and_then(|b| b.bucket_region()).map(str::to_string),
+            created: listed.as_ref()
+                .and_then(|b| b.creation_date())
+                .and_then(|created| chrono::DateTime::from_timestamp(created.secs(), 0)),
+            lifecycle_rules,
+            versioning: Some(versioning),
+        })
+    }
+
+    /**
+     * The bucket named `bucket` as S3 lists it, if the credentials can see it
+     */
+    async fn find_bucket(&self, bucket: &str) -> Result<Option<Bucket>> {
+        let mut c_token = None;
+        loop {
+            let page = self.client.list_buckets(bucket, c_token).await?;
+            c_token = page.continuation_token().map(str::to_string);
+            let found = page.buckets.into_iter().flatten().find(|b| b.name() == Some(bucket));
+            if found.is_some() {
+                return Ok(found);
+            }
+            if c_token.is_none() {
+                return Ok(None);
+            }
         }
+    }
 
+    // TODO combine with pub above?
+    #[tracing::instrument(level = "debug", skip(self, verbose))]
+    async fn get_versions(&self, bucket: &str, prefix: &str, verbose: bool) -> Result<Vec<ListObjectVersionsOutput>> {
         let mut next_key = None;
         let mut next_version = None;
 
         let mut acc: Vec<ListObjectVersionsOutput> = Vec::new();
         let mut prev_records_counter: usize = 0;
+        let mut total_items = 0;
         let mut formatter = Formatter::new();
         formatter.with_decimals(1);
 
-        if verbose {print!("Requesting version pages ...")};
-        let mut h = std::io::stdout();
-        loop {
+        // On stderr, so as not to mix with a report written to stdout
+        if verbose {eprint!("Requesting version pages ...")};
+        let mut h = std::io::stderr();
+        for page in 0.. {
             if verbose {
                 write!(h, "." )?;
                 h.flush()?;
             }
 
-            let out = next_page(&self.client, bucket, prefix, next_key, next_version).await?;
+            let out = self.client.list_object_versions(bucket, prefix, next_key, next_version)
+                .instrument(tracing::debug_span!("page", page))
+                .await?;
 
             next_key = out.next_key_marker.clone();
             next_version = out.next_version_id_marker.clone();
+            let items = out.versions().len() + out.delete_markers().len();
             acc.push(out);
+            total_items += items;
+            progress::emit(&ProgressEvent::PageListed { bucket, prefix, page, items, total_items });
 
             let records_so_far = acc.iter().map(|v|v.versions().len()).sum::<usize>();
             if records_so_far - prev_records_counter > 20000 {
@@ -122,17 +174,19 @@ impl S3Wrapper {
                 break;
             }
         }
-        println!(" done");
+        if verbose {eprintln!(" done")};
 
         Ok(acc)
     }
 
+    #[tracing::instrument(level = "debug", skip(self, verbose))]
     pub async fn purge_all_versions_of_everything(&self, bucket: &str, prefix: &str, verbose: bool) -> Result<()> {
         //TODO
         // self.assert_versioning_active().await?;
         let version_pages = self.get_versions(bucket, prefix, verbose).await?;
 
-        for page in version_pages {
+        let mut totals = PurgeTotals::default();
+        for (page_number, page) in version_pages.into_iter().enumerate() {
             let mut object_identifiers = Vec::new();
 
             let object_versions = page.versions.unwrap_or_default();
@@ -146,34 +200,130 @@ impl S3Wrapper {
                     .expect("Build error for delete markers.")
             });
             object_identifiers.extend(it);
-
-            let it = object_versions.into_iter().map(|item| {
-                ObjectIdentifier::builder()
-                    .set_version_id(item.version_id)
-                    .set_key(item.key)
-                    .build()
-                    .expect("Build error for object versions.")
-            });
-            object_identifiers.extend(it);
+            for version in &object_versions {
+                object_identifiers.push(version_identifier(version)?);
+            }
 
             if !object_identifiers.is_empty() {
-                log::info!("Deleting {} identifiers", object_identifiers.len());
-                self.client
-                    .delete_objects()
-                    .bucket(bucket)
-                    .delete(
-                        Delete::builder()
-                                .set_objects(Some(object_identifiers))
-                                .build()
-                                .wrap_err("Build error on Delete::builder")?
-                        )
-                    .send()
+                let versions = &object_versions;
+                self.delete_batch(bucket, page_number, object_identifiers, versions, &mut totals)
                     .await?;
             } else {
                 log::info!("Nothing to delete")
             }
         }
 
+        totals.result()
+    }
+
+    /**
+     * Delete exactly `versions` from `bucket`, e.g. the orphans
+     * [`list_orphans`](super::orphans::list_orphans) found, leaving everything else alone.
+     * Versions are deleted by ID, so an object written again since they were listed keeps
+     * its new version.
+     */
+    #[tracing::instrument(level = "debug", skip(self, versions))]
+    pub async fn delete_versions(&self, bucket: &str, versions: &[ObjectVersion]) -> Result<()> {
+        let mut totals = PurgeTotals::default();
+        for (batch_number, batch) in versions.chunks(MAX_DELETE_BATCH).enumerate() {
+            let identifiers = batch.iter().map(version_identifier).collect::<Result<_>>()?;
+            self.delete_batch(bucket, batch_number, identifiers, batch, &mut totals).await?;
+        }
+
+        totals.result()
+    }
+
+    /**
+     * Delete `identifiers` in one request, adding the outcome to `totals`.  Only the sizes of
+     * `versions` count towards the bytes deleted, as delete markers have none.
+     */
+    async fn delete_batch(
+        &self,
+        bucket: &str,
+        page: usize,
+        identifiers: Vec<ObjectIdentifier>,
+        versions: &[ObjectVersion],
+        totals: &mut PurgeTotals,
+    ) -> Result<()> {
+        log::info!("Deleting {} identifiers", identifiers.len());
+        let delete = Delete::builder()
+            .set_objects(Some(identifiers))
+            .build()
+            .wrap_err("Build error on Delete::builder")?;
+        let output = self.client
+            .delete_objects(bucket, delete)
+            .instrument(tracing::debug_span!("delete", page))
+            .await?;
+        // Objects which couldn't be deleted don't fail the request as a whole
+        for error in output.errors() {
+            log::error!(
+                "Failed to delete {} (version {}): {}",
+                error.key().unwrap_or_default(),
+                error.version_id().unwrap_or_default(),
+                error.message().or(error.code()).unwrap_or_default()
+            );
+        }
+        totals.failed += output.errors().len();
+
+        let not_deleted: HashSet<(&str, &str)> = output.errors().iter()
+            .map(|e| (e.key().unwrap_or_default(), e.version_id().unwrap_or_default()))
+            .collect();
+        let bytes = versions.iter()
+            .filter(|v| {
+                let id = (v.key().unwrap_or_default(), v.version_id().unwrap_or_default());
+                !not_deleted.contains(&id)
+            })
+            .filter_map(|v| v.size)
+            .sum::<i64>() as u64;
+        totals.deleted += output.deleted().len();
+        totals.bytes += bytes;
+        progress::emit(&ProgressEvent::VersionsDeleted {
+            bucket,
+            page,
+            deleted: output.deleted().len(),
+            failed: output.errors().len(),
+            bytes,
+            total_deleted: totals.deleted,
+            total_bytes: totals.bytes,
+        });
+        Ok(())
+    }
+}
+
+/// Most object versions S3 deletes in one request
+const MAX_DELETE_BATCH: usize = 1000;
+
+/// Running totals of a purge, across its delete requests
+#[derive(Default)]
+struct PurgeTotals {
+    failed: usize,
+    deleted: usize,
+    bytes: u64,
+}
+impl PurgeTotals {
+    /**
+     * A partial failure if anything couldn't be deleted
+     */
+    fn result(&self) -> Result<()> {
+        let bytes = format_size(self.bytes);
+        log::info!("Deleted {} versions and delete markers, {}", self.deleted, bytes);
+        if self.failed > 0 {
+            let message = format!("Failed to delete {} object versions", self.failed);
+            return Err(Failure::Partial.because(message));
+        }
         Ok(())
     }
 }
+
+/**
+ * What to delete `version` by, which fails if it has no key
+ */
+fn version_identifier(version: &ObjectVersion) -> Result<ObjectIdentifier> {
+    ObjectIdentifier::builder()
+        .set_version_id(version.version_id.clone())
+        .set_key(version.key.clone())
+        .build()
+        .wrap_err_with(|| {
+            format!("Can't delete version {} without a key", version.version_id().unwrap_or("none"))
+        })
+}